@@ -0,0 +1,300 @@
+use std::cell::RefCell;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+
+use libc;
+
+use adapter::Adapter;
+use agent::AgentError;
+use common;
+use device::Device;
+use error::BtError;
+use media::{self, AcquiredTransport, MediaEndpoint, MediaEndpointManager, MediaTransport, SbcCapabilities, TransportConfiguration, TransportState};
+use registry::SignalWatch;
+use uuid;
+
+static A2DP_SINK_OBJ_PATH: &'static str = "/io/bluezrs/a2dp_sink";
+
+// The length of the SBC frame starting with header, None if it isn't one
+fn sbc_frame_length(header: &[u8]) -> Option<usize> {
+    if header.len() < 3 || header[0] != 0x9c {
+        return None;
+    }
+
+    let blocks = [4, 8, 12, 16][((header[1] >> 4) & 0x03) as usize];
+    let channel_mode = (header[1] >> 2) & 0x03;
+    let subbands = if header[1] & 0x01 != 0 { 8 } else { 4 };
+    let bitpool = header[2] as usize;
+    let channels = if channel_mode == 0 { 1 } else { 2 };
+
+    let bits = match channel_mode {
+        // Mono and dual channel
+        0 | 1 => blocks * channels * bitpool,
+        2 => blocks * bitpool,
+        // Joint stereo
+        _ => subbands + blocks * bitpool,
+    };
+
+    Some(4 + (4 * subbands * channels) / 8 + bits.div_ceil(8))
+}
+
+// The payload of an RTP packet
+fn rtp_payload(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+
+    let mut offset = 12 + (packet[0] & 0x0f) as usize * 4;
+    if packet[0] & 0x10 != 0 {
+        let ext = packet.get(offset + 2..offset + 4);
+        offset += 4 + ext.map(|x| (x[0] as usize) << 8 | x[1] as usize).unwrap_or(0) * 4;
+    }
+
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.saturating_sub(packet[end - 1] as usize);
+    }
+
+    packet.get(offset..end)
+}
+
+#[derive(Default)]
+struct SinkState {
+    transport: Option<MediaTransport>,
+    device: Option<Device>,
+    configuration: Option<SbcCapabilities>,
+}
+
+type SharedSinkStateT = Rc<RefCell<SinkState>>;
+
+struct SinkEndpoint {
+    capabilities: SbcCapabilities,
+    state: SharedSinkStateT,
+}
+
+impl MediaEndpoint for SinkEndpoint {
+    fn get_object_path(&self) -> &str {
+        A2DP_SINK_OBJ_PATH
+    }
+
+    fn get_uuid(&self) -> &str {
+        uuid::A2DP_SINK_UUID
+    }
+
+    fn get_codec(&self) -> u8 {
+        media::A2DP_CODEC_SBC
+    }
+
+    fn get_capabilities(&self) -> Vec<u8> {
+        self.capabilities.to_bytes()
+    }
+
+    fn select_configuration(&self, capabilities: &[u8]) -> Result<Vec<u8>, AgentError> {
+        SbcCapabilities::from_bytes(capabilities)
+            .and_then(|x| self.capabilities.select(&x))
+            .map(|x| x.to_bytes())
+            .ok_or(AgentError::Rejected)
+    }
+
+    fn set_configuration(&self, transport_object_path: &str, configuration: TransportConfiguration) -> Result<(), AgentError> {
+        let mut state = self.state.borrow_mut();
+        if state.transport.is_some() {
+            return Err(AgentError::Rejected);
+        }
+
+        state.transport = Some(MediaTransport::new(configuration.device.conn(), transport_object_path));
+        state.configuration = SbcCapabilities::from_bytes(&configuration.configuration);
+        state.device = Some(configuration.device);
+        Ok(())
+    }
+
+    fn clear_configuration(&self, transport_object_path: &str) {
+        let mut state = self.state.borrow_mut();
+        if state.transport.as_ref().map(|x| x.object_path() == transport_object_path).unwrap_or(false) {
+            *state = SinkState::default();
+        }
+    }
+
+    fn release(&self) {
+        *self.state.borrow_mut() = SinkState::default();
+    }
+}
+
+// Receives A2DP audio from one device at a time (e.g. a phone) and hands out the SBC frames,
+// decoding them (e.g. with libsbc) is left to the application
+pub struct A2dpSink {
+    conn: super::Connection,
+    state: SharedSinkStateT,
+    _manager: MediaEndpointManager,
+}
+
+impl A2dpSink {
+    pub fn new(adapter: &Adapter, capabilities: SbcCapabilities) -> Result<A2dpSink, BtError> {
+        let state: SharedSinkStateT = Rc::new(RefCell::new(SinkState::default()));
+        let manager = MediaEndpointManager::new(adapter, Box::new(SinkEndpoint { capabilities: capabilities, state: state.clone() }));
+        try!(manager.register_endpoint());
+
+        Ok(A2dpSink { conn: adapter.conn().clone(), state: state, _manager: manager })
+    }
+
+    // The configuration the source picked, once a device is connected
+    pub fn configuration(&self) -> Option<SbcCapabilities> {
+        self.state.borrow().configuration
+    }
+
+    pub fn device(&self) -> Option<Device> {
+        self.state.borrow().device.clone()
+    }
+
+    // Serves the connection and calls f for every received SBC frame until f returns false.
+    // The stream is acquired whenever the source starts it and released when it stops.
+    pub fn run<F>(&self, mut f: F) -> Result<(), BtError> where F: FnMut(&Device, &[u8]) -> bool {
+        let filter = format!("sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='{}'",
+                             common::SERVICE_NAME, media::MEDIA_TRANSPORT_INTERFACE);
+        let watch = try!(SignalWatch::new(&self.conn, &[filter]));
+
+        let result = self.run_loop(&watch, &mut f);
+
+        try!(watch.close());
+        result
+    }
+
+    fn run_loop(&self, watch: &SignalWatch, f: &mut FnMut(&Device, &[u8]) -> bool) -> Result<(), BtError> {
+        let mut acquired: Option<AcquiredTransport> = None;
+        let mut buf = Vec::new();
+
+        loop {
+            // Only waits on the bus while there is no stream to wait on
+            let timeout = if acquired.is_some() { 0 } else { 100 };
+            self.conn.registry().process(timeout);
+            while let Some(s) = watch.take_queued() {
+                let transport = self.state.borrow().transport.clone();
+                let transport = match transport {
+                    Some(ref transport) if s.path().map(|x| *x == *transport.object_path()).unwrap_or(false) => transport.clone(),
+                    _ => continue,
+                };
+
+                let state = common::dbus_properties_changed(&s, media::MEDIA_TRANSPORT_INTERFACE)
+                    .and_then(|x| x.get("State").and_then(|x| x.inner().ok()).and_then(TransportState::from_str));
+                match state {
+                    Some(TransportState::Pending) if acquired.is_none() => {
+                        acquired = transport.try_acquire().ok();
+                    }
+                    Some(TransportState::Idle) => acquired = None,
+                    _ => {}
+                }
+            }
+
+            // Dropped along with the configuration (ClearConfiguration)
+            if self.state.borrow().transport.is_none() {
+                acquired = None;
+            }
+
+            let stream = match acquired {
+                Some(ref mut stream) => stream,
+                None => continue,
+            };
+
+            let mut pfd = libc::pollfd { fd: stream.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut pfd, 1, 100) } <= 0 {
+                continue;
+            }
+
+            buf.resize(stream.read_mtu().max(1) as usize, 0);
+            let len = match stream.read(&mut buf) {
+                Ok(len) if len > 0 => len,
+                _ => { acquired = None; continue; }
+            };
+
+            let device = match self.state.borrow().device.clone() {
+                Some(device) => device,
+                None => continue,
+            };
+
+            // The media payload header (fragmentation flags, number of frames), followed by the frames.
+            // Fragmented frames only happen with MTUs smaller than a frame and are skipped.
+            let payload = match rtp_payload(&buf[..len]) {
+                Some(payload) if !payload.is_empty() && payload[0] & 0x80 == 0 => &payload[1..],
+                _ => continue,
+            };
+
+            let mut offset = 0;
+            while let Some(frame_len) = sbc_frame_length(&payload[offset..]) {
+                let frame = match payload.get(offset..offset + frame_len) {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                if !f(&device, frame) {
+                    return Ok(());
+                }
+                offset += frame_len;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rtp_payload, sbc_frame_length};
+
+    #[test]
+    fn sbc_frame_length_of_known_frames() {
+        // 44.1 kHz, 16 blocks, joint stereo, 8 subbands, bitpool 53 (the usual high quality setting)
+        assert_eq!(sbc_frame_length(&[0x9c, 0xbd, 53, 0x00]), Some(119));
+        // 16 blocks, mono, 8 subbands, bitpool 31
+        assert_eq!(sbc_frame_length(&[0x9c, 0x31, 31, 0x00]), Some(70));
+        // 16 blocks, stereo, 8 subbands, bitpool 35
+        assert_eq!(sbc_frame_length(&[0x9c, 0x39, 35, 0x00]), Some(4 + 8 + 70));
+        // 4 blocks, dual channel, 4 subbands, bitpool 2
+        assert_eq!(sbc_frame_length(&[0x9c, 0x04, 2, 0x00]), Some(4 + 4 + 2));
+    }
+
+    #[test]
+    fn sbc_frame_length_of_invalid_headers() {
+        assert_eq!(sbc_frame_length(&[]), None);
+        assert_eq!(sbc_frame_length(&[0x9c, 0xbd]), None);
+        assert_eq!(sbc_frame_length(&[0x00, 0xbd, 53, 0x00]), None);
+        assert!(sbc_frame_length(&[0x9c, 0xff, 0xff]).is_some());
+    }
+
+    #[test]
+    fn rtp_payload_of_known_packets() {
+        let mut packet = vec![0x80, 0x60, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(rtp_payload(&packet), Some(&[1u8, 2, 3][..]));
+
+        // One CSRC
+        let mut packet = vec![0x81, 0x60, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 9, 9, 9, 9];
+        packet.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(rtp_payload(&packet), Some(&[1u8, 2, 3][..]));
+
+        // A header extension of one word
+        let mut packet = vec![0x90, 0x60, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0xbe, 0xde, 0x00, 0x01, 9, 9, 9, 9];
+        packet.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(rtp_payload(&packet), Some(&[1u8, 2, 3][..]));
+
+        // Two bytes of padding
+        let mut packet = vec![0xa0, 0x60, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&[1, 2, 3, 0, 2]);
+        assert_eq!(rtp_payload(&packet), Some(&[1u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn rtp_payload_of_malformed_packets() {
+        assert_eq!(rtp_payload(&[]), None);
+        assert_eq!(rtp_payload(&[0x80, 0x60, 0x00]), None);
+        // RTP version 1
+        assert_eq!(rtp_payload(&[0x40, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1]), None);
+        // 15 CSRCs announced, none there
+        assert_eq!(rtp_payload(&[0x8f, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1]), None);
+        // Truncated header extension
+        assert_eq!(rtp_payload(&[0x90, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0xbe]), None);
+        // Extension longer than the packet
+        assert_eq!(rtp_payload(&[0x90, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0xbe, 0xde, 0xff, 0xff]), None);
+        // More padding than payload
+        assert_eq!(rtp_payload(&[0xa0, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff]), None);
+        // Only a header
+        assert_eq!(rtp_payload(&[0x80, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]), Some(&[][..]));
+    }
+}
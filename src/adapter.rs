@@ -1,11 +1,18 @@
 use std::collections::BTreeMap;
-use std::time::Instant;
+use std::time::Duration;
 
 use dbus;
 
+use admin_policy;
+use class::ClassOfDevice;
 use common;
-use device::{self, Device};
+use device::{self, AddressType, Device};
+use discovery::{DiscoveryEvents, DiscoverySessionBuilder};
 use error::BtError;
+use mgmt;
+use network;
+use properties::{self, PropertyValue};
+use registry::SignalWatch;
 
 pub static ADAPTER_INTERFACE: &'static str = "org.bluez.Adapter1";
 
@@ -15,6 +22,30 @@ pub struct Adapter {
     object_path: String,
 }
 
+// An Adapter which can be sent to other threads, see SyncConnection
+#[derive(Clone, Debug)]
+pub struct SyncAdapter {
+    conn: super::SyncConnection,
+    object_path: String,
+}
+
+impl SyncAdapter {
+    pub fn new(conn: &super::SyncConnection, object_path: &str) -> SyncAdapter {
+        SyncAdapter { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    // Runs f with the adapter on the I/O thread of the connection
+    pub fn run<T, F>(&self, f: F) -> Result<T, BtError>
+        where F: FnOnce(&Adapter) -> Result<T, BtError> + Send + 'static, T: Send + 'static {
+        let object_path = self.object_path.clone();
+        self.conn.run(move |conn| f(&Adapter::new(conn, &object_path)))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AdapterProperties {
     pub address: String,
@@ -22,6 +53,7 @@ pub struct AdapterProperties {
     pub alias: String,
     pub class: u32,
     pub powered: bool,
+    pub power_state: Option<PowerState>,
     pub discoverable: bool,
     pub discoverable_timeout: u32,
     pub pairable: bool,
@@ -29,9 +61,138 @@ pub struct AdapterProperties {
     pub discovering: bool,
     pub uuids: Vec<String>,
     pub modalias: Option<String>,
+    pub roles: Vec<String>,
+    pub experimental_features: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    On,
+    Off,
+    OffEnabling,
+    OnDisabling,
+    OffBlocked,
+}
+
+impl PowerState {
+    fn from_str(s: &str) -> Option<PowerState> {
+        match s {
+            "on" => Some(PowerState::On),
+            "off" => Some(PowerState::Off),
+            "off-enabling" => Some(PowerState::OffEnabling),
+            "on-disabling" => Some(PowerState::OnDisabling),
+            "off-blocked" => Some(PowerState::OffBlocked),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ControllerInfo {
+    pub manufacturer: u16,
+    pub hci_version: u8,
+    pub hci_revision: u16,
+    pub lmp_version: u8,
+    pub lmp_subversion: u16,
+}
+
+impl ControllerInfo {
+    pub fn bluetooth_version(&self) -> Option<&'static str> {
+        match self.hci_version {
+            0 => Some("1.0b"),
+            1 => Some("1.1"),
+            2 => Some("1.2"),
+            3 => Some("2.0"),
+            4 => Some("2.1"),
+            5 => Some("3.0"),
+            6 => Some("4.0"),
+            7 => Some("4.1"),
+            8 => Some("4.2"),
+            9 => Some("5.0"),
+            10 => Some("5.1"),
+            11 => Some("5.2"),
+            12 => Some("5.3"),
+            13 => Some("5.4"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscoveryTransport {
+    Auto,
+    BrEdr,
+    Le,
+}
+
+impl DiscoveryTransport {
+    fn to_str(self) -> &'static str {
+        match self {
+            DiscoveryTransport::Auto => "auto",
+            DiscoveryTransport::BrEdr => "bredr",
+            DiscoveryTransport::Le => "le",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DiscoveryFilter {
+    pub uuids: Vec<String>,
+    pub rssi: Option<i16>,
+    pub pathloss: Option<u16>,
+    pub transport: Option<DiscoveryTransport>,
+    pub duplicate_data: Option<bool>,
+    pub discoverable: Option<bool>,
+    pub pattern: Option<String>,
+}
+
+impl DiscoveryFilter {
+    pub(crate) fn to_message_item(&self) -> dbus::MessageItem {
+        let mut entries = Vec::new();
+        if !self.uuids.is_empty() {
+            let uuids = self.uuids.iter().map(|x| x.as_str().into()).collect();
+            entries.push(common::dbus_dict_entry("UUIDs", dbus::MessageItem::Array(uuids, "s".into())));
+        }
+        if let Some(rssi) = self.rssi {
+            entries.push(common::dbus_dict_entry("RSSI", rssi));
+        }
+        if let Some(pathloss) = self.pathloss {
+            entries.push(common::dbus_dict_entry("Pathloss", pathloss));
+        }
+        if let Some(transport) = self.transport {
+            entries.push(common::dbus_dict_entry("Transport", transport.to_str()));
+        }
+        if let Some(duplicate_data) = self.duplicate_data {
+            entries.push(common::dbus_dict_entry("DuplicateData", duplicate_data));
+        }
+        if let Some(discoverable) = self.discoverable {
+            entries.push(common::dbus_dict_entry("Discoverable", discoverable));
+        }
+        if let Some(ref pattern) = self.pattern {
+            entries.push(common::dbus_dict_entry("Pattern", pattern.as_str()));
+        }
+
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AdapterEvent {
+    Added(Adapter),
+    Removed(Adapter),
+}
+
+#[derive(Debug)]
+pub struct DisconnectReport {
+    pub disconnected: Vec<Device>,
+    pub failed: Vec<(Device, BtError)>,
 }
 
 impl Adapter {
+    pub fn new(conn: &super::Connection, object_path: &str) -> Self {
+        Adapter { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
     pub fn conn(&self) -> &super::Connection {
         &self.conn
     }
@@ -40,12 +201,38 @@ impl Adapter {
         &self.object_path
     }
 
+    // On the SyncConnection to the bus of this adapter's connection
+    pub fn to_sync(&self) -> Result<SyncAdapter, BtError> {
+        Ok(SyncAdapter::new(&try!(self.conn.to_sync()), &self.object_path))
+    }
+
     //
     // Properties
     //
     pub fn get_properties(&self) -> Result<AdapterProperties, BtError> {
         let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, ADAPTER_INTERFACE, 1000);
-        Ok(AdapterProperties::new(try!(p.get_all())))
+        AdapterProperties::new(try!(p.get_all()))
+    }
+
+    pub fn get_properties_with_timeout(&self, timeout: Duration) -> Result<AdapterProperties, BtError> {
+        AdapterProperties::new(try!(common::dbus_get_all_properties_with_timeout(&self.conn, &self.object_path, ADAPTER_INTERFACE, timeout)))
+    }
+
+    // Reads the controller's local version over a raw HCI socket, so it needs CAP_NET_RAW
+    pub fn get_controller_info(&self) -> Result<ControllerInfo, BtError> {
+        let index = try!(mgmt::adapter_index(&self.object_path));
+        let version = try!(mgmt::read_local_version(index));
+        if version.len() < 8 {
+            return Err(BtError::DBusInternal("invalid Read Local Version Information reply".to_string()));
+        }
+
+        Ok(ControllerInfo {
+            hci_version: version[0],
+            hci_revision: version[1] as u16 | (version[2] as u16) << 8,
+            lmp_version: version[3],
+            manufacturer: version[4] as u16 | (version[5] as u16) << 8,
+            lmp_subversion: version[6] as u16 | (version[7] as u16) << 8,
+        })
     }
 
     pub fn set_alias(&self, val: &str) -> Result<(), BtError> {
@@ -56,6 +243,30 @@ impl Adapter {
         common::dbus_set_property(&self.conn, &self.object_path, ADAPTER_INTERFACE, "Powered", val)
     }
 
+    // Powers the adapter on and blocks until BlueZ confirms it, after which discovery can be started
+    pub fn wait_powered(&self, timeout: Duration) -> Result<(), BtError> {
+        self.set_powered_and_wait(true, timeout)
+    }
+
+    // Powers the adapter off and back on, waiting up to settle_timeout for each transition
+    pub fn power_cycle(&self, settle_timeout: Duration) -> Result<(), BtError> {
+        try!(self.set_powered_and_wait(false, settle_timeout));
+        self.set_powered_and_wait(true, settle_timeout)
+    }
+
+    fn set_powered_and_wait(&self, val: bool, timeout: Duration) -> Result<(), BtError> {
+        try!(self.set_powered(val));
+        self.wait_for_property("Powered", val, timeout)
+    }
+
+    pub fn wait_for_property<T>(&self, name: &str, expected: T, timeout: Duration) -> Result<(), BtError> where T: Into<PropertyValue> {
+        properties::wait_for_property(&self.conn, &self.object_path, ADAPTER_INTERFACE, name, expected, timeout)
+    }
+
+    pub fn get_service_allow_list(&self) -> Result<Vec<String>, BtError> {
+        admin_policy::get_service_allow_list(&self.conn, &self.object_path)
+    }
+
     pub fn set_discoverable(&self, val: bool) -> Result<(), BtError> {
         common::dbus_set_property(&self.conn, &self.object_path, ADAPTER_INTERFACE, "Discoverable", val)
     }
@@ -79,84 +290,125 @@ impl Adapter {
         common::dbus_call_method0(&self.conn, &self.object_path, ADAPTER_INTERFACE, "StartDiscovery")
     }
 
-    pub fn start_discovery_session<F>(&self, duration: u32, mut f: F) -> Result<(), BtError> where F: FnMut(Device) -> () {
-        let conn = self.conn();
-
-        let filter1 = format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesAdded'", common::SERVICE_NAME);
-        let filter2 = format!("sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'", common::SERVICE_NAME);
-
-        try!(conn.add_match(&filter1));
-        try!(conn.add_match(&filter2));
-
-        try!(self.start_discovery());
-
-        let now = Instant::now();
+    // An empty (default) filter clears the current one
+    pub fn set_discovery_filter(&self, filter: &DiscoveryFilter) -> Result<(), BtError> {
+        common::dbus_call_method1(&self.conn, &self.object_path, ADAPTER_INTERFACE, "SetDiscoveryFilter", filter.to_message_item())
+    }
 
-        'outer: for i in conn.iter(100) {
-            if let dbus::ConnectionItem::Signal(ref s) = i {
-                let member = s.member().unwrap();
+    pub fn get_discovery_filters(&self) -> Result<Vec<String>, BtError> {
+        let reply = try!(common::dbus_call_method0_with_reply(&self.conn, &self.object_path, ADAPTER_INTERFACE, "GetDiscoveryFilters"));
+        parse_discovery_filters(&reply)
+    }
 
-                if &*member == "PropertiesChanged" && &*s.path().unwrap() == self.object_path() {
-                    let items = s.get_items();
+    pub fn discovery_session(&self) -> DiscoverySessionBuilder<'_> {
+        DiscoverySessionBuilder::new(self)
+    }
 
-                    let iface = items.get(0).unwrap();
-                    let iface: &str = iface.inner().unwrap();
+    pub fn discover(&self, filter: DiscoveryFilter) -> Result<DiscoveryEvents<'_>, BtError> {
+        Ok(try!(self.discovery_session().filter(filter).start()).events())
+    }
 
-                    if iface == ADAPTER_INTERFACE {
-                        let props = items.get(1).unwrap();
-                        let props: &[dbus::MessageItem] = props.inner().unwrap();
+    pub fn start_discovery_session<F>(&self, duration: u32, f: F) -> Result<(), BtError> where F: FnMut(Device) -> () {
+        let mut builder = self.discovery_session();
+        if duration > 0 {
+            builder = builder.duration(Duration::from_secs(duration as u64));
+        }
 
-                        for p in props {
-                            let (name, val) = p.inner().unwrap();
-                            let name: &str = name.inner().unwrap();
+        let mut session = try!(builder.start());
+        try!(session.run(f));
+        session.stop()
+    }
 
-                            if name == "Discovering" {
-                                let val: bool = (val.inner() as Result<&dbus::MessageItem, ()>).unwrap().inner().unwrap();
-                                if !val {
-                                    break 'outer;
-                                }
-                            }
-                        }
+    // Calls f with the new Connected state of any device of this adapter until f returns false
+    pub fn on_device_connection_changed<F>(&self, mut f: F) -> Result<(), BtError> where F: FnMut(Device, bool) -> bool {
+        let filter = format!("sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='{}',path_namespace='{}'",
+                             common::SERVICE_NAME, device::DEVICE_INTERFACE, self.object_path);
+        let watch = try!(SignalWatch::new(&self.conn, &[filter]));
+
+        while self.conn.registry().is_connected() {
+            if let Some(s) = watch.next_signal(100) {
+                let connected = common::dbus_properties_changed(&s, device::DEVICE_INTERFACE)
+                    .and_then(|props| props.get("Connected").and_then(|x| x.inner().ok()));
+
+                if let (Some(connected), Some(obj_path)) = (connected, s.path()) {
+                    let device = Device::new(&self.conn, &obj_path);
+                    if device.adapter_object_path() == self.object_path && !f(device, connected) {
+                        break;
                     }
                 }
+            }
+        }
 
-                else if &*member == "InterfacesAdded" {
-                    let items = s.get_items();
-
-                    let obj_path = items.get(0).unwrap();
-                    let obj_path: &str = obj_path.inner().unwrap();
-
-                    let dict = items.get(1).unwrap();
-                    let dict: &[dbus::MessageItem] = dict.inner().unwrap();
-
-                    for kv in dict {
-                        let (iface, _) = kv.inner().unwrap();
-                        let iface: &str = iface.inner().unwrap();
+        watch.close()
+    }
 
-                        if iface == device::DEVICE_INTERFACE {
-                            let device = Device::new(conn, obj_path);
-                            if device.adapter_object_path() == self.object_path() {
-                                f(device);
-                            }
-                        }
+    // Calls f for every device of this adapter that BlueZ drops (RemoveDevice, cache expiry) until f returns false.
+    // Handles of removed devices fail with UnknownObject from then on.
+    pub fn on_device_removed<F>(&self, mut f: F) -> Result<(), BtError> where F: FnMut(Device) -> bool {
+        let filter = format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesRemoved'", common::SERVICE_NAME);
+        let watch = try!(SignalWatch::new(&self.conn, &[filter]));
+
+        while self.conn.registry().is_connected() {
+            if let Some(s) = watch.next_signal(100) {
+                if let Some(obj_path) = common::dbus_interfaces_removed(&s, device::DEVICE_INTERFACE) {
+                    let device = Device::new(&self.conn, &obj_path);
+                    if device.adapter_object_path() == self.object_path && !f(device) {
+                        break;
                     }
                 }
             }
+        }
 
-            if duration > 0 && now.elapsed().as_secs() >= duration as u64 {
-                try!(self.stop_discovery());
-                break 'outer;
+        watch.close()
+    }
+
+    pub fn stop_discovery(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, ADAPTER_INTERFACE, "StopDiscovery")
+    }
+
+    // Starts disconnecting all connected devices at once, then waits for all of them. A device which
+    // fails is put in the report, the rest are still disconnected.
+    pub fn disconnect_all(&self) -> Result<DisconnectReport, BtError> {
+        let calls: Vec<_> = try!(device::get_devices_with_properties(self)).into_iter()
+            .filter(|x| x.1.connected)
+            .map(|(device, _)| {
+                let call = device.start_disconnect();
+                (device, call)
+            })
+            .collect();
+
+        // The replies of the other calls are collected while waiting for one
+        let mut report = DisconnectReport { disconnected: Vec::new(), failed: Vec::new() };
+        for (device, call) in calls {
+            match call.wait() {
+                Ok(()) => report.disconnected.push(device),
+                Err(e) => report.failed.push((device, e)),
             }
         }
 
-        try!(conn.remove_match(&filter1));
-        try!(conn.remove_match(&filter2));
+        Ok(report)
+    }
 
-        Ok(())
+    // Experimental in BlueZ, needs bluetoothd to run with --experimental
+    pub fn connect_device(&self, address: &str, address_type: Option<AddressType>) -> Result<Device, BtError> {
+        let reply = try!(common::dbus_call_method1_with_reply(&self.conn, &self.object_path, ADAPTER_INTERFACE, "ConnectDevice",
+                                                              connect_device_arg(address, address_type)));
+        parse_connect_device(&self.conn, &reply)
     }
 
-    pub fn stop_discovery(&self) -> Result<(), BtError> {
-        common::dbus_call_method0(&self.conn, &self.object_path, ADAPTER_INTERFACE, "StopDiscovery")
+    // The uuid is the served role, either a UUID (uuid::NAP_UUID etc.) or "nap"/"gn"/"panu".
+    // The bridge interface (e.g. "br0") has to exist already.
+    pub fn register_network_server(&self, uuid: &str, bridge: &str) -> Result<(), BtError> {
+        network::register_server(&self.conn, &self.object_path, uuid, bridge)
+    }
+
+    pub fn unregister_network_server(&self, uuid: &str) -> Result<(), BtError> {
+        network::unregister_server(&self.conn, &self.object_path, uuid)
+    }
+
+    // An empty list lifts the restriction
+    pub fn set_service_allow_list(&self, uuids: &[&str]) -> Result<(), BtError> {
+        admin_policy::set_service_allow_list(&self.conn, &self.object_path, uuids)
     }
 
     pub fn remove_device(&self, device: &Device) -> Result<(), BtError> {
@@ -164,33 +416,101 @@ impl Adapter {
         //if !device.object_path().starts_with(&self.object_path) {}
         common::dbus_call_method1(&self.conn, &self.object_path, ADAPTER_INTERFACE, "RemoveDevice", device.object_path())
     }
+
+    // Returns false if the adapter doesn't know a device with this address
+    pub fn remove_device_by_address(&self, address: &str) -> Result<bool, BtError> {
+        for (device, props) in try!(device::get_devices_with_properties(self)) {
+            if props.address.eq_ignore_ascii_case(address) {
+                try!(self.remove_device(&device));
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Returns the number of removed devices
+    pub fn remove_all_devices(&self, keep_paired: bool) -> Result<usize, BtError> {
+        let mut count = 0;
+        for (device, props) in try!(device::get_devices_with_properties(self)) {
+            if keep_paired && props.paired {
+                continue;
+            }
+            try!(self.remove_device(&device));
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+pub(crate) fn parse_discovery_filters(reply: &dbus::Message) -> Result<Vec<String>, BtError> {
+    let filters: Vec<String> = try!(reply.get1::<dbus::arg::Array<&str, _>>()
+        .ok_or_else(|| BtError::DBusInternal("invalid GetDiscoveryFilters reply".to_string())))
+        .map(|x| x.to_string())
+        .collect();
+    Ok(filters)
+}
+
+pub(crate) fn connect_device_arg(address: &str, address_type: Option<AddressType>) -> dbus::MessageItem {
+    let mut entries = vec![common::dbus_dict_entry("Address", address)];
+    match address_type {
+        Some(AddressType::Public) => entries.push(common::dbus_dict_entry("AddressType", "public")),
+        Some(AddressType::Random) => entries.push(common::dbus_dict_entry("AddressType", "random")),
+        None => {}
+    }
+    dbus::MessageItem::Array(entries, "{sv}".into())
+}
+
+pub(crate) fn parse_connect_device(conn: &super::Connection, reply: &dbus::Message) -> Result<Device, BtError> {
+    let device_obj_path: dbus::Path = try!(reply.get1().ok_or_else(|| BtError::DBusInternal("invalid ConnectDevice reply".to_string())));
+    Ok(Device::new(conn, &device_obj_path))
 }
 
 impl AdapterProperties {
-    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> AdapterProperties {
+    pub fn class_of_device(&self) -> ClassOfDevice {
+        ClassOfDevice::new(self.class)
+    }
+
+    // Roles are "central", "peripheral" and "central-peripheral"
+    pub fn supports_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|x| x == role)
+    }
+
+    pub(crate) fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> Result<AdapterProperties, BtError> {
 
         fn _get_prop<'a, T>(props_map: &'a BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<T>
             where T: dbus::FromMessageItem<'a> {
             props_map.get(name).and_then(|x| (x.inner() as Result<T, ()>).ok())
         }
 
-        AdapterProperties {
-            address: _get_prop::<&str>(&props_map, "Address").unwrap().to_string(),
-            name: _get_prop::<&str>(&props_map, "Name").unwrap().to_string(),
-            alias: _get_prop::<&str>(&props_map, "Alias").unwrap().to_string(),
-            class: _get_prop(&props_map, "Class").unwrap(),
-            powered: _get_prop(&props_map, "Powered").unwrap(),
-            discoverable: _get_prop(&props_map, "Discoverable").unwrap(),
-            discoverable_timeout: _get_prop(&props_map, "DiscoverableTimeout").unwrap(),
-            pairable: _get_prop(&props_map, "Pairable").unwrap(),
-            pairable_timeout: _get_prop(&props_map, "PairableTimeout").unwrap(),
-            discovering: _get_prop(&props_map, "Discovering").unwrap(),
-            uuids: _get_prop::<&[dbus::MessageItem]>(&props_map, "UUIDs").unwrap()
+        Ok(AdapterProperties {
+            address: try!(common::dbus_required_prop::<&str>(&props_map, "Address")).to_string(),
+            name: try!(common::dbus_required_prop::<&str>(&props_map, "Name")).to_string(),
+            alias: try!(common::dbus_required_prop::<&str>(&props_map, "Alias")).to_string(),
+            class: try!(common::dbus_required_prop(&props_map, "Class")),
+            powered: try!(common::dbus_required_prop(&props_map, "Powered")),
+            power_state: _get_prop::<&str>(&props_map, "PowerState").and_then(PowerState::from_str),
+            discoverable: try!(common::dbus_required_prop(&props_map, "Discoverable")),
+            discoverable_timeout: try!(common::dbus_required_prop(&props_map, "DiscoverableTimeout")),
+            pairable: try!(common::dbus_required_prop(&props_map, "Pairable")),
+            pairable_timeout: try!(common::dbus_required_prop(&props_map, "PairableTimeout")),
+            discovering: try!(common::dbus_required_prop(&props_map, "Discovering")),
+            uuids: _get_prop::<&[dbus::MessageItem]>(&props_map, "UUIDs").unwrap_or(&[])
                 .iter()
-                .map(|x| (x.inner() as Result<&str, ()>).unwrap().to_string())
+                .filter_map(|x| (x.inner() as Result<&str, ()>).ok())
+                .map(|x| x.to_string())
                 .collect(),
             modalias: _get_prop::<&str>(&props_map, "Modalias").map(|x| x.to_string()),
-        }
+            roles: _get_prop::<&[dbus::MessageItem]>(&props_map, "Roles").unwrap_or(&[])
+                .iter()
+                .filter_map(|x| (x.inner() as Result<&str, ()>).ok())
+                .map(|x| x.to_string())
+                .collect(),
+            experimental_features: _get_prop::<&[dbus::MessageItem]>(&props_map, "ExperimentalFeatures").unwrap_or(&[])
+                .iter()
+                .filter_map(|x| (x.inner() as Result<&str, ()>).ok())
+                .map(|x| x.to_string())
+                .collect(),
+        })
     }
 }
 
@@ -202,12 +522,45 @@ pub fn get_adapters(conn: &super::Connection) -> Result<Vec<Adapter>, BtError> {
     )
 }
 
-pub fn find_adapter(conn: &super::Connection, name_or_addr: Option<&str>) -> Result<Option<Adapter>, BtError> {
-    let adapters = try!(get_adapters(conn));
+pub fn get_adapters_with_properties(conn: &super::Connection) -> Result<Vec<(Adapter, AdapterProperties)>, BtError> {
+    let adapters = try!(common::dbus_get_managed_objects_with_props(conn,
+                                                                    "/",
+                                                                    ADAPTER_INTERFACE,
+                                                                    |conn, obj_path, props_map| AdapterProperties::new(props_map)
+                                                                        .map(|p| (Adapter { conn: conn, object_path: obj_path.to_string() }, p))
+    ));
+    adapters.into_iter().collect()
+}
+
+// Calls f whenever an adapter is plugged in or removed until f returns false
+pub fn watch_adapters<F>(conn: &super::Connection, mut f: F) -> Result<(), BtError> where F: FnMut(AdapterEvent) -> bool {
+    let filter1 = format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesAdded'", common::SERVICE_NAME);
+    let filter2 = format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesRemoved'", common::SERVICE_NAME);
+
+    let watch = try!(SignalWatch::new(conn, &[filter1, filter2]));
+
+    while conn.registry().is_connected() {
+        if let Some(s) = watch.next_signal(100) {
+            let event = if let Some(obj_path) = common::dbus_interfaces_added(&s, ADAPTER_INTERFACE) {
+                AdapterEvent::Added(Adapter { conn: conn.clone(), object_path: obj_path })
+            } else if let Some(obj_path) = common::dbus_interfaces_removed(&s, ADAPTER_INTERFACE) {
+                AdapterEvent::Removed(Adapter { conn: conn.clone(), object_path: obj_path })
+            } else {
+                continue;
+            };
+
+            if !f(event) {
+                break;
+            }
+        }
+    }
+
+    watch.close()
+}
 
+pub fn find_adapter(conn: &super::Connection, name_or_addr: Option<&str>) -> Result<Option<Adapter>, BtError> {
     if let Some(name_or_addr) = name_or_addr {
-        for adapter in adapters {
-            let p = try!(adapter.get_properties());
+        for (adapter, p) in try!(get_adapters_with_properties(conn)) {
             if p.address == name_or_addr || p.alias == name_or_addr || p.name == name_or_addr {
                 return Ok(Some(adapter));
             }
@@ -215,5 +568,5 @@ pub fn find_adapter(conn: &super::Connection, name_or_addr: Option<&str>) -> Res
         return Ok(None);
     }
 
-    Ok(adapters.into_iter().next())
+    Ok(try!(get_adapters(conn)).into_iter().next())
 }
@@ -0,0 +1,32 @@
+use std::fmt;
+
+use error::BtError;
+use mgmt;
+
+// A BD_ADDR, stored in the little-endian order of the kernel socket API
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BtAddress([u8; 6]);
+
+impl BtAddress {
+    pub const ANY: BtAddress = BtAddress([0; 6]);
+
+    // "AA:BB:CC:DD:EE:FF"
+    pub fn parse(address: &str) -> Result<BtAddress, BtError> {
+        mgmt::parse_address(address).map(BtAddress)
+    }
+
+    pub fn from_bytes(bytes: [u8; 6]) -> BtAddress {
+        BtAddress(bytes)
+    }
+
+    pub fn bytes(&self) -> [u8; 6] {
+        self.0
+    }
+}
+
+impl fmt::Display for BtAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = self.0;
+        write!(f, "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", b[5], b[4], b[3], b[2], b[1], b[0])
+    }
+}
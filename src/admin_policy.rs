@@ -0,0 +1,27 @@
+use dbus;
+
+use common;
+use error::BtError;
+
+pub static ADMIN_POLICY_SET_INTERFACE: &'static str = "org.bluez.AdminPolicySet1";
+pub static ADMIN_POLICY_STATUS_INTERFACE: &'static str = "org.bluez.AdminPolicyStatus1";
+
+// Only the services of the list can be used by remote devices, an empty list allows all of them.
+// Needs the admin plugin of bluetoothd.
+pub fn set_service_allow_list(conn: &super::Connection, adapter_object_path: &str, uuids: &[&str]) -> Result<(), BtError> {
+    let uuids = uuids.iter().map(|x| (*x).into()).collect();
+    common::dbus_call_method1(conn, adapter_object_path, ADMIN_POLICY_SET_INTERFACE, "SetServiceAllowList", dbus::MessageItem::Array(uuids, "s".into()))
+}
+
+// The active allow list of the adapter, empty if all services are allowed
+pub fn get_service_allow_list(conn: &super::Connection, adapter_object_path: &str) -> Result<Vec<String>, BtError> {
+    let val = try!(common::dbus_get_property(conn, adapter_object_path, ADMIN_POLICY_STATUS_INTERFACE, "ServiceAllowList"));
+    let uuids: &[dbus::MessageItem] = try!(val.inner().map_err(|_| BtError::DBusInternal("invalid ServiceAllowList value".to_string())));
+    Ok(uuids.iter().filter_map(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string()).collect())
+}
+
+// Whether some of the device's services are blocked by the allow list
+pub fn get_affected_by_policy(conn: &super::Connection, device_object_path: &str) -> Result<bool, BtError> {
+    let val = try!(common::dbus_get_property(conn, device_object_path, ADMIN_POLICY_STATUS_INTERFACE, "AffectedByPolicy"));
+    val.inner().map_err(|_| BtError::DBusInternal("invalid AffectedByPolicy value".to_string()))
+}
@@ -1,11 +1,18 @@
+use std::cell::{OnceCell, RefCell};
 use std::fmt;
 use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
 
 use dbus;
+use dbus::tree::{MethodErr, MethodResult};
 
 use common;
-use device::Device;
+use device::{Device, DeviceProperties};
+use discovery::StopHandle;
 use error::BtError;
+use registry::{ObjectHandlerT, Registration};
+use uuid;
 
 pub static AGENT_INTERFACE: &'static str = "org.bluez.Agent1";
 pub static AGENT_MANAGER_INTERFACE: &'static str = "org.bluez.AgentManager1";
@@ -20,7 +27,7 @@ pub enum AgentCapability {
 }
 
 impl AgentCapability {
-    fn to_str(&self) -> &'static str {
+    pub(crate) fn to_str(&self) -> &'static str {
         match *self {
             AgentCapability::DisplayOnly => "DisplayOnly",
             AgentCapability::DisplayYesNo => "DisplayYesNo",
@@ -34,15 +41,73 @@ impl AgentCapability {
 pub enum AgentError {
     Rejected,
     Canceled,
+    // Any D-Bus error name (e.g. "org.bluez.Error.AuthenticationTimeout") with a human-readable message
+    Custom { name: String, message: String },
 }
 
 impl AgentError {
-    fn bluez_error(&self) -> &'static str {
+    pub fn custom(name: &str, message: &str) -> AgentError {
+        AgentError::Custom { name: name.to_string(), message: message.to_string() }
+    }
+
+    // Custom names which aren't valid D-Bus error names are replaced by org.bluez.Error.Failed
+    pub(crate) fn bluez_error(&self) -> &str {
         match *self {
             AgentError::Rejected => "org.bluez.Error.Rejected",
             AgentError::Canceled => "org.bluez.Error.Canceled",
+            AgentError::Custom { ref name, .. } => {
+                if dbus::ErrorName::new(name.as_str()).is_ok() { name } else { "org.bluez.Error.Failed" }
+            }
+        }
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        match *self {
+            AgentError::Rejected => "Rejected by agent",
+            AgentError::Canceled => "Canceled by agent",
+            AgentError::Custom { ref message, .. } => message,
         }
     }
+
+    pub(crate) fn method_err(&self) -> dbus::tree::MethodErr {
+        (self.bluez_error().to_string(), self.message()).into()
+    }
+}
+
+// The device of an agent request. Its properties are fetched on first use and then kept, so
+// handlers that don't need them don't block the agent on a D-Bus call.
+#[derive(Clone, Debug)]
+pub struct PairingRequest {
+    pub device: Device,
+    // None inside if the properties couldn't be read
+    properties: OnceCell<Option<DeviceProperties>>,
+    // Taken from the object path (.../dev_AA_BB_CC_DD_EE_FF) if the properties can't be read
+    path_address: String,
+}
+
+impl PairingRequest {
+    pub fn new(device: Device) -> PairingRequest {
+        let path_address = device.object_path().rsplit('/').next().unwrap_or("").trim_start_matches("dev_").replace("_", ":");
+        PairingRequest { device: device, properties: OnceCell::new(), path_address: path_address }
+    }
+
+    // None if the properties couldn't be read
+    pub fn properties(&self) -> Option<&DeviceProperties> {
+        self.properties.get_or_init(|| self.device.get_properties().ok()).as_ref()
+    }
+
+    pub fn address(&self) -> &str {
+        self.properties().map(|x| x.address.as_str()).unwrap_or(&self.path_address)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.properties().and_then(|x| x.name.as_ref()).map(|x| x.as_str())
+    }
+
+    // The alias (which defaults to the name) or the address, for showing the device to the user
+    pub fn display_name(&self) -> &str {
+        self.properties().map(|x| x.alias.as_str()).unwrap_or(&self.path_address)
+    }
 }
 
 pub trait Agent {
@@ -54,15 +119,20 @@ pub trait Agent {
         AgentCapability::KeyboardDisplay
     }
 
-    fn request_pincode(&self, device: Device) -> Result<String, AgentError>;
-    fn display_pincode(&self, device: Device, pincode: &str) -> Result<(), AgentError>;
-    fn request_passkey(&self, device: Device) -> Result<u32, AgentError>;
-    fn display_passkey(&self, device: Device, passkey: u32, entered: u16);
-    fn request_confirmation(&self, device: Device, passkey: u32) -> Result<(), AgentError>;
-    fn request_authorization(&self, device: Device) -> Result<(), AgentError>;
-    fn authorize_service(&self, device: Device, uuid: &str) -> Result<(), AgentError>;
+    fn request_pincode(&self, request: PairingRequest) -> Result<String, AgentError>;
+    fn display_pincode(&self, request: PairingRequest, pincode: &str) -> Result<(), AgentError>;
+    fn request_passkey(&self, request: PairingRequest) -> Result<u32, AgentError>;
+    fn display_passkey(&self, request: PairingRequest, passkey: u32, entered: u16);
+    fn request_confirmation(&self, request: PairingRequest, passkey: u32) -> Result<(), AgentError>;
+    fn request_authorization(&self, request: PairingRequest) -> Result<(), AgentError>;
+    fn authorize_service(&self, request: PairingRequest, uuid: &str) -> Result<(), AgentError>;
     fn cancel(&self);
     fn release(&self);
+
+    // Called with the profile name of the service if it's a known one (see uuid::get_profile_name())
+    fn authorize_service_with_name(&self, request: PairingRequest, uuid: &str, _service_name: Option<&str>) -> Result<(), AgentError> {
+        self.authorize_service(request, uuid)
+    }
 }
 
 impl fmt::Debug for Agent {
@@ -71,176 +141,429 @@ impl fmt::Debug for Agent {
     }
 }
 
+// Same as Agent, but handlers may mutate the agent's own state (e.g. remember the last displayed passkey).
+// Use it with AgentManager::new_mut.
+pub trait AgentMut {
+    fn get_object_path(&self) -> &str {
+        "/io/bluezrs/agent1"
+    }
+
+    fn get_capability(&self) -> AgentCapability {
+        AgentCapability::KeyboardDisplay
+    }
+
+    fn request_pincode(&mut self, request: PairingRequest) -> Result<String, AgentError>;
+    fn display_pincode(&mut self, request: PairingRequest, pincode: &str) -> Result<(), AgentError>;
+    fn request_passkey(&mut self, request: PairingRequest) -> Result<u32, AgentError>;
+    fn display_passkey(&mut self, request: PairingRequest, passkey: u32, entered: u16);
+    fn request_confirmation(&mut self, request: PairingRequest, passkey: u32) -> Result<(), AgentError>;
+    fn request_authorization(&mut self, request: PairingRequest) -> Result<(), AgentError>;
+    fn authorize_service(&mut self, request: PairingRequest, uuid: &str) -> Result<(), AgentError>;
+    fn cancel(&mut self);
+    fn release(&mut self);
+
+    fn authorize_service_with_name(&mut self, request: PairingRequest, uuid: &str, _service_name: Option<&str>) -> Result<(), AgentError> {
+        self.authorize_service(request, uuid)
+    }
+}
+
+// The tree dispatches one call at a time, so the RefCell is never borrowed twice
+struct AgentMutWrapper {
+    object_path: String,
+    agent: RefCell<Box<AgentMut>>,
+}
+
+impl Agent for AgentMutWrapper {
+    fn get_object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    fn get_capability(&self) -> AgentCapability {
+        self.agent.borrow().get_capability()
+    }
+
+    fn request_pincode(&self, request: PairingRequest) -> Result<String, AgentError> {
+        self.agent.borrow_mut().request_pincode(request)
+    }
+
+    fn display_pincode(&self, request: PairingRequest, pincode: &str) -> Result<(), AgentError> {
+        self.agent.borrow_mut().display_pincode(request, pincode)
+    }
+
+    fn request_passkey(&self, request: PairingRequest) -> Result<u32, AgentError> {
+        self.agent.borrow_mut().request_passkey(request)
+    }
+
+    fn display_passkey(&self, request: PairingRequest, passkey: u32, entered: u16) {
+        self.agent.borrow_mut().display_passkey(request, passkey, entered)
+    }
+
+    fn request_confirmation(&self, request: PairingRequest, passkey: u32) -> Result<(), AgentError> {
+        self.agent.borrow_mut().request_confirmation(request, passkey)
+    }
+
+    fn request_authorization(&self, request: PairingRequest) -> Result<(), AgentError> {
+        self.agent.borrow_mut().request_authorization(request)
+    }
+
+    fn authorize_service(&self, request: PairingRequest, uuid: &str) -> Result<(), AgentError> {
+        self.agent.borrow_mut().authorize_service(request, uuid)
+    }
+
+    fn authorize_service_with_name(&self, request: PairingRequest, uuid: &str, service_name: Option<&str>) -> Result<(), AgentError> {
+        self.agent.borrow_mut().authorize_service_with_name(request, uuid, service_name)
+    }
+
+    fn cancel(&self) {
+        self.agent.borrow_mut().cancel()
+    }
+
+    fn release(&self) {
+        self.agent.borrow_mut().release()
+    }
+}
+
+// Answers PIN code requests with a fixed PIN and confirms everything else, e.g. for OBD dongles
+#[derive(Clone, Debug)]
+pub struct FixedPinAgent {
+    pincode: String,
+}
+
+impl FixedPinAgent {
+    pub fn new(pincode: &str) -> FixedPinAgent {
+        FixedPinAgent { pincode: pincode.to_string() }
+    }
+}
+
+impl Agent for FixedPinAgent {
+    fn request_pincode(&self, _request: PairingRequest) -> Result<String, AgentError> { Ok(self.pincode.clone()) }
+    fn display_pincode(&self, _request: PairingRequest, _pincode: &str) -> Result<(), AgentError> { Ok(()) }
+    fn request_passkey(&self, _request: PairingRequest) -> Result<u32, AgentError> { Err(AgentError::Rejected) }
+    fn display_passkey(&self, _request: PairingRequest, _passkey: u32, _entered: u16) {}
+    fn request_confirmation(&self, _request: PairingRequest, _passkey: u32) -> Result<(), AgentError> { Ok(()) }
+    fn request_authorization(&self, _request: PairingRequest) -> Result<(), AgentError> { Ok(()) }
+    fn authorize_service(&self, _request: PairingRequest, _uuid: &str) -> Result<(), AgentError> { Ok(()) }
+    fn cancel(&self) {}
+    fn release(&self) {}
+}
+
+// Answers passkey requests with a fixed passkey and confirms everything else
+#[derive(Clone, Debug)]
+pub struct FixedPasskeyAgent {
+    passkey: u32,
+}
+
+impl FixedPasskeyAgent {
+    pub fn new(passkey: u32) -> FixedPasskeyAgent {
+        FixedPasskeyAgent { passkey: passkey }
+    }
+}
+
+impl Agent for FixedPasskeyAgent {
+    fn request_pincode(&self, _request: PairingRequest) -> Result<String, AgentError> { Ok(format!("{:06}", self.passkey)) }
+    fn display_pincode(&self, _request: PairingRequest, _pincode: &str) -> Result<(), AgentError> { Ok(()) }
+    fn request_passkey(&self, _request: PairingRequest) -> Result<u32, AgentError> { Ok(self.passkey) }
+    fn display_passkey(&self, _request: PairingRequest, _passkey: u32, _entered: u16) {}
+    fn request_confirmation(&self, _request: PairingRequest, _passkey: u32) -> Result<(), AgentError> { Ok(()) }
+    fn request_authorization(&self, _request: PairingRequest) -> Result<(), AgentError> { Ok(()) }
+    fn authorize_service(&self, _request: PairingRequest, _uuid: &str) -> Result<(), AgentError> { Ok(()) }
+    fn cancel(&self) {}
+    fn release(&self) {}
+}
+
+// Just Works pairing: accepts every request, optionally only from the allowed addresses
+#[derive(Clone, Debug, Default)]
+pub struct AutoAcceptAgent {
+    allowed_addresses: Option<Vec<String>>,
+}
+
+impl AutoAcceptAgent {
+    pub fn new() -> AutoAcceptAgent {
+        AutoAcceptAgent::default()
+    }
+
+    pub fn with_allowed_addresses(addresses: &[&str]) -> AutoAcceptAgent {
+        AutoAcceptAgent { allowed_addresses: Some(addresses.iter().map(|x| x.to_uppercase()).collect()) }
+    }
+
+    fn check(&self, request: &PairingRequest) -> Result<(), AgentError> {
+        let allowed_addresses = match self.allowed_addresses {
+            Some(ref allowed_addresses) => allowed_addresses,
+            None => return Ok(()),
+        };
+
+        if allowed_addresses.iter().any(|x| *x == request.address().to_uppercase()) {
+            Ok(())
+        } else {
+            Err(AgentError::Rejected)
+        }
+    }
+}
+
+impl Agent for AutoAcceptAgent {
+    fn get_capability(&self) -> AgentCapability {
+        AgentCapability::NoInputNoOutput
+    }
+
+    fn request_pincode(&self, _request: PairingRequest) -> Result<String, AgentError> { Err(AgentError::Rejected) }
+    fn display_pincode(&self, request: PairingRequest, _pincode: &str) -> Result<(), AgentError> { self.check(&request) }
+    fn request_passkey(&self, _request: PairingRequest) -> Result<u32, AgentError> { Err(AgentError::Rejected) }
+    fn display_passkey(&self, _request: PairingRequest, _passkey: u32, _entered: u16) {}
+    fn request_confirmation(&self, request: PairingRequest, _passkey: u32) -> Result<(), AgentError> { self.check(&request) }
+    fn request_authorization(&self, request: PairingRequest) -> Result<(), AgentError> { self.check(&request) }
+    fn authorize_service(&self, request: PairingRequest, _uuid: &str) -> Result<(), AgentError> { self.check(&request) }
+    fn cancel(&self) {}
+    fn release(&self) {}
+}
+
+pub struct ServeHandle {
+    stop_handle: StopHandle,
+    thread: Option<thread::JoinHandle<Result<(), BtError>>>,
+}
+
+impl ServeHandle {
+    pub(crate) fn new(stop_handle: StopHandle, thread: thread::JoinHandle<Result<(), BtError>>) -> ServeHandle {
+        ServeHandle { stop_handle: stop_handle, thread: Some(thread) }
+    }
+
+    pub fn stop(&self) {
+        self.stop_handle.stop();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop_handle.is_stopped()
+    }
+
+    // Stops serving and waits for the thread to unregister the agent. Returns an error if the
+    // agent couldn't be (un)registered.
+    pub fn join(mut self) -> Result<(), BtError> {
+        self.stop();
+        self.thread.take().map(|x| x.join()).unwrap_or(Ok(Ok(())))
+            .unwrap_or_else(|_| Err(BtError::DBusInternal("agent thread panicked".to_string())))
+    }
+}
+
+impl Drop for ServeHandle {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 type SharedAgentT = Rc<Box<Agent>>;
 
-#[derive(Copy, Clone, Default, Debug)]
-struct TData;
-impl dbus::tree::DataType for TData {
-    type ObjectPath = SharedAgentT;
-    type Property = ();
-    type Interface = ();
-    type Method = Option<super::Connection>;
-    type Signal = ();
+// An Agent1 method call with its arguments
+pub(crate) enum AgentRequest<'a> {
+    RequestPinCode(PairingRequest),
+    DisplayPinCode(PairingRequest, &'a str),
+    RequestPasskey(PairingRequest),
+    DisplayPasskey(PairingRequest, u32, u16),
+    RequestConfirmation(PairingRequest, u32),
+    RequestAuthorization(PairingRequest),
+    AuthorizeService(PairingRequest, &'a str),
+    Cancel,
+    Release,
+}
+
+impl<'a> AgentRequest<'a> {
+    fn parse(conn: &super::Connection, msg: &'a dbus::Message) -> Result<AgentRequest<'a>, MethodErr> {
+        fn _request(conn: &super::Connection, device_obj_path: Option<dbus::Path>) -> Result<PairingRequest, MethodErr> {
+            let device_obj_path = try!(device_obj_path.ok_or_else(MethodErr::no_arg));
+            Ok(PairingRequest::new(Device::new(conn, &device_obj_path)))
+        }
+
+        let member = msg.member().map(|x| x.to_string()).unwrap_or_default();
+        match member.as_str() {
+            "RequestPinCode" => Ok(AgentRequest::RequestPinCode(try!(_request(conn, msg.get1())))),
+            "DisplayPinCode" => {
+                let (device_obj_path, pincode): (Option<dbus::Path>, Option<&str>) = msg.get2();
+                let pincode = try!(pincode.ok_or_else(MethodErr::no_arg));
+                Ok(AgentRequest::DisplayPinCode(try!(_request(conn, device_obj_path)), pincode))
+            }
+            "RequestPasskey" => Ok(AgentRequest::RequestPasskey(try!(_request(conn, msg.get1())))),
+            "DisplayPasskey" => {
+                let (device_obj_path, passkey, entered): (Option<dbus::Path>, Option<u32>, Option<u16>) = msg.get3();
+                let (passkey, entered) = (try!(passkey.ok_or_else(MethodErr::no_arg)), try!(entered.ok_or_else(MethodErr::no_arg)));
+                Ok(AgentRequest::DisplayPasskey(try!(_request(conn, device_obj_path)), passkey, entered))
+            }
+            "RequestConfirmation" => {
+                let (device_obj_path, passkey): (Option<dbus::Path>, Option<u32>) = msg.get2();
+                let passkey = try!(passkey.ok_or_else(MethodErr::no_arg));
+                Ok(AgentRequest::RequestConfirmation(try!(_request(conn, device_obj_path)), passkey))
+            }
+            "RequestAuthorization" => Ok(AgentRequest::RequestAuthorization(try!(_request(conn, msg.get1())))),
+            "AuthorizeService" => {
+                let (device_obj_path, service_uuid): (Option<dbus::Path>, Option<&str>) = msg.get2();
+                let service_uuid = try!(service_uuid.ok_or_else(MethodErr::no_arg));
+                Ok(AgentRequest::AuthorizeService(try!(_request(conn, device_obj_path)), service_uuid))
+            }
+            "Cancel" => Ok(AgentRequest::Cancel),
+            "Release" => Ok(AgentRequest::Release),
+            _ => Err(MethodErr::no_method(&member)),
+        }
+    }
+}
+
+// Answers a request, or returns no reply to answer it later
+pub(crate) type AgentRequestHandlerT = Rc<Fn(&dbus::Message, AgentRequest) -> MethodResult>;
+
+// The agent object of AgentManager and AsyncAgentManager
+pub(crate) fn agent_object(conn: &super::Connection, object_path: &str, handler: AgentRequestHandlerT) -> ObjectHandlerT {
+    let f = dbus::tree::Factory::new_fn::<()>();
+    let method = |name: &'static str| {
+        let (conn, handler) = (conn.clone(), handler.clone());
+        f.method(name, (), move |m| handler(m.msg, try!(AgentRequest::parse(&conn, m.msg))))
+    };
+
+    let tree = f.tree(()).add(
+        f.object_path(object_path.to_string(), ()).introspectable().add(
+            f.interface(AGENT_INTERFACE, ())
+                .add_m(method("RequestPinCode").in_arg(("device", "o")).out_arg("s"))
+                .add_m(method("DisplayPinCode").in_arg(("device", "o")).in_arg(("pincode", "s")))
+                .add_m(method("RequestPasskey").in_arg(("device", "o")).out_arg("u"))
+                .add_m(method("DisplayPasskey").in_arg(("device", "o")).in_arg(("passkey", "u")).in_arg(("entered", "q")))
+                .add_m(method("RequestConfirmation").in_arg(("device", "o")).in_arg(("passkey", "u")))
+                .add_m(method("RequestAuthorization").in_arg(("device", "o")))
+                .add_m(method("AuthorizeService").in_arg(("device", "o")).in_arg(("uuid", "s")))
+                .add_m(method("Cancel"))
+                .add_m(method("Release"))
+    ));
+
+    Rc::new(move |msg| tree.handle(msg))
+}
+
+pub(crate) trait AgentReply {
+    fn append_to(self, msg: dbus::Message) -> dbus::Message;
+}
+
+impl AgentReply for () {
+    fn append_to(self, msg: dbus::Message) -> dbus::Message { msg }
+}
+
+impl AgentReply for String {
+    fn append_to(self, msg: dbus::Message) -> dbus::Message { msg.append1(self) }
+}
+
+impl AgentReply for u32 {
+    fn append_to(self, msg: dbus::Message) -> dbus::Message { msg.append1(self) }
+}
+
+pub(crate) fn agent_reply<T: AgentReply>(msg: &dbus::Message, result: Result<T, AgentError>) -> MethodResult {
+    match result {
+        Ok(r) => Ok(vec![r.append_to(msg.method_return())]),
+        Err(e) => Err(e.method_err()),
+    }
 }
 
 pub struct AgentManager {
     conn: super::Connection,
-    tree: dbus::tree::Tree<dbus::tree::MTFn<TData>, TData>,
     agent: SharedAgentT,
+    registration: Registration,
 }
 
 impl AgentManager {
     pub fn new(conn: &super::Connection, agent: Box<Agent>) -> AgentManager {
         let agent = Rc::new(agent);
 
-        let f = dbus::tree::Factory::new_fn();
-
-        let tree = f.tree().add(
-            f.object_path(agent.get_object_path().to_string(), agent.clone()).introspectable().add(
-                f.interface(AGENT_INTERFACE, ())
-                    .add_m(
-                        f.method("RequestPinCode", Some(conn.clone()), move |m| {
-                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
-                            let agent: &SharedAgentT = m.path.get_data();
-
-                            let msg = m.msg;
-                            let device_obj_path: dbus::Path = msg.get1().unwrap();
-                            let pincode = agent.request_pincode(Device::new(conn, &device_obj_path));
-
-                            match pincode {
-                                Ok(pincode) => Ok(vec![m.msg.method_return().append1(pincode)]),
-                                Err(e) => Err(dbus::tree::MethodErr::failed(&e.bluez_error()))
-                            }
-                        }).in_arg(("device", "o")).out_arg("s")
-                    )
-                    .add_m(
-                        f.method("DisplayPinCode", Some(conn.clone()), move |m| {
-                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
-                            let agent: &SharedAgentT = m.path.get_data();
-
-                            let msg = m.msg;
-                            let (device_obj_path, pincode): (Option<dbus::Path>, Option<&str>) = msg.get2();
-                            let r = agent.display_pincode(Device::new(conn, &device_obj_path.unwrap()), pincode.unwrap());
-
-                            match r {
-                                Ok(_) => Ok(vec![m.msg.method_return()]),
-                                Err(e) => Err(dbus::tree::MethodErr::failed(&e.bluez_error()))
-                            }
-                        }).in_arg(("device", "o")).in_arg(("pincode", "s"))
-                    )
-                    .add_m(
-                        f.method("RequestPasskey", Some(conn.clone()), move |m| {
-                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
-                            let agent: &SharedAgentT = m.path.get_data();
-
-                            let msg = m.msg;
-                            let device_obj_path: dbus::Path = msg.get1().unwrap();
-                            let passkey = agent.request_passkey(Device::new(conn, &device_obj_path));
-
-                            match passkey {
-                                Ok(passkey) => Ok(vec![m.msg.method_return().append1(passkey)]),
-                                Err(e) => Err(dbus::tree::MethodErr::failed(&e.bluez_error()))
-                            }
-                        }).in_arg(("device", "o")).out_arg("u")
-                    )
-                    .add_m(
-                        f.method("DisplayPasskey", Some(conn.clone()), move |m| {
-                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
-                            let agent: &SharedAgentT = m.path.get_data();
-
-                            let msg = m.msg;
-                            let (device_obj_path, passkey, entered): (Option<dbus::Path>, Option<u32>, Option<u16>) = msg.get3();
-                            agent.display_passkey(Device::new(conn, &device_obj_path.unwrap()), passkey.unwrap(), entered.unwrap());
-
-                            Ok(vec![m.msg.method_return()])
-                        }).in_arg(("device", "o")).in_arg(("passkey", "u")).in_arg(("entered", "q"))
-                    )
-                    .add_m(
-                        f.method("RequestConfirmation", Some(conn.clone()), move |m| {
-                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
-                            let agent: &SharedAgentT = m.path.get_data();
-
-                            let msg = m.msg;
-                            let (device_obj_path, passkey): (Option<dbus::Path>, Option<u32>) = msg.get2();
-                            let r = agent.request_confirmation(Device::new(conn, &device_obj_path.unwrap()), passkey.unwrap());
-
-                            match r {
-                                Ok(_) => Ok(vec![m.msg.method_return()]),
-                                Err(e) => Err(dbus::tree::MethodErr::failed(&e.bluez_error()))
-                            }
-                        }).in_arg(("device", "o")).in_arg(("passkey", "u"))
-                    )
-                    .add_m(
-                        f.method("RequestAuthorization", Some(conn.clone()), move |m| {
-                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
-                            let agent: &SharedAgentT = m.path.get_data();
-
-                            let msg = m.msg;
-                            let device_obj_path: dbus::Path = msg.get1().unwrap();
-                            let r = agent.request_authorization(Device::new(conn, &device_obj_path));
-
-                            match r {
-                                Ok(_) => Ok(vec![m.msg.method_return()]),
-                                Err(e) => Err(dbus::tree::MethodErr::failed(&e.bluez_error()))
-                            }
-                        }).in_arg(("device", "o"))
-                    )
-                    .add_m(
-                        f.method("AuthorizeService", Some(conn.clone()), move |m| {
-                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
-                            let agent: &SharedAgentT = m.path.get_data();
-
-                            let msg = m.msg;
-                            let (device_obj_path, uuid): (Option<dbus::Path>, Option<&str>) = msg.get2();
-                            let r = agent.authorize_service(Device::new(conn, &device_obj_path.unwrap()), uuid.unwrap());
-
-                            match r {
-                                Ok(_) => Ok(vec![m.msg.method_return()]),
-                                Err(e) => Err(dbus::tree::MethodErr::failed(&e.bluez_error()))
-                            }
-                        }).in_arg(("device", "o")).in_arg(("uuid", "s"))
-                    )
-                    .add_m(
-                        f.method("Cancel", None, move |m| {
-                            let agent: &SharedAgentT = m.path.get_data();
-                            agent.cancel();
-                            Ok(vec![m.msg.method_return()])
-                        })
-                    )
-                    .add_m(
-                        f.method("Release", None, move |m| {
-                            let agent: &SharedAgentT = m.path.get_data();
-                            agent.release();
-                            Ok(vec![m.msg.method_return()])
-                        })
-                    )
-        ));
-
-        AgentManager { conn: conn.clone(), tree: tree, agent: agent }
+        let handler_agent = agent.clone();
+        let handler = agent_object(conn, agent.get_object_path(), Rc::new(move |msg, request| {
+            let agent = &handler_agent;
+            match request {
+                AgentRequest::RequestPinCode(request) => agent_reply(msg, agent.request_pincode(request)),
+                AgentRequest::DisplayPinCode(request, pincode) => agent_reply(msg, agent.display_pincode(request, pincode)),
+                AgentRequest::RequestPasskey(request) => agent_reply(msg, agent.request_passkey(request)),
+                AgentRequest::DisplayPasskey(request, passkey, entered) => {
+                    agent.display_passkey(request, passkey, entered);
+                    Ok(vec![msg.method_return()])
+                }
+                AgentRequest::RequestConfirmation(request, passkey) => agent_reply(msg, agent.request_confirmation(request, passkey)),
+                AgentRequest::RequestAuthorization(request) => agent_reply(msg, agent.request_authorization(request)),
+                AgentRequest::AuthorizeService(request, service_uuid) => {
+                    agent_reply(msg, agent.authorize_service_with_name(request, service_uuid, uuid::get_profile_name(service_uuid)))
+                }
+                AgentRequest::Cancel => {
+                    agent.cancel();
+                    Ok(vec![msg.method_return()])
+                }
+                AgentRequest::Release => {
+                    agent.release();
+                    Ok(vec![msg.method_return()])
+                }
+            }
+        }));
+
+        let object_path = agent.get_object_path().to_string();
+        let registration = Registration::new(conn, vec![object_path.clone()], handler, move |conn| {
+            common::dbus_start_call(conn, AGENT_MANAGER_OBJ_PATH, AGENT_MANAGER_INTERFACE, "UnregisterAgent",
+                                    &[dbus::MessageItem::ObjectPath(dbus::Path::new(object_path.clone()).unwrap())], Duration::from_secs(60), |_| Ok(()))
+        });
+
+        AgentManager { conn: conn.clone(), agent: agent, registration: registration }
+    }
+
+    pub fn new_mut(conn: &super::Connection, agent: Box<AgentMut>) -> AgentManager {
+        let object_path = agent.get_object_path().to_string();
+        AgentManager::new(conn, Box::new(AgentMutWrapper { object_path: object_path, agent: RefCell::new(agent) }))
     }
 
     pub fn register_agent(&self) -> Result<(), BtError> {
         let agent_capabitily = self.agent.get_capability().to_str();
         let agent_obj_path = dbus::Path::new(self.agent.get_object_path()).unwrap();
 
-        try!(self.tree.set_registered(&self.conn, true));
-        try!(common::dbus_call_method2(&self.conn, AGENT_MANAGER_OBJ_PATH, AGENT_MANAGER_INTERFACE, "RegisterAgent", agent_obj_path, agent_capabitily));
+        self.registration.register(|| {
+            common::dbus_call_method2(&self.conn, AGENT_MANAGER_OBJ_PATH, AGENT_MANAGER_INTERFACE, "RegisterAgent", agent_obj_path, agent_capabitily)
+        })
+    }
 
-        Ok(())
+    pub fn handle_message(&self, msg: &dbus::Message) -> bool {
+        self.registration.handle_message(msg)
+    }
+
+    // Processes the queued messages, waiting up to timeout_ms for the first one, and returns how
+    // many were handled. Meant to be called from an existing event loop.
+    pub fn process_pending(&self, timeout_ms: i32) -> usize {
+        self.conn.registry().process(timeout_ms)
     }
 
+    // Also serves the other objects registered on the connection
     pub fn serve(&self, cb: Option<&Fn() -> bool>) {
-        for _ in self.tree.run(&self.conn, self.conn.iter(100)) {
-            if let Some(cb) = cb {
-                if !cb() { break; }
+        self.conn.registry().serve(cb)
+    }
+
+    // Serves the agent on its own thread and bus connection until the handle is stopped.
+    // The agent is created on that thread, since neither it nor the connection can be sent there.
+    pub fn serve_in_background<F>(make_agent: F, default_agent: bool) -> ServeHandle
+        where F: FnOnce() -> Box<Agent> + Send + 'static {
+        let stop_handle = StopHandle::new();
+        let thread_stop_handle = stop_handle.clone();
+
+        let thread = thread::spawn(move || {
+            let conn = try!(super::Connection::new());
+            let manager = AgentManager::new(&conn, make_agent());
+
+            try!(manager.register_agent());
+            if default_agent {
+                if let Err(e) = manager.request_default_agent() {
+                    let _ = manager.unregister_agent();
+                    return Err(e);
+                }
             }
-        }
+
+            manager.serve(Some(&|| !thread_stop_handle.is_stopped()));
+            manager.unregister_agent()
+        });
+
+        ServeHandle::new(stop_handle, thread)
     }
 
     pub fn unregister_agent(&self) -> Result<(), BtError> {
-        let agent_obj_path = dbus::Path::new(self.agent.get_object_path()).unwrap();
-        try!(common::dbus_call_method1(&self.conn, AGENT_MANAGER_OBJ_PATH, AGENT_MANAGER_INTERFACE, "UnregisterAgent", agent_obj_path));
-        Ok(())
+        self.registration.unregister()
+    }
+
+    pub(crate) fn registration(&self) -> &Registration {
+        &self.registration
     }
 
     pub fn request_default_agent(&self) -> Result<(), BtError> {
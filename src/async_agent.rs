@@ -0,0 +1,306 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::Duration;
+
+use dbus;
+use dbus::tree::{MethodErr, MethodResult};
+
+use agent::{self, AgentCapability, AgentError, AgentRequest, PairingRequest, AGENT_INTERFACE, AGENT_MANAGER_INTERFACE, AGENT_MANAGER_OBJ_PATH};
+use common;
+use error::BtError;
+use registry::Registration;
+use uuid;
+
+pub type AgentFuture<T> = Pin<Box<Future<Output = Result<T, AgentError>>>>;
+
+// Same as Agent, but requests may be answered later (e.g. after a GUI dialog was closed).
+// The D-Bus loop of AsyncAgentManager keeps running while a future is pending, which is polled
+// again once it wakes its waker.
+pub trait AsyncAgent {
+    fn get_object_path(&self) -> &str {
+        "/io/bluezrs/agent1"
+    }
+
+    fn get_capability(&self) -> AgentCapability {
+        AgentCapability::KeyboardDisplay
+    }
+
+    fn request_pincode(&self, request: PairingRequest) -> AgentFuture<String>;
+    fn display_pincode(&self, request: PairingRequest, pincode: &str) -> AgentFuture<()>;
+    fn request_passkey(&self, request: PairingRequest) -> AgentFuture<u32>;
+    fn display_passkey(&self, request: PairingRequest, passkey: u32, entered: u16);
+    fn request_confirmation(&self, request: PairingRequest, passkey: u32) -> AgentFuture<()>;
+    fn request_authorization(&self, request: PairingRequest) -> AgentFuture<()>;
+    fn authorize_service(&self, request: PairingRequest, uuid: &str) -> AgentFuture<()>;
+    fn cancel(&self);
+    fn release(&self);
+
+    fn authorize_service_with_name(&self, request: PairingRequest, uuid: &str, _service_name: Option<&str>) -> AgentFuture<()> {
+        self.authorize_service(request, uuid)
+    }
+}
+
+type ReplyFuture = Pin<Box<Future<Output = Result<Option<dbus::MessageItem>, AgentError>>>>;
+
+// Converts the agent's answer into the reply argument
+struct MapReply<T> {
+    inner: AgentFuture<T>,
+    f: fn(T) -> Option<dbus::MessageItem>,
+}
+
+impl<T> Future for MapReply<T> {
+    type Output = Result<Option<dbus::MessageItem>, AgentError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let f = self.f;
+        match self.inner.as_mut().poll(cx) {
+            Poll::Ready(r) => Poll::Ready(r.map(f)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn map_reply<T: 'static>(inner: AgentFuture<T>, f: fn(T) -> Option<dbus::MessageItem>) -> ReplyFuture {
+    Box::pin(MapReply { inner: inner, f: f })
+}
+
+// Marks its request as ready to be polled again and wakes whoever serves the manager
+struct ReplyWaker {
+    woken: AtomicBool,
+    task: TaskWakerT,
+}
+
+impl Wake for ReplyWaker {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        if let Some(ref waker) = *self.task.lock().unwrap_or_else(|e| e.into_inner()) {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+// Unparks the thread in serve()
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+type TaskWakerT = Arc<Mutex<Option<Waker>>>;
+
+struct PendingReply {
+    call: Rc<dbus::Message>,
+    future: ReplyFuture,
+    waker: Arc<ReplyWaker>,
+}
+
+impl PendingReply {
+    // Polls the future if its waker was woken, returns the reply once it's done
+    fn poll(&mut self) -> Option<Option<dbus::Message>> {
+        if !self.waker.woken.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+
+        let waker = Waker::from(self.waker.clone());
+        match self.future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(result) => Some(reply_to(&self.call, result)),
+            Poll::Pending => None,
+        }
+    }
+}
+
+fn reply_to(msg: &dbus::Message, result: Result<Option<dbus::MessageItem>, AgentError>) -> Option<dbus::Message> {
+    match result {
+        Ok(Some(item)) => Some(msg.method_return().append(item)),
+        Ok(None) => Some(msg.method_return()),
+        Err(e) => dbus::Message::new_error(msg, e.bluez_error(), e.message()),
+    }
+}
+
+// Shared with the handler of the agent object
+struct AgentState {
+    conn: super::Connection,
+    agent: Box<AsyncAgent>,
+    pending: RefCell<Vec<PendingReply>>,
+    task: TaskWakerT,
+}
+
+impl AgentState {
+    fn handle_request(&self, msg: &dbus::Message, request: AgentRequest) -> MethodResult {
+        let mut future = match request {
+            AgentRequest::RequestPinCode(request) => {
+                map_reply(self.agent.request_pincode(request), |x| Some(dbus::MessageItem::Str(x)))
+            }
+            AgentRequest::DisplayPinCode(request, pincode) => {
+                map_reply(self.agent.display_pincode(request, pincode), |_| None)
+            }
+            AgentRequest::RequestPasskey(request) => {
+                map_reply(self.agent.request_passkey(request), |x| Some(dbus::MessageItem::UInt32(x)))
+            }
+            AgentRequest::DisplayPasskey(request, passkey, entered) => {
+                self.agent.display_passkey(request, passkey, entered);
+                return Ok(vec![msg.method_return()]);
+            }
+            AgentRequest::RequestConfirmation(request, passkey) => {
+                map_reply(self.agent.request_confirmation(request, passkey), |_| None)
+            }
+            AgentRequest::RequestAuthorization(request) => {
+                map_reply(self.agent.request_authorization(request), |_| None)
+            }
+            AgentRequest::AuthorizeService(request, service_uuid) => {
+                map_reply(self.agent.authorize_service_with_name(request, service_uuid, uuid::get_profile_name(service_uuid)), |_| None)
+            }
+            AgentRequest::Cancel => {
+                // BlueZ has given up on the request it's waiting for, so its reply isn't needed anymore
+                self.pending.borrow_mut().pop();
+                self.agent.cancel();
+                return Ok(vec![msg.method_return()]);
+            }
+            AgentRequest::Release => {
+                self.pending.borrow_mut().clear();
+                self.agent.release();
+                return Ok(vec![msg.method_return()]);
+            }
+        };
+
+        let waker = Arc::new(ReplyWaker { woken: AtomicBool::new(false), task: self.task.clone() });
+        if let Poll::Ready(result) = future.as_mut().poll(&mut Context::from_waker(&Waker::from(waker.clone()))) {
+            return Ok(reply_to(msg, result).into_iter().collect());
+        }
+
+        match self.conn.registry().current_call(msg) {
+            Some(call) => {
+                self.pending.borrow_mut().push(PendingReply { call: call, future: future, waker: waker });
+                Ok(vec![])
+            }
+            None => Err(MethodErr::failed(&"the request can't be answered later when dispatched by reference")),
+        }
+    }
+
+    fn poll_pending(&self) {
+        // Taken out while polling, futures may process the connection and start new requests
+        let pending: Vec<PendingReply> = self.pending.borrow_mut().drain(..).collect();
+
+        let mut waiting = Vec::new();
+        for mut reply in pending {
+            match reply.poll() {
+                Some(msg) => {
+                    if let Some(msg) = msg {
+                        let _ = self.conn.send(msg);
+                    }
+                }
+                None => waiting.push(reply),
+            }
+        }
+
+        let mut pending = self.pending.borrow_mut();
+        waiting.extend(pending.drain(..));
+        *pending = waiting;
+    }
+}
+
+pub struct AsyncAgentManager {
+    conn: super::Connection,
+    state: Rc<AgentState>,
+    registration: Registration,
+}
+
+impl AsyncAgentManager {
+    pub fn new(conn: &super::Connection, agent: Box<AsyncAgent>) -> AsyncAgentManager {
+        let object_path = agent.get_object_path().to_string();
+        let state = Rc::new(AgentState {
+            conn: conn.clone(),
+            agent: agent,
+            pending: RefCell::new(Vec::new()),
+            task: Arc::new(Mutex::new(None)),
+        });
+
+        let handler_state = state.clone();
+        let handler = agent::agent_object(conn, &object_path, Rc::new(move |msg, request| handler_state.handle_request(msg, request)));
+        let registration = Registration::new(conn, vec![object_path.clone()], handler, move |conn| {
+            common::dbus_start_call(conn, AGENT_MANAGER_OBJ_PATH, AGENT_MANAGER_INTERFACE, "UnregisterAgent",
+                                    &[dbus::MessageItem::ObjectPath(dbus::Path::new(object_path.clone()).unwrap())], Duration::from_secs(60), |_| Ok(()))
+        });
+
+        AsyncAgentManager { conn: conn.clone(), state: state, registration: registration }
+    }
+
+    pub fn register_agent(&self) -> Result<(), BtError> {
+        let agent_capabitily = self.state.agent.get_capability().to_str();
+        let agent_obj_path = dbus::Path::new(self.state.agent.get_object_path()).unwrap();
+
+        self.registration.register(|| {
+            common::dbus_call_method2(&self.conn, AGENT_MANAGER_OBJ_PATH, AGENT_MANAGER_INTERFACE, "RegisterAgent", agent_obj_path, agent_capabitily)
+        })
+    }
+
+    pub fn unregister_agent(&self) -> Result<(), BtError> {
+        try!(self.registration.unregister());
+        self.state.pending.borrow_mut().clear();
+        Ok(())
+    }
+
+    pub fn request_default_agent(&self) -> Result<(), BtError> {
+        let agent_obj_path = dbus::Path::new(self.state.agent.get_object_path()).unwrap();
+        try!(common::dbus_call_method1(&self.conn, AGENT_MANAGER_OBJ_PATH, AGENT_MANAGER_INTERFACE, "RequestDefaultAgent", agent_obj_path));
+        Ok(())
+    }
+
+    // Starts handling an agent call, the reply is sent by poll_pending() once the future completes.
+    // Gives the message back if it isn't addressed to the agent.
+    pub fn handle_message(&self, msg: dbus::Message) -> Option<dbus::Message> {
+        let is_agent_call = msg.path().map(|x| *x == *self.state.agent.get_object_path()).unwrap_or(false) &&
+                            msg.interface().map(|x| *x == *AGENT_INTERFACE).unwrap_or(false);
+        if !is_agent_call {
+            return Some(msg);
+        }
+
+        self.conn.registry().with_call(msg, |msg| self.registration.handle_message(msg));
+        None
+    }
+
+    // Sends the replies of the completed requests. Only the requests whose waker was woken are
+    // polled again.
+    pub fn poll_pending(&self) {
+        self.state.poll_pending();
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.state.pending.borrow().is_empty()
+    }
+
+    // Same as AgentManager::process_pending, completed requests are answered as well
+    pub fn process_pending(&self, timeout_ms: i32) -> usize {
+        let handled = self.conn.registry().process(timeout_ms);
+        self.poll_pending();
+        handled
+    }
+
+    // Sleeps until there is something to read on the connection or a request can make progress
+    pub fn serve(&self, cb: Option<&Fn() -> bool>) {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        *self.state.task.lock().unwrap_or_else(|e| e.into_inner()) = Some(waker.clone());
+
+        while self.conn.registry().is_connected() {
+            self.process_pending(0);
+
+            if let Some(cb) = cb {
+                if !cb() { break; }
+            }
+
+            // Parked for at most 100 ms, for cb
+            self.conn.registry().wake_on_readable(None, &waker);
+            thread::park_timeout(Duration::from_millis(100));
+        }
+
+        *self.state.task.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
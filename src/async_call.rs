@@ -0,0 +1,150 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use dbus;
+
+use adapter::{self, Adapter, AdapterProperties, DiscoveryFilter, ADAPTER_INTERFACE};
+use common;
+use device::{AddressType, Device, DeviceOperation, DeviceProperties, DEVICE_INTERFACE};
+use error::BtError;
+use pending::PendingCall;
+
+// Polling the future processes the connection, so objects registered on it (e.g. the agent for
+// Pair) are served while it's awaited. It doesn't depend on a runtime, but like the connection it
+// isn't Send, so it has to be awaited on the thread that made it (e.g. in a tokio LocalSet).
+impl<T> Future for PendingCall<T> {
+    type Output = Result<T, BtError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, BtError>> {
+        if self.is_done() {
+            return Poll::Ready(Err(BtError::DBusInternal("the result was already returned by poll()".to_string())));
+        }
+
+        match PendingCall::poll(&*self) {
+            Some(result) => Poll::Ready(result),
+            None => {
+                self.wake_on_reply(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Every single D-Bus call of Adapter and Device has an async variant here. Left out are the
+// helpers made of several calls or waiting for signals (wait_powered(), power_cycle(),
+// disconnect_all(), remove_all_devices(), discovery sessions, watchers...), the MGMT/admin
+// policy/network ones, and get_controller_info(), which doesn't use D-Bus.
+impl Adapter {
+    pub fn get_properties_async(&self) -> PendingCall<AdapterProperties> {
+        common::dbus_start_get_all_properties(self.conn(), self.object_path(), ADAPTER_INTERFACE, AdapterProperties::new)
+    }
+
+    pub fn set_alias_async(&self, val: &str) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), ADAPTER_INTERFACE, "Alias", val)
+    }
+
+    pub fn set_powered_async(&self, val: bool) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), ADAPTER_INTERFACE, "Powered", val)
+    }
+
+    pub fn set_discoverable_async(&self, val: bool) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), ADAPTER_INTERFACE, "Discoverable", val)
+    }
+
+    pub fn set_discoverable_timeout_async(&self, val: u32) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), ADAPTER_INTERFACE, "DiscoverableTimeout", val)
+    }
+
+    pub fn set_pairable_async(&self, val: bool) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), ADAPTER_INTERFACE, "Pairable", val)
+    }
+
+    pub fn set_pairable_timeout_async(&self, val: u32) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), ADAPTER_INTERFACE, "PairableTimeout", val)
+    }
+
+    // BlueZ stops discovery once the connection that started it goes away
+    pub fn start_discovery_async(&self) -> PendingCall<()> {
+        self.start_call("StartDiscovery", &[], |_| Ok(()))
+    }
+
+    pub fn stop_discovery_async(&self) -> PendingCall<()> {
+        self.start_call("StopDiscovery", &[], |_| Ok(()))
+    }
+
+    pub fn set_discovery_filter_async(&self, filter: &DiscoveryFilter) -> PendingCall<()> {
+        self.start_call("SetDiscoveryFilter", &[filter.to_message_item()], |_| Ok(()))
+    }
+
+    pub fn get_discovery_filters_async(&self) -> PendingCall<Vec<String>> {
+        self.start_call("GetDiscoveryFilters", &[], |reply| adapter::parse_discovery_filters(&reply))
+    }
+
+    pub fn remove_device_async(&self, device: &Device) -> PendingCall<()> {
+        self.start_call("RemoveDevice", &[dbus::MessageItem::ObjectPath(device.object_path().to_string().into())], |_| Ok(()))
+    }
+
+    pub fn connect_device_async(&self, address: &str, address_type: Option<AddressType>) -> PendingCall<Device> {
+        let conn = self.conn().clone();
+        self.start_call("ConnectDevice", &[adapter::connect_device_arg(address, address_type)],
+                        move |reply| adapter::parse_connect_device(&conn, &reply))
+    }
+
+    fn start_call<T, F>(&self, method_name: &str, method_args: &[dbus::MessageItem], parse: F) -> PendingCall<T>
+        where F: Fn(dbus::Message) -> Result<T, BtError> + 'static {
+        common::dbus_start_call(self.conn(), self.object_path(), ADAPTER_INTERFACE, method_name, method_args, Duration::from_secs(60), parse)
+    }
+}
+
+impl Device {
+    pub fn get_properties_async(&self) -> PendingCall<DeviceProperties> {
+        common::dbus_start_get_all_properties(self.conn(), self.object_path(), DEVICE_INTERFACE, DeviceProperties::new)
+    }
+
+    pub fn set_alias_async(&self, val: &str) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), DEVICE_INTERFACE, "Alias", val)
+    }
+
+    pub fn set_trusted_async(&self, val: bool) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), DEVICE_INTERFACE, "Trusted", val)
+    }
+
+    pub fn set_blocked_async(&self, val: bool) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), DEVICE_INTERFACE, "Blocked", val)
+    }
+
+    pub fn set_wake_allowed_async(&self, val: bool) -> PendingCall<()> {
+        common::dbus_start_set_property(self.conn(), self.object_path(), DEVICE_INTERFACE, "WakeAllowed", val)
+    }
+
+    pub fn connect_async(&self) -> PendingCall<()> {
+        self.start_connect()
+    }
+
+    pub fn connect_with_timeout_async(&self, timeout: Duration) -> PendingCall<()> {
+        self.start_call(DeviceOperation::Connect, "Connect", &[], timeout)
+    }
+
+    pub fn disconnect_async(&self) -> PendingCall<()> {
+        self.start_disconnect()
+    }
+
+    pub fn connect_profile_async(&self, uuid: &str) -> PendingCall<()> {
+        self.start_connect_profile(uuid)
+    }
+
+    pub fn disconnect_profile_async(&self, uuid: &str) -> PendingCall<()> {
+        self.start_call(DeviceOperation::DisconnectProfile, "DisconnectProfile", &[uuid.into()], Duration::from_secs(60))
+    }
+
+    // The agent registered on this connection handles the pairing, or the default one if there is none
+    pub fn pair_async(&self) -> PendingCall<()> {
+        self.start_pair()
+    }
+
+    pub fn cancel_pairing_async(&self) -> PendingCall<()> {
+        common::dbus_start_call(self.conn(), self.object_path(), DEVICE_INTERFACE, "CancelPairing", &[], Duration::from_secs(60), |_| Ok(()))
+    }
+}
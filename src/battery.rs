@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use dbus;
+
+use adapter::Adapter;
+use common;
+use device::Device;
+use error::BtError;
+use registry::Registration;
+
+pub static BATTERY_PROVIDER_INTERFACE: &'static str = "org.bluez.BatteryProvider1";
+pub static BATTERY_PROVIDER_MANAGER_INTERFACE: &'static str = "org.bluez.BatteryProviderManager1";
+pub static BATTERY_PROVIDER_APP_OBJ_PATH: &'static str = "/io/bluezrs/battery";
+
+#[derive(Copy, Clone, Default, Debug)]
+struct TData;
+impl dbus::tree::DataType for TData {
+    type Tree = ();
+    type ObjectPath = ();
+    type Property = dbus::MessageItem;
+    type Interface = ();
+    type Method = ();
+    type Signal = ();
+}
+
+type BatteryTreeT = dbus::tree::Tree<dbus::tree::MTFn<TData>, TData>;
+
+#[derive(Clone, Debug)]
+struct Battery {
+    object_path: String,
+    device_object_path: String,
+    percentage: u8,
+    source: Option<String>,
+}
+
+impl Battery {
+    fn props(&self) -> Vec<dbus::MessageItem> {
+        let mut entries = vec![
+            common::dbus_dict_entry("Percentage", dbus::MessageItem::Byte(self.percentage)),
+            common::dbus_dict_entry("Device", dbus::MessageItem::ObjectPath(dbus::Path::new(self.device_object_path.clone()).unwrap())),
+        ];
+        if let Some(ref source) = self.source {
+            entries.push(common::dbus_dict_entry("Source", source.as_str()));
+        }
+        entries
+    }
+
+    fn to_object_path(&self) -> dbus::tree::ObjectPath<dbus::tree::MTFn<TData>, TData> {
+        let f = dbus::tree::Factory::new_fn::<TData>();
+
+        let mut iface = f.interface(BATTERY_PROVIDER_INTERFACE, ())
+            .add_p(f.property::<u8, _>("Percentage", dbus::MessageItem::Byte(self.percentage)).default_get())
+            .add_p(f.property::<dbus::Path, _>("Device", dbus::MessageItem::ObjectPath(dbus::Path::new(self.device_object_path.clone()).unwrap())).default_get());
+        if let Some(ref source) = self.source {
+            iface = iface.add_p(f.property::<&str, _>("Source", dbus::MessageItem::Str(source.clone())).default_get());
+        }
+
+        f.object_path(self.object_path.clone(), ()).introspectable().add(iface)
+    }
+}
+
+// Exports battery levels BlueZ can't read itself (e.g. from a vendor GATT characteristic) as
+// Battery1 of the devices. Batteries can be set and removed before and after registering.
+pub struct BatteryProviderManager {
+    conn: super::Connection,
+    adapter_object_path: String,
+    tree: Rc<RefCell<BatteryTreeT>>,
+    batteries: RefCell<BTreeMap<String, Battery>>,
+    registration: Registration,
+}
+
+impl BatteryProviderManager {
+    pub fn new(adapter: &Adapter) -> BatteryProviderManager {
+        let f = dbus::tree::Factory::new_fn();
+        let tree = Rc::new(RefCell::new(f.tree(()).add(f.object_path(BATTERY_PROVIDER_APP_OBJ_PATH, ()).introspectable().object_manager())));
+
+        let handler_tree = tree.clone();
+        let adapter_object_path = adapter.object_path().to_string();
+        let registration = Registration::new(adapter.conn(), vec![BATTERY_PROVIDER_APP_OBJ_PATH.to_string()], Rc::new(move |msg| handler_tree.borrow().handle(msg)), move |conn| {
+            let app_obj_path = dbus::Path::new(BATTERY_PROVIDER_APP_OBJ_PATH).unwrap();
+            common::dbus_start_call(conn, &adapter_object_path, BATTERY_PROVIDER_MANAGER_INTERFACE, "UnregisterBatteryProvider",
+                                    &[dbus::MessageItem::ObjectPath(app_obj_path)], Duration::from_secs(60), |_| Ok(()))
+        });
+
+        BatteryProviderManager {
+            conn: adapter.conn().clone(),
+            adapter_object_path: adapter.object_path().to_string(),
+            tree: tree,
+            batteries: RefCell::new(BTreeMap::new()),
+            registration: registration,
+        }
+    }
+
+    fn emit(&self, object_path: &str, interface: &str, member: &str, items: &[dbus::MessageItem]) -> Result<(), BtError> {
+        let mut signal = try!(dbus::Message::new_signal(object_path, interface, member).map_err(BtError::DBusInternal));
+        signal.append_items(items);
+        try!(self.conn.send(signal).map_err(|_| BtError::DBusInternal("failed to send a battery provider signal".to_string())));
+        Ok(())
+    }
+
+    pub fn register_provider(&self) -> Result<(), BtError> {
+        let app_obj_path = dbus::Path::new(BATTERY_PROVIDER_APP_OBJ_PATH).unwrap();
+        self.registration.register(|| {
+            common::dbus_call_method1(&self.conn, &self.adapter_object_path, BATTERY_PROVIDER_MANAGER_INTERFACE, "RegisterBatteryProvider", app_obj_path)
+        })
+    }
+
+    pub fn unregister_provider(&self) -> Result<(), BtError> {
+        self.registration.unregister()
+    }
+
+    pub(crate) fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    // Adds the battery of the device or updates its level (0-100). The source describes where the
+    // level comes from (e.g. "HFP 1.7", "GATT Battery Service").
+    pub fn set_battery(&self, device: &Device, percentage: u8, source: Option<&str>) -> Result<(), BtError> {
+        let device_obj_path = device.object_path().to_string();
+        let device_id = device_obj_path.rsplit('/').next().unwrap_or("");
+
+        let battery = Battery {
+            object_path: format!("{}/{}", BATTERY_PROVIDER_APP_OBJ_PATH, device_id),
+            device_object_path: device_obj_path.clone(),
+            percentage: percentage.min(100),
+            source: source.map(|x| x.to_string()),
+        };
+        let path = dbus::Path::new(battery.object_path.clone()).unwrap();
+
+        let is_new = !self.batteries.borrow().contains_key(&device_obj_path);
+        {
+            let mut tree = self.tree.borrow_mut();
+            tree.remove(&path);
+            tree.insert(battery.to_object_path());
+        }
+        self.batteries.borrow_mut().insert(device_obj_path, battery.clone());
+
+        if is_new {
+            try!(self.registration.add_object(&battery.object_path));
+        }
+        if !self.registration.is_registered() {
+            return Ok(());
+        }
+
+        if is_new {
+            let ifaces = dbus::MessageItem::DictEntry(Box::new(BATTERY_PROVIDER_INTERFACE.into()), Box::new(dbus::MessageItem::Array(battery.props(), "{sv}".into())));
+            self.emit(BATTERY_PROVIDER_APP_OBJ_PATH, "org.freedesktop.DBus.ObjectManager", "InterfacesAdded",
+                      &[dbus::MessageItem::ObjectPath(path), dbus::MessageItem::Array(vec![ifaces], "{sa{sv}}".into())])
+        } else {
+            self.emit(&battery.object_path, "org.freedesktop.DBus.Properties", "PropertiesChanged",
+                      &[BATTERY_PROVIDER_INTERFACE.into(), dbus::MessageItem::Array(battery.props(), "{sv}".into()), dbus::MessageItem::Array(vec![], "s".into())])
+        }
+    }
+
+    // Returns false if the device had no battery
+    pub fn remove_battery(&self, device: &Device) -> Result<bool, BtError> {
+        let battery = match self.batteries.borrow_mut().remove(device.object_path()) {
+            Some(battery) => battery,
+            None => return Ok(false),
+        };
+        let path = dbus::Path::new(battery.object_path.clone()).unwrap();
+        self.tree.borrow_mut().remove(&path);
+
+        self.registration.remove_object(&battery.object_path);
+        if self.registration.is_registered() {
+            try!(self.emit(BATTERY_PROVIDER_APP_OBJ_PATH, "org.freedesktop.DBus.ObjectManager", "InterfacesRemoved",
+                           &[dbus::MessageItem::ObjectPath(path), dbus::MessageItem::Array(vec![BATTERY_PROVIDER_INTERFACE.into()], "s".into())]));
+        }
+
+        Ok(true)
+    }
+
+    pub fn get_battery(&self, device: &Device) -> Option<u8> {
+        self.batteries.borrow().get(device.object_path()).map(|x| x.percentage)
+    }
+}
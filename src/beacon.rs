@@ -0,0 +1,235 @@
+pub static EDDYSTONE_SERVICE_UUID: &'static str = "0000feaa-0000-1000-8000-00805f9b34fb";
+pub const APPLE_COMPANY_ID: u16 = 0x004c;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IBeacon {
+    pub uuid: String,
+    pub major: u16,
+    pub minor: u16,
+    pub tx_power: i8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AltBeacon {
+    pub manufacturer_id: u16,
+    pub beacon_id: Vec<u8>,
+    pub reference_rssi: i8,
+    pub manufacturer_reserved: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EddystoneUid {
+    pub tx_power: i8,
+    pub namespace: Vec<u8>,
+    pub instance: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EddystoneUrl {
+    pub tx_power: i8,
+    pub url: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EddystoneTlm {
+    pub version: u8,
+    pub battery_voltage: u16,
+    pub temperature: f32,
+    pub advertising_count: u32,
+    pub uptime_deciseconds: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BeaconFrame {
+    IBeacon(IBeacon),
+    AltBeacon(AltBeacon),
+    EddystoneUid(EddystoneUid),
+    EddystoneUrl(EddystoneUrl),
+    EddystoneTlm(EddystoneTlm),
+}
+
+fn get_u16_be(data: &[u8], offset: usize) -> u16 {
+    (data[offset] as u16) << 8 | data[offset + 1] as u16
+}
+
+fn get_u32_be(data: &[u8], offset: usize) -> u32 {
+    (get_u16_be(data, offset) as u32) << 16 | get_u16_be(data, offset + 2) as u32
+}
+
+fn format_uuid(data: &[u8]) -> String {
+    let hex: Vec<String> = data.iter().map(|x| format!("{:02x}", x)).collect();
+    format!("{}-{}-{}-{}-{}", hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(), hex[8..10].concat(), hex[10..16].concat())
+}
+
+pub fn decode_manufacturer_data(company_id: u16, data: &[u8]) -> Option<BeaconFrame> {
+    if company_id == APPLE_COMPANY_ID && data.len() >= 23 && data[0] == 0x02 && data[1] == 0x15 {
+        return Some(BeaconFrame::IBeacon(IBeacon {
+            uuid: format_uuid(&data[2..18]),
+            major: get_u16_be(data, 18),
+            minor: get_u16_be(data, 20),
+            tx_power: data[22] as i8,
+        }));
+    }
+
+    if data.len() >= 24 && data[0] == 0xbe && data[1] == 0xac {
+        return Some(BeaconFrame::AltBeacon(AltBeacon {
+            manufacturer_id: company_id,
+            beacon_id: data[2..22].to_vec(),
+            reference_rssi: data[22] as i8,
+            manufacturer_reserved: data[23],
+        }));
+    }
+
+    None
+}
+
+pub fn decode_service_data(uuid: &str, data: &[u8]) -> Option<BeaconFrame> {
+    if !uuid.eq_ignore_ascii_case(EDDYSTONE_SERVICE_UUID) || data.is_empty() {
+        return None;
+    }
+
+    match data[0] {
+        0x00 if data.len() >= 18 => {
+            Some(BeaconFrame::EddystoneUid(EddystoneUid {
+                tx_power: data[1] as i8,
+                namespace: data[2..12].to_vec(),
+                instance: data[12..18].to_vec(),
+            }))
+        }
+        0x10 if data.len() >= 3 => {
+            decode_eddystone_url(&data[2..]).map(|url| BeaconFrame::EddystoneUrl(EddystoneUrl { tx_power: data[1] as i8, url: url }))
+        }
+        0x20 if data.len() >= 14 && data[1] == 0x00 => {
+            Some(BeaconFrame::EddystoneTlm(EddystoneTlm {
+                version: data[1],
+                battery_voltage: get_u16_be(data, 2),
+                temperature: (get_u16_be(data, 4) as i16) as f32 / 256.0,
+                advertising_count: get_u32_be(data, 6),
+                uptime_deciseconds: get_u32_be(data, 10),
+            }))
+        }
+        _ => None,
+    }
+}
+
+fn decode_eddystone_url(data: &[u8]) -> Option<String> {
+    let mut url = match data[0] {
+        0x00 => "http://www.",
+        0x01 => "https://www.",
+        0x02 => "http://",
+        0x03 => "https://",
+        _ => return None,
+    }.to_string();
+
+    for &b in &data[1..] {
+        match b {
+            0x00 => url.push_str(".com/"),
+            0x01 => url.push_str(".org/"),
+            0x02 => url.push_str(".edu/"),
+            0x03 => url.push_str(".net/"),
+            0x04 => url.push_str(".info/"),
+            0x05 => url.push_str(".biz/"),
+            0x06 => url.push_str(".gov/"),
+            0x07 => url.push_str(".com"),
+            0x08 => url.push_str(".org"),
+            0x09 => url.push_str(".edu"),
+            0x0a => url.push_str(".net"),
+            0x0b => url.push_str(".info"),
+            0x0c => url.push_str(".biz"),
+            0x0d => url.push_str(".gov"),
+            0x21..=0x7e => url.push(b as char),
+            _ => return None,
+        }
+    }
+
+    Some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ibeacon_data() -> Vec<u8> {
+        let mut data = vec![0x02, 0x15];
+        data.extend_from_slice(&[0xe2, 0xc5, 0x6d, 0xb5, 0xdf, 0xfb, 0x48, 0xd2, 0xb0, 0x60, 0xd0, 0xf5, 0xa7, 0x10, 0x96, 0xe0]);
+        data.extend_from_slice(&[0x00, 0x01, 0x00, 0x02, 0xc5]);
+        data
+    }
+
+    #[test]
+    fn decode_ibeacon() {
+        assert_eq!(decode_manufacturer_data(APPLE_COMPANY_ID, &ibeacon_data()), Some(BeaconFrame::IBeacon(IBeacon {
+            uuid: "e2c56db5-dffb-48d2-b060-d0f5a71096e0".to_string(),
+            major: 1,
+            minor: 2,
+            tx_power: -59,
+        })));
+
+        let data = ibeacon_data();
+        assert_eq!(decode_manufacturer_data(APPLE_COMPANY_ID, &data[..data.len() - 1]), None);
+        assert_eq!(decode_manufacturer_data(0x0075, &data), None);
+        assert_eq!(decode_manufacturer_data(APPLE_COMPANY_ID, &[]), None);
+        assert_eq!(decode_manufacturer_data(APPLE_COMPANY_ID, &[0x02]), None);
+    }
+
+    #[test]
+    fn decode_altbeacon() {
+        let mut data = vec![0xbe, 0xac];
+        data.extend_from_slice(&[0x11; 20]);
+        data.extend_from_slice(&[0xbf, 0x2a]);
+        assert_eq!(decode_manufacturer_data(0x0118, &data), Some(BeaconFrame::AltBeacon(AltBeacon {
+            manufacturer_id: 0x0118,
+            beacon_id: vec![0x11; 20],
+            reference_rssi: -65,
+            manufacturer_reserved: 0x2a,
+        })));
+        assert_eq!(decode_manufacturer_data(0x0118, &data[..23]), None);
+    }
+
+    #[test]
+    fn decode_eddystone_frames() {
+        let mut uid = vec![0x00, 0xe7];
+        uid.extend_from_slice(&[0xaa; 10]);
+        uid.extend_from_slice(&[0xbb; 6]);
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &uid), Some(BeaconFrame::EddystoneUid(EddystoneUid {
+            tx_power: -25,
+            namespace: vec![0xaa; 10],
+            instance: vec![0xbb; 6],
+        })));
+
+        let url = [0x10, 0xeb, 0x03, b'g', b'o', b'o', b'g', b'l', b'e', 0x07];
+        assert_eq!(decode_service_data(&EDDYSTONE_SERVICE_UUID.to_uppercase(), &url), Some(BeaconFrame::EddystoneUrl(EddystoneUrl {
+            tx_power: -21,
+            url: "https://google.com".to_string(),
+        })));
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x10, 0x00, 0x00, b'a', 0x00, b'b']),
+                   Some(BeaconFrame::EddystoneUrl(EddystoneUrl { tx_power: 0, url: "http://www.a.com/b".to_string() })));
+
+        let tlm = [0x20, 0x00, 0x0b, 0xb8, 0x19, 0x80, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x64];
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &tlm), Some(BeaconFrame::EddystoneTlm(EddystoneTlm {
+            version: 0,
+            battery_voltage: 3000,
+            temperature: 25.5,
+            advertising_count: 10,
+            uptime_deciseconds: 100,
+        })));
+    }
+
+    #[test]
+    fn decode_malformed_eddystone_frames() {
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[]), None);
+        assert_eq!(decode_service_data("0000180f-0000-1000-8000-00805f9b34fb", &[0x00; 18]), None);
+        // Truncated UID and TLM
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x00; 17]), None);
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x20, 0x00, 0x0b]), None);
+        // Unknown TLM version and frame type
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x20, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x30, 0x00, 0x00]), None);
+        // URL without a scheme, with an unknown scheme or an invalid character
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x10, 0xeb]), None);
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x10, 0xeb, 0x04, b'a']), None);
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x10, 0xeb, 0x03, b'a', 0x7f]), None);
+        assert_eq!(decode_service_data(EDDYSTONE_SERVICE_UUID, &[0x10, 0xeb, 0x03]),
+                   Some(BeaconFrame::EddystoneUrl(EddystoneUrl { tx_power: -21, url: "https://".to_string() })));
+    }
+}
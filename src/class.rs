@@ -0,0 +1,346 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MajorClass {
+    Miscellaneous,
+    Computer,
+    Phone,
+    NetworkAccessPoint,
+    AudioVideo,
+    Peripheral,
+    Imaging,
+    Wearable,
+    Toy,
+    Health,
+    Uncategorized,
+    Reserved(u8),
+}
+
+impl MajorClass {
+    fn from_u8(val: u8) -> MajorClass {
+        match val {
+            0x00 => MajorClass::Miscellaneous,
+            0x01 => MajorClass::Computer,
+            0x02 => MajorClass::Phone,
+            0x03 => MajorClass::NetworkAccessPoint,
+            0x04 => MajorClass::AudioVideo,
+            0x05 => MajorClass::Peripheral,
+            0x06 => MajorClass::Imaging,
+            0x07 => MajorClass::Wearable,
+            0x08 => MajorClass::Toy,
+            0x09 => MajorClass::Health,
+            0x1f => MajorClass::Uncategorized,
+            _ => MajorClass::Reserved(val),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComputerClass {
+    Uncategorized,
+    Desktop,
+    Server,
+    Laptop,
+    Handheld,
+    PalmSize,
+    Wearable,
+    Tablet,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhoneClass {
+    Uncategorized,
+    Cellular,
+    Cordless,
+    Smartphone,
+    WiredModem,
+    Isdn,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioVideoClass {
+    Uncategorized,
+    Headset,
+    Handsfree,
+    Microphone,
+    Loudspeaker,
+    Headphones,
+    PortableAudio,
+    CarAudio,
+    SetTopBox,
+    HifiAudio,
+    Vcr,
+    VideoCamera,
+    Camcorder,
+    VideoMonitor,
+    VideoDisplayLoudspeaker,
+    VideoConferencing,
+    GamingToy,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeripheralClass {
+    Uncategorized,
+    Keyboard,
+    Pointing,
+    KeyboardPointing,
+    Joystick,
+    Gamepad,
+    RemoteControl,
+    Sensing,
+    DigitizerTablet,
+    CardReader,
+    DigitalPen,
+    Scanner,
+    Gesture,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImagingClass {
+    Display,
+    Camera,
+    Scanner,
+    Printer,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WearableClass {
+    Wristwatch,
+    Pager,
+    Jacket,
+    Helmet,
+    Glasses,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceClass {
+    Miscellaneous,
+    Computer(ComputerClass),
+    Phone(PhoneClass),
+    NetworkAccessPoint,
+    AudioVideo(AudioVideoClass),
+    Peripheral(PeripheralClass),
+    Imaging(ImagingClass),
+    Wearable(WearableClass),
+    Toy,
+    Health,
+    Uncategorized,
+    Reserved(u8),
+}
+
+impl DeviceClass {
+    fn new(major: MajorClass, minor: u8) -> DeviceClass {
+        match major {
+            MajorClass::Miscellaneous => DeviceClass::Miscellaneous,
+            MajorClass::Computer => DeviceClass::Computer(match minor {
+                0x00 => ComputerClass::Uncategorized,
+                0x01 => ComputerClass::Desktop,
+                0x02 => ComputerClass::Server,
+                0x03 => ComputerClass::Laptop,
+                0x04 => ComputerClass::Handheld,
+                0x05 => ComputerClass::PalmSize,
+                0x06 => ComputerClass::Wearable,
+                0x07 => ComputerClass::Tablet,
+                _ => ComputerClass::Other(minor),
+            }),
+            MajorClass::Phone => DeviceClass::Phone(match minor {
+                0x00 => PhoneClass::Uncategorized,
+                0x01 => PhoneClass::Cellular,
+                0x02 => PhoneClass::Cordless,
+                0x03 => PhoneClass::Smartphone,
+                0x04 => PhoneClass::WiredModem,
+                0x05 => PhoneClass::Isdn,
+                _ => PhoneClass::Other(minor),
+            }),
+            MajorClass::NetworkAccessPoint => DeviceClass::NetworkAccessPoint,
+            MajorClass::AudioVideo => DeviceClass::AudioVideo(match minor {
+                0x00 => AudioVideoClass::Uncategorized,
+                0x01 => AudioVideoClass::Headset,
+                0x02 => AudioVideoClass::Handsfree,
+                0x04 => AudioVideoClass::Microphone,
+                0x05 => AudioVideoClass::Loudspeaker,
+                0x06 => AudioVideoClass::Headphones,
+                0x07 => AudioVideoClass::PortableAudio,
+                0x08 => AudioVideoClass::CarAudio,
+                0x09 => AudioVideoClass::SetTopBox,
+                0x0a => AudioVideoClass::HifiAudio,
+                0x0b => AudioVideoClass::Vcr,
+                0x0c => AudioVideoClass::VideoCamera,
+                0x0d => AudioVideoClass::Camcorder,
+                0x0e => AudioVideoClass::VideoMonitor,
+                0x0f => AudioVideoClass::VideoDisplayLoudspeaker,
+                0x10 => AudioVideoClass::VideoConferencing,
+                0x12 => AudioVideoClass::GamingToy,
+                _ => AudioVideoClass::Other(minor),
+            }),
+            // The upper two bits say keyboard/pointing, the lower four the device type
+            MajorClass::Peripheral => DeviceClass::Peripheral(match (minor >> 4, minor & 0x0f) {
+                (0x01, _) => PeripheralClass::Keyboard,
+                (0x02, _) => PeripheralClass::Pointing,
+                (0x03, _) => PeripheralClass::KeyboardPointing,
+                (_, 0x00) => PeripheralClass::Uncategorized,
+                (_, 0x01) => PeripheralClass::Joystick,
+                (_, 0x02) => PeripheralClass::Gamepad,
+                (_, 0x03) => PeripheralClass::RemoteControl,
+                (_, 0x04) => PeripheralClass::Sensing,
+                (_, 0x05) => PeripheralClass::DigitizerTablet,
+                (_, 0x06) => PeripheralClass::CardReader,
+                (_, 0x07) => PeripheralClass::DigitalPen,
+                (_, 0x08) => PeripheralClass::Scanner,
+                (_, 0x09) => PeripheralClass::Gesture,
+                _ => PeripheralClass::Other(minor),
+            }),
+            // Imaging minor classes are bit flags, the most specific one wins
+            MajorClass::Imaging => DeviceClass::Imaging(
+                if minor & 0x20 != 0 { ImagingClass::Printer }
+                else if minor & 0x10 != 0 { ImagingClass::Scanner }
+                else if minor & 0x08 != 0 { ImagingClass::Camera }
+                else if minor & 0x04 != 0 { ImagingClass::Display }
+                else { ImagingClass::Other(minor) }
+            ),
+            MajorClass::Wearable => DeviceClass::Wearable(match minor {
+                0x01 => WearableClass::Wristwatch,
+                0x02 => WearableClass::Pager,
+                0x03 => WearableClass::Jacket,
+                0x04 => WearableClass::Helmet,
+                0x05 => WearableClass::Glasses,
+                _ => WearableClass::Other(minor),
+            }),
+            MajorClass::Toy => DeviceClass::Toy,
+            MajorClass::Health => DeviceClass::Health,
+            MajorClass::Uncategorized => DeviceClass::Uncategorized,
+            MajorClass::Reserved(val) => DeviceClass::Reserved(val),
+        }
+    }
+}
+
+// Service class bits 13-23 of the Class of Device, shifted down to start at bit 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServiceClasses(u16);
+
+impl ServiceClasses {
+    pub const LIMITED_DISCOVERABLE: ServiceClasses = ServiceClasses(1 << 0);
+    pub const LE_AUDIO: ServiceClasses = ServiceClasses(1 << 1);
+    pub const POSITIONING: ServiceClasses = ServiceClasses(1 << 3);
+    pub const NETWORKING: ServiceClasses = ServiceClasses(1 << 4);
+    pub const RENDERING: ServiceClasses = ServiceClasses(1 << 5);
+    pub const CAPTURING: ServiceClasses = ServiceClasses(1 << 6);
+    pub const OBJECT_TRANSFER: ServiceClasses = ServiceClasses(1 << 7);
+    pub const AUDIO: ServiceClasses = ServiceClasses(1 << 8);
+    pub const TELEPHONY: ServiceClasses = ServiceClasses(1 << 9);
+    pub const INFORMATION: ServiceClasses = ServiceClasses(1 << 10);
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, other: ServiceClasses) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        let names = [
+            (ServiceClasses::LIMITED_DISCOVERABLE, "Limited Discoverable"),
+            (ServiceClasses::LE_AUDIO, "LE Audio"),
+            (ServiceClasses::POSITIONING, "Positioning"),
+            (ServiceClasses::NETWORKING, "Networking"),
+            (ServiceClasses::RENDERING, "Rendering"),
+            (ServiceClasses::CAPTURING, "Capturing"),
+            (ServiceClasses::OBJECT_TRANSFER, "Object Transfer"),
+            (ServiceClasses::AUDIO, "Audio"),
+            (ServiceClasses::TELEPHONY, "Telephony"),
+            (ServiceClasses::INFORMATION, "Information"),
+        ];
+        names.iter().filter(|x| self.contains(x.0)).map(|x| x.1).collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClassOfDevice(u32);
+
+impl ClassOfDevice {
+    pub fn new(class: u32) -> ClassOfDevice {
+        ClassOfDevice(class & 0xff_ffff)
+    }
+
+    pub fn value(self) -> u32 {
+        self.0
+    }
+
+    pub fn major_class(self) -> MajorClass {
+        MajorClass::from_u8(((self.0 >> 8) & 0x1f) as u8)
+    }
+
+    // The meaning of the minor class depends on the major class
+    pub fn minor_class(self) -> u8 {
+        ((self.0 >> 2) & 0x3f) as u8
+    }
+
+    pub fn device_class(self) -> DeviceClass {
+        DeviceClass::new(self.major_class(), self.minor_class())
+    }
+
+    pub fn service_classes(self) -> ServiceClasses {
+        ServiceClasses(((self.0 >> 13) & 0x7ff) as u16)
+    }
+}
+
+impl fmt::Display for ClassOfDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:?} (minor 0x{:02x})", self.major_class(), self.minor_class()));
+
+        let services = self.service_classes().names();
+        if !services.is_empty() {
+            try!(write!(f, ", services: {}", services.join(", ")));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_known_classes() {
+        let phone = ClassOfDevice::new(0x5a020c);
+        assert_eq!(phone.major_class(), MajorClass::Phone);
+        assert_eq!(phone.device_class(), DeviceClass::Phone(PhoneClass::Smartphone));
+        assert_eq!(phone.service_classes().names(), vec!["Networking", "Capturing", "Object Transfer", "Telephony"]);
+
+        let headset = ClassOfDevice::new(0x240404);
+        assert_eq!(headset.device_class(), DeviceClass::AudioVideo(AudioVideoClass::Headset));
+        assert!(headset.service_classes().contains(ServiceClasses::AUDIO));
+        assert!(!headset.service_classes().contains(ServiceClasses::TELEPHONY));
+        assert_eq!(headset.to_string(), "AudioVideo (minor 0x01), services: Rendering, Audio");
+
+        assert_eq!(ClassOfDevice::new(0x00010c).device_class(), DeviceClass::Computer(ComputerClass::Laptop));
+        assert_eq!(ClassOfDevice::new(0x002540).device_class(), DeviceClass::Peripheral(PeripheralClass::Keyboard));
+        assert_eq!(ClassOfDevice::new(0x002540).service_classes(), ServiceClasses::LIMITED_DISCOVERABLE);
+        assert_eq!(ClassOfDevice::new(0x000580).device_class(), DeviceClass::Peripheral(PeripheralClass::Pointing));
+        assert_eq!(ClassOfDevice::new(0x000508).device_class(), DeviceClass::Peripheral(PeripheralClass::Gamepad));
+        assert_eq!(ClassOfDevice::new(0x000680).device_class(), DeviceClass::Imaging(ImagingClass::Printer));
+        assert_eq!(ClassOfDevice::new(0x000704).device_class(), DeviceClass::Wearable(WearableClass::Wristwatch));
+    }
+
+    #[test]
+    fn decode_unknown_classes() {
+        assert_eq!(ClassOfDevice::new(0).device_class(), DeviceClass::Miscellaneous);
+        assert_eq!(ClassOfDevice::new(0).to_string(), "Miscellaneous (minor 0x00)");
+        assert_eq!(ClassOfDevice::new(0x001f00).device_class(), DeviceClass::Uncategorized);
+        assert_eq!(ClassOfDevice::new(0x000b00).device_class(), DeviceClass::Reserved(0x0b));
+        assert_eq!(ClassOfDevice::new(0x0001fc).device_class(), DeviceClass::Computer(ComputerClass::Other(0x3f)));
+        assert_eq!(ClassOfDevice::new(0x00040c).device_class(), DeviceClass::AudioVideo(AudioVideoClass::Other(0x03)));
+        assert_eq!(ClassOfDevice::new(0x000600).device_class(), DeviceClass::Imaging(ImagingClass::Other(0)));
+        // Bits above the 24 bit class are ignored
+        assert_eq!(ClassOfDevice::new(0xff5a020c).value(), 0x5a020c);
+        assert_eq!(ClassOfDevice::new(0xffffffff).service_classes().bits(), 0x7ff);
+    }
+}
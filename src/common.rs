@@ -1,6 +1,11 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
 use dbus;
 
 use error::BtError;
+use pending::PendingCall;
 
 pub static SERVICE_NAME: &'static str = "org.bluez";
 
@@ -8,6 +13,15 @@ pub fn dbus_get_managed_objects<T, F>(conn: &super::Connection,
                                       path: &str,
                                       iface: &str,
                                       f: F) -> Result<Vec<T>, BtError> where F: Fn(super::Connection, &str) -> T {
+    dbus_get_managed_objects_with_props(conn, path, iface, |conn, obj_path, _| f(conn, obj_path))
+}
+
+// Same as dbus_get_managed_objects, but also passes the iface properties (unwrapped from their variants)
+pub fn dbus_get_managed_objects_with_props<T, F>(conn: &super::Connection,
+                                                 path: &str,
+                                                 iface: &str,
+                                                 f: F) -> Result<Vec<T>, BtError>
+                                                 where F: Fn(super::Connection, &str, BTreeMap<String, dbus::MessageItem>) -> T {
     let mut path = path.to_string();
     if !path.ends_with("/") { path.push_str("/"); }
     let mut objects_vec = Vec::new();
@@ -26,11 +40,12 @@ pub fn dbus_get_managed_objects<T, F>(conn: &super::Connection,
         let obj_ifaces: &[dbus::MessageItem] = obj_ifaces.inner().unwrap();
 
         for obj_iface in obj_ifaces {
-            let (obj_iface_name, _) = obj_iface.inner().unwrap();
+            let (obj_iface_name, obj_props) = obj_iface.inner().unwrap();
             let obj_iface_name: &str = obj_iface_name.inner().unwrap();
 
             if obj_iface_name == iface && obj_path.starts_with(&path) {
-                objects_vec.push(f(conn.clone(), obj_path));
+                let obj_props: &[dbus::MessageItem] = obj_props.inner().unwrap_or(&[]);
+                objects_vec.push(f(conn.clone(), obj_path, dbus_props_to_map(obj_props)));
             }
         }
     }
@@ -38,10 +53,113 @@ pub fn dbus_get_managed_objects<T, F>(conn: &super::Connection,
     Ok(objects_vec)
 }
 
-pub fn dbus_get_property<'a, T>(conn: &super::Connection,
-                                object_path: &str,
-                                interface: &str,
-                                prop_name: &str) -> Result<dbus::MessageItem, BtError> {
+// An entry of an a{sv} dict, e.g. of the options passed to the Register* methods
+pub fn dbus_dict_entry<T: Into<dbus::MessageItem>>(key: &str, val: T) -> dbus::MessageItem {
+    dbus::MessageItem::DictEntry(Box::new(key.into()), Box::new(dbus::MessageItem::Variant(Box::new(val.into()))))
+}
+
+pub fn dbus_props_to_map(props: &[dbus::MessageItem]) -> BTreeMap<String, dbus::MessageItem> {
+    let mut props_map = BTreeMap::new();
+
+    for kv in props {
+        if let Ok((name, val)) = kv.inner() as Result<(&dbus::MessageItem, &dbus::MessageItem), ()> {
+            if let Ok(name) = name.inner() as Result<&str, ()> {
+                let val = (val.inner() as Result<&dbus::MessageItem, ()>).unwrap_or(val);
+                props_map.insert(name.to_string(), val.clone());
+            }
+        }
+    }
+
+    props_map
+}
+
+// A property of a props map which BlueZ always sets, an error if it's missing or has another type
+pub fn dbus_required_prop<'a, T>(props_map: &'a BTreeMap<String, dbus::MessageItem>, name: &str) -> Result<T, BtError>
+    where T: dbus::FromMessageItem<'a> {
+    props_map.get(name)
+        .and_then(|x| (x.inner() as Result<T, ()>).ok())
+        .ok_or_else(|| BtError::DBusInternal(format!("missing or invalid {} property", name)))
+}
+
+// Returns the object path from an InterfacesAdded signal if one of the added interfaces is iface
+pub fn dbus_interfaces_added(s: &dbus::Message, iface: &str) -> Option<String> {
+    dbus_interfaces_added_with_props(s, iface).map(|x| x.0)
+}
+
+// Same as dbus_interfaces_added, but also returns the iface properties (unwrapped from their variants)
+pub fn dbus_interfaces_added_with_props(s: &dbus::Message, iface: &str) -> Option<(String, BTreeMap<String, dbus::MessageItem>)> {
+    match s.member() {
+        Some(ref member) if &**member == "InterfacesAdded" => {}
+        _ => return None,
+    }
+
+    let items = s.get_items();
+
+    let obj_path: Option<&str> = items.get(0).and_then(|x| x.inner().ok());
+    let dict: &[dbus::MessageItem] = items.get(1).and_then(|x| x.inner().ok()).unwrap_or(&[]);
+
+    for kv in dict {
+        let (obj_iface, obj_props) = kv.inner().unwrap();
+        let obj_iface: &str = obj_iface.inner().unwrap();
+
+        if obj_iface == iface {
+            let obj_props: &[dbus::MessageItem] = obj_props.inner().unwrap_or(&[]);
+            return obj_path.map(|x| (x.to_string(), dbus_props_to_map(obj_props)));
+        }
+    }
+
+    None
+}
+
+// Returns the object path from an InterfacesRemoved signal if one of the removed interfaces is iface
+pub fn dbus_interfaces_removed(s: &dbus::Message, iface: &str) -> Option<String> {
+    match s.member() {
+        Some(ref member) if &**member == "InterfacesRemoved" => {}
+        _ => return None,
+    }
+
+    let items = s.get_items();
+
+    let obj_path: Option<&str> = items.get(0).and_then(|x| x.inner().ok());
+    let ifaces: &[dbus::MessageItem] = items.get(1).and_then(|x| x.inner().ok()).unwrap_or(&[]);
+
+    if ifaces.iter().any(|x| x.inner() == Ok(iface)) {
+        return obj_path.map(|x| x.to_string());
+    }
+
+    None
+}
+
+// Returns the changed properties (unwrapped from their variants) from a PropertiesChanged signal for iface
+pub fn dbus_properties_changed(s: &dbus::Message, iface: &str) -> Option<BTreeMap<String, dbus::MessageItem>> {
+    dbus_properties_changed_with_invalidated(s, iface).map(|x| x.0)
+}
+
+// Same as dbus_properties_changed, but also returns the names of the invalidated properties
+pub fn dbus_properties_changed_with_invalidated(s: &dbus::Message, iface: &str) -> Option<(BTreeMap<String, dbus::MessageItem>, Vec<String>)> {
+    match s.member() {
+        Some(ref member) if &**member == "PropertiesChanged" => {}
+        _ => return None,
+    }
+
+    let items = s.get_items();
+
+    let changed_iface: &str = items.get(0).and_then(|x| x.inner().ok()).unwrap_or("");
+    if changed_iface != iface {
+        return None;
+    }
+
+    let props: &[dbus::MessageItem] = items.get(1).and_then(|x| x.inner().ok()).unwrap_or(&[]);
+    let invalidated: &[dbus::MessageItem] = items.get(2).and_then(|x| x.inner().ok()).unwrap_or(&[]);
+    let invalidated = invalidated.iter().filter_map(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string()).collect();
+
+    Some((dbus_props_to_map(props), invalidated))
+}
+
+pub fn dbus_get_property(conn: &super::Connection,
+                         object_path: &str,
+                         interface: &str,
+                         prop_name: &str) -> Result<dbus::MessageItem, BtError> {
     let p = dbus::Props::new(conn, SERVICE_NAME, object_path, interface, 1000);
     Ok(try!(p.get(prop_name)))
 }
@@ -67,6 +185,17 @@ pub fn dbus_call_method0(conn: &super::Connection,
     Ok(())
 }
 
+pub fn dbus_call_method0_with_reply(conn: &super::Connection,
+                                    object_path: &str,
+                                    interface: &str,
+                                    method_name: &str) -> Result<dbus::Message, BtError> {
+    let m = try!(
+        dbus::Message::new_method_call(SERVICE_NAME, object_path, interface, method_name)
+            .map_err(BtError::DBusInternal)
+    );
+    Ok(try!(conn.send_with_reply_and_block(m, 60000)))
+}
+
 pub fn dbus_call_method1<T>(conn: &super::Connection,
                             object_path: &str,
                             interface: &str,
@@ -81,6 +210,19 @@ pub fn dbus_call_method1<T>(conn: &super::Connection,
     Ok(())
 }
 
+pub fn dbus_call_method1_with_reply<T>(conn: &super::Connection,
+                                       object_path: &str,
+                                       interface: &str,
+                                       method_name: &str,
+                                       method_arg1: T) -> Result<dbus::Message, BtError> where T: dbus::arg::Append {
+    let mut m = try!(
+        dbus::Message::new_method_call(SERVICE_NAME, object_path, interface, method_name)
+            .map_err(BtError::DBusInternal)
+    );
+    m = m.append1(method_arg1);
+    Ok(try!(conn.send_with_reply_and_block(m, 60000)))
+}
+
 pub fn dbus_call_method2<T1, T2>(conn: &super::Connection,
                                  object_path: &str,
                                  interface: &str,
@@ -96,3 +238,108 @@ pub fn dbus_call_method2<T1, T2>(conn: &super::Connection,
     try!(conn.send_with_reply_and_block(m, 60000));
     Ok(())
 }
+
+pub fn dbus_call_method2_with_reply<T1, T2>(conn: &super::Connection,
+                                            object_path: &str,
+                                            interface: &str,
+                                            method_name: &str,
+                                            method_arg1: T1,
+                                            method_arg2: T2) -> Result<dbus::Message, BtError>
+                                                             where T1: dbus::arg::Append, T2: dbus::arg::Append {
+    let mut m = try!(
+        dbus::Message::new_method_call(SERVICE_NAME, object_path, interface, method_name)
+            .map_err(BtError::DBusInternal)
+    );
+    m = m.append2(method_arg1, method_arg2);
+    Ok(try!(conn.send_with_reply_and_block(m, 60000)))
+}
+
+pub fn dbus_call_method3<T1, T2, T3>(conn: &super::Connection,
+                                     object_path: &str,
+                                     interface: &str,
+                                     method_name: &str,
+                                     method_arg1: T1,
+                                     method_arg2: T2,
+                                     method_arg3: T3) -> Result<(), BtError>
+                                                      where T1: dbus::arg::Append, T2: dbus::arg::Append, T3: dbus::arg::Append {
+    let mut m = try!(
+        dbus::Message::new_method_call(SERVICE_NAME, object_path, interface, method_name)
+            .map_err(BtError::DBusInternal)
+    );
+    m = m.append3(method_arg1, method_arg2, method_arg3);
+    try!(conn.send_with_reply_and_block(m, 60000));
+    Ok(())
+}
+
+// Same as the helpers above, which wait up to 1 s for properties and 60 s for methods, but with
+// the caller's timeout. BlueZ keeps going when the call times out, the reply is just dropped.
+pub fn dbus_call_method_with_timeout(conn: &super::Connection,
+                                     object_path: &str,
+                                     interface: &str,
+                                     method_name: &str,
+                                     method_args: &[dbus::MessageItem],
+                                     timeout: Duration) -> Result<dbus::Message, BtError> {
+    let mut m = try!(
+        dbus::Message::new_method_call(SERVICE_NAME, object_path, interface, method_name)
+            .map_err(BtError::DBusInternal)
+    );
+    m.append_items(method_args);
+    conn.send_with_reply_and_block(m, timeout_ms(timeout)).map_err(timeout_err)
+}
+
+// Same as dbus_call_method_with_timeout, but returns right away. The reply is passed to parse.
+pub fn dbus_start_call<T, F>(conn: &super::Connection,
+                             object_path: &str,
+                             interface: &str,
+                             method_name: &str,
+                             method_args: &[dbus::MessageItem],
+                             timeout: Duration,
+                             parse: F) -> PendingCall<T> where F: Fn(dbus::Message) -> Result<T, BtError> + 'static {
+    let mut m = match dbus::Message::new_method_call(SERVICE_NAME, object_path, interface, method_name) {
+        Ok(m) => m,
+        Err(err) => return PendingCall::failed(conn, BtError::DBusInternal(err)),
+    };
+    m.append_items(method_args);
+    PendingCall::send(conn, m, timeout, parse)
+}
+
+pub fn dbus_start_get_all_properties<T, F>(conn: &super::Connection,
+                                           object_path: &str,
+                                           interface: &str,
+                                           parse: F) -> PendingCall<T>
+                                           where F: Fn(BTreeMap<String, dbus::MessageItem>) -> Result<T, BtError> + 'static {
+    dbus_start_call(conn, object_path, "org.freedesktop.DBus.Properties", "GetAll", &[interface.into()], Duration::from_secs(1), move |reply| {
+        let items = reply.get_items();
+        let props: &[dbus::MessageItem] = try!(items.get(0).and_then(|x| x.inner().ok())
+            .ok_or_else(|| BtError::DBusInternal("invalid GetAll reply".to_string())));
+        parse(dbus_props_to_map(props))
+    })
+}
+
+pub fn dbus_start_set_property<T>(conn: &super::Connection,
+                                  object_path: &str,
+                                  interface: &str,
+                                  prop_name: &str,
+                                  prop_val: T) -> PendingCall<()> where T: Into<dbus::MessageItem> {
+    let args = [interface.into(), prop_name.into(), dbus::MessageItem::Variant(Box::new(prop_val.into()))];
+    dbus_start_call(conn, object_path, "org.freedesktop.DBus.Properties", "Set", &args, Duration::from_secs(1), |_| Ok(()))
+}
+
+pub fn dbus_get_all_properties_with_timeout(conn: &super::Connection,
+                                            object_path: &str,
+                                            interface: &str,
+                                            timeout: Duration) -> Result<BTreeMap<String, dbus::MessageItem>, BtError> {
+    let p = dbus::Props::new(conn, SERVICE_NAME, object_path, interface, timeout_ms(timeout));
+    p.get_all().map_err(timeout_err)
+}
+
+fn timeout_ms(timeout: Duration) -> i32 {
+    cmp::min(timeout.as_millis(), i32::MAX as u128) as i32
+}
+
+pub fn timeout_err(err: dbus::Error) -> BtError {
+    match err.name() {
+        Some("org.freedesktop.DBus.Error.NoReply") | Some("org.freedesktop.DBus.Error.Timeout") => BtError::Timeout,
+        _ => BtError::DBus(err),
+    }
+}
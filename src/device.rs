@@ -1,22 +1,63 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use dbus;
 
 use adapter::{self, Adapter};
+use admin_policy;
+use agent::{Agent, AgentManager};
+use beacon::{self, BeaconFrame};
+use class::{ClassOfDevice, DeviceClass};
 use common;
+use device_set::DeviceSet;
 use error::BtError;
+use input::Input;
+use mgmt;
+use network::Network;
+use pending::PendingCall;
+use properties::{self, PropertiesWatcher, PropertyValue};
 
 pub static DEVICE_INTERFACE: &'static str = "org.bluez.Device1";
 
+// Operations in flight per device object path, shared by all connections in the process
+static PENDING_OPERATIONS: Mutex<Vec<(String, DeviceOperation)>> = Mutex::new(Vec::new());
+
 #[derive(Clone, Debug)]
 pub struct Device {
     conn: super::Connection,
     object_path: String,
 }
 
+// A Device which can be sent to other threads, see SyncConnection
+#[derive(Clone, Debug)]
+pub struct SyncDevice {
+    conn: super::SyncConnection,
+    object_path: String,
+}
+
+impl SyncDevice {
+    pub fn new(conn: &super::SyncConnection, object_path: &str) -> SyncDevice {
+        SyncDevice { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    // Runs f with the device on the I/O thread of the connection
+    pub fn run<T, F>(&self, f: F) -> Result<T, BtError>
+        where F: FnOnce(&Device) -> Result<T, BtError> + Send + 'static, T: Send + 'static {
+        let object_path = self.object_path.clone();
+        self.conn.run(move |conn| f(&Device::new(conn, &object_path)))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DeviceProperties {
     pub address: String,
+    pub address_type: Option<AddressType>,
     pub name: Option<String>,
     pub alias: String,
     pub icon: Option<String>,
@@ -24,13 +65,156 @@ pub struct DeviceProperties {
     pub appearance: Option<u16>,
     pub uuids: Vec<String>,
     pub paired: bool,
+    pub bonded: bool,
     pub connected: bool,
     pub trusted: bool,
     pub blocked: bool,
+    pub wake_allowed: Option<bool>,
     pub legacy_pairing: bool,
+    pub services_resolved: bool,
     pub modalias: Option<String>,
     pub rssi: Option<i16>,
-    // TODO: ManufacturerData, ServiceData, GattServices
+    pub tx_power: Option<i16>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    pub service_data: HashMap<String, Vec<u8>>,
+    pub advertising_flags: Vec<u8>,
+    pub advertising_data: HashMap<u8, Vec<u8>>,
+    // Object paths of the coordinated sets (DeviceSet1) the device is a member of
+    pub sets: Vec<String>,
+    // TODO: GattServices
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressType {
+    Public,
+    Random,
+}
+
+impl AddressType {
+    fn from_str(s: &str) -> Option<AddressType> {
+        match s {
+            "public" => Some(AddressType::Public),
+            "random" => Some(AddressType::Random),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LePhy {
+    Le1M,
+    Le2M,
+    LeCoded,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub rssi: i8,
+    pub tx_power: i8,
+    pub max_tx_power: i8,
+    // The LE PHYs selected on the controller (MGMT Get PHY Configuration), which are the ones it
+    // allows for its connections. They aren't necessarily the PHYs this link currently uses.
+    pub controller_selected_tx_phys: Vec<LePhy>,
+    pub controller_selected_rx_phys: Vec<LePhy>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceOperation {
+    Connect,
+    Disconnect,
+    ConnectProfile,
+    DisconnectProfile,
+    Pair,
+}
+
+impl DeviceOperation {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceOperation::Connect => "Connect",
+            DeviceOperation::Disconnect => "Disconnect",
+            DeviceOperation::ConnectProfile => "ConnectProfile",
+            DeviceOperation::DisconnectProfile => "DisconnectProfile",
+            DeviceOperation::Pair => "Pair",
+        }
+    }
+
+    // Disconnect is how BlueZ cancels a pending connect, everything else has to wait its turn
+    fn conflicts_with(self, pending: DeviceOperation) -> bool {
+        let cancels = pending == DeviceOperation::Connect || pending == DeviceOperation::ConnectProfile;
+        !(self == DeviceOperation::Disconnect && cancels)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+            multiplier: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay before the given retry (1 is the first retry)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 1..retry {
+            delay *= self.multiplier;
+            if delay >= self.max_delay {
+                return self.max_delay;
+            }
+        }
+        if delay > self.max_delay { self.max_delay } else { delay }
+    }
+}
+
+// The transient failures BlueZ reports for LE connections which usually succeed on a second try
+fn is_transient_connect_error(err: &BtError) -> bool {
+    match *err {
+        BtError::Busy(..) => true,
+        BtError::DBus(ref err) => {
+            let msg = err.message().unwrap_or("");
+            err.name() == Some("org.bluez.Error.InProgress") ||
+                msg.contains("le-connection-abort-by-local") ||
+                msg.contains("Software caused connection abort")
+        }
+        _ => false,
+    }
+}
+
+struct OperationGuard {
+    object_path: String,
+    op: DeviceOperation,
+}
+
+impl OperationGuard {
+    fn begin(object_path: &str, op: DeviceOperation) -> Result<OperationGuard, BtError> {
+        let mut pending_ops = PENDING_OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(&(_, pending)) = pending_ops.iter().find(|&&(ref path, pending)| path == object_path && op.conflicts_with(pending)) {
+            return Err(BtError::Busy(pending.name()));
+        }
+        pending_ops.push((object_path.to_string(), op));
+        Ok(OperationGuard { object_path: object_path.to_string(), op: op })
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        let mut pending_ops = PENDING_OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = pending_ops.iter().position(|&(ref path, op)| *path == self.object_path && op == self.op) {
+            pending_ops.remove(i);
+        }
+    }
 }
 
 impl Device {
@@ -39,10 +223,9 @@ impl Device {
     }
 
     pub fn find(adapter: &Adapter, name_or_addr: &str) -> Result<Option<Self>, BtError> {
-        let devices = try!(get_devices(adapter));
+        let devices = try!(get_devices_with_properties(adapter));
 
-        for device in devices {
-            let p = try!(device.get_properties());
+        for (device, p) in devices {
             if p.address == name_or_addr || p.alias == name_or_addr || (p.name.is_some() && p.name.unwrap() == name_or_addr) {
                 return Ok(Some(device));
             }
@@ -51,20 +234,104 @@ impl Device {
         Ok(None)
     }
 
+    pub fn conn(&self) -> &super::Connection {
+        &self.conn
+    }
+
     pub fn object_path(&self) -> &str {
         &self.object_path
     }
 
+    // On the SyncConnection to the bus of this device's connection
+    pub fn to_sync(&self) -> Result<SyncDevice, BtError> {
+        Ok(SyncDevice::new(&try!(self.conn.to_sync()), &self.object_path))
+    }
+
     pub fn adapter_object_path(&self) -> &str {
         self.object_path.rsplitn(2, '/').last().unwrap()
     }
 
+    // Only usable if the device implements Input1 (see input::get_inputs())
+    pub fn input(&self) -> Input {
+        Input::new(self)
+    }
+
+    // Only usable if the device offers a PAN service (NAP or GN)
+    pub fn network(&self) -> Network {
+        Network::new(self)
+    }
+
     //
     // Properties
     //
     pub fn get_properties(&self) -> Result<DeviceProperties, BtError> {
         let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, DEVICE_INTERFACE, 1000);
-        Ok(DeviceProperties::new(try!(p.get_all())))
+        DeviceProperties::new(try!(p.get_all()))
+    }
+
+    pub fn get_properties_with_timeout(&self, timeout: Duration) -> Result<DeviceProperties, BtError> {
+        DeviceProperties::new(try!(common::dbus_get_all_properties_with_timeout(&self.conn, &self.object_path, DEVICE_INTERFACE, timeout)))
+    }
+
+    pub fn get_affected_by_policy(&self) -> Result<bool, BtError> {
+        admin_policy::get_affected_by_policy(&self.conn, &self.object_path)
+    }
+
+    pub fn get_sets(&self) -> Result<Vec<DeviceSet>, BtError> {
+        Ok(try!(self.get_properties()).sets.iter().map(|x| DeviceSet::new(&self.conn, x)).collect())
+    }
+
+    pub fn get_services_resolved(&self) -> Result<bool, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, DEVICE_INTERFACE, "ServicesResolved"));
+        val.inner().map_err(|_| BtError::DBusInternal("invalid ServicesResolved value".to_string()))
+    }
+
+    // Queries the active link via MGMT, so it needs CAP_NET_ADMIN
+    pub fn get_connection_info(&self) -> Result<ConnectionInfo, BtError> {
+
+        fn _decode_phys(selected_phys: u32, phy_bits: [(u32, LePhy); 3]) -> Vec<LePhy> {
+            phy_bits.iter().filter(|x| selected_phys & x.0 != 0).map(|x| x.1).collect()
+        }
+
+        let index = try!(mgmt::adapter_index(self.adapter_object_path()));
+
+        let address = try!(common::dbus_get_property(&self.conn, &self.object_path, DEVICE_INTERFACE, "Address"));
+        let address = try!(mgmt::parse_address(address.inner().unwrap_or("")));
+
+        let address_type = common::dbus_get_property(&self.conn, &self.object_path, DEVICE_INTERFACE, "AddressType").ok();
+        let address_types = match address_type.as_ref().and_then(|x| x.inner().ok()).and_then(AddressType::from_str) {
+            Some(AddressType::Random) => vec![mgmt::BDADDR_LE_RANDOM],
+            _ => vec![mgmt::BDADDR_BREDR, mgmt::BDADDR_LE_PUBLIC],
+        };
+
+        for address_type in address_types {
+            let mut params = address.to_vec();
+            params.push(address_type);
+
+            let info = match mgmt::send_command(index, mgmt::MGMT_OP_GET_CONN_INFO, &params) {
+                Ok(info) => info,
+                Err(BtError::Mgmt(mgmt::MGMT_STATUS_NOT_CONNECTED)) => continue,
+                Err(e) => return Err(e),
+            };
+            if info.len() < 10 {
+                return Err(BtError::DBusInternal("invalid Get Connection Information reply".to_string()));
+            }
+
+            let phys = try!(mgmt::send_command(index, mgmt::MGMT_OP_GET_PHY_CONFIGURATION, &[]));
+            let selected_phys = if phys.len() >= 12 {
+                phys[8] as u32 | (phys[9] as u32) << 8 | (phys[10] as u32) << 16 | (phys[11] as u32) << 24
+            } else { 0 };
+
+            return Ok(ConnectionInfo {
+                rssi: info[7] as i8,
+                tx_power: info[8] as i8,
+                max_tx_power: info[9] as i8,
+                controller_selected_tx_phys: _decode_phys(selected_phys, [(1 << 9, LePhy::Le1M), (1 << 11, LePhy::Le2M), (1 << 13, LePhy::LeCoded)]),
+                controller_selected_rx_phys: _decode_phys(selected_phys, [(1 << 10, LePhy::Le1M), (1 << 12, LePhy::Le2M), (1 << 14, LePhy::LeCoded)]),
+            });
+        }
+
+        Err(BtError::Mgmt(mgmt::MGMT_STATUS_NOT_CONNECTED))
     }
 
     pub fn set_alias(&self, val: &str) -> Result<(), BtError> {
@@ -79,64 +346,270 @@ impl Device {
         common::dbus_set_property(&self.conn, &self.object_path, DEVICE_INTERFACE, "Blocked", val)
     }
 
+    pub fn set_wake_allowed(&self, val: bool) -> Result<(), BtError> {
+        common::dbus_set_property(&self.conn, &self.object_path, DEVICE_INTERFACE, "WakeAllowed", val)
+    }
+
+    // Yields RSSI samples as BlueZ reports them, which only happens while discovery is running
+    pub fn watch_rssi(&self) -> Result<RssiWatcher, BtError> {
+        let watcher = try!(properties::watch_properties(&self.conn, &self.object_path, DEVICE_INTERFACE));
+        Ok(RssiWatcher { watcher: watcher, timeout: None })
+    }
+
+    // E.g. wait_for_property("Connected", true, timeout) after connect()
+    pub fn wait_for_property<T>(&self, name: &str, expected: T, timeout: Duration) -> Result<(), BtError> where T: Into<PropertyValue> {
+        properties::wait_for_property(&self.conn, &self.object_path, DEVICE_INTERFACE, name, expected, timeout)
+    }
+
     //
     // Methods
     //
     pub fn connect(&self) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::Connect));
         common::dbus_call_method0(&self.conn, &self.object_path, DEVICE_INTERFACE, "Connect")
     }
 
+    // Fails with BtError::Timeout if BlueZ doesn't reply in time (the connection attempt may still go on)
+    pub fn connect_with_timeout(&self, timeout: Duration) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::Connect));
+        try!(common::dbus_call_method_with_timeout(&self.conn, &self.object_path, DEVICE_INTERFACE, "Connect", &[], timeout));
+        Ok(())
+    }
+
+    // Retries transient failures with backoff, other errors are returned right away
+    pub fn connect_with_retry(&self, policy: &RetryPolicy) -> Result<(), BtError> {
+        let mut attempt = 1;
+        loop {
+            match self.connect() {
+                Err(ref e) if attempt < policy.max_attempts && is_transient_connect_error(e) => {
+                    thread::sleep(policy.delay(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
     pub fn disconnect(&self) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::Disconnect));
         common::dbus_call_method0(&self.conn, &self.object_path, DEVICE_INTERFACE, "Disconnect")
     }
 
+    pub fn disconnect_with_timeout(&self, timeout: Duration) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::Disconnect));
+        try!(common::dbus_call_method_with_timeout(&self.conn, &self.object_path, DEVICE_INTERFACE, "Disconnect", &[], timeout));
+        Ok(())
+    }
+
     pub fn connect_profile(&self, uuid: &str) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::ConnectProfile));
         common::dbus_call_method1(&self.conn, &self.object_path, DEVICE_INTERFACE, "ConnectProfile", uuid)
     }
 
+    pub fn connect_profile_with_timeout(&self, uuid: &str, timeout: Duration) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::ConnectProfile));
+        try!(common::dbus_call_method_with_timeout(&self.conn, &self.object_path, DEVICE_INTERFACE, "ConnectProfile", &[uuid.into()], timeout));
+        Ok(())
+    }
+
     pub fn disconnect_profile(&self, uuid: &str) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::DisconnectProfile));
         common::dbus_call_method1(&self.conn, &self.object_path, DEVICE_INTERFACE, "DisconnectProfile", uuid)
     }
 
     pub fn pair(&self) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::Pair));
         common::dbus_call_method0(&self.conn, &self.object_path, DEVICE_INTERFACE, "Pair")
     }
 
+    // Pairing which needs user input can take longer than the default 60 s
+    pub fn pair_with_timeout(&self, timeout: Duration) -> Result<(), BtError> {
+        let _guard = try!(OperationGuard::begin(&self.object_path, DeviceOperation::Pair));
+        try!(common::dbus_call_method_with_timeout(&self.conn, &self.object_path, DEVICE_INTERFACE, "Pair", &[], timeout));
+        Ok(())
+    }
+
+    // BlueZ asks the agent registered by the connection calling Pair, so the agent is registered
+    // here and served on this connection until the reply is there
+    pub fn pair_with_agent(&self, agent: Box<Agent>) -> Result<(), BtError> {
+        let agent_manager = AgentManager::new(&self.conn, agent);
+        try!(agent_manager.register_agent());
+
+        let result = self.start_pair().wait();
+
+        let r = agent_manager.unregister_agent();
+        result.and(r)
+    }
+
     pub fn cancel_pairing(&self) -> Result<(), BtError> {
         common::dbus_call_method0(&self.conn, &self.object_path, DEVICE_INTERFACE, "CancelPairing")
     }
 
-    // TODO:
-    // pub fn connect_with_agent(&self, agent: Box<agent::Agent>) -> Result<(), BtError> {}
+    //
+    // Non-blocking methods, the reply is read whenever this connection is processed
+    //
+    pub fn start_connect(&self) -> PendingCall<()> {
+        self.start_call(DeviceOperation::Connect, "Connect", &[], Duration::from_secs(60))
+    }
+
+    pub fn start_disconnect(&self) -> PendingCall<()> {
+        self.start_call(DeviceOperation::Disconnect, "Disconnect", &[], Duration::from_secs(60))
+    }
+
+    pub fn start_connect_profile(&self, uuid: &str) -> PendingCall<()> {
+        self.start_call(DeviceOperation::ConnectProfile, "ConnectProfile", &[uuid.into()], Duration::from_secs(60))
+    }
+
+    // BlueZ asks the agent registered on this connection, so it has to be served while waiting
+    // (which poll() and wait() do), or the default agent if there is none
+    pub fn start_pair(&self) -> PendingCall<()> {
+        self.start_call(DeviceOperation::Pair, "Pair", &[], Duration::from_secs(60))
+    }
+
+    pub(crate) fn start_call(&self, op: DeviceOperation, method_name: &str, method_args: &[dbus::MessageItem], timeout: Duration) -> PendingCall<()> {
+        let guard = match OperationGuard::begin(&self.object_path, op) {
+            Ok(guard) => guard,
+            Err(err) => return PendingCall::failed(&self.conn, err),
+        };
+
+        common::dbus_start_call(&self.conn, &self.object_path, DEVICE_INTERFACE, method_name, method_args, timeout, |_| Ok(()))
+            .with_guard(guard)
+    }
+}
+
+pub struct RssiWatcher {
+    watcher: PropertiesWatcher,
+    timeout: Option<Duration>,
+}
+
+impl RssiWatcher {
+    // Ends the iteration when no sample arrives within timeout
+    pub fn timeout(mut self, timeout: Duration) -> RssiWatcher {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl Iterator for RssiWatcher {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let now = Instant::now();
+
+        loop {
+            // Other properties of the device change too, they don't restart the timeout
+            if let Some(timeout) = self.timeout {
+                match timeout.checked_sub(now.elapsed()) {
+                    Some(remaining) => self.watcher.set_timeout(Some(remaining)),
+                    None => return None,
+                }
+            }
+
+            match self.watcher.next() {
+                Some(event) => {
+                    if let Some(&PropertyValue::Int16(rssi)) = event.get("RSSI") {
+                        return Some(rssi);
+                    }
+                }
+                None => return None,
+            }
+        }
+    }
 }
 
 impl DeviceProperties {
-    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> DeviceProperties {
+    // None for devices without a Class of Device, e.g. LE only ones
+    pub fn device_class(&self) -> Option<DeviceClass> {
+        self.class.map(|x| ClassOfDevice::new(x).device_class())
+    }
+
+    pub fn manufacturer_data_for(&self, company_id: u16) -> Option<&[u8]> {
+        self.manufacturer_data.get(&company_id).map(|x| &x[..])
+    }
+
+    // Also accepts 16-bit UUIDs such as "feaa"
+    pub fn service_data_for(&self, uuid: &str) -> Option<&[u8]> {
+        let uuid = if uuid.len() == 4 {
+            format!("0000{}-0000-1000-8000-00805f9b34fb", uuid)
+        } else {
+            uuid.to_string()
+        };
+
+        self.service_data.iter()
+            .find(|&(k, _)| k.eq_ignore_ascii_case(&uuid))
+            .map(|(_, v)| &v[..])
+    }
+
+    pub fn beacon_frames<'a>(&'a self) -> impl Iterator<Item = BeaconFrame> + 'a {
+        let mf_frames = self.manufacturer_data.iter().filter_map(|(k, v)| beacon::decode_manufacturer_data(*k, v));
+        let sd_frames = self.service_data.iter().filter_map(|(k, v)| beacon::decode_service_data(k, v));
+        mf_frames.chain(sd_frames)
+    }
+
+    pub(crate) fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> Result<DeviceProperties, BtError> {
 
         fn _get_prop<'a, T>(props_map: &'a BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<T>
             where T: dbus::FromMessageItem<'a> {
             props_map.get(name).and_then(|x| (x.inner() as Result<T, ()>).ok())
         }
 
-        DeviceProperties {
-            address: _get_prop::<&str>(&props_map, "Address").unwrap().to_string(),
+        fn _get_bytes(item: &dbus::MessageItem) -> Vec<u8> {
+            let item = (item.inner() as Result<&dbus::MessageItem, ()>).unwrap_or(item);
+            (item.inner() as Result<&[dbus::MessageItem], ()>).unwrap_or(&[])
+                .iter()
+                .filter_map(|x| (x.inner() as Result<u8, ()>).ok())
+                .collect()
+        }
+
+        Ok(DeviceProperties {
+            address: try!(common::dbus_required_prop::<&str>(&props_map, "Address")).to_string(),
+            address_type: _get_prop::<&str>(&props_map, "AddressType").and_then(AddressType::from_str),
             name: _get_prop::<&str>(&props_map, "Name").map(|x| x.to_string()),
-            alias: _get_prop::<&str>(&props_map, "Alias").unwrap().to_string(),
+            alias: try!(common::dbus_required_prop::<&str>(&props_map, "Alias")).to_string(),
             icon: _get_prop::<&str>(&props_map, "Icon").map(|x| x.to_string()),
             class: _get_prop(&props_map, "Class"),
             appearance: _get_prop(&props_map, "Appearance"),
-            uuids: _get_prop::<&[dbus::MessageItem]>(&props_map, "UUIDs").unwrap_or(&Vec::new())
+            uuids: _get_prop::<&[dbus::MessageItem]>(&props_map, "UUIDs").unwrap_or(&[])
                 .iter()
-                .map(|x| (x.inner() as Result<&str, ()>).unwrap().to_string())
+                .filter_map(|x| (x.inner() as Result<&str, ()>).ok())
+                .map(|x| x.to_string())
                 .collect(),
-            paired: _get_prop(&props_map, "Paired").unwrap(),
-            connected: _get_prop(&props_map, "Connected").unwrap(),
-            trusted: _get_prop(&props_map, "Trusted").unwrap(),
-            blocked: _get_prop(&props_map, "Blocked").unwrap(),
-            legacy_pairing: _get_prop(&props_map, "LegacyPairing").unwrap(),
+            paired: try!(common::dbus_required_prop(&props_map, "Paired")),
+            bonded: _get_prop(&props_map, "Bonded").unwrap_or(false),
+            connected: try!(common::dbus_required_prop(&props_map, "Connected")),
+            trusted: try!(common::dbus_required_prop(&props_map, "Trusted")),
+            blocked: try!(common::dbus_required_prop(&props_map, "Blocked")),
+            wake_allowed: _get_prop(&props_map, "WakeAllowed"),
+            legacy_pairing: try!(common::dbus_required_prop(&props_map, "LegacyPairing")),
+            services_resolved: _get_prop(&props_map, "ServicesResolved").unwrap_or(false),
             modalias: _get_prop::<&str>(&props_map, "Modalias").map(|x| x.to_string()),
             rssi: _get_prop(&props_map, "RSSI"),
-        }
+            tx_power: _get_prop(&props_map, "TxPower"),
+            manufacturer_data: _get_prop::<&[dbus::MessageItem]>(&props_map, "ManufacturerData").unwrap_or(&[])
+                .iter()
+                .filter_map(|x| x.inner().ok())
+                .filter_map(|(k, v): (&dbus::MessageItem, &dbus::MessageItem)| (k.inner() as Result<u16, ()>).ok().map(|k| (k, _get_bytes(v))))
+                .collect(),
+            service_data: _get_prop::<&[dbus::MessageItem]>(&props_map, "ServiceData").unwrap_or(&[])
+                .iter()
+                .filter_map(|x| x.inner().ok())
+                .filter_map(|(k, v): (&dbus::MessageItem, &dbus::MessageItem)| (k.inner() as Result<&str, ()>).ok().map(|k| (k.to_string(), _get_bytes(v))))
+                .collect(),
+            advertising_flags: props_map.get("AdvertisingFlags").map(_get_bytes).unwrap_or_default(),
+            advertising_data: _get_prop::<&[dbus::MessageItem]>(&props_map, "AdvertisingData").unwrap_or(&[])
+                .iter()
+                .filter_map(|x| x.inner().ok())
+                .filter_map(|(k, v): (&dbus::MessageItem, &dbus::MessageItem)| (k.inner() as Result<u8, ()>).ok().map(|k| (k, _get_bytes(v))))
+                .collect(),
+            sets: _get_prop::<&[dbus::MessageItem]>(&props_map, "Sets").unwrap_or(&[])
+                .iter()
+                .filter_map(|x| x.inner().ok())
+                .filter_map(|(k, _): (&dbus::MessageItem, &dbus::MessageItem)| match *k {
+                    dbus::MessageItem::ObjectPath(ref path) => Some(path.to_string()),
+                    _ => None,
+                })
+                .collect(),
+        })
     }
 }
 
@@ -147,3 +620,49 @@ pub fn get_devices(adapter: &adapter::Adapter) -> Result<Vec<Device>, BtError> {
                                      |conn, obj_path| Device { conn: conn, object_path: obj_path.to_string() }
     )
 }
+
+// Takes the properties from the GetManagedObjects reply instead of querying every device
+pub fn get_devices_with_properties(adapter: &adapter::Adapter) -> Result<Vec<(Device, DeviceProperties)>, BtError> {
+    let devices = try!(common::dbus_get_managed_objects_with_props(adapter.conn(),
+                                                                   adapter.object_path(),
+                                                                   DEVICE_INTERFACE,
+                                                                   |conn, obj_path, props_map| DeviceProperties::new(props_map)
+                                                                       .map(|p| (Device { conn: conn, object_path: obj_path.to_string() }, p))
+    ));
+    devices.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use dbus::MessageItem;
+
+    use super::DeviceProperties;
+
+    fn props_map(props: Vec<(&str, MessageItem)>) -> BTreeMap<String, MessageItem> {
+        props.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+    }
+
+    #[test]
+    fn properties_from_partial_maps() {
+        assert!(DeviceProperties::new(BTreeMap::new()).is_err());
+        assert!(DeviceProperties::new(props_map(vec![("Address", "00:1A:7D:DA:71:13".into()), ("Alias", "x".into())])).is_err());
+        // Wrong type
+        assert!(DeviceProperties::new(props_map(vec![("Address", 1u32.into())])).is_err());
+
+        let p = DeviceProperties::new(props_map(vec![
+            ("Address", "00:1A:7D:DA:71:13".into()),
+            ("Alias", "Headset".into()),
+            ("Paired", true.into()),
+            ("Connected", false.into()),
+            ("Trusted", false.into()),
+            ("Blocked", false.into()),
+            ("LegacyPairing", false.into()),
+        ])).unwrap();
+        assert_eq!(p.address, "00:1A:7D:DA:71:13");
+        assert!(p.paired);
+        assert!(p.uuids.is_empty());
+        assert_eq!(p.name, None);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use dbus;
+
+use adapter::Adapter;
+use common;
+use device::Device;
+use error::BtError;
+
+pub static DEVICE_SET_INTERFACE: &'static str = "org.bluez.DeviceSet1";
+
+#[derive(Clone, Debug)]
+pub struct DeviceSetProperties {
+    pub adapter: Option<String>,
+    pub auto_connect: bool,
+    pub devices: Vec<String>,
+    pub size: Option<u8>,
+}
+
+impl DeviceSetProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> DeviceSetProperties {
+        fn _get_path(item: &dbus::MessageItem) -> Option<String> {
+            match *item {
+                dbus::MessageItem::ObjectPath(ref path) => Some(path.to_string()),
+                _ => None,
+            }
+        }
+
+        DeviceSetProperties {
+            adapter: props_map.get("Adapter").and_then(_get_path),
+            auto_connect: props_map.get("AutoConnect").and_then(|x| x.inner().ok()).unwrap_or(false),
+            devices: props_map.get("Devices")
+                .and_then(|x| (x.inner() as Result<&[dbus::MessageItem], ()>).ok())
+                .map(|x| x.iter().filter_map(_get_path).collect())
+                .unwrap_or_default(),
+            size: props_map.get("Size").and_then(|x| x.inner().ok()),
+        }
+    }
+}
+
+// A coordinated set (CSIP), e.g. a pair of earbuds, which can be connected as a unit
+#[derive(Clone, Debug)]
+pub struct DeviceSet {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl DeviceSet {
+    pub fn new(conn: &super::Connection, object_path: &str) -> DeviceSet {
+        DeviceSet { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<DeviceSetProperties, BtError> {
+        let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, DEVICE_SET_INTERFACE, 1000);
+        Ok(DeviceSetProperties::new(try!(p.get_all())))
+    }
+
+    // The members known so far, which may be fewer than the set size
+    pub fn get_devices(&self) -> Result<Vec<Device>, BtError> {
+        Ok(try!(self.get_properties()).devices.iter().map(|x| Device::new(&self.conn, x)).collect())
+    }
+
+    pub fn set_auto_connect(&self, val: bool) -> Result<(), BtError> {
+        common::dbus_set_property(&self.conn, &self.object_path, DEVICE_SET_INTERFACE, "AutoConnect", val)
+    }
+
+    // Connects all members of the set
+    pub fn connect(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, DEVICE_SET_INTERFACE, "Connect")
+    }
+
+    pub fn disconnect(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, DEVICE_SET_INTERFACE, "Disconnect")
+    }
+}
+
+pub fn get_device_sets(adapter: &Adapter) -> Result<Vec<(DeviceSet, DeviceSetProperties)>, BtError> {
+    common::dbus_get_managed_objects_with_props(adapter.conn(),
+                                                adapter.object_path(),
+                                                DEVICE_SET_INTERFACE,
+                                                |conn, obj_path, props_map| (DeviceSet::new(&conn, obj_path), DeviceSetProperties::new(props_map))
+    )
+}
@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use dbus;
+
+use adapter::{self, Adapter, DiscoveryFilter};
+use common;
+use device::{self, Device};
+use error::BtError;
+use registry::SignalWatch;
+
+#[derive(Clone, Debug)]
+pub enum DiscoveryEvent {
+    DeviceFound(Device),
+    // Only the properties that changed, unwrapped from their variants
+    DeviceUpdated(Device, BTreeMap<String, dbus::MessageItem>),
+    DeviceRemoved(Device),
+}
+
+// Can be moved to another thread (e.g. a GUI's cancel button) to end a running session
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    pub fn new() -> StopHandle {
+        StopHandle::default()
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+pub struct DiscoverySessionBuilder<'a> {
+    adapter: &'a Adapter,
+    filter: Option<DiscoveryFilter>,
+    duration: Option<Duration>,
+    dedup: bool,
+    stop_handle: StopHandle,
+}
+
+impl<'a> DiscoverySessionBuilder<'a> {
+    pub fn new(adapter: &'a Adapter) -> DiscoverySessionBuilder<'a> {
+        DiscoverySessionBuilder { adapter: adapter, filter: None, duration: None, dedup: true, stop_handle: StopHandle::new() }
+    }
+
+    pub fn filter(mut self, filter: DiscoveryFilter) -> DiscoverySessionBuilder<'a> {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> DiscoverySessionBuilder<'a> {
+        self.duration = Some(duration);
+        self
+    }
+
+    // With dedup (the default) every device is reported once, otherwise on every RSSI update as well
+    pub fn dedup(mut self, dedup: bool) -> DiscoverySessionBuilder<'a> {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn stop_handle(mut self, stop_handle: &StopHandle) -> DiscoverySessionBuilder<'a> {
+        self.stop_handle = stop_handle.clone();
+        self
+    }
+
+    pub fn start(self) -> Result<DiscoverySession<'a>, BtError> {
+        let conn = self.adapter.conn();
+
+        let rules = [
+            format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesAdded'", common::SERVICE_NAME),
+            format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesRemoved'", common::SERVICE_NAME),
+            format!("sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'", common::SERVICE_NAME),
+        ];
+
+        // The session is created up front, so whatever was set up before a failure is undone by its drop
+        let mut session = DiscoverySession {
+            adapter: self.adapter,
+            watch: Some(try!(SignalWatch::new(conn, &rules))),
+            filter_set: false,
+            discovering: false,
+            started: Instant::now(),
+            duration: self.duration,
+            dedup: self.dedup,
+            seen: HashSet::new(),
+            stop_handle: self.stop_handle,
+        };
+
+        if let Some(ref filter) = self.filter {
+            try!(self.adapter.set_discovery_filter(filter));
+            session.filter_set = true;
+        }
+
+        try!(self.adapter.start_discovery());
+        session.discovering = true;
+        session.started = Instant::now();
+
+        Ok(session)
+    }
+}
+
+// Stops discovery, clears the filter and removes its match rules when dropped
+pub struct DiscoverySession<'a> {
+    adapter: &'a Adapter,
+    watch: Option<SignalWatch>,
+    filter_set: bool,
+    discovering: bool,
+    started: Instant,
+    duration: Option<Duration>,
+    dedup: bool,
+    seen: HashSet<String>,
+    stop_handle: StopHandle,
+}
+
+impl<'a> DiscoverySession<'a> {
+    pub fn adapter(&self) -> &Adapter {
+        self.adapter
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.duration.map(|x| self.started.elapsed() >= x).unwrap_or(false)
+    }
+
+    // Calls f for discovered devices until the duration elapses, the stop handle is triggered
+    // or discovery is stopped elsewhere
+    pub fn run<F>(&mut self, mut f: F) -> Result<(), BtError> where F: FnMut(Device) {
+        let dedup = self.dedup;
+        self.run_events(|event| {
+            match event {
+                DiscoveryEvent::DeviceFound(device) => f(device),
+                DiscoveryEvent::DeviceUpdated(device, ref props) if !dedup && props.contains_key("RSSI") => f(device),
+                _ => {}
+            }
+        })
+    }
+
+    // Every device gets a single DeviceFound, later changes come as DeviceUpdated
+    pub fn run_events<F>(&mut self, mut f: F) -> Result<(), BtError> where F: FnMut(DiscoveryEvent) {
+        while let Some(event) = self.next_event(None) {
+            f(event);
+        }
+        Ok(())
+    }
+
+    // Blocks until the next event; None once the session is over or the timeout elapsed
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> Option<DiscoveryEvent> {
+        let conn = self.adapter.conn().clone();
+        let now = Instant::now();
+
+        loop {
+            let signal = match self.watch {
+                Some(ref watch) => watch.next_signal(100),
+                None => return None,
+            };
+            if let Some(s) = signal {
+                if let Some(event) = self.process_signal(&conn, &s) {
+                    return Some(event);
+                }
+            }
+
+            if self.is_finished() || !conn.registry().is_connected() || timeout.map(|x| now.elapsed() >= x).unwrap_or(false) {
+                break;
+            }
+        }
+
+        None
+    }
+
+    pub fn events(self) -> DiscoveryEvents<'a> {
+        DiscoveryEvents { session: self, timeout: None }
+    }
+
+    pub fn stop(mut self) -> Result<(), BtError> {
+        self.cleanup()
+    }
+
+    fn is_finished(&self) -> bool {
+        !self.discovering || self.is_expired() || self.stop_handle.is_stopped()
+    }
+
+    fn take_queued(&self) -> Option<Rc<dbus::Message>> {
+        self.watch.as_ref().and_then(|x| x.take_queued())
+    }
+
+    fn process_signal(&mut self, conn: &super::Connection, s: &dbus::Message) -> Option<DiscoveryEvent> {
+        let obj_path = match s.path() {
+            Some(path) => path.to_string(),
+            None => return None,
+        };
+
+        if obj_path == self.adapter.object_path() {
+            let discovering = common::dbus_properties_changed(s, adapter::ADAPTER_INTERFACE)
+                .and_then(|props| props.get("Discovering").and_then(|x| x.inner().ok()));
+            if discovering == Some(false) {
+                self.discovering = false;
+            }
+            return None;
+        }
+
+        let adapter_prefix = format!("{}/", self.adapter.object_path());
+
+        if let Some(device_obj_path) = common::dbus_interfaces_added(s, device::DEVICE_INTERFACE) {
+            if !device_obj_path.starts_with(&adapter_prefix) || !self.seen.insert(device_obj_path.clone()) {
+                return None;
+            }
+            return Some(DiscoveryEvent::DeviceFound(Device::new(conn, &device_obj_path)));
+        }
+
+        if let Some(device_obj_path) = common::dbus_interfaces_removed(s, device::DEVICE_INTERFACE) {
+            if !self.seen.remove(&device_obj_path) {
+                return None;
+            }
+            return Some(DiscoveryEvent::DeviceRemoved(Device::new(conn, &device_obj_path)));
+        }
+
+        if let Some(props) = common::dbus_properties_changed(s, device::DEVICE_INTERFACE) {
+            if !obj_path.starts_with(&adapter_prefix) {
+                return None;
+            }
+            // Already known devices only show up through RSSI updates
+            if self.seen.insert(obj_path.clone()) {
+                return Some(DiscoveryEvent::DeviceFound(Device::new(conn, &obj_path)));
+            }
+            return Some(DiscoveryEvent::DeviceUpdated(Device::new(conn, &obj_path), props));
+        }
+
+        None
+    }
+
+    // Every step is attempted; the first error is returned
+    fn cleanup(&mut self) -> Result<(), BtError> {
+        let mut result = Ok(());
+
+        if self.discovering {
+            self.discovering = false;
+            let r = self.adapter.stop_discovery();
+            if result.is_ok() { result = r; }
+        }
+
+        if self.filter_set {
+            self.filter_set = false;
+            let r = self.adapter.set_discovery_filter(&DiscoveryFilter::default());
+            if result.is_ok() { result = r; }
+        }
+
+        if let Some(watch) = self.watch.take() {
+            let r = watch.close();
+            if result.is_ok() { result = r; }
+        }
+
+        result
+    }
+}
+
+// Owns its session, so discovery stops once the iterator is dropped
+pub struct DiscoveryEvents<'a> {
+    session: DiscoverySession<'a>,
+    timeout: Option<Duration>,
+}
+
+impl<'a> DiscoveryEvents<'a> {
+    // Ends the iteration when no event arrives within timeout
+    pub fn timeout(mut self, timeout: Duration) -> DiscoveryEvents<'a> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn session(&self) -> &DiscoverySession<'a> {
+        &self.session
+    }
+}
+
+impl<'a> Iterator for DiscoveryEvents<'a> {
+    type Item = DiscoveryEvent;
+
+    fn next(&mut self) -> Option<DiscoveryEvent> {
+        self.session.next_event(self.timeout)
+    }
+}
+
+impl<'a> Drop for DiscoverySession<'a> {
+    fn drop(&mut self) {
+        let _ = self.cleanup();
+    }
+}
+
+// Runs discovery on several adapters at once. The adapters may come from different Connections,
+// each of them is processed.
+pub struct Scanner<'a> {
+    adapters: &'a [Adapter],
+    filter: Option<DiscoveryFilter>,
+    duration: Option<Duration>,
+    stop_handle: StopHandle,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(adapters: &'a [Adapter]) -> Scanner<'a> {
+        Scanner { adapters: adapters, filter: None, duration: None, stop_handle: StopHandle::new() }
+    }
+
+    pub fn filter(mut self, filter: DiscoveryFilter) -> Scanner<'a> {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Scanner<'a> {
+        self.duration = Some(duration);
+        self
+    }
+
+    pub fn stop_handle(mut self, stop_handle: &StopHandle) -> Scanner<'a> {
+        self.stop_handle = stop_handle.clone();
+        self
+    }
+
+    // Calls f with the source adapter of every event until discovery has ended on all adapters
+    pub fn run<F>(&self, mut f: F) -> Result<(), BtError> where F: FnMut(&Adapter, DiscoveryEvent) {
+        let mut conns: Vec<super::Connection> = Vec::new();
+        for adapter in self.adapters {
+            if !conns.iter().any(|x| ptr::eq(x.registry(), adapter.conn().registry())) {
+                conns.push(adapter.conn().clone());
+            }
+        }
+        if conns.is_empty() {
+            return Ok(());
+        }
+
+        let mut sessions = Vec::new();
+        for adapter in self.adapters {
+            let mut builder = DiscoverySessionBuilder::new(adapter).stop_handle(&self.stop_handle);
+            if let Some(ref filter) = self.filter {
+                builder = builder.filter(filter.clone());
+            }
+            if let Some(duration) = self.duration {
+                builder = builder.duration(duration);
+            }
+            sessions.push(try!(builder.start()));
+        }
+
+        // Waits up to 100 ms per round in total
+        let timeout_ms = (100 / conns.len()) as i32;
+        loop {
+            for conn in &conns {
+                conn.registry().process(timeout_ms);
+            }
+            for session in sessions.iter_mut() {
+                let conn = session.adapter.conn().clone();
+                while let Some(s) = session.take_queued() {
+                    if session.is_finished() {
+                        continue;
+                    }
+                    if let Some(event) = session.process_signal(&conn, &s) {
+                        f(session.adapter, event);
+                    }
+                }
+            }
+
+            // Sessions on a closed connection won't get any more events
+            if sessions.iter().all(|x| x.is_finished() || !x.adapter.conn().registry().is_connected()) {
+                break;
+            }
+        }
+
+        let mut result = Ok(());
+        for session in sessions {
+            let r = session.stop();
+            if result.is_ok() { result = r; }
+        }
+        result
+    }
+}
@@ -1,4 +1,4 @@
-use std::{error, fmt};
+use std::{error, fmt, io};
 
 use dbus;
 
@@ -6,6 +6,12 @@ use dbus;
 pub enum BtError {
     DBus(dbus::Error),
     DBusInternal(String),
+    Io(io::Error),
+    Mgmt(u8),
+    // The device already has a conflicting operation pending, e.g. "Connect"
+    Busy(&'static str),
+    Timeout,
+    At(String),
 }
 
 impl From<dbus::Error> for BtError {
@@ -14,11 +20,22 @@ impl From<dbus::Error> for BtError {
     }
 }
 
+impl From<io::Error> for BtError {
+    fn from(err: io::Error) -> BtError {
+        BtError::Io(err)
+    }
+}
+
 impl fmt::Display for BtError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             BtError::DBus(ref err) => err.fmt(f),
             BtError::DBusInternal(ref err_msg) => write!(f, "{}", err_msg),
+            BtError::Io(ref err) => err.fmt(f),
+            BtError::Mgmt(status) => write!(f, "mgmt command failed with status 0x{:02x}", status),
+            BtError::Busy(op) => write!(f, "device is busy with a pending {} operation", op),
+            BtError::Timeout => write!(f, "operation timed out"),
+            BtError::At(ref err_msg) => write!(f, "AT command {}", err_msg),
         }
     }
 }
@@ -28,6 +45,11 @@ impl error::Error for BtError {
         match *self {
             BtError::DBus(ref err) => err.description(),
             BtError::DBusInternal(ref err_msg) => err_msg,
+            BtError::Io(ref err) => err.description(),
+            BtError::Mgmt(..) => "mgmt command failed",
+            BtError::Busy(..) => "device is busy",
+            BtError::Timeout => "operation timed out",
+            BtError::At(..) => "AT command failed",
         }
     }
 
@@ -35,6 +57,11 @@ impl error::Error for BtError {
         match *self {
             BtError::DBus(ref err) => Some(err),
             BtError::DBusInternal(..) => None,
+            BtError::Io(ref err) => Some(err),
+            BtError::Mgmt(..) => None,
+            BtError::Busy(..) => None,
+            BtError::Timeout => None,
+            BtError::At(..) => None,
         }
     }
 }
@@ -0,0 +1,143 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use dbus;
+
+use common;
+use discovery::StopHandle;
+use error::BtError;
+use registry::SignalWatch;
+
+type SignalCallbackT = Rc<RefCell<Box<FnMut(&dbus::Message)>>>;
+
+struct SignalHandler {
+    id: u32,
+    watch: Rc<SignalWatch>,
+    path_namespace: String,
+    interface: String,
+    member: Option<String>,
+    callback: SignalCallbackT,
+}
+
+impl SignalHandler {
+    fn matches(&self, s: &dbus::Message) -> bool {
+        let path_matches = s.path().map(|x| {
+            self.path_namespace == "/" || *x == *self.path_namespace || x.starts_with(&format!("{}/", self.path_namespace))
+        }).unwrap_or(false);
+
+        path_matches &&
+            s.interface().map(|x| *x == *self.interface).unwrap_or(false) &&
+            self.member.as_ref().map(|m| s.member().map(|x| *x == **m).unwrap_or(false)).unwrap_or(true)
+    }
+}
+
+// Serves all objects of the connection's registry and routes BlueZ signals to their handlers,
+// so one loop is enough for agents, profiles, advertisements and GATT applications together
+pub struct EventLoop {
+    conn: super::Connection,
+    handlers: RefCell<Vec<SignalHandler>>,
+    next_id: Cell<u32>,
+    stop_handle: StopHandle,
+}
+
+impl EventLoop {
+    pub fn new(conn: &super::Connection) -> EventLoop {
+        EventLoop {
+            conn: conn.clone(),
+            handlers: RefCell::new(Vec::new()),
+            next_id: Cell::new(1),
+            stop_handle: StopHandle::new(),
+        }
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    pub fn conn(&self) -> &super::Connection {
+        &self.conn
+    }
+
+    // Calls f for the BlueZ signals of the interface sent by object_path or any object below it
+    // (e.g. ("/org/bluez/hci0", "org.freedesktop.DBus.Properties", Some("PropertiesChanged"))).
+    // Returns an id for remove_signal_handler().
+    pub fn add_signal_handler<F>(&self, object_path: &str, interface: &str, member: Option<&str>, f: F) -> Result<u32, BtError>
+        where F: FnMut(&dbus::Message) + 'static {
+        let mut match_rule = format!("type='signal',sender='{}',interface='{}',path_namespace='{}'", common::SERVICE_NAME, interface, object_path);
+        if let Some(member) = member {
+            match_rule.push_str(&format!(",member='{}'", member));
+        }
+        let watch = try!(SignalWatch::new(&self.conn, &[match_rule]));
+
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        self.handlers.borrow_mut().push(SignalHandler {
+            id: id,
+            watch: Rc::new(watch),
+            path_namespace: object_path.to_string(),
+            interface: interface.to_string(),
+            member: member.map(|x| x.to_string()),
+            callback: Rc::new(RefCell::new(Box::new(f))),
+        });
+
+        Ok(id)
+    }
+
+    pub fn remove_signal_handler(&self, id: u32) -> bool {
+        let mut handlers = self.handlers.borrow_mut();
+        match handlers.iter().position(|x| x.id == id) {
+            Some(pos) => {
+                handlers.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Routes a single message, returns true if it was handled
+    pub fn dispatch(&self, msg: &dbus::Message) -> bool {
+        match msg.msg_type() {
+            dbus::MessageType::MethodCall => self.conn.registry().dispatch(msg),
+            dbus::MessageType::Signal => {
+                // Collected first, so callbacks may add or remove handlers
+                let callbacks: Vec<SignalCallbackT> = self.handlers.borrow().iter()
+                    .filter(|x| x.matches(msg))
+                    .map(|x| x.callback.clone())
+                    .collect();
+
+                for callback in &callbacks {
+                    (*callback.borrow_mut())(msg);
+                }
+                !callbacks.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    // Processes the connection, waiting up to timeout_ms for the first message, and passes the
+    // queued signals to their handlers. Returns how many messages were handled.
+    pub fn run_once(&self, timeout_ms: i32) -> usize {
+        let handled = self.conn.registry().process(timeout_ms);
+
+        // Collected first, so callbacks may add or remove handlers
+        let handlers: Vec<(Rc<SignalWatch>, SignalCallbackT)> = self.handlers.borrow().iter()
+            .map(|x| (x.watch.clone(), x.callback.clone()))
+            .collect();
+
+        for (watch, callback) in handlers {
+            while let Some(s) = watch.take_queued() {
+                (*callback.borrow_mut())(&s);
+            }
+        }
+
+        handled
+    }
+
+    // Runs until the stop handle is triggered
+    pub fn run(&self) {
+        while !self.stop_handle.is_stopped() {
+            self.run_once(100);
+        }
+    }
+}
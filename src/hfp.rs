@@ -0,0 +1,552 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use dbus;
+use libc;
+
+use address::BtAddress;
+use device::Device;
+use error::BtError;
+use profile::{self, ProfileManager, ProfileOptions, ProfileRole};
+use sco::{ScoCodec, ScoListener, ScoStream};
+use socket;
+use uuid;
+
+static HANDS_FREE_OBJ_PATH: &'static str = "/io/bluezrs/hfp_hf";
+
+// The AT+BRSF bits of the audio gateway
+const AG_THREE_WAY_CALLING: u32 = 0x0001;
+const AG_CODEC_NEGOTIATION: u32 = 0x0200;
+
+// How long the SLC setup waits for each answer of the audio gateway
+const SLC_TIMEOUT_SECS: u64 = 5;
+
+const CODEC_CVSD: u8 = 1;
+const CODEC_MSBC: u8 = 2;
+
+fn codec_from_id(id: u8) -> ScoCodec {
+    match id {
+        CODEC_CVSD => ScoCodec::Cvsd,
+        _ => ScoCodec::Transparent,
+    }
+}
+
+// The hands-free side features, sent with AT+BRSF and in the SDP record
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HfFeatures {
+    pub echo_cancelling: bool,
+    pub three_way_calling: bool,
+    pub calling_line_id: bool,
+    pub voice_recognition: bool,
+    pub remote_volume: bool,
+    pub enhanced_call_status: bool,
+    pub enhanced_call_control: bool,
+    // Wideband speech (mSBC)
+    pub codec_negotiation: bool,
+}
+
+impl HfFeatures {
+    fn to_u32(self) -> u32 {
+        let bits = [self.echo_cancelling, self.three_way_calling, self.calling_line_id, self.voice_recognition,
+                    self.remote_volume, self.enhanced_call_status, self.enhanced_call_control, self.codec_negotiation];
+        bits.iter().enumerate().fold(0, |acc, (i, &x)| if x { acc | 1 << i } else { acc })
+    }
+
+    // The SDP record has the first five bits in common, followed by wideband speech
+    fn sdp_features(self) -> u16 {
+        let mut features = (self.to_u32() & 0x1f) as u16;
+        if self.codec_negotiation {
+            features |= 0x20;
+        }
+        features
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AtCommand {
+    SupportedFeatures(u32),
+    AvailableCodecs(Vec<u8>),
+    IndicatorsTest,
+    IndicatorsRead,
+    EventReporting(bool),
+    CallHoldTest,
+    CallingLineId(bool),
+    Answer,
+    HangUp,
+    Dial(String),
+    SpeakerVolume(u8),
+    MicrophoneVolume(u8),
+    CodecConfirmation(u8),
+    CodecConnection,
+    // Sent as is, without the "AT" prefix (e.g. "+NREC=0")
+    Raw(String),
+}
+
+impl AtCommand {
+    pub fn to_line(&self) -> String {
+        let cmd = match *self {
+            AtCommand::SupportedFeatures(features) => format!("+BRSF={}", features),
+            AtCommand::AvailableCodecs(ref ids) => format!("+BAC={}", ids.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",")),
+            AtCommand::IndicatorsTest => "+CIND=?".to_string(),
+            AtCommand::IndicatorsRead => "+CIND?".to_string(),
+            AtCommand::EventReporting(enable) => format!("+CMER=3,0,0,{}", enable as u8),
+            AtCommand::CallHoldTest => "+CHLD=?".to_string(),
+            AtCommand::CallingLineId(enable) => format!("+CLIP={}", enable as u8),
+            AtCommand::Answer => "A".to_string(),
+            AtCommand::HangUp => "+CHUP".to_string(),
+            AtCommand::Dial(ref number) => format!("D{};", number),
+            AtCommand::SpeakerVolume(gain) => format!("+VGS={}", gain),
+            AtCommand::MicrophoneVolume(gain) => format!("+VGM={}", gain),
+            AtCommand::CodecConfirmation(id) => format!("+BCS={}", id),
+            AtCommand::CodecConnection => "+BCC".to_string(),
+            AtCommand::Raw(ref cmd) => cmd.clone(),
+        };
+        format!("AT{}\r", cmd)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AtResponse {
+    Ok,
+    Error,
+    CmeError(u32),
+    Ring,
+    // "+NAME: a,b,c", values are split at the top level commas and unquoted
+    Result { name: String, values: Vec<String> },
+    Other(String),
+}
+
+impl AtResponse {
+    pub fn parse(line: &str) -> AtResponse {
+        let line = line.trim();
+        match line {
+            "OK" => return AtResponse::Ok,
+            "ERROR" => return AtResponse::Error,
+            "RING" => return AtResponse::Ring,
+            _ => {}
+        }
+
+        if let Some(code) = line.strip_prefix("+CME ERROR:").and_then(|x| x.trim().parse().ok()) {
+            return AtResponse::CmeError(code);
+        }
+
+        if let Some(line) = line.strip_prefix('+') {
+            let (name, rest) = match line.find(':') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => (line, ""),
+            };
+            return AtResponse::Result { name: name.trim().to_string(), values: split_values(rest) };
+        }
+
+        AtResponse::Other(line.to_string())
+    }
+
+    fn value(&self, name: &str, index: usize) -> Option<&str> {
+        match *self {
+            AtResponse::Result { name: ref n, ref values } if n == name => values.get(index).map(|x| x.as_str()),
+            _ => None,
+        }
+    }
+}
+
+fn split_values(s: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut value = String::new();
+    let (mut depth, mut quoted) = (0, false);
+
+    for c in s.chars() {
+        match c {
+            '"' => { quoted = !quoted; value.push(c); }
+            '(' if !quoted => { depth += 1; value.push(c); }
+            // A stray ')' mustn't stop the splitting for the rest of the line
+            ')' if !quoted => { if depth > 0 { depth -= 1; } value.push(c); }
+            ',' if !quoted && depth == 0 => { values.push(unquote(&value)); value.clear(); }
+            _ => value.push(c),
+        }
+    }
+    if !s.trim().is_empty() {
+        values.push(unquote(&value));
+    }
+
+    values
+}
+
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        s[1..s.len() - 1].to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+// The indicator names of "+CIND: ("service",(0,1)),("call",(0,1)),..." in order
+fn parse_indicator_names(values: &[String]) -> Vec<String> {
+    values.iter().map(|x| x.split('"').nth(1).unwrap_or("").to_string()).collect()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CallSetup {
+    #[default]
+    None,
+    Incoming,
+    Outgoing,
+    Alerting,
+}
+
+impl CallSetup {
+    fn from_u8(val: u8) -> CallSetup {
+        match val {
+            1 => CallSetup::Incoming,
+            2 => CallSetup::Outgoing,
+            3 => CallSetup::Alerting,
+            _ => CallSetup::None,
+        }
+    }
+}
+
+// The standard indicators of the audio gateway
+#[derive(Clone, Debug, Default)]
+pub struct CallState {
+    pub service: bool,
+    pub call: bool,
+    pub call_setup: CallSetup,
+    pub call_held: u8,
+    pub signal: u8,
+    pub roaming: bool,
+    pub battery: u8,
+}
+
+impl CallState {
+    fn update(&mut self, name: &str, value: u8) {
+        match name {
+            "service" => self.service = value != 0,
+            "call" => self.call = value != 0,
+            "callsetup" => self.call_setup = CallSetup::from_u8(value),
+            "callheld" => self.call_held = value,
+            "signal" => self.signal = value,
+            "roam" => self.roaming = value != 0,
+            "battchg" => self.battery = value,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HfpEvent {
+    Indicator { name: String, value: u8 },
+    Ring,
+    CallingLine(String),
+    SpeakerVolume(u8),
+    MicrophoneVolume(u8),
+    // Already confirmed, the next audio link uses the codec
+    CodecSelected(ScoCodec),
+    Unknown(AtResponse),
+}
+
+// The hands-free (car kit / headset) side of an HFP service level connection
+pub struct HandsFree {
+    device: Device,
+    address: BtAddress,
+    file: File,
+    buf: Vec<u8>,
+    events: VecDeque<AtResponse>,
+    features: HfFeatures,
+    ag_features: u32,
+    indicators: Vec<String>,
+    call_state: CallState,
+    codec: ScoCodec,
+    _manager: ProfileManager,
+}
+
+impl HandsFree {
+    // Registers the hands-free profile and waits for an audio gateway (phone) to connect
+    pub fn accept(conn: &super::Connection, features: HfFeatures, timeout: Option<Duration>) -> Result<HandsFree, BtError> {
+        let options = HandsFree::profile_options(ProfileRole::Server, features);
+        let (device, fd, manager) = try!(profile::accept_single(conn, HANDS_FREE_OBJ_PATH, uuid::HFP_HS_UUID, options, timeout));
+        HandsFree::establish(device, fd, features, manager)
+    }
+
+    // Connects to the audio gateway of the device
+    pub fn connect(device: &Device, features: HfFeatures) -> Result<HandsFree, BtError> {
+        let options = HandsFree::profile_options(ProfileRole::Client, features);
+        let (device, fd, manager) = try!(profile::connect_single(device, HANDS_FREE_OBJ_PATH, uuid::HFP_HS_UUID, uuid::HFP_AG_UUID, options));
+        HandsFree::establish(device, fd, features, manager)
+    }
+
+    fn profile_options(role: ProfileRole, features: HfFeatures) -> ProfileOptions {
+        ProfileOptions {
+            name: Some("Hands-Free".to_string()),
+            role: Some(role),
+            version: Some(0x0107),
+            features: Some(features.sdp_features()),
+            ..ProfileOptions::default()
+        }
+    }
+
+    fn establish(device: Device, fd: dbus::OwnedFd, features: HfFeatures, manager: ProfileManager) -> Result<HandsFree, BtError> {
+        let address = try!(BtAddress::parse(&try!(device.get_properties()).address));
+        let mut hf = HandsFree {
+            device: device,
+            address: address,
+            file: try!(socket::from_owned_fd(fd)),
+            buf: Vec::new(),
+            events: VecDeque::new(),
+            features: features,
+            ag_features: 0,
+            indicators: Vec::new(),
+            call_state: CallState::default(),
+            codec: ScoCodec::Cvsd,
+            _manager: manager,
+        };
+
+        // A gateway that stops answering during the SLC would block the reads forever
+        try!(socket::set_timeout(hf.file.as_raw_fd(), libc::SO_RCVTIMEO, Some(Duration::from_secs(SLC_TIMEOUT_SECS))));
+        match hf.service_level_connection() {
+            Ok(()) => {}
+            Err(BtError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Err(BtError::Timeout),
+            Err(e) => return Err(e),
+        }
+        try!(socket::set_timeout(hf.file.as_raw_fd(), libc::SO_RCVTIMEO, None));
+
+        Ok(hf)
+    }
+
+    // The SLC establishment of the HFP spec, up to the optional three-way calling query
+    fn service_level_connection(&mut self) -> Result<(), BtError> {
+        let brsf = try!(self.command(&AtCommand::SupportedFeatures(self.features.to_u32())));
+        self.ag_features = brsf.iter().filter_map(|x| x.value("BRSF", 0)).next().and_then(|x| x.parse().ok()).unwrap_or(0);
+
+        if self.features.codec_negotiation && self.ag_features & AG_CODEC_NEGOTIATION != 0 {
+            try!(self.command(&AtCommand::AvailableCodecs(vec![CODEC_CVSD, CODEC_MSBC])));
+        }
+
+        let cind = try!(self.command(&AtCommand::IndicatorsTest));
+        self.indicators = cind.iter()
+            .filter_map(|x| match *x {
+                AtResponse::Result { ref name, ref values } if name == "CIND" => Some(parse_indicator_names(values)),
+                _ => None,
+            })
+            .next()
+            .unwrap_or_default();
+
+        let cind = try!(self.command(&AtCommand::IndicatorsRead));
+        for response in &cind {
+            if let AtResponse::Result { ref name, ref values } = *response {
+                if name != "CIND" { continue; }
+                for (name, value) in self.indicators.iter().zip(values) {
+                    self.call_state.update(name, value.parse().unwrap_or(0));
+                }
+            }
+        }
+
+        try!(self.command(&AtCommand::EventReporting(true)));
+
+        if self.features.three_way_calling && self.ag_features & AG_THREE_WAY_CALLING != 0 {
+            try!(self.command(&AtCommand::CallHoldTest));
+        }
+        if self.features.calling_line_id {
+            try!(self.command(&AtCommand::CallingLineId(true)));
+        }
+
+        Ok(())
+    }
+
+    fn read_response(&mut self) -> Result<AtResponse, BtError> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|&x| x == b'\r' || x == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..i + 1).collect();
+                let line = String::from_utf8_lossy(&line[..i]).trim().to_string();
+                if !line.is_empty() {
+                    return Ok(AtResponse::parse(&line));
+                }
+                continue;
+            }
+
+            let mut chunk = [0u8; 256];
+            let len = try!(self.file.read(&mut chunk));
+            if len == 0 {
+                return Err(BtError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "HFP connection closed")));
+            }
+            self.buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    fn is_unsolicited(response: &AtResponse) -> bool {
+        match *response {
+            AtResponse::Ring => true,
+            AtResponse::Result { ref name, .. } => ["CIEV", "CLIP", "VGS", "VGM", "BCS", "CCWA"].contains(&name.as_str()),
+            _ => false,
+        }
+    }
+
+    // Sends the command and returns its result lines. Unsolicited results received meanwhile are
+    // kept for next_event().
+    pub fn command(&mut self, cmd: &AtCommand) -> Result<Vec<AtResponse>, BtError> {
+        try!(self.file.write_all(cmd.to_line().as_bytes()));
+
+        let mut results = Vec::new();
+        loop {
+            match try!(self.read_response()) {
+                AtResponse::Ok => return Ok(results),
+                AtResponse::Error => return Err(BtError::At(format!("{} failed", cmd.to_line().trim()))),
+                AtResponse::CmeError(code) => return Err(BtError::At(format!("{} failed with CME error {}", cmd.to_line().trim(), code))),
+                ref response if HandsFree::is_unsolicited(response) => self.events.push_back(response.clone()),
+                response => results.push(response),
+            }
+        }
+    }
+
+    // Blocks until the audio gateway reports something (see set_read_timeout())
+    pub fn next_event(&mut self) -> Result<HfpEvent, BtError> {
+        let response = match self.events.pop_front() {
+            Some(response) => response,
+            None => try!(self.read_response()),
+        };
+
+        let event = match response {
+            AtResponse::Ring => HfpEvent::Ring,
+            AtResponse::Result { ref name, ref values } => {
+                let number = values.first().and_then(|x| x.parse::<u8>().ok());
+                match (name.as_str(), number) {
+                    ("CIEV", Some(index)) => {
+                        let name = self.indicators.get((index as usize).wrapping_sub(1)).cloned().unwrap_or_default();
+                        let value = values.get(1).and_then(|x| x.parse().ok()).unwrap_or(0);
+                        self.call_state.update(&name, value);
+                        HfpEvent::Indicator { name: name, value: value }
+                    }
+                    ("CLIP", _) | ("CCWA", _) => HfpEvent::CallingLine(values.first().cloned().unwrap_or_default()),
+                    ("VGS", Some(gain)) => HfpEvent::SpeakerVolume(gain),
+                    ("VGM", Some(gain)) => HfpEvent::MicrophoneVolume(gain),
+                    ("BCS", Some(id)) => {
+                        try!(self.command(&AtCommand::CodecConfirmation(id)));
+                        self.codec = codec_from_id(id);
+                        HfpEvent::CodecSelected(self.codec)
+                    }
+                    _ => HfpEvent::Unknown(response.clone()),
+                }
+            }
+            response => HfpEvent::Unknown(response),
+        };
+
+        Ok(event)
+    }
+
+    pub fn answer(&mut self) -> Result<(), BtError> {
+        self.command(&AtCommand::Answer).map(|_| ())
+    }
+
+    pub fn hang_up(&mut self) -> Result<(), BtError> {
+        self.command(&AtCommand::HangUp).map(|_| ())
+    }
+
+    pub fn dial(&mut self, number: &str) -> Result<(), BtError> {
+        self.command(&AtCommand::Dial(number.to_string())).map(|_| ())
+    }
+
+    // Gains are 0-15
+    pub fn set_speaker_volume(&mut self, gain: u8) -> Result<(), BtError> {
+        self.command(&AtCommand::SpeakerVolume(gain.min(15))).map(|_| ())
+    }
+
+    pub fn set_microphone_volume(&mut self, gain: u8) -> Result<(), BtError> {
+        self.command(&AtCommand::MicrophoneVolume(gain.min(15))).map(|_| ())
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn ag_features(&self) -> u32 {
+        self.ag_features
+    }
+
+    pub fn call_state(&self) -> &CallState {
+        &self.call_state
+    }
+
+    pub fn codec(&self) -> ScoCodec {
+        self.codec
+    }
+
+    // The audio gateway usually opens the audio link itself, bind this before answering or dialing
+    pub fn audio_listener(&self) -> Result<ScoListener, BtError> {
+        ScoListener::bind(&BtAddress::ANY, self.codec)
+    }
+
+    // With codec negotiation, request the link with AtCommand::CodecConnection and accept it instead
+    pub fn connect_audio(&self) -> Result<ScoStream, BtError> {
+        ScoStream::connect(&BtAddress::ANY, &self.address, self.codec)
+    }
+
+    // None blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_RCVTIMEO, timeout)
+    }
+}
+
+impl AsRawFd for HandsFree {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_indicator_names, AtResponse};
+
+    fn result(name: &str, values: &[&str]) -> AtResponse {
+        AtResponse::Result { name: name.to_string(), values: values.iter().map(|x| x.to_string()).collect() }
+    }
+
+    #[test]
+    fn parse_final_results() {
+        assert_eq!(AtResponse::parse("OK"), AtResponse::Ok);
+        assert_eq!(AtResponse::parse("\r\nOK\r\n"), AtResponse::Ok);
+        assert_eq!(AtResponse::parse("ERROR"), AtResponse::Error);
+        assert_eq!(AtResponse::parse("RING"), AtResponse::Ring);
+        assert_eq!(AtResponse::parse("+CME ERROR: 30"), AtResponse::CmeError(30));
+    }
+
+    #[test]
+    fn parse_unsolicited_results() {
+        assert_eq!(AtResponse::parse("+BRSF: 871"), result("BRSF", &["871"]));
+        assert_eq!(AtResponse::parse("+CIEV: 2,1"), result("CIEV", &["2", "1"]));
+        assert_eq!(AtResponse::parse("+CLIP: \"+15551234567\",145"), result("CLIP", &["+15551234567", "145"]));
+        assert_eq!(AtResponse::parse("+CIND: 1,0,0,3,0,4,0"), result("CIND", &["1", "0", "0", "3", "0", "4", "0"]));
+        assert_eq!(AtResponse::parse("+BCS:2"), result("BCS", &["2"]));
+        assert_eq!(AtResponse::parse("+CHLD: (0,1,2,3)"), result("CHLD", &["(0,1,2,3)"]));
+        // Quoted commas don't split
+        assert_eq!(AtResponse::parse("+COPS: 0,0,\"Foo, Inc.\""), result("COPS", &["0", "0", "Foo, Inc."]));
+    }
+
+    #[test]
+    fn parse_indicator_list() {
+        let cind = AtResponse::parse("+CIND: (\"service\",(0,1)),(\"call\",(0,1)),(\"callsetup\",(0-3)),(\"signal\",(0-5))");
+        match cind {
+            AtResponse::Result { ref name, ref values } => {
+                assert_eq!(name, "CIND");
+                assert_eq!(parse_indicator_names(values), vec!["service", "call", "callsetup", "signal"]);
+            }
+            _ => panic!("unexpected {:?}", cind),
+        }
+    }
+
+    #[test]
+    fn parse_malformed_lines() {
+        assert_eq!(AtResponse::parse(""), AtResponse::Other(String::new()));
+        assert_eq!(AtResponse::parse("garbage"), AtResponse::Other("garbage".to_string()));
+        assert_eq!(AtResponse::parse("+"), result("", &[]));
+        assert_eq!(AtResponse::parse("+CIEV"), result("CIEV", &[]));
+        assert_eq!(AtResponse::parse("+CIEV:"), result("CIEV", &[]));
+        assert_eq!(AtResponse::parse("+CME ERROR: x"), result("CME ERROR", &["x"]));
+        // Unbalanced quotes and parentheses
+        assert_eq!(AtResponse::parse("+CLIP: \"123,145"), result("CLIP", &["\"123,145"]));
+        assert_eq!(AtResponse::parse("+CHLD: 0),(1"), result("CHLD", &["0)", "(1"]));
+        assert_eq!(AtResponse::parse("+X: \""), result("X", &["\""]));
+        assert_eq!(parse_indicator_names(&["(service".to_string()]), vec![""]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use dbus;
+
+use adapter::Adapter;
+use common;
+use device::Device;
+use error::BtError;
+
+pub static INPUT_INTERFACE: &'static str = "org.bluez.Input1";
+
+// Which side re-establishes the HID connection after it's lost
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReconnectMode {
+    None,
+    Host,
+    Device,
+    Any,
+}
+
+impl ReconnectMode {
+    fn from_str(s: &str) -> Option<ReconnectMode> {
+        match s {
+            "none" => Some(ReconnectMode::None),
+            "host" => Some(ReconnectMode::Host),
+            "device" => Some(ReconnectMode::Device),
+            "any" => Some(ReconnectMode::Any),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InputProperties {
+    pub reconnect_mode: Option<ReconnectMode>,
+}
+
+impl InputProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> InputProperties {
+        InputProperties {
+            reconnect_mode: props_map.get("ReconnectMode").and_then(|x| x.inner().ok()).and_then(ReconnectMode::from_str),
+        }
+    }
+}
+
+// The HID side of a device, only present on (classic) HID devices handled by BlueZ's input plugin
+#[derive(Clone, Debug)]
+pub struct Input {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl Input {
+    pub fn new(device: &Device) -> Input {
+        Input { conn: device.conn().clone(), object_path: device.object_path().to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<InputProperties, BtError> {
+        let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, INPUT_INTERFACE, 1000);
+        Ok(InputProperties::new(try!(p.get_all())))
+    }
+
+    pub fn get_reconnect_mode(&self) -> Result<Option<ReconnectMode>, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, INPUT_INTERFACE, "ReconnectMode"));
+        Ok(val.inner().ok().and_then(ReconnectMode::from_str))
+    }
+}
+
+// The devices of the adapter which implement Input1
+pub fn get_inputs(adapter: &Adapter) -> Result<Vec<(Device, InputProperties)>, BtError> {
+    common::dbus_get_managed_objects_with_props(adapter.conn(),
+                                                adapter.object_path(),
+                                                INPUT_INTERFACE,
+                                                |conn, obj_path, props_map| (Device::new(&conn, obj_path), InputProperties::new(props_map))
+    )
+}
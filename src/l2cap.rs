@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use libc;
+
+use address::BtAddress;
+use device::AddressType;
+use error::BtError;
+use mgmt;
+use socket;
+
+const BTPROTO_L2CAP: libc::c_int = 0;
+const BT_SECURITY: libc::c_int = 4;
+const BT_SNDMTU: libc::c_int = 12;
+const BT_RCVMTU: libc::c_int = 13;
+
+#[repr(C)]
+struct SockaddrL2 {
+    l2_family: libc::sa_family_t,
+    l2_psm: u16,
+    l2_bdaddr: [u8; 6],
+    l2_cid: u16,
+    l2_bdaddr_type: u8,
+}
+
+impl SockaddrL2 {
+    fn new(address: &BtAddress, address_type: AddressType, psm: u16) -> SockaddrL2 {
+        SockaddrL2 {
+            l2_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            l2_psm: psm.to_le(),
+            l2_bdaddr: address.bytes(),
+            l2_cid: 0,
+            l2_bdaddr_type: match address_type {
+                AddressType::Public => mgmt::BDADDR_LE_PUBLIC,
+                AddressType::Random => mgmt::BDADDR_LE_RANDOM,
+            },
+        }
+    }
+
+    fn address_type(&self) -> AddressType {
+        if self.l2_bdaddr_type == mgmt::BDADDR_LE_RANDOM { AddressType::Random } else { AddressType::Public }
+    }
+}
+
+#[repr(C)]
+struct BtSecurity {
+    level: u8,
+    key_size: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityLevel {
+    Sdp,
+    Low,
+    Medium,
+    High,
+    Fips,
+}
+
+impl SecurityLevel {
+    fn to_u8(self) -> u8 {
+        match self {
+            SecurityLevel::Sdp => 0,
+            SecurityLevel::Low => 1,
+            SecurityLevel::Medium => 2,
+            SecurityLevel::High => 3,
+            SecurityLevel::Fips => 4,
+        }
+    }
+
+    fn from_u8(val: u8) -> SecurityLevel {
+        match val {
+            0 => SecurityLevel::Sdp,
+            1 => SecurityLevel::Low,
+            2 => SecurityLevel::Medium,
+            3 => SecurityLevel::High,
+            _ => SecurityLevel::Fips,
+        }
+    }
+}
+
+// Socket options applied before connecting / listening, unset ones are left to the kernel
+#[derive(Clone, Debug, Default)]
+pub struct L2capOptions {
+    pub security: Option<SecurityLevel>,
+    pub receive_mtu: Option<u16>,
+}
+
+impl L2capOptions {
+    fn apply(&self, sock: &File) -> Result<(), BtError> {
+        if let Some(security) = self.security {
+            try!(socket::set_option(sock, socket::SOL_BLUETOOTH, BT_SECURITY, &BtSecurity { level: security.to_u8(), key_size: 0 }));
+        }
+        if let Some(receive_mtu) = self.receive_mtu {
+            try!(socket::set_option(sock, socket::SOL_BLUETOOTH, BT_RCVMTU, &receive_mtu));
+        }
+        Ok(())
+    }
+}
+
+// An LE L2CAP connection-oriented channel. Every read and write is one SDU.
+pub struct L2capStream {
+    address: BtAddress,
+    address_type: AddressType,
+    psm: u16,
+    file: File,
+}
+
+impl L2capStream {
+    pub fn connect(address: &BtAddress, address_type: AddressType, psm: u16, options: &L2capOptions) -> Result<L2capStream, BtError> {
+        let file = try!(socket::open(libc::SOCK_SEQPACKET, BTPROTO_L2CAP));
+        try!(options.apply(&file));
+        try!(socket::connect(&file, &SockaddrL2::new(address, address_type, psm)));
+
+        Ok(L2capStream { address: *address, address_type: address_type, psm: psm, file: file })
+    }
+
+    pub fn peer_address(&self) -> &BtAddress {
+        &self.address
+    }
+
+    pub fn peer_address_type(&self) -> AddressType {
+        self.address_type
+    }
+
+    pub fn psm(&self) -> u16 {
+        self.psm
+    }
+
+    // The negotiated MTUs, SDUs larger than the send MTU are rejected by the kernel
+    pub fn send_mtu(&self) -> Result<u16, BtError> {
+        socket::get_option(&self.file, socket::SOL_BLUETOOTH, BT_SNDMTU)
+    }
+
+    pub fn receive_mtu(&self) -> Result<u16, BtError> {
+        socket::get_option(&self.file, socket::SOL_BLUETOOTH, BT_RCVMTU)
+    }
+
+    pub fn security(&self) -> Result<SecurityLevel, BtError> {
+        let security: BtSecurity = try!(socket::get_option(&self.file, socket::SOL_BLUETOOTH, BT_SECURITY));
+        Ok(SecurityLevel::from_u8(security.level))
+    }
+
+    // None blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_RCVTIMEO, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_SNDTIMEO, timeout)
+    }
+}
+
+impl Read for L2capStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for L2capStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for L2capStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+pub struct L2capListener {
+    psm: u16,
+    options: L2capOptions,
+    file: File,
+}
+
+impl L2capListener {
+    // Listens on the adapter's address (BtAddress::ANY for all adapters). With psm 0 the kernel
+    // picks a free dynamic PSM, see psm().
+    pub fn bind(adapter_address: &BtAddress, address_type: AddressType, psm: u16, options: &L2capOptions) -> Result<L2capListener, BtError> {
+        let file = try!(socket::open(libc::SOCK_SEQPACKET, BTPROTO_L2CAP));
+        try!(options.apply(&file));
+        try!(socket::bind(&file, &SockaddrL2::new(adapter_address, address_type, psm)));
+        try!(socket::listen(&file, 5));
+
+        let local: SockaddrL2 = try!(socket::local_address(&file));
+        Ok(L2capListener { psm: u16::from_le(local.l2_psm), options: options.clone(), file: file })
+    }
+
+    pub fn psm(&self) -> u16 {
+        self.psm
+    }
+
+    pub fn options(&self) -> &L2capOptions {
+        &self.options
+    }
+
+    // Blocks until a device connects
+    pub fn accept(&self) -> Result<L2capStream, BtError> {
+        let (file, peer): (File, SockaddrL2) = try!(socket::accept(&self.file));
+        Ok(L2capStream {
+            address: BtAddress::from_bytes(peer.l2_bdaddr),
+            address_type: peer.address_type(),
+            psm: self.psm,
+            file: file,
+        })
+    }
+}
+
+impl AsRawFd for L2capListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
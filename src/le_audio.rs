@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use dbus;
+
+use adapter::Adapter;
+use common;
+use device::Device;
+use error::BtError;
+use media::{MediaTransport, MEDIA_TRANSPORT_INTERFACE};
+
+// The BAP interfaces are still experimental in BlueZ and may change between releases
+
+pub static MEDIA_ASSISTANT_INTERFACE: &'static str = "org.bluez.MediaAssistant1";
+
+pub const CODEC_LC3: u8 = 0x06;
+
+// The QoS of a transport, connected (CIG/CIS) or broadcast (BIG/BIS). Intervals and delays are in microseconds.
+#[derive(Clone, Debug, Default)]
+pub struct TransportQos {
+    pub cig: Option<u8>,
+    pub cis: Option<u8>,
+    pub big: Option<u8>,
+    pub bis: Option<u8>,
+    pub interval: Option<u32>,
+    pub framing: Option<bool>,
+    pub phy: Option<u8>,
+    pub sdu: Option<u16>,
+    pub retransmissions: Option<u8>,
+    pub latency: Option<u16>,
+    pub presentation_delay: Option<u32>,
+}
+
+impl TransportQos {
+    fn new(props_map: &BTreeMap<String, dbus::MessageItem>) -> TransportQos {
+        fn _get_prop<'a, T>(props_map: &'a BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<T>
+            where T: dbus::FromMessageItem<'a> {
+            props_map.get(name).and_then(|x| (x.inner() as Result<T, ()>).ok())
+        }
+
+        TransportQos {
+            cig: _get_prop(props_map, "CIG"),
+            cis: _get_prop(props_map, "CIS"),
+            big: _get_prop(props_map, "BIG"),
+            bis: _get_prop(props_map, "BIS"),
+            interval: _get_prop(props_map, "Interval"),
+            // A byte in some BlueZ versions
+            framing: _get_prop(props_map, "Framing").or_else(|| _get_prop::<u8>(props_map, "Framing").map(|x| x != 0)),
+            phy: _get_prop(props_map, "PHY"),
+            sdu: _get_prop(props_map, "SDU"),
+            retransmissions: _get_prop(props_map, "Retransmissions"),
+            latency: _get_prop(props_map, "Latency"),
+            presentation_delay: _get_prop(props_map, "PresentationDelay").or_else(|| _get_prop(props_map, "Delay")),
+        }
+    }
+}
+
+impl MediaTransport {
+    pub fn get_qos(&self) -> Result<TransportQos, BtError> {
+        let val = try!(common::dbus_get_property(self.conn(), self.object_path(), MEDIA_TRANSPORT_INTERFACE, "QoS"));
+        let props: &[dbus::MessageItem] = try!(val.inner().map_err(|_| BtError::DBusInternal("invalid QoS value".to_string())));
+        Ok(TransportQos::new(&common::dbus_props_to_map(props)))
+    }
+
+    // The transports acquired together with this one (e.g. both directions of a CIS, or the BISes of a stereo broadcast)
+    pub fn get_links(&self) -> Result<Vec<MediaTransport>, BtError> {
+        let val = match common::dbus_get_property(self.conn(), self.object_path(), MEDIA_TRANSPORT_INTERFACE, "Links") {
+            Ok(val) => val,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let links: &[dbus::MessageItem] = try!(val.inner().map_err(|_| BtError::DBusInternal("invalid Links value".to_string())));
+
+        Ok(links.iter().filter_map(|x| match *x {
+            dbus::MessageItem::ObjectPath(ref path) => Some(MediaTransport::new(self.conn(), path)),
+            _ => None,
+        }).collect())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AssistantState {
+    Idle,
+    Pending,
+    Requesting,
+    Active,
+}
+
+impl AssistantState {
+    fn from_str(s: &str) -> Option<AssistantState> {
+        match s {
+            "idle" => Some(AssistantState::Idle),
+            "pending" => Some(AssistantState::Pending),
+            "requesting" => Some(AssistantState::Requesting),
+            "active" => Some(AssistantState::Active),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaAssistantProperties {
+    pub state: Option<AssistantState>,
+    // The broadcast sink (scan delegator) the stream would be pushed to
+    pub device: Option<String>,
+    pub metadata: Vec<u8>,
+    pub qos: TransportQos,
+}
+
+impl MediaAssistantProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> MediaAssistantProperties {
+        let qos: &[dbus::MessageItem] = props_map.get("QoS").and_then(|x| x.inner().ok()).unwrap_or(&[]);
+
+        MediaAssistantProperties {
+            state: props_map.get("State").and_then(|x| x.inner().ok()).and_then(AssistantState::from_str),
+            device: props_map.get("Device").and_then(|x| match *x {
+                dbus::MessageItem::ObjectPath(ref path) => Some(path.to_string()),
+                _ => None,
+            }),
+            metadata: props_map.get("Metadata")
+                .and_then(|x| (x.inner() as Result<&[dbus::MessageItem], ()>).ok())
+                .map(|x| x.iter().filter_map(|x| x.inner().ok()).collect())
+                .unwrap_or_default(),
+            qos: TransportQos::new(&common::dbus_props_to_map(qos)),
+        }
+    }
+}
+
+// A broadcast stream (BIS) discovered by the broadcast assistant, which can be pushed to a sink
+#[derive(Clone, Debug)]
+pub struct MediaAssistant {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl MediaAssistant {
+    pub fn new(conn: &super::Connection, object_path: &str) -> MediaAssistant {
+        MediaAssistant { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<MediaAssistantProperties, BtError> {
+        let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, MEDIA_ASSISTANT_INTERFACE, 1000);
+        Ok(MediaAssistantProperties::new(try!(p.get_all())))
+    }
+
+    pub fn get_device(&self) -> Result<Option<Device>, BtError> {
+        Ok(try!(self.get_properties()).device.map(|x| Device::new(&self.conn, &x)))
+    }
+
+    // Asks the sink to sync to the stream. The broadcast code is needed for encrypted broadcasts.
+    pub fn push(&self, broadcast_code: Option<[u8; 16]>, metadata: Option<&[u8]>) -> Result<(), BtError> {
+        fn _bytes(bytes: &[u8]) -> dbus::MessageItem {
+            dbus::MessageItem::Array(bytes.iter().map(|x| dbus::MessageItem::Byte(*x)).collect(), "y".into())
+        }
+
+        let mut props = Vec::new();
+        if let Some(metadata) = metadata {
+            props.push(common::dbus_dict_entry("Metadata", _bytes(metadata)));
+        }
+        if let Some(code) = broadcast_code {
+            let qos = dbus::MessageItem::Array(vec![common::dbus_dict_entry("BCode", _bytes(&code))], "{sv}".into());
+            props.push(common::dbus_dict_entry("QoS", qos));
+        }
+
+        common::dbus_call_method1(&self.conn, &self.object_path, MEDIA_ASSISTANT_INTERFACE, "Push", dbus::MessageItem::Array(props, "{sv}".into()))
+    }
+}
+
+pub fn get_assistants(adapter: &Adapter) -> Result<Vec<(MediaAssistant, MediaAssistantProperties)>, BtError> {
+    common::dbus_get_managed_objects_with_props(adapter.conn(),
+                                                adapter.object_path(),
+                                                MEDIA_ASSISTANT_INTERFACE,
+                                                |conn, obj_path, props_map| (MediaAssistant::new(&conn, obj_path), MediaAssistantProperties::new(props_map))
+    )
+}
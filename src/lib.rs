@@ -1,4 +1,5 @@
 extern crate dbus;
+extern crate libc;
 
 use std::rc::Rc;
 use std::ops::Deref;
@@ -6,11 +7,44 @@ use std::ops::Deref;
 #[derive(Clone, Debug)]
 pub struct Connection {
     dbus: Rc<dbus::Connection>,
+    registry: Rc<registry::ObjectRegistry>,
+    // Unknown for connections made with from_dbus
+    bus: Option<dbus::BusType>,
 }
 
 impl Connection {
     pub fn new() -> Result<Self, error::BtError> {
-        Ok(Connection { dbus: Rc::new(try!(dbus::Connection::get_private(dbus::BusType::System))) })
+        Connection::with_bus(dbus::BusType::System)
+    }
+
+    // obexd lives on the session bus
+    pub fn session() -> Result<Self, error::BtError> {
+        Connection::with_bus(dbus::BusType::Session)
+    }
+
+    // Shares a bus connection with other code. Whoever reads the connection has to pass the items
+    // to registry().handle(), so the objects exported through this crate are served and pending
+    // calls and signal watches get their messages.
+    pub fn from_dbus(dbus: Rc<dbus::Connection>) -> Self {
+        let registry = Rc::new(registry::ObjectRegistry::new(dbus.clone()));
+        Connection { dbus: dbus, registry: registry, bus: None }
+    }
+
+    fn with_bus(bus: dbus::BusType) -> Result<Self, error::BtError> {
+        let conn = Connection::from_dbus(Rc::new(try!(dbus::Connection::get_private(bus))));
+        Ok(Connection { bus: Some(bus), ..conn })
+    }
+
+    // The SyncConnection to the same bus, for handles which can be sent to other threads
+    pub fn to_sync(&self) -> Result<SyncConnection, error::BtError> {
+        match self.bus {
+            Some(bus) => SyncConnection::with_bus(bus),
+            None => Err(error::BtError::DBusInternal("the bus of a connection made with from_dbus is unknown".to_string())),
+        }
+    }
+
+    pub fn registry(&self) -> &registry::ObjectRegistry {
+        &self.registry
     }
 }
 
@@ -22,9 +56,70 @@ impl Deref for Connection {
     }
 }
 
+pub use sync_connection::SyncConnection;
+
+pub mod a2dp;
+pub mod address;
+pub mod admin_policy;
 pub mod agent;
+#[cfg(feature = "async")]
+pub mod async_agent;
+#[cfg(feature = "async")]
+pub mod async_call;
+pub mod battery;
+pub mod beacon;
 pub mod adapter;
+pub mod class;
 pub mod device;
+pub mod device_set;
+pub mod discovery;
+pub mod event_loop;
+#[cfg(feature = "hfp")]
+pub mod hfp;
+pub mod input;
+#[cfg(feature = "l2cap")]
+pub mod l2cap;
+#[cfg(feature = "le-audio")]
+pub mod le_audio;
+pub mod media;
+pub mod monitor;
+pub mod network;
+#[cfg(feature = "obex")]
+pub mod obex;
+#[cfg(feature = "obex")]
+pub mod obex_agent;
+#[cfg(feature = "obex")]
+pub mod obex_ftp;
+#[cfg(feature = "obex")]
+pub mod obex_map;
+#[cfg(feature = "obex")]
+pub mod obex_pbap;
+#[cfg(feature = "obex")]
+pub mod obex_push;
+#[cfg(feature = "obex")]
+pub mod obex_queue;
+#[cfg(feature = "obex")]
+pub mod obex_sync;
+pub mod pairing;
+pub mod pending;
+pub mod player;
+pub mod profile;
+pub mod properties;
+pub mod reconnect;
+#[cfg(feature = "rfcomm")]
+pub mod rfcomm;
+pub mod registry;
+pub mod scan;
+#[cfg(feature = "sco")]
+pub mod sco;
+pub mod serial;
+pub mod session;
+pub mod shutdown;
+pub mod sync_agent;
+pub mod sync_connection;
 pub mod error;
+pub mod uuid;
 
 mod common;
+mod mgmt;
+mod socket;
@@ -0,0 +1,760 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use dbus;
+
+use adapter::Adapter;
+use agent::AgentError;
+use common;
+use device::Device;
+use error::BtError;
+use properties::{self, PropertiesWatcher, PropertyValue};
+use registry::{Registration, SignalWatch};
+
+pub static MEDIA_INTERFACE: &'static str = "org.bluez.Media1";
+pub static MEDIA_ENDPOINT_INTERFACE: &'static str = "org.bluez.MediaEndpoint1";
+pub static MEDIA_TRANSPORT_INTERFACE: &'static str = "org.bluez.MediaTransport1";
+
+pub const A2DP_CODEC_SBC: u8 = 0x00;
+pub const A2DP_CODEC_MPEG24: u8 = 0x02;
+pub const A2DP_CODEC_VENDOR: u8 = 0xff;
+
+pub const SBC_FREQ_16000: u8 = 0x80;
+pub const SBC_FREQ_32000: u8 = 0x40;
+pub const SBC_FREQ_44100: u8 = 0x20;
+pub const SBC_FREQ_48000: u8 = 0x10;
+
+pub const SBC_CHANNEL_MODE_MONO: u8 = 0x08;
+pub const SBC_CHANNEL_MODE_DUAL_CHANNEL: u8 = 0x04;
+pub const SBC_CHANNEL_MODE_STEREO: u8 = 0x02;
+pub const SBC_CHANNEL_MODE_JOINT_STEREO: u8 = 0x01;
+
+pub const SBC_BLOCK_LENGTH_4: u8 = 0x08;
+pub const SBC_BLOCK_LENGTH_8: u8 = 0x04;
+pub const SBC_BLOCK_LENGTH_12: u8 = 0x02;
+pub const SBC_BLOCK_LENGTH_16: u8 = 0x01;
+
+pub const SBC_SUBBANDS_4: u8 = 0x02;
+pub const SBC_SUBBANDS_8: u8 = 0x01;
+
+pub const SBC_ALLOCATION_SNR: u8 = 0x02;
+pub const SBC_ALLOCATION_LOUDNESS: u8 = 0x01;
+
+pub const AAC_OBJECT_TYPE_MPEG2_LC: u8 = 0x80;
+pub const AAC_OBJECT_TYPE_MPEG4_LC: u8 = 0x40;
+pub const AAC_OBJECT_TYPE_MPEG4_LTP: u8 = 0x20;
+pub const AAC_OBJECT_TYPE_MPEG4_SCA: u8 = 0x10;
+
+// The 12 sampling frequency bits, from 8000 (0x800) to 96000 (0x001)
+pub const AAC_FREQ_32000: u16 = 0x020;
+pub const AAC_FREQ_44100: u16 = 0x010;
+pub const AAC_FREQ_48000: u16 = 0x008;
+pub const AAC_FREQ_96000: u16 = 0x001;
+
+pub const AAC_CHANNELS_1: u8 = 0x02;
+pub const AAC_CHANNELS_2: u8 = 0x01;
+
+// Picks the first flag of preferred which is set in flags
+fn select_flag(flags: u8, preferred: &[u8]) -> Option<u8> {
+    preferred.iter().cloned().find(|x| flags & x != 0)
+}
+
+// The SBC codec information element. Every field is a set of flags, a configuration has one flag per field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SbcCapabilities {
+    pub frequencies: u8,
+    pub channel_modes: u8,
+    pub block_lengths: u8,
+    pub subbands: u8,
+    pub allocation_methods: u8,
+    pub min_bitpool: u8,
+    pub max_bitpool: u8,
+}
+
+impl SbcCapabilities {
+    // Everything, with the bitpool range of the high quality profile
+    pub fn all() -> SbcCapabilities {
+        SbcCapabilities {
+            frequencies: 0x0f << 4,
+            channel_modes: 0x0f,
+            block_lengths: 0x0f,
+            subbands: 0x03,
+            allocation_methods: 0x03,
+            min_bitpool: 2,
+            max_bitpool: 53,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<SbcCapabilities> {
+        if bytes.len() != 4 {
+            return None;
+        }
+        Some(SbcCapabilities {
+            frequencies: bytes[0] & 0xf0,
+            channel_modes: bytes[0] & 0x0f,
+            block_lengths: bytes[1] >> 4,
+            subbands: (bytes[1] >> 2) & 0x03,
+            allocation_methods: bytes[1] & 0x03,
+            min_bitpool: bytes[2],
+            max_bitpool: bytes[3],
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            self.frequencies & 0xf0 | self.channel_modes & 0x0f,
+            (self.block_lengths & 0x0f) << 4 | (self.subbands & 0x03) << 2 | self.allocation_methods & 0x03,
+            self.min_bitpool,
+            self.max_bitpool,
+        ]
+    }
+
+    // The best configuration both sides support, None if there is none
+    pub fn select(&self, remote: &SbcCapabilities) -> Option<SbcCapabilities> {
+        let min_bitpool = self.min_bitpool.max(remote.min_bitpool);
+        let max_bitpool = self.max_bitpool.min(remote.max_bitpool);
+        if min_bitpool > max_bitpool {
+            return None;
+        }
+
+        let selected = (
+            select_flag(self.frequencies & remote.frequencies, &[SBC_FREQ_48000, SBC_FREQ_44100, SBC_FREQ_32000, SBC_FREQ_16000]),
+            select_flag(self.channel_modes & remote.channel_modes,
+                        &[SBC_CHANNEL_MODE_JOINT_STEREO, SBC_CHANNEL_MODE_STEREO, SBC_CHANNEL_MODE_DUAL_CHANNEL, SBC_CHANNEL_MODE_MONO]),
+            select_flag(self.block_lengths & remote.block_lengths, &[SBC_BLOCK_LENGTH_16, SBC_BLOCK_LENGTH_12, SBC_BLOCK_LENGTH_8, SBC_BLOCK_LENGTH_4]),
+            select_flag(self.subbands & remote.subbands, &[SBC_SUBBANDS_8, SBC_SUBBANDS_4]),
+            select_flag(self.allocation_methods & remote.allocation_methods, &[SBC_ALLOCATION_LOUDNESS, SBC_ALLOCATION_SNR]),
+        );
+
+        match selected {
+            (Some(frequency), Some(channel_mode), Some(block_length), Some(subbands), Some(allocation_method)) => Some(SbcCapabilities {
+                frequencies: frequency,
+                channel_modes: channel_mode,
+                block_lengths: block_length,
+                subbands: subbands,
+                allocation_methods: allocation_method,
+                min_bitpool: min_bitpool,
+                max_bitpool: max_bitpool,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// The MPEG-2/4 AAC codec information element
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AacCapabilities {
+    pub object_types: u8,
+    pub frequencies: u16,
+    pub channels: u8,
+    pub vbr: bool,
+    // Bits per second, 0 if unknown
+    pub bitrate: u32,
+}
+
+impl AacCapabilities {
+    pub fn from_bytes(bytes: &[u8]) -> Option<AacCapabilities> {
+        if bytes.len() != 6 {
+            return None;
+        }
+        Some(AacCapabilities {
+            object_types: bytes[0],
+            frequencies: (bytes[1] as u16) << 4 | (bytes[2] >> 4) as u16,
+            channels: (bytes[2] >> 2) & 0x03,
+            vbr: bytes[3] & 0x80 != 0,
+            bitrate: ((bytes[3] & 0x7f) as u32) << 16 | (bytes[4] as u32) << 8 | bytes[5] as u32,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            self.object_types,
+            (self.frequencies >> 4) as u8,
+            ((self.frequencies & 0x0f) as u8) << 4 | (self.channels & 0x03) << 2,
+            if self.vbr { 0x80 } else { 0 } | ((self.bitrate >> 16) & 0x7f) as u8,
+            (self.bitrate >> 8) as u8,
+            self.bitrate as u8,
+        ]
+    }
+}
+
+// The SetConfiguration properties of the new transport
+#[derive(Clone, Debug)]
+pub struct TransportConfiguration {
+    pub device: Device,
+    pub uuid: Option<String>,
+    pub codec: Option<u8>,
+    pub configuration: Vec<u8>,
+}
+
+impl TransportConfiguration {
+    fn new(conn: &super::Connection, transport_obj_path: &str, props_map: BTreeMap<String, dbus::MessageItem>) -> TransportConfiguration {
+        // Transports are children of their device, in case the property is missing
+        let device_obj_path = props_map.get("Device").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string())
+            .unwrap_or_else(|| transport_obj_path.rsplitn(2, '/').last().unwrap_or("").to_string());
+
+        TransportConfiguration {
+            device: Device::new(conn, &device_obj_path),
+            uuid: props_map.get("UUID").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+            codec: props_map.get("Codec").and_then(|x| x.inner().ok()),
+            configuration: props_map.get("Configuration")
+                .and_then(|x| (x.inner() as Result<&[dbus::MessageItem], ()>).ok())
+                .map(|x| x.iter().filter_map(|x| x.inner().ok()).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportState {
+    Idle,
+    Pending,
+    Active,
+}
+
+impl TransportState {
+    pub(crate) fn from_str(s: &str) -> Option<TransportState> {
+        match s {
+            "idle" => Some(TransportState::Idle),
+            "pending" => Some(TransportState::Pending),
+            "active" => Some(TransportState::Active),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TransportStateEvent {
+    pub transport_object_path: String,
+    pub device: Device,
+    pub uuid: String,
+    pub old_state: Option<TransportState>,
+    pub new_state: TransportState,
+}
+
+fn find_str_prop<'a>(props: &'a [dbus::MessageItem], name: &str) -> Option<&'a str> {
+    for p in props {
+        if let Ok((k, v)) = p.inner() as Result<(&dbus::MessageItem, &dbus::MessageItem), ()> {
+            if k.inner() == Ok(name) {
+                let v = (v.inner() as Result<&dbus::MessageItem, ()>).unwrap_or(v);
+                return v.inner().ok();
+            }
+        }
+    }
+    None
+}
+
+fn get_transport_str_prop(conn: &super::Connection, transport_obj_path: &str, name: &str) -> Result<String, BtError> {
+    let val = try!(common::dbus_get_property(conn, transport_obj_path, MEDIA_TRANSPORT_INTERFACE, name));
+    let val: &str = try!(val.inner().map_err(|_| BtError::DBusInternal(format!("invalid {} value", name))));
+    Ok(val.to_string())
+}
+
+// A stream configured by SetConfiguration (see MediaEndpoint), or found with watch_transport_states()
+#[derive(Clone, Debug)]
+pub struct MediaTransport {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl MediaTransport {
+    pub fn new(conn: &super::Connection, object_path: &str) -> MediaTransport {
+        MediaTransport { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn conn(&self) -> &super::Connection {
+        &self.conn
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_state(&self) -> Result<TransportState, BtError> {
+        let state = try!(get_transport_str_prop(&self.conn, &self.object_path, "State"));
+        TransportState::from_str(&state).ok_or_else(|| BtError::DBusInternal("invalid State value".to_string()))
+    }
+
+    // Blocks until the stream is ready
+    pub fn acquire(&self) -> Result<AcquiredTransport, BtError> {
+        self.acquire_with("Acquire")
+    }
+
+    // Only succeeds while the transport is pending (i.e. the remote side started streaming)
+    pub fn try_acquire(&self) -> Result<AcquiredTransport, BtError> {
+        self.acquire_with("TryAcquire")
+    }
+
+    fn acquire_with(&self, method_name: &str) -> Result<AcquiredTransport, BtError> {
+        let reply = try!(common::dbus_call_method0_with_reply(&self.conn, &self.object_path, MEDIA_TRANSPORT_INTERFACE, method_name));
+
+        let mut items = reply.get_items().into_iter();
+        match (items.next(), items.next(), items.next()) {
+            (Some(dbus::MessageItem::UnixFd(fd)), Some(dbus::MessageItem::UInt16(read_mtu)), Some(dbus::MessageItem::UInt16(write_mtu))) => {
+                Ok(AcquiredTransport {
+                    transport: self.clone(),
+                    file: unsafe { File::from_raw_fd(fd.into_fd()) },
+                    read_mtu: read_mtu,
+                    write_mtu: write_mtu,
+                    released: false,
+                })
+            }
+            _ => Err(BtError::DBusInternal(format!("invalid {} reply", method_name))),
+        }
+    }
+
+    pub fn release(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_TRANSPORT_INTERFACE, "Release")
+    }
+
+    // The AVRCP absolute volume (0-127), only present if the remote device supports it
+    pub fn get_volume(&self) -> Result<u16, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, MEDIA_TRANSPORT_INTERFACE, "Volume"));
+        val.inner().map_err(|_| BtError::DBusInternal("invalid Volume value".to_string()))
+    }
+
+    pub fn set_volume(&self, volume: u16) -> Result<(), BtError> {
+        common::dbus_set_property(&self.conn, &self.object_path, MEDIA_TRANSPORT_INTERFACE, "Volume", volume.min(127))
+    }
+
+    // Yields the volume whenever either side changes it
+    pub fn watch_volume(&self) -> Result<VolumeWatcher, BtError> {
+        let watcher = try!(properties::watch_properties(&self.conn, &self.object_path, MEDIA_TRANSPORT_INTERFACE));
+        Ok(VolumeWatcher { watcher: watcher })
+    }
+}
+
+pub struct VolumeWatcher {
+    watcher: PropertiesWatcher,
+}
+
+impl VolumeWatcher {
+    // Ends the iteration when no change arrives within timeout
+    pub fn timeout(self, timeout: Duration) -> VolumeWatcher {
+        VolumeWatcher { watcher: self.watcher.timeout(timeout) }
+    }
+}
+
+impl Iterator for VolumeWatcher {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        for event in &mut self.watcher {
+            if let Some(&PropertyValue::UInt16(volume)) = event.get("Volume") {
+                return Some(volume);
+            }
+        }
+        None
+    }
+}
+
+// The (encoded) audio stream of an acquired transport, released when dropped
+pub struct AcquiredTransport {
+    transport: MediaTransport,
+    file: File,
+    read_mtu: u16,
+    write_mtu: u16,
+    released: bool,
+}
+
+impl AcquiredTransport {
+    pub fn transport(&self) -> &MediaTransport {
+        &self.transport
+    }
+
+    pub fn read_mtu(&self) -> u16 {
+        self.read_mtu
+    }
+
+    pub fn write_mtu(&self) -> u16 {
+        self.write_mtu
+    }
+
+    pub fn release(mut self) -> Result<(), BtError> {
+        self.released = true;
+        self.transport.release()
+    }
+}
+
+impl Read for AcquiredTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for AcquiredTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for AcquiredTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for AcquiredTransport {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.transport.release();
+        }
+    }
+}
+
+// Calls f for every idle/pending/active transition of any media transport until f returns false
+pub fn watch_transport_states<F>(conn: &super::Connection, mut f: F) -> Result<(), BtError> where F: FnMut(TransportStateEvent) -> bool {
+    let filter1 = format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesAdded'", common::SERVICE_NAME);
+    let filter2 = format!("sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='{}'", common::SERVICE_NAME, MEDIA_TRANSPORT_INTERFACE);
+
+    let filter3 = format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesRemoved'", common::SERVICE_NAME);
+
+    let watch = try!(SignalWatch::new(conn, &[filter1, filter2, filter3]));
+
+    // The last state, device and uuid of every transport seen. Device and UUID never change, so
+    // they're taken from InterfacesAdded or looked up once when a transport is first seen.
+    let mut transports: HashMap<String, (TransportState, String, String)> = HashMap::new();
+
+    'outer: while conn.registry().is_connected() {
+        if let Some(ref s) = watch.next_signal(100) {
+            let member = s.member().unwrap();
+            let items = s.get_items();
+
+            let (obj_path, new_state, added) = if &*member == "PropertiesChanged" {
+                let iface: &str = items.get(0).and_then(|x| x.inner().ok()).unwrap_or("");
+                if iface != MEDIA_TRANSPORT_INTERFACE {
+                    continue;
+                }
+
+                let props: &[dbus::MessageItem] = items.get(1).and_then(|x| x.inner().ok()).unwrap_or(&[]);
+                match find_str_prop(props, "State").and_then(TransportState::from_str) {
+                    Some(state) => (s.path().unwrap().to_string(), state, None),
+                    None => continue,
+                }
+            }
+
+            else if &*member == "InterfacesAdded" {
+                let obj_path: &str = items.get(0).and_then(|x| x.inner().ok()).unwrap_or("");
+                let dict: &[dbus::MessageItem] = items.get(1).and_then(|x| x.inner().ok()).unwrap_or(&[]);
+
+                let mut state = None;
+                let mut added = None;
+                for kv in dict {
+                    let (iface, props) = kv.inner().unwrap();
+                    if iface.inner() == Ok(MEDIA_TRANSPORT_INTERFACE) {
+                        let props: &[dbus::MessageItem] = props.inner().unwrap_or(&[]);
+                        state = find_str_prop(props, "State").and_then(TransportState::from_str);
+                        if let (Some(device), Some(uuid)) = (find_str_prop(props, "Device"), find_str_prop(props, "UUID")) {
+                            added = Some((device.to_string(), uuid.to_string()));
+                        }
+                    }
+                }
+                match state {
+                    Some(state) => (obj_path.to_string(), state, added),
+                    None => continue,
+                }
+            }
+
+            // A transport that comes back at the same path starts over without an old state
+            else if let Some(obj_path) = common::dbus_interfaces_removed(s, MEDIA_TRANSPORT_INTERFACE) {
+                transports.remove(&obj_path);
+                continue;
+            }
+
+            else { continue; };
+
+            let (old_state, device_obj_path, uuid) = match (transports.remove(&obj_path), added) {
+                (Some((old_state, device_obj_path, uuid)), _) => (Some(old_state), device_obj_path, uuid),
+                (None, Some((device_obj_path, uuid))) => (None, device_obj_path, uuid),
+                (None, None) => {
+                    // A transport that was there before the watch, or one that is already gone
+                    let device_obj_path = get_transport_str_prop(conn, &obj_path, "Device");
+                    let uuid = get_transport_str_prop(conn, &obj_path, "UUID");
+                    match (device_obj_path, uuid) {
+                        (Ok(device_obj_path), Ok(uuid)) => (None, device_obj_path, uuid),
+                        _ => continue,
+                    }
+                }
+            };
+            transports.insert(obj_path.clone(), (new_state, device_obj_path.clone(), uuid.clone()));
+            if old_state == Some(new_state) {
+                continue;
+            }
+
+            let event = TransportStateEvent {
+                transport_object_path: obj_path,
+                device: Device::new(conn, &device_obj_path),
+                uuid: uuid,
+                old_state: old_state,
+                new_state: new_state,
+            };
+            if !f(event) {
+                break 'outer;
+            }
+        }
+    }
+
+    watch.close()
+}
+
+// A local A2DP (or other media) endpoint. The uuid is the role it plays, e.g. uuid::A2DP_SINK_UUID
+// to receive audio. Errors are reported to BlueZ the same way as agent errors.
+pub trait MediaEndpoint {
+    fn get_object_path(&self) -> &str {
+        "/io/bluezrs/media_endpoint"
+    }
+
+    fn get_uuid(&self) -> &str;
+    fn get_codec(&self) -> u8;
+    fn get_capabilities(&self) -> Vec<u8>;
+
+    // Picks a configuration out of the remote capabilities (e.g. with SbcCapabilities::select())
+    fn select_configuration(&self, capabilities: &[u8]) -> Result<Vec<u8>, AgentError>;
+    fn set_configuration(&self, transport_object_path: &str, configuration: TransportConfiguration) -> Result<(), AgentError>;
+    fn clear_configuration(&self, transport_object_path: &str);
+    fn release(&self);
+
+    // Only used by BAP (LE Audio) endpoints. Metadata is LTV encoded, contexts are bitmasks of
+    // the audio context types.
+    fn get_metadata(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn get_locations(&self) -> Option<u32> {
+        None
+    }
+
+    fn get_supported_context(&self) -> Option<u16> {
+        None
+    }
+
+    fn get_context(&self) -> Option<u16> {
+        None
+    }
+}
+
+impl fmt::Debug for MediaEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "MediaEndpoint(object_path: \"{}\", uuid: \"{}\", codec: {})", self.get_object_path(), self.get_uuid(), self.get_codec())
+    }
+}
+
+type SharedEndpointT = Rc<Box<MediaEndpoint>>;
+
+#[derive(Copy, Clone, Default, Debug)]
+struct TData;
+impl dbus::tree::DataType for TData {
+    type Tree = ();
+    type ObjectPath = SharedEndpointT;
+    type Property = ();
+    type Interface = ();
+    type Method = Option<super::Connection>;
+    type Signal = ();
+}
+
+pub struct MediaEndpointManager {
+    conn: super::Connection,
+    adapter_object_path: String,
+    endpoint: SharedEndpointT,
+    registration: Registration,
+}
+
+impl MediaEndpointManager {
+    pub fn new(adapter: &Adapter, endpoint: Box<MediaEndpoint>) -> MediaEndpointManager {
+        let conn = adapter.conn();
+        let endpoint = Rc::new(endpoint);
+
+        let f = dbus::tree::Factory::new_fn::<TData>();
+
+        let tree = f.tree(()).add(
+            f.object_path(endpoint.get_object_path().to_string(), endpoint.clone()).introspectable().add(
+                f.interface(MEDIA_ENDPOINT_INTERFACE, ())
+                    .add_m(
+                        f.method("SelectConfiguration", None, move |m| {
+                            let endpoint: &SharedEndpointT = m.path.get_data();
+
+                            let capabilities: &[u8] = try!(m.msg.get1().ok_or_else(dbus::tree::MethodErr::no_arg));
+                            match endpoint.select_configuration(capabilities) {
+                                Ok(configuration) => Ok(vec![m.msg.method_return().append1(&configuration[..])]),
+                                Err(e) => Err(e.method_err()),
+                            }
+                        }).in_arg(("capabilities", "ay")).out_arg(("configuration", "ay"))
+                    )
+                    .add_m(
+                        f.method("SetConfiguration", Some(conn.clone()), move |m| {
+                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
+                            let endpoint: &SharedEndpointT = m.path.get_data();
+
+                            let items = m.msg.get_items();
+                            let (transport_obj_path, props) = match (items.first(), items.get(1)) {
+                                (Some(dbus::MessageItem::ObjectPath(path)), Some(dbus::MessageItem::Array(props, _))) => (path.to_string(), props),
+                                _ => return Err(dbus::tree::MethodErr::no_arg()),
+                            };
+
+                            let configuration = TransportConfiguration::new(conn, &transport_obj_path, common::dbus_props_to_map(props));
+                            match endpoint.set_configuration(&transport_obj_path, configuration) {
+                                Ok(_) => Ok(vec![m.msg.method_return()]),
+                                Err(e) => Err(e.method_err()),
+                            }
+                        }).in_arg(("transport", "o")).in_arg(("properties", "a{sv}"))
+                    )
+                    .add_m(
+                        f.method("ClearConfiguration", None, move |m| {
+                            let endpoint: &SharedEndpointT = m.path.get_data();
+
+                            let transport_obj_path: dbus::Path = try!(m.msg.get1().ok_or_else(dbus::tree::MethodErr::no_arg));
+                            endpoint.clear_configuration(&transport_obj_path);
+                            Ok(vec![m.msg.method_return()])
+                        }).in_arg(("transport", "o"))
+                    )
+                    .add_m(
+                        f.method("Release", None, move |m| {
+                            let endpoint: &SharedEndpointT = m.path.get_data();
+                            endpoint.release();
+                            Ok(vec![m.msg.method_return()])
+                        })
+                    )
+        ));
+
+        let object_path = endpoint.get_object_path().to_string();
+        let adapter_object_path = adapter.object_path().to_string();
+        let registration = Registration::new(conn, vec![object_path.clone()], Rc::new(move |msg| tree.handle(msg)), move |conn| {
+            common::dbus_start_call(conn, &adapter_object_path, MEDIA_INTERFACE, "UnregisterEndpoint",
+                                    &[dbus::MessageItem::ObjectPath(dbus::Path::new(object_path.clone()).unwrap())], Duration::from_secs(60), |_| Ok(()))
+        });
+
+        MediaEndpointManager {
+            conn: conn.clone(),
+            adapter_object_path: adapter.object_path().to_string(),
+            endpoint: endpoint,
+            registration: registration,
+        }
+    }
+
+    fn properties(&self) -> dbus::MessageItem {
+        fn _bytes(bytes: Vec<u8>) -> dbus::MessageItem {
+            dbus::MessageItem::Array(bytes.into_iter().map(dbus::MessageItem::Byte).collect(), "y".into())
+        }
+
+        let mut entries = vec![
+            common::dbus_dict_entry("UUID", self.endpoint.get_uuid()),
+            common::dbus_dict_entry("Codec", dbus::MessageItem::Byte(self.endpoint.get_codec())),
+            common::dbus_dict_entry("Capabilities", _bytes(self.endpoint.get_capabilities())),
+        ];
+        if let Some(metadata) = self.endpoint.get_metadata() {
+            entries.push(common::dbus_dict_entry("Metadata", _bytes(metadata)));
+        }
+        if let Some(locations) = self.endpoint.get_locations() {
+            entries.push(common::dbus_dict_entry("Locations", locations));
+        }
+        if let Some(supported_context) = self.endpoint.get_supported_context() {
+            entries.push(common::dbus_dict_entry("SupportedContext", supported_context));
+        }
+        if let Some(context) = self.endpoint.get_context() {
+            entries.push(common::dbus_dict_entry("Context", context));
+        }
+
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+
+    pub fn register_endpoint(&self) -> Result<(), BtError> {
+        let endpoint_obj_path = dbus::Path::new(self.endpoint.get_object_path()).unwrap();
+
+        self.registration.register(|| {
+            common::dbus_call_method2(&self.conn, &self.adapter_object_path, MEDIA_INTERFACE, "RegisterEndpoint", endpoint_obj_path, self.properties())
+        })
+    }
+
+    pub fn unregister_endpoint(&self) -> Result<(), BtError> {
+        self.registration.unregister()
+    }
+
+    pub(crate) fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    pub fn handle_message(&self, msg: &dbus::Message) -> bool {
+        self.registration.handle_message(msg)
+    }
+
+    // Also serves the other objects registered on the connection
+    pub fn serve(&self, cb: Option<&Fn() -> bool>) {
+        self.conn.registry().serve(cb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sbc_select_prefers_best_quality() {
+        let remote = SbcCapabilities::from_bytes(&[0xff, 0xff, 2, 53]).unwrap();
+        let selected = SbcCapabilities::all().select(&remote).unwrap();
+        assert_eq!(selected, SbcCapabilities {
+            frequencies: SBC_FREQ_48000,
+            channel_modes: SBC_CHANNEL_MODE_JOINT_STEREO,
+            block_lengths: SBC_BLOCK_LENGTH_16,
+            subbands: SBC_SUBBANDS_8,
+            allocation_methods: SBC_ALLOCATION_LOUDNESS,
+            min_bitpool: 2,
+            max_bitpool: 53,
+        });
+    }
+
+    #[test]
+    fn sbc_select_intersects_capabilities() {
+        // 44.1 kHz joint stereo, 16 blocks, 8 subbands, loudness, bitpool 10..35
+        let remote = SbcCapabilities::from_bytes(&[0x21, 0x15, 10, 35]).unwrap();
+        let selected = SbcCapabilities::all().select(&remote).unwrap();
+        assert_eq!(selected.to_bytes(), vec![0x21, 0x15, 10, 35]);
+
+        // 16 kHz mono only, 4 blocks, 4 subbands, SNR
+        let remote = SbcCapabilities::from_bytes(&[0x88, 0x8a, 2, 53]).unwrap();
+        assert_eq!(SbcCapabilities::all().select(&remote).unwrap().to_bytes(), vec![0x88, 0x8a, 2, 53]);
+    }
+
+    #[test]
+    fn sbc_select_without_common_configuration() {
+        let local = SbcCapabilities::from_bytes(&[0x11, 0xff, 2, 53]).unwrap();
+        // No common frequency
+        assert_eq!(local.select(&SbcCapabilities::from_bytes(&[0x21, 0xff, 2, 53]).unwrap()), None);
+        // No common channel mode
+        assert_eq!(local.select(&SbcCapabilities::from_bytes(&[0x18, 0xff, 2, 53]).unwrap()), None);
+        // Disjoint bitpool ranges
+        assert_eq!(local.select(&SbcCapabilities::from_bytes(&[0x11, 0xff, 54, 64]).unwrap()), None);
+        // Nothing set at all
+        assert_eq!(local.select(&SbcCapabilities::from_bytes(&[0x00, 0x00, 0, 0]).unwrap()), None);
+    }
+
+    #[test]
+    fn sbc_from_bytes_rejects_wrong_lengths() {
+        assert_eq!(SbcCapabilities::from_bytes(&[]), None);
+        assert_eq!(SbcCapabilities::from_bytes(&[0xff, 0xff, 2]), None);
+        assert_eq!(SbcCapabilities::from_bytes(&[0xff, 0xff, 2, 53, 0]), None);
+        assert_eq!(SbcCapabilities::from_bytes(&SbcCapabilities::all().to_bytes()), Some(SbcCapabilities::all()));
+    }
+
+    #[test]
+    fn aac_bytes_round_trip() {
+        let aac = AacCapabilities {
+            object_types: AAC_OBJECT_TYPE_MPEG2_LC,
+            frequencies: AAC_FREQ_44100 | AAC_FREQ_48000,
+            channels: AAC_CHANNELS_1 | AAC_CHANNELS_2,
+            vbr: true,
+            bitrate: 320000,
+        };
+        assert_eq!(aac.to_bytes(), vec![0x80, 0x01, 0x8c, 0x84, 0xe2, 0x00]);
+        assert_eq!(AacCapabilities::from_bytes(&aac.to_bytes()), Some(aac));
+        assert_eq!(AacCapabilities::from_bytes(&[0x80, 0x01, 0x8c]), None);
+    }
+}
@@ -0,0 +1,244 @@
+use std::io;
+use std::mem;
+
+use libc;
+
+use error::BtError;
+
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_DEV_NONE: u16 = 0xffff;
+const HCI_CHANNEL_CONTROL: u16 = 3;
+
+const MGMT_EV_CMD_COMPLETE: u16 = 0x0001;
+const MGMT_EV_CMD_STATUS: u16 = 0x0002;
+
+pub const MGMT_OP_GET_CONN_INFO: u16 = 0x0031;
+pub const MGMT_OP_GET_PHY_CONFIGURATION: u16 = 0x0044;
+
+pub const MGMT_STATUS_SUCCESS: u8 = 0x00;
+pub const MGMT_STATUS_NOT_CONNECTED: u8 = 0x02;
+
+pub const BDADDR_BREDR: u8 = 0x00;
+pub const BDADDR_LE_PUBLIC: u8 = 0x01;
+pub const BDADDR_LE_RANDOM: u8 = 0x02;
+
+const MGMT_TIMEOUT_MS: libc::c_int = 1000;
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+struct MgmtSocket {
+    fd: libc::c_int,
+}
+
+impl MgmtSocket {
+    fn open() -> Result<MgmtSocket, BtError> {
+        let fd = unsafe { libc::socket(libc::AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC, BTPROTO_HCI) };
+        if fd < 0 {
+            return Err(BtError::Io(io::Error::last_os_error()));
+        }
+        let sock = MgmtSocket { fd: fd };
+
+        let addr = SockaddrHci {
+            hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            hci_dev: HCI_DEV_NONE,
+            hci_channel: HCI_CHANNEL_CONTROL,
+        };
+        let r = unsafe {
+            libc::bind(fd, &addr as *const SockaddrHci as *const libc::sockaddr, mem::size_of::<SockaddrHci>() as libc::socklen_t)
+        };
+        if r < 0 {
+            return Err(BtError::Io(io::Error::last_os_error()));
+        }
+
+        Ok(sock)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<(), BtError> {
+        let r = unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if r < 0 {
+            return Err(BtError::Io(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, BtError> {
+        let mut pfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let r = unsafe { libc::poll(&mut pfd, 1, MGMT_TIMEOUT_MS) };
+        if r < 0 {
+            return Err(BtError::Io(io::Error::last_os_error()));
+        }
+        if r == 0 {
+            return Err(BtError::Io(io::Error::new(io::ErrorKind::TimedOut, "mgmt command timed out")));
+        }
+
+        let r = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if r < 0 {
+            return Err(BtError::Io(io::Error::last_os_error()));
+        }
+        Ok(r as usize)
+    }
+}
+
+impl Drop for MgmtSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+// Sends a single command and returns the parameters of its Command Complete event
+pub fn send_command(index: u16, opcode: u16, params: &[u8]) -> Result<Vec<u8>, BtError> {
+    let sock = try!(MgmtSocket::open());
+
+    let mut cmd = Vec::with_capacity(6 + params.len());
+    cmd.extend_from_slice(&[opcode as u8, (opcode >> 8) as u8]);
+    cmd.extend_from_slice(&[index as u8, (index >> 8) as u8]);
+    cmd.extend_from_slice(&[params.len() as u8, (params.len() >> 8) as u8]);
+    cmd.extend_from_slice(params);
+    try!(sock.write(&cmd));
+
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = try!(sock.read(&mut buf));
+        if len < 9 {
+            continue;
+        }
+
+        let event = get_u16(&buf, 0);
+        let ev_index = get_u16(&buf, 2);
+        let ev_opcode = get_u16(&buf, 6);
+        let status = buf[8];
+
+        if ev_index != index || ev_opcode != opcode {
+            continue;
+        }
+
+        if event == MGMT_EV_CMD_COMPLETE || event == MGMT_EV_CMD_STATUS {
+            if status != MGMT_STATUS_SUCCESS {
+                return Err(BtError::Mgmt(status));
+            }
+            return Ok(buf[9..len].to_vec());
+        }
+    }
+}
+
+pub fn adapter_index(adapter_object_path: &str) -> Result<u16, BtError> {
+    adapter_object_path.rsplit("/hci").next()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| BtError::DBusInternal(format!("cannot get adapter index from {}", adapter_object_path)))
+}
+
+pub fn parse_address(address: &str) -> Result<[u8; 6], BtError> {
+    let mut addr = [0u8; 6];
+    let parts: Vec<&str> = address.split(':').collect();
+    if parts.len() != 6 {
+        return Err(BtError::DBusInternal(format!("invalid address {}", address)));
+    }
+    for (i, part) in parts.iter().enumerate() {
+        // from_str_radix would also take a sign or a single digit
+        if part.len() != 2 || !part.chars().all(|x| x.is_ascii_hexdigit()) {
+            return Err(BtError::DBusInternal(format!("invalid address {}", address)));
+        }
+        addr[5 - i] = try!(u8::from_str_radix(part, 16).map_err(|_| BtError::DBusInternal(format!("invalid address {}", address))));
+    }
+    Ok(addr)
+}
+
+const SOL_HCI: libc::c_int = 0;
+const HCI_FILTER: libc::c_int = 2;
+const HCI_CHANNEL_RAW: u16 = 0;
+const HCI_COMMAND_PKT: u8 = 0x01;
+const HCI_EVENT_PKT: u8 = 0x04;
+const EVT_CMD_COMPLETE: u8 = 0x0e;
+const OP_READ_LOCAL_VERSION: u16 = 0x1001;
+
+#[repr(C)]
+struct HciFilter {
+    type_mask: u32,
+    event_mask: [u32; 2],
+    opcode: u16,
+}
+
+// Read Local Version Information isn't available over MGMT, so it goes through a raw HCI socket
+pub fn read_local_version(index: u16) -> Result<Vec<u8>, BtError> {
+    let fd = unsafe { libc::socket(libc::AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC, BTPROTO_HCI) };
+    if fd < 0 {
+        return Err(BtError::Io(io::Error::last_os_error()));
+    }
+    let sock = MgmtSocket { fd: fd };
+
+    let addr = SockaddrHci {
+        hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        hci_dev: index,
+        hci_channel: HCI_CHANNEL_RAW,
+    };
+    let r = unsafe {
+        libc::bind(fd, &addr as *const SockaddrHci as *const libc::sockaddr, mem::size_of::<SockaddrHci>() as libc::socklen_t)
+    };
+    if r < 0 {
+        return Err(BtError::Io(io::Error::last_os_error()));
+    }
+
+    let filter = HciFilter {
+        type_mask: 1 << HCI_EVENT_PKT,
+        event_mask: [1 << EVT_CMD_COMPLETE, 0],
+        opcode: OP_READ_LOCAL_VERSION,
+    };
+    let r = unsafe {
+        libc::setsockopt(fd, SOL_HCI, HCI_FILTER, &filter as *const HciFilter as *const libc::c_void, mem::size_of::<HciFilter>() as libc::socklen_t)
+    };
+    if r < 0 {
+        return Err(BtError::Io(io::Error::last_os_error()));
+    }
+
+    try!(sock.write(&[HCI_COMMAND_PKT, OP_READ_LOCAL_VERSION as u8, (OP_READ_LOCAL_VERSION >> 8) as u8, 0]));
+
+    let mut buf = [0u8; 260];
+    loop {
+        let len = try!(sock.read(&mut buf));
+        // packet type, event code, length, num packets, opcode, status
+        if len < 7 || buf[0] != HCI_EVENT_PKT || buf[1] != EVT_CMD_COMPLETE || get_u16(&buf, 4) != OP_READ_LOCAL_VERSION {
+            continue;
+        }
+        if buf[6] != 0 {
+            return Err(BtError::Mgmt(buf[6]));
+        }
+        return Ok(buf[7..len].to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adapter_index, parse_address};
+
+    #[test]
+    fn parse_valid_addresses() {
+        assert_eq!(parse_address("00:1A:7D:DA:71:13").unwrap(), [0x13, 0x71, 0xda, 0x7d, 0x1a, 0x00]);
+        assert_eq!(parse_address("ff:ff:ff:ff:ff:ff").unwrap(), [0xff; 6]);
+    }
+
+    #[test]
+    fn parse_invalid_addresses() {
+        for address in &["", ":", "00:1A:7D:DA:71", "00:1A:7D:DA:71:13:00", "00:1A:7D:DA:71:", "0:1A:7D:DA:71:13",
+                         "000:1A:7D:DA:71:13", "+0:1A:7D:DA:71:13", "GG:1A:7D:DA:71:13", "00-1A-7D-DA-71-13", "\u{e9}:1A:7D:DA:71:13"] {
+            assert!(parse_address(address).is_err(), "{} was accepted", address);
+        }
+    }
+
+    #[test]
+    fn adapter_indexes() {
+        assert_eq!(adapter_index("/org/bluez/hci0").unwrap(), 0);
+        assert_eq!(adapter_index("/org/bluez/hci12").unwrap(), 12);
+        assert!(adapter_index("/org/bluez").is_err());
+        assert!(adapter_index("/org/bluez/hci").is_err());
+        assert!(adapter_index("/org/bluez/hcix").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use dbus;
+
+use adapter::Adapter;
+use common;
+use device::Device;
+use error::BtError;
+use registry::Registration;
+
+pub static ADV_MONITOR_INTERFACE: &'static str = "org.bluez.AdvertisementMonitor1";
+pub static ADV_MONITOR_MANAGER_INTERFACE: &'static str = "org.bluez.AdvertisementMonitorManager1";
+pub static ADV_MONITOR_APP_OBJ_PATH: &'static str = "/io/bluezrs/monitor";
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pub start_position: u8,
+    pub ad_data_type: u8,
+    pub content: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RssiThresholds {
+    pub high_threshold: Option<i16>,
+    pub high_timeout: Option<u16>,
+    pub low_threshold: Option<i16>,
+    pub low_timeout: Option<u16>,
+    pub sampling_period: Option<u16>,
+}
+
+impl Pattern {
+    pub fn new(start_position: u8, ad_data_type: u8, content: &[u8]) -> Pattern {
+        Pattern { start_position: start_position, ad_data_type: ad_data_type, content: content.to_vec() }
+    }
+
+    fn to_message_item(&self) -> dbus::MessageItem {
+        dbus::MessageItem::Struct(vec![
+            dbus::MessageItem::Byte(self.start_position),
+            dbus::MessageItem::Byte(self.ad_data_type),
+            dbus::MessageItem::Array(self.content.iter().map(|x| dbus::MessageItem::Byte(*x)).collect(), "y".into()),
+        ])
+    }
+}
+
+pub trait AdvertisementMonitor {
+    fn get_patterns(&self) -> Vec<Pattern>;
+
+    fn get_rssi_thresholds(&self) -> RssiThresholds {
+        RssiThresholds::default()
+    }
+
+    fn device_found(&self, device: Device);
+    fn device_lost(&self, device: Device);
+
+    fn activate(&self) {}
+    fn release(&self) {}
+}
+
+impl fmt::Debug for AdvertisementMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "AdvertisementMonitor(patterns: {:?}, rssi: {:?})", self.get_patterns(), self.get_rssi_thresholds())
+    }
+}
+
+type SharedMonitorT = Rc<Box<AdvertisementMonitor>>;
+
+#[derive(Copy, Clone, Default, Debug)]
+struct TData;
+impl dbus::tree::DataType for TData {
+    type Tree = ();
+    type ObjectPath = Option<SharedMonitorT>;
+    type Property = dbus::MessageItem;
+    type Interface = ();
+    type Method = Option<super::Connection>;
+    type Signal = ();
+}
+
+pub struct AdvertisementMonitorManager {
+    conn: super::Connection,
+    adapter_object_path: String,
+    registration: Registration,
+    // How many DeviceFound calls were served
+    devices_found: Rc<Cell<u32>>,
+}
+
+impl AdvertisementMonitorManager {
+    pub fn new(adapter: &Adapter, monitors: Vec<Box<AdvertisementMonitor>>) -> AdvertisementMonitorManager {
+        let conn = adapter.conn();
+
+        let f = dbus::tree::Factory::new_fn::<TData>();
+
+        let mut tree = f.tree(()).add(
+            f.object_path(ADV_MONITOR_APP_OBJ_PATH, None).introspectable().object_manager()
+        );
+        let mut object_paths = vec![ADV_MONITOR_APP_OBJ_PATH.to_string()];
+        let devices_found = Rc::new(Cell::new(0u32));
+
+        for (i, monitor) in monitors.into_iter().enumerate() {
+            let monitor = Rc::new(monitor);
+
+            let patterns: Vec<dbus::MessageItem> = monitor.get_patterns().iter().map(|x| x.to_message_item()).collect();
+            let rssi = monitor.get_rssi_thresholds();
+
+            let devices_found = devices_found.clone();
+            let mut iface = f.interface(ADV_MONITOR_INTERFACE, ())
+                .add_p(f.property::<&str, _>("Type", dbus::MessageItem::Str("or_patterns".to_string())).default_get())
+                .add_p(f.property::<&[(u8, u8, &[u8])], _>("Patterns", dbus::MessageItem::Array(patterns, "(yyay)".into())).default_get());
+
+            if let Some(val) = rssi.high_threshold {
+                iface = iface.add_p(f.property::<i16, _>("RSSIHighThreshold", dbus::MessageItem::Int16(val)).default_get());
+            }
+            if let Some(val) = rssi.high_timeout {
+                iface = iface.add_p(f.property::<u16, _>("RSSIHighTimeout", dbus::MessageItem::UInt16(val)).default_get());
+            }
+            if let Some(val) = rssi.low_threshold {
+                iface = iface.add_p(f.property::<i16, _>("RSSILowThreshold", dbus::MessageItem::Int16(val)).default_get());
+            }
+            if let Some(val) = rssi.low_timeout {
+                iface = iface.add_p(f.property::<u16, _>("RSSILowTimeout", dbus::MessageItem::UInt16(val)).default_get());
+            }
+            if let Some(val) = rssi.sampling_period {
+                iface = iface.add_p(f.property::<u16, _>("RSSISamplingPeriod", dbus::MessageItem::UInt16(val)).default_get());
+            }
+
+            iface = iface
+                .add_m(
+                    f.method("DeviceFound", Some(conn.clone()), move |m| {
+                        let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
+                        let monitor = (m.path.get_data() as &Option<SharedMonitorT>).as_ref().unwrap();
+
+                        let device_obj_path: dbus::Path = m.msg.get1().unwrap();
+                        devices_found.set(devices_found.get().wrapping_add(1));
+                        monitor.device_found(Device::new(conn, &device_obj_path));
+
+                        Ok(vec![m.msg.method_return()])
+                    }).in_arg(("device", "o"))
+                )
+                .add_m(
+                    f.method("DeviceLost", Some(conn.clone()), move |m| {
+                        let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
+                        let monitor = (m.path.get_data() as &Option<SharedMonitorT>).as_ref().unwrap();
+
+                        let device_obj_path: dbus::Path = m.msg.get1().unwrap();
+                        monitor.device_lost(Device::new(conn, &device_obj_path));
+
+                        Ok(vec![m.msg.method_return()])
+                    }).in_arg(("device", "o"))
+                )
+                .add_m(
+                    f.method("Activate", None, move |m| {
+                        let monitor = (m.path.get_data() as &Option<SharedMonitorT>).as_ref().unwrap();
+                        monitor.activate();
+                        Ok(vec![m.msg.method_return()])
+                    })
+                )
+                .add_m(
+                    f.method("Release", None, move |m| {
+                        let monitor = (m.path.get_data() as &Option<SharedMonitorT>).as_ref().unwrap();
+                        monitor.release();
+                        Ok(vec![m.msg.method_return()])
+                    })
+                );
+
+            let obj_path = format!("{}/monitor{}", ADV_MONITOR_APP_OBJ_PATH, i);
+            tree = tree.add(f.object_path(obj_path.clone(), Some(monitor)).introspectable().add(iface));
+            object_paths.push(obj_path);
+        }
+
+        let adapter_object_path = adapter.object_path().to_string();
+        let registration = Registration::new(conn, object_paths, Rc::new(move |msg| tree.handle(msg)), move |conn| {
+            let app_obj_path = dbus::Path::new(ADV_MONITOR_APP_OBJ_PATH).unwrap();
+            common::dbus_start_call(conn, &adapter_object_path, ADV_MONITOR_MANAGER_INTERFACE, "UnregisterMonitor",
+                                    &[dbus::MessageItem::ObjectPath(app_obj_path)], Duration::from_secs(60), |_| Ok(()))
+        });
+
+        AdvertisementMonitorManager {
+            conn: conn.clone(),
+            adapter_object_path: adapter.object_path().to_string(),
+            registration: registration,
+            devices_found: devices_found,
+        }
+    }
+
+    pub fn register_monitors(&self) -> Result<(), BtError> {
+        let app_obj_path = dbus::Path::new(ADV_MONITOR_APP_OBJ_PATH).unwrap();
+
+        self.registration.register(|| {
+            common::dbus_call_method1(&self.conn, &self.adapter_object_path, ADV_MONITOR_MANAGER_INTERFACE, "RegisterMonitor", app_obj_path)
+        })
+    }
+
+    pub(crate) fn devices_found(&self) -> u32 {
+        self.devices_found.get()
+    }
+
+    pub fn handle_message(&self, msg: &dbus::Message) -> bool {
+        self.registration.handle_message(msg)
+    }
+
+    // Also serves the other objects registered on the connection
+    pub fn serve(&self, cb: Option<&Fn() -> bool>) {
+        self.conn.registry().serve(cb)
+    }
+
+    pub fn unregister_monitors(&self) -> Result<(), BtError> {
+        self.registration.unregister()
+    }
+
+    pub(crate) fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    pub fn get_supported_monitor_types(&self) -> Result<Vec<String>, BtError> {
+        let p = try!(common::dbus_get_property(&self.conn, &self.adapter_object_path, ADV_MONITOR_MANAGER_INTERFACE, "SupportedMonitorTypes"));
+        let p: &[dbus::MessageItem] = try!(p.inner().map_err(|_| BtError::DBusInternal("invalid SupportedMonitorTypes value".to_string())));
+        Ok(p.iter().filter_map(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string()).collect())
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use dbus;
+
+use common;
+use device::Device;
+use error::BtError;
+
+pub static NETWORK_INTERFACE: &'static str = "org.bluez.Network1";
+pub static NETWORK_SERVER_INTERFACE: &'static str = "org.bluez.NetworkServer1";
+
+#[derive(Clone, Debug)]
+pub struct NetworkProperties {
+    pub connected: bool,
+    // The network interface (e.g. "bnep0"), only set while connected
+    pub interface: Option<String>,
+    pub uuid: Option<String>,
+}
+
+impl NetworkProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> NetworkProperties {
+        NetworkProperties {
+            connected: props_map.get("Connected").and_then(|x| x.inner().ok()).unwrap_or(false),
+            interface: props_map.get("Interface").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+            uuid: props_map.get("UUID").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+        }
+    }
+}
+
+// The PAN client (PANU) side of a device offering NAP or GN
+#[derive(Clone, Debug)]
+pub struct Network {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl Network {
+    pub fn new(device: &Device) -> Network {
+        Network { conn: device.conn().clone(), object_path: device.object_path().to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<NetworkProperties, BtError> {
+        let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, NETWORK_INTERFACE, 1000);
+        Ok(NetworkProperties::new(try!(p.get_all())))
+    }
+
+    // The role to connect to, either a UUID (uuid::NAP_UUID, uuid::GN_UUID) or "nap"/"gn".
+    // Returns the network interface name, which still has to be configured (e.g. with DHCP).
+    pub fn connect(&self, uuid: &str) -> Result<String, BtError> {
+        let reply = try!(common::dbus_call_method1_with_reply(&self.conn, &self.object_path, NETWORK_INTERFACE, "Connect", uuid));
+        let interface: &str = try!(reply.get1().ok_or_else(|| BtError::DBusInternal("invalid Connect reply".to_string())));
+        Ok(interface.to_string())
+    }
+
+    pub fn disconnect(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, NETWORK_INTERFACE, "Disconnect")
+    }
+}
+
+// Serving NAP/GN/PANU on the adapter, connected devices are added to the bridge.
+// BlueZ drops the registration when the registering connection goes away.
+pub fn register_server(conn: &super::Connection, adapter_object_path: &str, uuid: &str, bridge: &str) -> Result<(), BtError> {
+    common::dbus_call_method2(conn, adapter_object_path, NETWORK_SERVER_INTERFACE, "Register", uuid, bridge)
+}
+
+pub fn unregister_server(conn: &super::Connection, adapter_object_path: &str, uuid: &str) -> Result<(), BtError> {
+    common::dbus_call_method1(conn, adapter_object_path, NETWORK_SERVER_INTERFACE, "Unregister", uuid)
+}
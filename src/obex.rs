@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use dbus;
+
+use common;
+use error::BtError;
+use obex_ftp::FileTransfer;
+use obex_map::MessageAccess;
+use obex_pbap::PhonebookAccess;
+use obex_push::ObjectPush;
+use obex_sync::Synchronization;
+use pending::PendingCall;
+use registry::SignalWatch;
+
+pub static OBEX_SERVICE_NAME: &'static str = "org.bluez.obex";
+pub static OBEX_CLIENT_OBJ_PATH: &'static str = "/org/bluez/obex";
+pub static OBEX_CLIENT_INTERFACE: &'static str = "org.bluez.obex.Client1";
+pub static OBEX_SESSION_INTERFACE: &'static str = "org.bluez.obex.Session1";
+pub static OBEX_TRANSFER_INTERFACE: &'static str = "org.bluez.obex.Transfer1";
+
+// The service a session is connected to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObexTarget {
+    Opp,
+    Ftp,
+    Pbap,
+    Map,
+    Sync,
+}
+
+impl ObexTarget {
+    fn to_str(self) -> &'static str {
+        match self {
+            ObexTarget::Opp => "opp",
+            ObexTarget::Ftp => "ftp",
+            ObexTarget::Pbap => "pbap",
+            ObexTarget::Map => "map",
+            ObexTarget::Sync => "sync",
+        }
+    }
+}
+
+// The CreateSession options besides the target, unset ones are left to obexd
+#[derive(Clone, Debug, Default)]
+pub struct SessionOptions {
+    // Address of the local adapter
+    pub source: Option<String>,
+    pub channel: Option<u8>,
+    pub psm: Option<u16>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ObexSessionProperties {
+    pub source: Option<String>,
+    pub destination: Option<String>,
+    pub channel: Option<u8>,
+    pub psm: Option<u16>,
+    // UUID of the target service
+    pub target: Option<String>,
+    pub root: Option<String>,
+}
+
+impl ObexSessionProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> ObexSessionProperties {
+        fn _get_str(props_map: &BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<String> {
+            props_map.get(name).and_then(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string())
+        }
+
+        ObexSessionProperties {
+            source: _get_str(&props_map, "Source"),
+            destination: _get_str(&props_map, "Destination"),
+            channel: props_map.get("Channel").and_then(|x| x.inner().ok()),
+            psm: props_map.get("PSM").and_then(|x| x.inner().ok()),
+            target: _get_str(&props_map, "Target"),
+            root: _get_str(&props_map, "Root"),
+        }
+    }
+}
+
+// The OBEX client of obexd, which runs on the session bus
+#[derive(Clone, Debug)]
+pub struct ObexClient {
+    conn: super::Connection,
+}
+
+impl ObexClient {
+    pub fn new() -> Result<ObexClient, BtError> {
+        Ok(ObexClient { conn: try!(super::Connection::session()) })
+    }
+
+    // conn has to be a session bus connection (see Connection::session())
+    pub fn with_conn(conn: &super::Connection) -> ObexClient {
+        ObexClient { conn: conn.clone() }
+    }
+
+    pub fn conn(&self) -> &super::Connection {
+        &self.conn
+    }
+
+    // destination is the address of the remote device
+    pub fn create_session(&self, destination: &str, target: ObexTarget) -> Result<ObexSession, BtError> {
+        self.create_session_with_options(destination, target, &SessionOptions::default())
+    }
+
+    pub fn create_session_with_options(&self, destination: &str, target: ObexTarget, options: &SessionOptions) -> Result<ObexSession, BtError> {
+        let mut entries = vec![common::dbus_dict_entry("Target", target.to_str())];
+        if let Some(ref source) = options.source {
+            entries.push(common::dbus_dict_entry("Source", source.as_str()));
+        }
+        if let Some(channel) = options.channel {
+            entries.push(common::dbus_dict_entry("Channel", channel));
+        }
+        if let Some(psm) = options.psm {
+            entries.push(common::dbus_dict_entry("PSM", psm));
+        }
+
+        let reply = try!(obex_call(&self.conn, OBEX_CLIENT_OBJ_PATH, OBEX_CLIENT_INTERFACE, "CreateSession",
+                                   &[destination.into(), dbus::MessageItem::Array(entries, "{sv}".into())]));
+        let session_obj_path: dbus::Path = try!(reply.get1().ok_or_else(|| BtError::DBusInternal("invalid CreateSession reply".to_string())));
+        Ok(ObexSession::new(&self.conn, &session_obj_path, target))
+    }
+
+    pub fn remove_session(&self, session: ObexSession) -> Result<(), BtError> {
+        let session_obj_path = dbus::Path::new(session.object_path()).unwrap();
+        try!(obex_call(&self.conn, OBEX_CLIENT_OBJ_PATH, OBEX_CLIENT_INTERFACE, "RemoveSession", &[dbus::MessageItem::ObjectPath(session_obj_path)]));
+        Ok(())
+    }
+}
+
+// The session stays open until it's removed or the connection which created it goes away
+#[derive(Clone, Debug)]
+pub struct ObexSession {
+    conn: super::Connection,
+    object_path: String,
+    target: ObexTarget,
+}
+
+impl ObexSession {
+    pub fn new(conn: &super::Connection, object_path: &str, target: ObexTarget) -> ObexSession {
+        ObexSession { conn: conn.clone(), object_path: object_path.to_string(), target: target }
+    }
+
+    pub fn conn(&self) -> &super::Connection {
+        &self.conn
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn target(&self) -> ObexTarget {
+        self.target
+    }
+
+    pub fn get_properties(&self) -> Result<ObexSessionProperties, BtError> {
+        Ok(ObexSessionProperties::new(try!(obex_get_properties(&self.conn, &self.object_path, OBEX_SESSION_INTERFACE))))
+    }
+
+    // The capabilities object of the remote device (XML)
+    pub fn get_capabilities(&self) -> Result<String, BtError> {
+        let reply = try!(obex_call(&self.conn, &self.object_path, OBEX_SESSION_INTERFACE, "GetCapabilities", &[]));
+        reply.get1::<String>().ok_or_else(|| BtError::DBusInternal("invalid GetCapabilities reply".to_string()))
+    }
+
+    // Only usable on an ObexTarget::Ftp session
+    pub fn file_transfer(&self) -> FileTransfer {
+        FileTransfer::new(self)
+    }
+
+    // Only usable on an ObexTarget::Map session
+    pub fn message_access(&self) -> MessageAccess {
+        MessageAccess::new(self)
+    }
+
+    // Only usable on an ObexTarget::Opp session
+    pub fn object_push(&self) -> ObjectPush {
+        ObjectPush::new(self)
+    }
+
+    // Only usable on an ObexTarget::Pbap session
+    pub fn phonebook_access(&self) -> PhonebookAccess {
+        PhonebookAccess::new(self)
+    }
+
+    // Only usable on an ObexTarget::Sync session
+    pub fn synchronization(&self) -> Synchronization {
+        Synchronization::new(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferStatus {
+    Queued,
+    Active,
+    Suspended,
+    Complete,
+    Error,
+}
+
+impl TransferStatus {
+    fn from_str(s: &str) -> Option<TransferStatus> {
+        match s {
+            "queued" => Some(TransferStatus::Queued),
+            "active" => Some(TransferStatus::Active),
+            "suspended" => Some(TransferStatus::Suspended),
+            "complete" => Some(TransferStatus::Complete),
+            "error" => Some(TransferStatus::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ObexTransferProperties {
+    pub status: Option<TransferStatus>,
+    pub name: Option<String>,
+    pub mime_type: Option<String>,
+    pub size: Option<u64>,
+    pub transferred: Option<u64>,
+    // The local file, if the transfer isn't kept in memory
+    pub filename: Option<String>,
+    pub session: Option<String>,
+}
+
+impl ObexTransferProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> ObexTransferProperties {
+        fn _get_str(props_map: &BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<String> {
+            props_map.get(name).and_then(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string())
+        }
+
+        ObexTransferProperties {
+            status: props_map.get("Status").and_then(|x| x.inner().ok()).and_then(TransferStatus::from_str),
+            name: _get_str(&props_map, "Name"),
+            mime_type: _get_str(&props_map, "Type"),
+            size: props_map.get("Size").and_then(|x| x.inner().ok()),
+            transferred: props_map.get("Transferred").and_then(|x| x.inner().ok()),
+            filename: _get_str(&props_map, "Filename"),
+            session: props_map.get("Session").and_then(|x| match *x {
+                dbus::MessageItem::ObjectPath(ref path) => Some(path.to_string()),
+                _ => None,
+            }),
+        }
+    }
+}
+
+// A queued or running transfer. obexd removes it once it's complete or failed.
+#[derive(Clone, Debug)]
+pub struct ObexTransfer {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl ObexTransfer {
+    pub fn new(conn: &super::Connection, object_path: &str) -> ObexTransfer {
+        ObexTransfer { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<ObexTransferProperties, BtError> {
+        Ok(ObexTransferProperties::new(try!(obex_get_properties(&self.conn, &self.object_path, OBEX_TRANSFER_INTERFACE))))
+    }
+
+    pub fn get_status(&self) -> Result<TransferStatus, BtError> {
+        let p = dbus::Props::new(&self.conn, OBEX_SERVICE_NAME, &self.object_path, OBEX_TRANSFER_INTERFACE, 1000);
+        let val = try!(p.get("Status"));
+        val.inner().ok().and_then(TransferStatus::from_str).ok_or_else(|| BtError::DBusInternal("invalid Status value".to_string()))
+    }
+
+    // Aborts the transfer, which then ends with TransferStatus::Error
+    pub fn cancel(&self) -> Result<(), BtError> {
+        try!(obex_call(&self.conn, &self.object_path, OBEX_TRANSFER_INTERFACE, "Cancel", &[]));
+        Ok(())
+    }
+
+    // Only a queued or active transfer can be suspended
+    pub fn suspend(&self) -> Result<(), BtError> {
+        try!(obex_call(&self.conn, &self.object_path, OBEX_TRANSFER_INTERFACE, "Suspend", &[]));
+        Ok(())
+    }
+
+    pub fn resume(&self) -> Result<(), BtError> {
+        try!(obex_call(&self.conn, &self.object_path, OBEX_TRANSFER_INTERFACE, "Resume", &[]));
+        Ok(())
+    }
+
+    // Blocks until the transfer is complete or failed, calling progress with the transferred
+    // bytes and the total size (if known) on every update
+    pub fn wait<F>(&self, mut progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        let watch = try!(SignalWatch::new(&self.conn, &[transfer_match_rule(&self.object_path)]));
+
+        obex_get_properties(&self.conn, &self.object_path, OBEX_TRANSFER_INTERFACE)
+            .and_then(|props_map| follow_transfer(&watch, self, props_map, &mut progress, &mut |_, _| Ok(())))
+    }
+}
+
+fn transfer_match_rule(path_namespace: &str) -> String {
+    format!("type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',path_namespace='{}'",
+            OBEX_SERVICE_NAME, path_namespace)
+}
+
+// Takes the (transfer, properties) reply of the methods which start a transfer
+fn transfer_from_reply(conn: &super::Connection, reply: &dbus::Message) -> Result<(ObexTransfer, BTreeMap<String, dbus::MessageItem>), BtError> {
+    let items = reply.get_items();
+    match (items.first(), items.get(1)) {
+        (Some(dbus::MessageItem::ObjectPath(path)), Some(dbus::MessageItem::Array(props, _))) => Ok((ObexTransfer::new(conn, path), common::dbus_props_to_map(props))),
+        _ => Err(BtError::DBusInternal("invalid transfer reply".to_string())),
+    }
+}
+
+// Follows the transfer from its last known properties until it's done. The PropertiesChanged
+// watch has to be made before, so that no update is missed. control is called with the current
+// status at least every 100 ms, an error ends the follow with it.
+fn follow_transfer<F, C>(watch: &SignalWatch, transfer: &ObexTransfer, props_map: BTreeMap<String, dbus::MessageItem>, progress: &mut F,
+                         control: &mut C) -> Result<(), BtError>
+    where F: FnMut(u64, Option<u64>), C: FnMut(&ObexTransfer, TransferStatus) -> Result<(), BtError> {
+    let object_path = transfer.object_path();
+    let mut status = props_map.get("Status").and_then(|x| x.inner().ok()).and_then(TransferStatus::from_str).unwrap_or(TransferStatus::Queued);
+    let mut size: Option<u64> = props_map.get("Size").and_then(|x| x.inner().ok());
+    let mut transferred: u64 = props_map.get("Transferred").and_then(|x| x.inner().ok()).unwrap_or(0);
+
+    while watch.conn().registry().is_connected() {
+        try!(control(transfer, status));
+
+        match status {
+            TransferStatus::Complete => {
+                progress(size.unwrap_or(transferred), size);
+                return Ok(());
+            }
+            TransferStatus::Error => return Err(BtError::DBusInternal(format!("OBEX transfer {} failed", object_path))),
+            _ => {}
+        }
+
+        let s = match watch.next_signal(100) {
+            Some(s) => s,
+            None => continue,
+        };
+        if s.path().map(|x| *x != *object_path).unwrap_or(true) {
+            continue;
+        }
+
+        if let Some(changed) = common::dbus_properties_changed(&s, OBEX_TRANSFER_INTERFACE) {
+            if let Some(val) = changed.get("Status").and_then(|x| x.inner().ok()).and_then(TransferStatus::from_str) {
+                status = val;
+            }
+            if let Some(val) = changed.get("Size").and_then(|x| x.inner().ok()) {
+                size = Some(val);
+            }
+            if let Some(val) = changed.get("Transferred").and_then(|x| x.inner().ok()) {
+                transferred = val;
+                progress(transferred, size);
+            }
+        }
+    }
+
+    Err(BtError::DBusInternal("connection closed".to_string()))
+}
+
+// Calls a method of the session which starts a transfer
+pub(crate) fn start_transfer(session: &ObexSession, interface: &str, method_name: &str, method_args: &[dbus::MessageItem]) -> Result<ObexTransfer, BtError> {
+    let reply = try!(obex_call(session.conn(), session.object_path(), interface, method_name, method_args));
+    Ok(try!(transfer_from_reply(session.conn(), &reply)).0)
+}
+
+// Same as start_transfer, but blocks until the transfer is done (see ObexTransfer::wait())
+pub(crate) fn run_transfer<F>(session: &ObexSession, interface: &str, method_name: &str, method_args: &[dbus::MessageItem], progress: F) -> Result<(), BtError>
+    where F: FnMut(u64, Option<u64>) {
+    run_transfer_with_control(session, interface, method_name, method_args, progress, |_, _| Ok(()))
+}
+
+// Same as run_transfer, control gets the transfer and its status at least every 100 ms while it
+// runs (e.g. to suspend it), an error ends the wait with it
+pub(crate) fn run_transfer_with_control<F, C>(session: &ObexSession, interface: &str, method_name: &str, method_args: &[dbus::MessageItem],
+                                              mut progress: F, mut control: C) -> Result<(), BtError>
+    where F: FnMut(u64, Option<u64>), C: FnMut(&ObexTransfer, TransferStatus) -> Result<(), BtError> {
+    // Transfers are created below the session, so they're matched before they exist
+    let watch = try!(SignalWatch::new(session.conn(), &[transfer_match_rule(session.object_path())]));
+
+    obex_call(session.conn(), session.object_path(), interface, method_name, method_args)
+        .and_then(|reply| transfer_from_reply(session.conn(), &reply))
+        .and_then(|(transfer, props_map)| follow_transfer(&watch, &transfer, props_map, &mut progress, &mut control))
+}
+
+// Same as the common helpers, but addressed to obexd
+pub(crate) fn obex_call(conn: &super::Connection,
+                        object_path: &str,
+                        interface: &str,
+                        method_name: &str,
+                        method_args: &[dbus::MessageItem]) -> Result<dbus::Message, BtError> {
+    let mut m = try!(
+        dbus::Message::new_method_call(OBEX_SERVICE_NAME, object_path, interface, method_name)
+            .map_err(BtError::DBusInternal)
+    );
+    m.append_items(method_args);
+    Ok(try!(conn.send_with_reply_and_block(m, 60000)))
+}
+
+// Same as obex_call, but returns right away
+pub(crate) fn obex_start_call(conn: &super::Connection,
+                              object_path: &str,
+                              interface: &str,
+                              method_name: &str,
+                              method_args: &[dbus::MessageItem]) -> PendingCall<()> {
+    let mut m = match dbus::Message::new_method_call(OBEX_SERVICE_NAME, object_path, interface, method_name) {
+        Ok(m) => m,
+        Err(err) => return PendingCall::failed(conn, BtError::DBusInternal(err)),
+    };
+    m.append_items(method_args);
+    PendingCall::send(conn, m, Duration::from_secs(60), |_| Ok(()))
+}
+
+pub(crate) fn obex_get_properties(conn: &super::Connection,
+                                  object_path: &str,
+                                  interface: &str) -> Result<BTreeMap<String, dbus::MessageItem>, BtError> {
+    let p = dbus::Props::new(conn, OBEX_SERVICE_NAME, object_path, interface, 1000);
+    Ok(try!(p.get_all()))
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::rc::Rc;
+
+use dbus;
+
+use agent::AgentError;
+use error::BtError;
+use registry::Registration;
+use obex::{self, ObexClient, ObexTransfer, OBEX_CLIENT_OBJ_PATH};
+
+pub static OBEX_AGENT_INTERFACE: &'static str = "org.bluez.obex.Agent1";
+pub static OBEX_AGENT_MANAGER_INTERFACE: &'static str = "org.bluez.obex.AgentManager1";
+
+// Decides about the files pushed to obexd's OPP server. Any error rejects the push.
+pub trait ObexAgent {
+    fn get_object_path(&self) -> &str {
+        "/io/bluezrs/obex_agent"
+    }
+
+    // Returns where to save the file, either a full path or a name relative to obexd's root
+    // folder. The transfer's properties tell the name and size of the file.
+    fn authorize_push(&self, transfer: ObexTransfer) -> Result<String, AgentError>;
+    fn cancel(&self);
+    fn release(&self);
+}
+
+impl fmt::Debug for ObexAgent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "ObexAgent(object_path: \"{}\")", self.get_object_path())
+    }
+}
+
+type SharedObexAgentT = Rc<Box<ObexAgent>>;
+
+#[derive(Copy, Clone, Default, Debug)]
+struct TData;
+impl dbus::tree::DataType for TData {
+    type Tree = ();
+    type ObjectPath = SharedObexAgentT;
+    type Property = ();
+    type Interface = ();
+    type Method = Option<super::Connection>;
+    type Signal = ();
+}
+
+// Only one agent can be registered with obexd at a time
+pub struct ObexAgentManager {
+    conn: super::Connection,
+    agent: SharedObexAgentT,
+    registration: Registration,
+}
+
+impl ObexAgentManager {
+    pub fn new(client: &ObexClient, agent: Box<ObexAgent>) -> ObexAgentManager {
+        let conn = client.conn();
+        let agent = Rc::new(agent);
+
+        let f = dbus::tree::Factory::new_fn::<TData>();
+
+        let tree = f.tree(()).add(
+            f.object_path(agent.get_object_path().to_string(), agent.clone()).introspectable().add(
+                f.interface(OBEX_AGENT_INTERFACE, ())
+                    .add_m(
+                        f.method("AuthorizePush", Some(conn.clone()), move |m| {
+                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
+                            let agent: &SharedObexAgentT = m.path.get_data();
+
+                            let transfer_obj_path: dbus::Path = try!(m.msg.get1().ok_or_else(dbus::tree::MethodErr::no_arg));
+                            match agent.authorize_push(ObexTransfer::new(conn, &transfer_obj_path)) {
+                                Ok(filename) => Ok(vec![m.msg.method_return().append1(filename)]),
+                                Err(e) => Err(e.method_err()),
+                            }
+                        }).in_arg(("transfer", "o")).out_arg("s")
+                    )
+                    .add_m(
+                        f.method("Cancel", None, move |m| {
+                            let agent: &SharedObexAgentT = m.path.get_data();
+                            agent.cancel();
+                            Ok(vec![m.msg.method_return()])
+                        })
+                    )
+                    .add_m(
+                        f.method("Release", None, move |m| {
+                            let agent: &SharedObexAgentT = m.path.get_data();
+                            agent.release();
+                            Ok(vec![m.msg.method_return()])
+                        })
+                    )
+        ));
+
+        let object_path = agent.get_object_path().to_string();
+        let registration = Registration::new(conn, vec![object_path.clone()], Rc::new(move |msg| tree.handle(msg)), move |conn| {
+            let agent_obj_path = dbus::Path::new(object_path.clone()).unwrap();
+            obex::obex_start_call(conn, OBEX_CLIENT_OBJ_PATH, OBEX_AGENT_MANAGER_INTERFACE, "UnregisterAgent", &[dbus::MessageItem::ObjectPath(agent_obj_path)])
+        });
+
+        ObexAgentManager { conn: conn.clone(), agent: agent, registration: registration }
+    }
+
+    pub fn register_agent(&self) -> Result<(), BtError> {
+        let agent_obj_path = dbus::Path::new(self.agent.get_object_path()).unwrap();
+
+        self.registration.register(|| {
+            obex::obex_call(&self.conn, OBEX_CLIENT_OBJ_PATH, OBEX_AGENT_MANAGER_INTERFACE, "RegisterAgent", &[dbus::MessageItem::ObjectPath(agent_obj_path)]).map(|_| ())
+        })
+    }
+
+    pub fn unregister_agent(&self) -> Result<(), BtError> {
+        self.registration.unregister()
+    }
+
+    pub(crate) fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    pub fn handle_message(&self, msg: &dbus::Message) -> bool {
+        self.registration.handle_message(msg)
+    }
+
+    // Also serves the other objects registered on the connection
+    pub fn serve(&self, cb: Option<&Fn() -> bool>) {
+        self.conn.registry().serve(cb)
+    }
+}
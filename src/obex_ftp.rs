@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use dbus;
+
+use common;
+use error::BtError;
+use obex::{self, ObexSession, ObexTransfer};
+
+pub static OBEX_FILE_TRANSFER_INTERFACE: &'static str = "org.bluez.obex.FileTransfer1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FolderEntryType {
+    Folder,
+    File,
+}
+
+impl FolderEntryType {
+    fn from_str(s: &str) -> Option<FolderEntryType> {
+        match s {
+            "folder" => Some(FolderEntryType::Folder),
+            "file" => Some(FolderEntryType::File),
+            _ => None,
+        }
+    }
+}
+
+// Timestamps are in the OBEX format (e.g. "20180101T120000Z"), permissions as in the folder listing ("RWD")
+#[derive(Clone, Debug)]
+pub struct FolderEntry {
+    pub name: String,
+    pub entry_type: Option<FolderEntryType>,
+    pub size: Option<u64>,
+    pub permission: Option<String>,
+    pub modified: Option<String>,
+    pub accessed: Option<String>,
+    pub created: Option<String>,
+}
+
+impl FolderEntry {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> FolderEntry {
+        fn _get_str(props_map: &BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<String> {
+            props_map.get(name).and_then(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string())
+        }
+
+        FolderEntry {
+            name: _get_str(&props_map, "Name").unwrap_or_default(),
+            entry_type: props_map.get("Type").and_then(|x| x.inner().ok()).and_then(FolderEntryType::from_str),
+            size: props_map.get("Size").and_then(|x| x.inner().ok()),
+            permission: _get_str(&props_map, "Permission").or_else(|| _get_str(&props_map, "User-perm")),
+            modified: _get_str(&props_map, "Modified"),
+            accessed: _get_str(&props_map, "Accessed"),
+            created: _get_str(&props_map, "Created"),
+        }
+    }
+}
+
+// FTP on an ObexTarget::Ftp session. Remote paths are relative to the current folder, local ones are file paths.
+#[derive(Clone, Debug)]
+pub struct FileTransfer {
+    session: ObexSession,
+}
+
+impl FileTransfer {
+    pub fn new(session: &ObexSession) -> FileTransfer {
+        FileTransfer { session: session.clone() }
+    }
+
+    pub fn session(&self) -> &ObexSession {
+        &self.session
+    }
+
+    // "" goes to the root folder and ".." to the parent
+    pub fn change_folder(&self, folder: &str) -> Result<(), BtError> {
+        self.call("ChangeFolder", &[folder.into()])
+    }
+
+    // Also changes into the new folder
+    pub fn create_folder(&self, folder: &str) -> Result<(), BtError> {
+        self.call("CreateFolder", &[folder.into()])
+    }
+
+    pub fn list_folder(&self) -> Result<Vec<FolderEntry>, BtError> {
+        let reply = try!(obex::obex_call(self.session.conn(), self.session.object_path(), OBEX_FILE_TRANSFER_INTERFACE, "ListFolder", &[]));
+        let items = reply.get_items();
+        let entries = match items.first() {
+            Some(dbus::MessageItem::Array(entries, _)) => entries,
+            _ => return Err(BtError::DBusInternal("invalid ListFolder reply".to_string())),
+        };
+
+        Ok(entries.iter()
+            .filter_map(|x| (x.inner() as Result<&[dbus::MessageItem], ()>).ok())
+            .map(|x| FolderEntry::new(common::dbus_props_to_map(x)))
+            .collect())
+    }
+
+    pub fn get_file(&self, target_file: &str, source_file: &str) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_FILE_TRANSFER_INTERFACE, "GetFile", &[target_file.into(), source_file.into()])
+    }
+
+    pub fn get_file_and_wait<F>(&self, target_file: &str, source_file: &str, progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_FILE_TRANSFER_INTERFACE, "GetFile", &[target_file.into(), source_file.into()], progress)
+    }
+
+    pub fn put_file(&self, source_file: &str, target_file: &str) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_FILE_TRANSFER_INTERFACE, "PutFile", &[source_file.into(), target_file.into()])
+    }
+
+    pub fn put_file_and_wait<F>(&self, source_file: &str, target_file: &str, progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_FILE_TRANSFER_INTERFACE, "PutFile", &[source_file.into(), target_file.into()], progress)
+    }
+
+    // Copies and moves happen on the remote device
+    pub fn copy_file(&self, source_file: &str, target_file: &str) -> Result<(), BtError> {
+        self.call("CopyFile", &[source_file.into(), target_file.into()])
+    }
+
+    pub fn move_file(&self, source_file: &str, target_file: &str) -> Result<(), BtError> {
+        self.call("MoveFile", &[source_file.into(), target_file.into()])
+    }
+
+    pub fn delete(&self, file: &str) -> Result<(), BtError> {
+        self.call("Delete", &[file.into()])
+    }
+
+    fn call(&self, method_name: &str, method_args: &[dbus::MessageItem]) -> Result<(), BtError> {
+        try!(obex::obex_call(self.session.conn(), self.session.object_path(), OBEX_FILE_TRANSFER_INTERFACE, method_name, method_args));
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dbus;
+
+use common;
+use error::BtError;
+use obex::{self, ObexSession, ObexTransfer};
+
+pub static OBEX_MESSAGE_ACCESS_INTERFACE: &'static str = "org.bluez.obex.MessageAccess1";
+pub static OBEX_MESSAGE_INTERFACE: &'static str = "org.bluez.obex.Message1";
+
+// Names the temporary files of push_bmessage_and_wait()
+static NEXT_BMESSAGE_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Email,
+    SmsGsm,
+    SmsCdma,
+    Mms,
+}
+
+impl MessageType {
+    fn from_str(s: &str) -> Option<MessageType> {
+        match s {
+            "email" => Some(MessageType::Email),
+            "sms-gsm" => Some(MessageType::SmsGsm),
+            "sms-cdma" => Some(MessageType::SmsCdma),
+            "mms" => Some(MessageType::Mms),
+            _ => None,
+        }
+    }
+
+    fn to_bmessage_str(self) -> &'static str {
+        match self {
+            MessageType::Email => "EMAIL",
+            MessageType::SmsGsm => "SMS_GSM",
+            MessageType::SmsCdma => "SMS_CDMA",
+            MessageType::Mms => "MMS",
+        }
+    }
+}
+
+// Unset filters are left to the phone. Types are "sms", "email" and "mms", periods are
+// timestamps like "20180101T120000".
+#[derive(Clone, Debug, Default)]
+pub struct MessageFilter {
+    pub offset: Option<u16>,
+    pub max_count: Option<u16>,
+    pub subject_length: Option<u8>,
+    pub fields: Vec<String>,
+    pub types: Vec<String>,
+    pub period_begin: Option<String>,
+    pub period_end: Option<String>,
+    pub read: Option<bool>,
+    pub recipient: Option<String>,
+    pub sender: Option<String>,
+    pub priority: Option<bool>,
+}
+
+impl MessageFilter {
+    fn to_message_item(&self) -> dbus::MessageItem {
+        fn _strings(vals: &[String]) -> dbus::MessageItem {
+            dbus::MessageItem::Array(vals.iter().map(|x| x.as_str().into()).collect(), "s".into())
+        }
+
+        let mut entries = Vec::new();
+        if let Some(offset) = self.offset {
+            entries.push(common::dbus_dict_entry("Offset", offset));
+        }
+        if let Some(max_count) = self.max_count {
+            entries.push(common::dbus_dict_entry("MaxCount", max_count));
+        }
+        if let Some(subject_length) = self.subject_length {
+            entries.push(common::dbus_dict_entry("SubjectLength", subject_length));
+        }
+        if !self.fields.is_empty() {
+            entries.push(common::dbus_dict_entry("Fields", _strings(&self.fields)));
+        }
+        if !self.types.is_empty() {
+            entries.push(common::dbus_dict_entry("Types", _strings(&self.types)));
+        }
+        if let Some(ref period_begin) = self.period_begin {
+            entries.push(common::dbus_dict_entry("PeriodBegin", period_begin.as_str()));
+        }
+        if let Some(ref period_end) = self.period_end {
+            entries.push(common::dbus_dict_entry("PeriodEnd", period_end.as_str()));
+        }
+        if let Some(read) = self.read {
+            entries.push(common::dbus_dict_entry("Read", read));
+        }
+        if let Some(ref recipient) = self.recipient {
+            entries.push(common::dbus_dict_entry("Recipient", recipient.as_str()));
+        }
+        if let Some(ref sender) = self.sender {
+            entries.push(common::dbus_dict_entry("Sender", sender.as_str()));
+        }
+        if let Some(priority) = self.priority {
+            entries.push(common::dbus_dict_entry("Priority", priority));
+        }
+
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+}
+
+// The PushMessage options, unset ones are left to the phone
+#[derive(Clone, Debug, Default)]
+pub struct PushOptions {
+    // Don't keep a copy in the sent folder
+    pub transparent: Option<bool>,
+    pub retry: Option<bool>,
+    // "utf8" or "native"
+    pub charset: Option<String>,
+}
+
+impl PushOptions {
+    fn to_message_item(&self) -> dbus::MessageItem {
+        let mut entries = Vec::new();
+        if let Some(transparent) = self.transparent {
+            entries.push(common::dbus_dict_entry("Transparent", transparent));
+        }
+        if let Some(retry) = self.retry {
+            entries.push(common::dbus_dict_entry("Retry", retry));
+        }
+        if let Some(ref charset) = self.charset {
+            entries.push(common::dbus_dict_entry("Charset", charset.as_str()));
+        }
+
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+}
+
+// A text message to push, formatted as a bMessage (MAP spec 3.1.3)
+#[derive(Clone, Debug)]
+pub struct BMessage {
+    pub message_type: MessageType,
+    // Phone number or e-mail address
+    pub recipient: String,
+    pub body: String,
+}
+
+impl fmt::Display for BMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tel_or_email = match self.message_type {
+            MessageType::Email => "EMAIL",
+            _ => "TEL",
+        };
+        let msg = format!("BEGIN:MSG\r\n{}\r\nEND:MSG\r\n", self.body);
+
+        try!(write!(f, "BEGIN:BMSG\r\nVERSION:1.0\r\nSTATUS:UNREAD\r\nTYPE:{}\r\nFOLDER:\r\n", self.message_type.to_bmessage_str()));
+        try!(write!(f, "BEGIN:BENV\r\nBEGIN:VCARD\r\nVERSION:2.1\r\nN:\r\n{}:{}\r\nEND:VCARD\r\n", tel_or_email, self.recipient));
+        try!(write!(f, "BEGIN:BBODY\r\nCHARSET:UTF-8\r\nLENGTH:{}\r\n{}END:BBODY\r\n", msg.len(), msg));
+        write!(f, "END:BENV\r\nEND:BMSG\r\n")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MessageProperties {
+    pub folder: Option<String>,
+    pub subject: Option<String>,
+    pub timestamp: Option<String>,
+    pub sender: Option<String>,
+    pub sender_address: Option<String>,
+    pub reply_to: Option<String>,
+    pub recipient: Option<String>,
+    pub recipient_address: Option<String>,
+    pub message_type: Option<MessageType>,
+    pub size: Option<u64>,
+    pub status: Option<String>,
+    pub priority: bool,
+    pub read: bool,
+    pub deleted: bool,
+    pub sent: bool,
+    pub protected: bool,
+}
+
+impl MessageProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> MessageProperties {
+        fn _get_str(props_map: &BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<String> {
+            props_map.get(name).and_then(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string())
+        }
+        fn _get_bool(props_map: &BTreeMap<String, dbus::MessageItem>, name: &str) -> bool {
+            props_map.get(name).and_then(|x| x.inner().ok()).unwrap_or(false)
+        }
+
+        MessageProperties {
+            folder: _get_str(&props_map, "Folder"),
+            subject: _get_str(&props_map, "Subject"),
+            timestamp: _get_str(&props_map, "Timestamp"),
+            sender: _get_str(&props_map, "Sender"),
+            sender_address: _get_str(&props_map, "SenderAddress"),
+            reply_to: _get_str(&props_map, "ReplyTo"),
+            recipient: _get_str(&props_map, "Recipient"),
+            recipient_address: _get_str(&props_map, "RecipientAddress"),
+            message_type: props_map.get("Type").and_then(|x| x.inner().ok()).and_then(MessageType::from_str),
+            size: props_map.get("Size").and_then(|x| x.inner().ok()),
+            status: _get_str(&props_map, "Status"),
+            priority: _get_bool(&props_map, "Priority"),
+            read: _get_bool(&props_map, "Read"),
+            deleted: _get_bool(&props_map, "Deleted"),
+            sent: _get_bool(&props_map, "Sent"),
+            protected: _get_bool(&props_map, "Protected"),
+        }
+    }
+}
+
+// A message of a listing
+#[derive(Clone, Debug)]
+pub struct Message {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl Message {
+    pub fn new(conn: &super::Connection, object_path: &str) -> Message {
+        Message { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<MessageProperties, BtError> {
+        Ok(MessageProperties::new(try!(obex::obex_get_properties(&self.conn, &self.object_path, OBEX_MESSAGE_INTERFACE))))
+    }
+
+    // Saves the message as a bMessage to target_file
+    pub fn get(&self, target_file: &str, attachment: bool) -> Result<ObexTransfer, BtError> {
+        let reply = try!(obex::obex_call(&self.conn, &self.object_path, OBEX_MESSAGE_INTERFACE, "Get", &[target_file.into(), attachment.into()]));
+        let transfer_obj_path: dbus::Path = try!(reply.get1().ok_or_else(|| BtError::DBusInternal("invalid Get reply".to_string())));
+        Ok(ObexTransfer::new(&self.conn, &transfer_obj_path))
+    }
+
+    pub fn set_read(&self, val: bool) -> Result<(), BtError> {
+        self.set_property("Read", val)
+    }
+
+    pub fn set_deleted(&self, val: bool) -> Result<(), BtError> {
+        self.set_property("Deleted", val)
+    }
+
+    fn set_property(&self, name: &str, val: bool) -> Result<(), BtError> {
+        let p = dbus::Props::new(&self.conn, obex::OBEX_SERVICE_NAME, &self.object_path, OBEX_MESSAGE_INTERFACE, 1000);
+        Ok(try!(p.set(name, val.into())))
+    }
+}
+
+// MAP on an ObexTarget::Map session. Folders are relative to the current one (e.g. "telecom/msg/inbox").
+#[derive(Clone, Debug)]
+pub struct MessageAccess {
+    session: ObexSession,
+}
+
+impl MessageAccess {
+    pub fn new(session: &ObexSession) -> MessageAccess {
+        MessageAccess { session: session.clone() }
+    }
+
+    pub fn session(&self) -> &ObexSession {
+        &self.session
+    }
+
+    // "" goes to the root folder and ".." to the parent
+    pub fn set_folder(&self, folder: &str) -> Result<(), BtError> {
+        try!(self.call("SetFolder", &[folder.into()]));
+        Ok(())
+    }
+
+    // The names of the subfolders of the current folder
+    pub fn list_folders(&self, offset: Option<u16>, max_count: Option<u16>) -> Result<Vec<String>, BtError> {
+        let filter = MessageFilter { offset: offset, max_count: max_count, ..MessageFilter::default() };
+        let reply = try!(self.call("ListFolders", &[filter.to_message_item()]));
+        let items = reply.get_items();
+        let folders: &[dbus::MessageItem] = items.first().and_then(|x| x.inner().ok()).unwrap_or(&[]);
+
+        Ok(folders.iter()
+            .filter_map(|x| (x.inner() as Result<&[dbus::MessageItem], ()>).ok())
+            .filter_map(|x| common::dbus_props_to_map(x).get("Name").and_then(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string()))
+            .collect())
+    }
+
+    pub fn list_filter_fields(&self) -> Result<Vec<String>, BtError> {
+        let reply = try!(self.call("ListFilterFields", &[]));
+        let items = reply.get_items();
+        let fields: &[dbus::MessageItem] = items.first().and_then(|x| x.inner().ok()).unwrap_or(&[]);
+        Ok(fields.iter().filter_map(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string()).collect())
+    }
+
+    pub fn list_messages(&self, folder: &str, filter: &MessageFilter) -> Result<Vec<(Message, MessageProperties)>, BtError> {
+        let reply = try!(self.call("ListMessages", &[folder.into(), filter.to_message_item()]));
+        let items = reply.get_items();
+        let messages: &[dbus::MessageItem] = items.first().and_then(|x| x.inner().ok()).unwrap_or(&[]);
+
+        Ok(messages.iter()
+            .filter_map(|x| x.inner().ok())
+            .filter_map(|(path, props): (&dbus::MessageItem, &dbus::MessageItem)| match (path, props) {
+                (dbus::MessageItem::ObjectPath(path), dbus::MessageItem::Array(props, _)) => {
+                    Some((Message::new(self.session.conn(), path), MessageProperties::new(common::dbus_props_to_map(props))))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    // Asks the phone to check for new messages
+    pub fn update_inbox(&self) -> Result<(), BtError> {
+        try!(self.call("UpdateInbox", &[]));
+        Ok(())
+    }
+
+    // source_file has to contain a bMessage (see BMessage), folder is usually "telecom/msg/outbox"
+    pub fn push_message(&self, source_file: &str, folder: &str, options: &PushOptions) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_MESSAGE_ACCESS_INTERFACE, "PushMessage", &[source_file.into(), folder.into(), options.to_message_item()])
+    }
+
+    pub fn push_message_and_wait<F>(&self, source_file: &str, folder: &str, options: &PushOptions, progress: F) -> Result<(), BtError>
+        where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_MESSAGE_ACCESS_INTERFACE, "PushMessage", &[source_file.into(), folder.into(), options.to_message_item()], progress)
+    }
+
+    // Writes the message to a temporary file for obexd and pushes it
+    pub fn push_bmessage_and_wait(&self, message: &BMessage, folder: &str, options: &PushOptions) -> Result<(), BtError> {
+        // The body ends at the first END:MSG, anything after it would be parsed as bMessage properties
+        if message.body.contains("END:MSG") {
+            return Err(BtError::DBusInternal("bMessage body must not contain END:MSG".to_string()));
+        }
+
+        // A new file only readable by us, so an existing file or symlink with the name isn't followed
+        let (path, mut file) = loop {
+            let path = env::temp_dir().join(format!("bluezrs-bmessage-{}-{}", process::id(), NEXT_BMESSAGE_ID.fetch_add(1, Ordering::SeqCst)));
+            match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+                Ok(file) => break (path, file),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(BtError::Io(e)),
+            }
+        };
+        let written = file.write_all(message.to_string().as_bytes());
+        drop(file);
+
+        let result = match written {
+            Ok(_) => self.push_message_and_wait(&path.to_string_lossy(), folder, options, |_, _| {}),
+            Err(e) => Err(BtError::Io(e)),
+        };
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    fn call(&self, method_name: &str, method_args: &[dbus::MessageItem]) -> Result<dbus::Message, BtError> {
+        obex::obex_call(self.session.conn(), self.session.object_path(), OBEX_MESSAGE_ACCESS_INTERFACE, method_name, method_args)
+    }
+}
@@ -0,0 +1,218 @@
+use dbus;
+
+use common;
+use error::BtError;
+use obex::{self, ObexSession, ObexTransfer};
+
+pub static OBEX_PHONEBOOK_ACCESS_INTERFACE: &'static str = "org.bluez.obex.PhonebookAccess1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhonebookLocation {
+    Internal,
+    // Numbered from 1
+    Sim(u8),
+}
+
+impl PhonebookLocation {
+    pub(crate) fn to_name(self) -> String {
+        match self {
+            PhonebookLocation::Internal => "int".to_string(),
+            PhonebookLocation::Sim(n) => format!("sim{}", n),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phonebook {
+    Contacts,
+    IncomingCalls,
+    OutgoingCalls,
+    MissedCalls,
+    CombinedCalls,
+    SpeedDial,
+    Favorites,
+}
+
+impl Phonebook {
+    fn to_str(self) -> &'static str {
+        match self {
+            Phonebook::Contacts => "pb",
+            Phonebook::IncomingCalls => "ich",
+            Phonebook::OutgoingCalls => "och",
+            Phonebook::MissedCalls => "mch",
+            Phonebook::CombinedCalls => "cch",
+            Phonebook::SpeedDial => "spd",
+            Phonebook::Favorites => "fav",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VCardFormat {
+    VCard21,
+    VCard30,
+}
+
+impl VCardFormat {
+    fn to_str(self) -> &'static str {
+        match self {
+            VCardFormat::VCard21 => "vcard21",
+            VCardFormat::VCard30 => "vcard30",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListOrder {
+    Indexed,
+    Alphanumeric,
+    Phonetic,
+}
+
+impl ListOrder {
+    fn to_str(self) -> &'static str {
+        match self {
+            ListOrder::Indexed => "indexed",
+            ListOrder::Alphanumeric => "alphanumeric",
+            ListOrder::Phonetic => "phonetic",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchField {
+    Name,
+    Number,
+    Sound,
+}
+
+impl SearchField {
+    fn to_str(self) -> &'static str {
+        match self {
+            SearchField::Name => "name",
+            SearchField::Number => "number",
+            SearchField::Sound => "sound",
+        }
+    }
+}
+
+// Unset filters are left to the phone. fields are vCard field names (e.g. "N", "TEL"),
+// see PhonebookAccess::list_filter_fields() for the supported ones.
+#[derive(Clone, Debug, Default)]
+pub struct PhonebookFilter {
+    pub format: Option<VCardFormat>,
+    pub order: Option<ListOrder>,
+    pub offset: Option<u16>,
+    pub max_count: Option<u16>,
+    pub fields: Vec<String>,
+}
+
+impl PhonebookFilter {
+    fn to_message_item(&self) -> dbus::MessageItem {
+        let mut entries = Vec::new();
+        if let Some(format) = self.format {
+            entries.push(common::dbus_dict_entry("Format", format.to_str()));
+        }
+        if let Some(order) = self.order {
+            entries.push(common::dbus_dict_entry("Order", order.to_str()));
+        }
+        if let Some(offset) = self.offset {
+            entries.push(common::dbus_dict_entry("Offset", offset));
+        }
+        if let Some(max_count) = self.max_count {
+            entries.push(common::dbus_dict_entry("MaxCount", max_count));
+        }
+        if !self.fields.is_empty() {
+            let fields = self.fields.iter().map(|x| x.as_str().into()).collect();
+            entries.push(common::dbus_dict_entry("Fields", dbus::MessageItem::Array(fields, "s".into())));
+        }
+
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+}
+
+// An entry of a listing, the handle is the vCard name to pull it with (e.g. "1.vcf")
+#[derive(Clone, Debug)]
+pub struct PhonebookEntry {
+    pub handle: String,
+    pub name: String,
+}
+
+// PBAP on an ObexTarget::Pbap session. A phonebook has to be selected first.
+#[derive(Clone, Debug)]
+pub struct PhonebookAccess {
+    session: ObexSession,
+}
+
+impl PhonebookAccess {
+    pub fn new(session: &ObexSession) -> PhonebookAccess {
+        PhonebookAccess { session: session.clone() }
+    }
+
+    pub fn session(&self) -> &ObexSession {
+        &self.session
+    }
+
+    pub fn select(&self, location: PhonebookLocation, phonebook: Phonebook) -> Result<(), BtError> {
+        try!(self.call("Select", &[location.to_name().into(), phonebook.to_str().into()]));
+        Ok(())
+    }
+
+    // Saves the whole phonebook to target_file
+    pub fn pull_all(&self, target_file: &str, filter: &PhonebookFilter) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_PHONEBOOK_ACCESS_INTERFACE, "PullAll", &[target_file.into(), filter.to_message_item()])
+    }
+
+    pub fn pull_all_and_wait<F>(&self, target_file: &str, filter: &PhonebookFilter, progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_PHONEBOOK_ACCESS_INTERFACE, "PullAll", &[target_file.into(), filter.to_message_item()], progress)
+    }
+
+    pub fn pull(&self, handle: &str, target_file: &str, filter: &PhonebookFilter) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_PHONEBOOK_ACCESS_INTERFACE, "Pull", &[handle.into(), target_file.into(), filter.to_message_item()])
+    }
+
+    pub fn pull_and_wait<F>(&self, handle: &str, target_file: &str, filter: &PhonebookFilter, progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_PHONEBOOK_ACCESS_INTERFACE, "Pull", &[handle.into(), target_file.into(), filter.to_message_item()], progress)
+    }
+
+    // Only the order, offset and max count of the filter apply
+    pub fn list(&self, filter: &PhonebookFilter) -> Result<Vec<PhonebookEntry>, BtError> {
+        let reply = try!(self.call("List", &[filter.to_message_item()]));
+        Ok(PhonebookAccess::entries_from_reply(&reply))
+    }
+
+    pub fn search(&self, field: SearchField, value: &str, filter: &PhonebookFilter) -> Result<Vec<PhonebookEntry>, BtError> {
+        let reply = try!(self.call("Search", &[field.to_str().into(), value.into(), filter.to_message_item()]));
+        Ok(PhonebookAccess::entries_from_reply(&reply))
+    }
+
+    // Number of entries in the selected phonebook
+    pub fn get_size(&self) -> Result<u16, BtError> {
+        let reply = try!(self.call("GetSize", &[]));
+        reply.get1().ok_or_else(|| BtError::DBusInternal("invalid GetSize reply".to_string()))
+    }
+
+    pub fn list_filter_fields(&self) -> Result<Vec<String>, BtError> {
+        let reply = try!(self.call("ListFilterFields", &[]));
+        let items = reply.get_items();
+        let fields: &[dbus::MessageItem] = items.first().and_then(|x| x.inner().ok()).unwrap_or(&[]);
+        Ok(fields.iter().filter_map(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string()).collect())
+    }
+
+    fn call(&self, method_name: &str, method_args: &[dbus::MessageItem]) -> Result<dbus::Message, BtError> {
+        obex::obex_call(self.session.conn(), self.session.object_path(), OBEX_PHONEBOOK_ACCESS_INTERFACE, method_name, method_args)
+    }
+
+    fn entries_from_reply(reply: &dbus::Message) -> Vec<PhonebookEntry> {
+        let items = reply.get_items();
+        let entries: &[dbus::MessageItem] = items.first().and_then(|x| x.inner().ok()).unwrap_or(&[]);
+
+        entries.iter()
+            .filter_map(|x| x.inner().ok())
+            .filter_map(|(handle, name): (&dbus::MessageItem, &dbus::MessageItem)| match (handle.inner() as Result<&str, ()>, name.inner() as Result<&str, ()>) {
+                (Ok(handle), Ok(name)) => Some(PhonebookEntry { handle: handle.to_string(), name: name.to_string() }),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,38 @@
+use error::BtError;
+use obex::{self, ObexSession, ObexTransfer};
+
+pub static OBEX_OBJECT_PUSH_INTERFACE: &'static str = "org.bluez.obex.ObjectPush1";
+
+// OPP on an ObexTarget::Opp session. Files are referenced by their local paths.
+#[derive(Clone, Debug)]
+pub struct ObjectPush {
+    session: ObexSession,
+}
+
+impl ObjectPush {
+    pub fn new(session: &ObexSession) -> ObjectPush {
+        ObjectPush { session: session.clone() }
+    }
+
+    pub fn session(&self) -> &ObexSession {
+        &self.session
+    }
+
+    pub fn send_file(&self, source_file: &str) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_OBJECT_PUSH_INTERFACE, "SendFile", &[source_file.into()])
+    }
+
+    // Blocks until the file is sent, progress gets the sent bytes and the file size
+    pub fn send_file_and_wait<F>(&self, source_file: &str, progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_OBJECT_PUSH_INTERFACE, "SendFile", &[source_file.into()], progress)
+    }
+
+    // Saves the default business card of the remote device to target_file
+    pub fn pull_business_card(&self, target_file: &str) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_OBJECT_PUSH_INTERFACE, "PullBusinessCard", &[target_file.into()])
+    }
+
+    pub fn exchange_business_cards(&self, client_file: &str, target_file: &str) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_OBJECT_PUSH_INTERFACE, "ExchangeBusinessCards", &[client_file.into(), target_file.into()])
+    }
+}
@@ -0,0 +1,270 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dbus;
+
+use device::RetryPolicy;
+use discovery::StopHandle;
+use error::BtError;
+use obex::{self, ObexSession, ObexTransfer, TransferStatus};
+use obex_ftp::OBEX_FILE_TRANSFER_INTERFACE;
+use obex_push::OBEX_OBJECT_PUSH_INTERFACE;
+
+// A file transfer of a TransferQueue. Files are referenced by their local paths, as in ObjectPush
+// and FileTransfer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueuedTransfer {
+    // ObjectPush::send_file(source_file), on an ObexTarget::Opp session
+    SendFile(String),
+    // FileTransfer::put_file(source_file, target_file), on an ObexTarget::Ftp session
+    PutFile(String, String),
+    // FileTransfer::get_file(target_file, source_file), on an ObexTarget::Ftp session
+    GetFile(String, String),
+}
+
+impl QueuedTransfer {
+    fn method(&self) -> (&'static str, &'static str, Vec<dbus::MessageItem>) {
+        match *self {
+            QueuedTransfer::SendFile(ref source_file) => (OBEX_OBJECT_PUSH_INTERFACE, "SendFile", vec![source_file.as_str().into()]),
+            QueuedTransfer::PutFile(ref source_file, ref target_file) => {
+                (OBEX_FILE_TRANSFER_INTERFACE, "PutFile", vec![source_file.as_str().into(), target_file.as_str().into()])
+            }
+            QueuedTransfer::GetFile(ref target_file, ref source_file) => {
+                (OBEX_FILE_TRANSFER_INTERFACE, "GetFile", vec![target_file.as_str().into(), source_file.as_str().into()])
+            }
+        }
+    }
+}
+
+// The u64 is the id push() returned for the transfer
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferQueueEvent {
+    // The attempt number, starting at 1
+    Started(u64, u32),
+    // The transferred bytes and the total size (if known)
+    Progress(u64, u64, Option<u64>),
+    Suspended(u64),
+    Resumed(u64),
+    // The attempt number and the error message, another attempt follows
+    AttemptFailed(u64, u32, String),
+    Completed(u64),
+    // The error message of the last attempt
+    Failed(u64, String),
+}
+
+// Suspends the running transfer of a TransferQueue (and holds back the next ones) until resumed.
+// Can be used from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct PauseHandle {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseHandle {
+    pub fn new() -> PauseHandle {
+        PauseHandle::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+// Failures which usually go away on another try: obexd was busy, or the transfer broke off
+fn is_transient_transfer_error(err: &BtError, last_status: Option<TransferStatus>) -> bool {
+    match *err {
+        BtError::DBus(ref err) => {
+            err.name() == Some("org.bluez.obex.Error.Failed") || err.name() == Some("org.bluez.obex.Error.InProgress")
+        }
+        _ => last_status == Some(TransferStatus::Error),
+    }
+}
+
+// Runs the transfers of a session one at a time, in the order they were pushed, since obexd
+// handles one transfer per session anyway. Transient failures are retried with the retry policy.
+pub struct TransferQueue {
+    session: ObexSession,
+    transfers: VecDeque<(u64, QueuedTransfer)>,
+    next_id: u64,
+    policy: RetryPolicy,
+    pause_handle: PauseHandle,
+    stop_handle: StopHandle,
+}
+
+impl TransferQueue {
+    pub fn new(session: &ObexSession) -> TransferQueue {
+        TransferQueue {
+            session: session.clone(),
+            transfers: VecDeque::new(),
+            next_id: 0,
+            policy: RetryPolicy::default(),
+            pause_handle: PauseHandle::new(),
+            stop_handle: StopHandle::new(),
+        }
+    }
+
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> TransferQueue {
+        self.policy = policy;
+        self
+    }
+
+    pub fn pause_handle(mut self, pause_handle: &PauseHandle) -> TransferQueue {
+        self.pause_handle = pause_handle.clone();
+        self
+    }
+
+    // A stop cancels the running transfer, which stays queued with the rest
+    pub fn stop_handle(mut self, stop_handle: &StopHandle) -> TransferQueue {
+        self.stop_handle = stop_handle.clone();
+        self
+    }
+
+    pub fn session(&self) -> &ObexSession {
+        &self.session
+    }
+
+    // Returns the id of the transfer in the events
+    pub fn push(&mut self, transfer: QueuedTransfer) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.transfers.push_back((id, transfer));
+        id
+    }
+
+    // The transfers which haven't completed or failed yet
+    pub fn pending(&self) -> Vec<(u64, QueuedTransfer)> {
+        self.transfers.iter().cloned().collect()
+    }
+
+    // Runs the queued transfers until the queue is empty or the stop handle is triggered. A transfer
+    // which failed for good is reported and dropped; the queue goes on and returns the first such
+    // error at the end.
+    pub fn run<F>(&mut self, mut f: F) -> Result<(), BtError> where F: FnMut(&TransferQueueEvent) {
+        let mut result = Ok(());
+
+        while let Some((id, transfer)) = self.transfers.front().cloned() {
+            if !self.wait_while_paused() {
+                break;
+            }
+
+            let r = self.run_one(id, &transfer, &mut f);
+            if self.stop_handle.is_stopped() {
+                break;
+            }
+            self.transfers.pop_front();
+            match r {
+                Ok(()) => f(&TransferQueueEvent::Completed(id)),
+                Err(e) => {
+                    f(&TransferQueueEvent::Failed(id, e.to_string()));
+                    if result.is_ok() { result = Err(e); }
+                }
+            }
+        }
+
+        result
+    }
+
+    fn run_one<F>(&self, id: u64, transfer: &QueuedTransfer, f: &mut F) -> Result<(), BtError> where F: FnMut(&TransferQueueEvent) {
+        let (interface, method_name, method_args) = transfer.method();
+
+        let mut attempt = 1;
+        loop {
+            f(&TransferQueueEvent::Started(id, attempt));
+
+            // Both closures report events while the transfer runs
+            let events = RefCell::new(&mut *f);
+            let mut last_status = None;
+            let mut suspended = false;
+            let r = obex::run_transfer_with_control(&self.session, interface, method_name, &method_args,
+                |transferred, size| (events.borrow_mut())(&TransferQueueEvent::Progress(id, transferred, size)),
+                |transfer, status| {
+                    last_status = Some(status);
+                    if let Some(event) = try!(self.control(id, transfer, status, &mut suspended)) {
+                        (events.borrow_mut())(&event);
+                    }
+                    Ok(())
+                });
+
+            match r {
+                Err(ref e) if !self.stop_handle.is_stopped() && attempt < self.policy.max_attempts && is_transient_transfer_error(e, last_status) => {
+                    f(&TransferQueueEvent::AttemptFailed(id, attempt, e.to_string()));
+                    if !self.wait(self.policy.delay(attempt)) {
+                        return Err(BtError::DBusInternal("the transfer queue was stopped".to_string()));
+                    }
+                    attempt += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    // Suspends or resumes the running transfer to match the pause handle, cancels it on a stop.
+    // suspended is what was asked last, the status only follows once obexd got to it.
+    fn control(&self, id: u64, transfer: &ObexTransfer, status: TransferStatus, suspended: &mut bool) -> Result<Option<TransferQueueEvent>, BtError> {
+        if self.stop_handle.is_stopped() {
+            let _ = transfer.cancel();
+            return Err(BtError::DBusInternal("the transfer queue was stopped".to_string()));
+        }
+        if status == TransferStatus::Complete || status == TransferStatus::Error || self.pause_handle.is_paused() == *suspended {
+            return Ok(None);
+        }
+
+        *suspended = !*suspended;
+        if *suspended {
+            try!(transfer.suspend());
+            Ok(Some(TransferQueueEvent::Suspended(id)))
+        } else {
+            try!(transfer.resume());
+            Ok(Some(TransferQueueEvent::Resumed(id)))
+        }
+    }
+
+    // Serves the connection while paused, returns false on a stop
+    fn wait_while_paused(&self) -> bool {
+        while self.pause_handle.is_paused() && !self.stop_handle.is_stopped() && self.session.conn().registry().is_connected() {
+            self.session.conn().registry().process(100);
+        }
+        !self.stop_handle.is_stopped()
+    }
+
+    // Serves the connection for the duration, returns false on a stop
+    fn wait(&self, duration: Duration) -> bool {
+        let now = Instant::now();
+        while now.elapsed() < duration && !self.stop_handle.is_stopped() && self.session.conn().registry().is_connected() {
+            self.session.conn().registry().process(100);
+        }
+        !self.stop_handle.is_stopped()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus;
+
+    use error::BtError;
+    use obex::TransferStatus;
+    use super::is_transient_transfer_error;
+
+    #[test]
+    fn transient_transfer_errors() {
+        let busy = BtError::DBus(dbus::Error::new_custom("org.bluez.obex.Error.InProgress", "busy"));
+        assert!(is_transient_transfer_error(&busy, None));
+
+        let denied = BtError::DBus(dbus::Error::new_custom("org.bluez.obex.Error.Forbidden", "denied"));
+        assert!(!is_transient_transfer_error(&denied, None));
+
+        let failed = BtError::DBusInternal("OBEX transfer failed".to_string());
+        assert!(is_transient_transfer_error(&failed, Some(TransferStatus::Error)));
+        assert!(!is_transient_transfer_error(&failed, Some(TransferStatus::Active)));
+    }
+}
@@ -0,0 +1,44 @@
+use error::BtError;
+use obex::{self, ObexSession, ObexTransfer};
+use obex_pbap::PhonebookLocation;
+
+pub static OBEX_SYNCHRONIZATION_INTERFACE: &'static str = "org.bluez.obex.Synchronization1";
+
+// IrMC sync on an ObexTarget::Sync session. The whole phonebook is exchanged as one vCard file.
+#[derive(Clone, Debug)]
+pub struct Synchronization {
+    session: ObexSession,
+}
+
+impl Synchronization {
+    pub fn new(session: &ObexSession) -> Synchronization {
+        Synchronization { session: session.clone() }
+    }
+
+    pub fn session(&self) -> &ObexSession {
+        &self.session
+    }
+
+    // Defaults to the internal phonebook
+    pub fn set_location(&self, location: PhonebookLocation) -> Result<(), BtError> {
+        try!(obex::obex_call(self.session.conn(), self.session.object_path(), OBEX_SYNCHRONIZATION_INTERFACE, "SetLocation", &[location.to_name().into()]));
+        Ok(())
+    }
+
+    pub fn get_phonebook(&self, target_file: &str) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_SYNCHRONIZATION_INTERFACE, "GetPhonebook", &[target_file.into()])
+    }
+
+    pub fn get_phonebook_and_wait<F>(&self, target_file: &str, progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_SYNCHRONIZATION_INTERFACE, "GetPhonebook", &[target_file.into()], progress)
+    }
+
+    // Replaces the phonebook of the device
+    pub fn put_phonebook(&self, source_file: &str) -> Result<ObexTransfer, BtError> {
+        obex::start_transfer(&self.session, OBEX_SYNCHRONIZATION_INTERFACE, "PutPhonebook", &[source_file.into()])
+    }
+
+    pub fn put_phonebook_and_wait<F>(&self, source_file: &str, progress: F) -> Result<(), BtError> where F: FnMut(u64, Option<u64>) {
+        obex::run_transfer(&self.session, OBEX_SYNCHRONIZATION_INTERFACE, "PutPhonebook", &[source_file.into()], progress)
+    }
+}
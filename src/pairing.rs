@@ -0,0 +1,136 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use agent::{Agent, AgentCapability, AgentError, PairingRequest};
+use device::Device;
+use error::BtError;
+
+#[derive(Clone, Debug)]
+pub enum PairingEvent {
+    PinCodeRequested(Device),
+    PinCodeDisplayed(Device, String),
+    PasskeyRequested(Device),
+    PasskeyDisplayed(Device, u32),
+    ConfirmationRequested(Device, u32),
+    Paired,
+    Trusted,
+    Connected,
+    Completed,
+    Failed(String),
+}
+
+type SharedCallbackT = Rc<RefCell<Box<FnMut(&PairingEvent)>>>;
+
+// Reports every request to the callback before handing it to the wrapped agent
+struct EventAgent {
+    agent: Box<Agent>,
+    callback: SharedCallbackT,
+}
+
+impl EventAgent {
+    fn emit(&self, event: PairingEvent) {
+        (*self.callback.borrow_mut())(&event);
+    }
+}
+
+impl Agent for EventAgent {
+    fn get_object_path(&self) -> &str {
+        self.agent.get_object_path()
+    }
+
+    fn get_capability(&self) -> AgentCapability {
+        self.agent.get_capability()
+    }
+
+    fn request_pincode(&self, request: PairingRequest) -> Result<String, AgentError> {
+        self.emit(PairingEvent::PinCodeRequested(request.device.clone()));
+        self.agent.request_pincode(request)
+    }
+
+    fn display_pincode(&self, request: PairingRequest, pincode: &str) -> Result<(), AgentError> {
+        self.emit(PairingEvent::PinCodeDisplayed(request.device.clone(), pincode.to_string()));
+        self.agent.display_pincode(request, pincode)
+    }
+
+    fn request_passkey(&self, request: PairingRequest) -> Result<u32, AgentError> {
+        self.emit(PairingEvent::PasskeyRequested(request.device.clone()));
+        self.agent.request_passkey(request)
+    }
+
+    fn display_passkey(&self, request: PairingRequest, passkey: u32, entered: u16) {
+        // Only the first display matters for the dialog, later ones update the entered count
+        if entered == 0 {
+            self.emit(PairingEvent::PasskeyDisplayed(request.device.clone(), passkey));
+        }
+        self.agent.display_passkey(request, passkey, entered)
+    }
+
+    fn request_confirmation(&self, request: PairingRequest, passkey: u32) -> Result<(), AgentError> {
+        self.emit(PairingEvent::ConfirmationRequested(request.device.clone(), passkey));
+        self.agent.request_confirmation(request, passkey)
+    }
+
+    fn request_authorization(&self, request: PairingRequest) -> Result<(), AgentError> {
+        self.agent.request_authorization(request)
+    }
+
+    fn authorize_service(&self, request: PairingRequest, uuid: &str) -> Result<(), AgentError> {
+        self.agent.authorize_service(request, uuid)
+    }
+
+    fn authorize_service_with_name(&self, request: PairingRequest, uuid: &str, service_name: Option<&str>) -> Result<(), AgentError> {
+        self.agent.authorize_service_with_name(request, uuid, service_name)
+    }
+
+    fn cancel(&self) {
+        self.agent.cancel()
+    }
+
+    fn release(&self) {
+        self.agent.release()
+    }
+}
+
+// Register agent -> Pair -> set trusted -> Connect, reporting progress along the way
+pub struct PairingSession {
+    device: Device,
+    agent: Box<Agent>,
+    trust: bool,
+    connect: bool,
+}
+
+impl PairingSession {
+    pub fn new(device: &Device, agent: Box<Agent>) -> PairingSession {
+        PairingSession { device: device.clone(), agent: agent, trust: true, connect: true }
+    }
+
+    pub fn trust(mut self, trust: bool) -> PairingSession {
+        self.trust = trust;
+        self
+    }
+
+    pub fn connect(mut self, connect: bool) -> PairingSession {
+        self.connect = connect;
+        self
+    }
+
+    pub fn run<F>(self, f: F) -> Result<(), BtError> where F: FnMut(&PairingEvent) + 'static {
+        let PairingSession { device, agent, trust, connect } = self;
+
+        let callback: SharedCallbackT = Rc::new(RefCell::new(Box::new(f)));
+        let emit = |event: PairingEvent| (*callback.borrow_mut())(&event);
+
+        let agent = EventAgent { agent: agent, callback: callback.clone() };
+
+        let result = device.pair_with_agent(Box::new(agent))
+            .map(|_| emit(PairingEvent::Paired))
+            .and_then(|_| if trust { device.set_trusted(true).map(|_| emit(PairingEvent::Trusted)) } else { Ok(()) })
+            .and_then(|_| if connect { device.connect().map(|_| emit(PairingEvent::Connected)) } else { Ok(()) });
+
+        match result {
+            Ok(_) => emit(PairingEvent::Completed),
+            Err(ref e) => emit(PairingEvent::Failed(e.to_string())),
+        }
+        result
+    }
+}
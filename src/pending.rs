@@ -0,0 +1,140 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+#[cfg(feature = "async")]
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use dbus;
+
+use common;
+use error::BtError;
+
+// A method call sent on the caller's connection without waiting for the reply. The reply is
+// picked up whenever that connection is processed (poll(), wait(), an EventLoop...), which also
+// serves the objects registered on it in the meantime, e.g. the agent for Pair. Dropping it
+// doesn't cancel the call.
+pub struct PendingCall<T> {
+    conn: super::Connection,
+    // The serial of the call, or why it couldn't be sent. None once the result was returned.
+    state: RefCell<Option<Result<u32, BtError>>>,
+    deadline: Instant,
+    parse: Box<Fn(dbus::Message) -> Result<T, BtError>>,
+    // Released when the reply is there
+    guard: RefCell<Option<Box<Any>>>,
+}
+
+impl<T> PendingCall<T> {
+    // Fails with BtError::Timeout if there is no reply within timeout, as the blocking calls do
+    pub(crate) fn send<F>(conn: &super::Connection, msg: dbus::Message, timeout: Duration, parse: F) -> PendingCall<T>
+        where F: Fn(dbus::Message) -> Result<T, BtError> + 'static {
+        PendingCall {
+            conn: conn.clone(),
+            state: RefCell::new(Some(conn.registry().send(msg))),
+            deadline: Instant::now() + timeout,
+            parse: Box::new(parse),
+            guard: RefCell::new(None),
+        }
+    }
+
+    pub(crate) fn failed(conn: &super::Connection, err: BtError) -> PendingCall<T> {
+        PendingCall {
+            conn: conn.clone(),
+            state: RefCell::new(Some(Err(err))),
+            deadline: Instant::now(),
+            parse: Box::new(|_| Err(BtError::DBusInternal("no method call was sent".to_string()))),
+            guard: RefCell::new(None),
+        }
+    }
+
+    // Keeps guard alive until the reply is there
+    pub(crate) fn with_guard<G>(self, guard: G) -> PendingCall<T> where G: Any {
+        *self.guard.borrow_mut() = Some(Box::new(guard));
+        self
+    }
+
+    // Returns the result once the reply is there, None while waiting and after the result was returned
+    pub fn poll(&self) -> Option<Result<T, BtError>> {
+        if self.is_done() {
+            return None;
+        }
+
+        self.conn.registry().process(0);
+        self.check()
+    }
+
+    // Same as poll(), but waits up to timeout for the reply
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<T, BtError>> {
+        let now = Instant::now();
+
+        while let Some(remaining) = timeout.checked_sub(now.elapsed()) {
+            if self.is_done() {
+                break;
+            }
+            if let Some(result) = self.check() {
+                return Some(result);
+            }
+            self.conn.registry().process(cmp::min(remaining.as_millis(), 100) as i32);
+        }
+
+        None
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state.borrow().is_none()
+    }
+
+    // Blocks until the reply is there
+    pub fn wait(self) -> Result<T, BtError> {
+        while !self.is_done() {
+            if let Some(result) = self.check() {
+                return result;
+            }
+            self.conn.registry().process(100);
+        }
+
+        Err(BtError::DBusInternal("the result was already returned by poll()".to_string()))
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn wake_on_reply(&self, waker: &Waker) {
+        match *self.state.borrow() {
+            Some(Ok(serial)) => self.conn.registry().wake_on_reply(serial, self.deadline, waker),
+            _ => waker.wake_by_ref(),
+        }
+    }
+
+    // Takes the result if the reply was read, or if the call failed or timed out
+    fn check(&self) -> Option<Result<T, BtError>> {
+        let state = self.state.borrow_mut().take();
+        let result = match state {
+            Some(Ok(serial)) => match self.conn.registry().take_reply(serial) {
+                Some(mut reply) => match reply.as_result().err() {
+                    Some(err) => Err(common::timeout_err(err)),
+                    None => (self.parse)(reply),
+                },
+                None if Instant::now() >= self.deadline => {
+                    self.conn.registry().forget_reply(serial);
+                    Err(BtError::Timeout)
+                }
+                None => {
+                    *self.state.borrow_mut() = Some(Ok(serial));
+                    return None;
+                }
+            },
+            Some(Err(err)) => Err(err),
+            None => return None,
+        };
+
+        self.guard.borrow_mut().take();
+        Some(result)
+    }
+}
+
+impl<T> Drop for PendingCall<T> {
+    fn drop(&mut self) {
+        if let Some(Ok(serial)) = *self.state.borrow() {
+            self.conn.registry().forget_reply(serial);
+        }
+    }
+}
@@ -0,0 +1,798 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use dbus;
+use dbus::tree::{MethodErr, MTFn, PropInfo};
+
+use adapter::Adapter;
+use common;
+use device::Device;
+use error::BtError;
+use media::MEDIA_INTERFACE;
+use properties::{self, PropertiesWatcher, PropertyValue};
+use registry::Registration;
+
+pub static MEDIA_PLAYER_INTERFACE: &'static str = "org.bluez.MediaPlayer1";
+pub static MEDIA_CONTROL_INTERFACE: &'static str = "org.bluez.MediaControl1";
+pub static MEDIA_FOLDER_INTERFACE: &'static str = "org.bluez.MediaFolder1";
+pub static MEDIA_ITEM_INTERFACE: &'static str = "org.bluez.MediaItem1";
+pub static MPRIS_PLAYER_INTERFACE: &'static str = "org.mpris.MediaPlayer2.Player";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlayerStatus {
+    Playing,
+    Stopped,
+    Paused,
+    ForwardSeek,
+    ReverseSeek,
+    Error,
+}
+
+impl PlayerStatus {
+    fn from_str(s: &str) -> Option<PlayerStatus> {
+        match s {
+            "playing" => Some(PlayerStatus::Playing),
+            "stopped" => Some(PlayerStatus::Stopped),
+            "paused" => Some(PlayerStatus::Paused),
+            "forward-seek" => Some(PlayerStatus::ForwardSeek),
+            "reverse-seek" => Some(PlayerStatus::ReverseSeek),
+            "error" => Some(PlayerStatus::Error),
+            _ => None,
+        }
+    }
+}
+
+// The metadata of the current track, unknown fields are None
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Track {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub number_of_tracks: Option<u32>,
+    pub track_number: Option<u32>,
+    // In milliseconds
+    pub duration: Option<u32>,
+}
+
+impl Track {
+    fn new(props_map: &BTreeMap<String, dbus::MessageItem>) -> Track {
+        fn _get_str(props_map: &BTreeMap<String, dbus::MessageItem>, name: &str) -> Option<String> {
+            props_map.get(name).and_then(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string())
+        }
+
+        Track {
+            title: _get_str(props_map, "Title"),
+            artist: _get_str(props_map, "Artist"),
+            album: _get_str(props_map, "Album"),
+            genre: _get_str(props_map, "Genre"),
+            number_of_tracks: props_map.get("NumberOfTracks").and_then(|x| x.inner().ok()),
+            track_number: props_map.get("TrackNumber").and_then(|x| x.inner().ok()),
+            duration: props_map.get("Duration").and_then(|x| x.inner().ok()),
+        }
+    }
+
+    fn from_item(item: &dbus::MessageItem) -> Track {
+        let props: &[dbus::MessageItem] = item.inner().unwrap_or(&[]);
+        Track::new(&common::dbus_props_to_map(props))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaPlayerProperties {
+    pub name: Option<String>,
+    pub status: Option<PlayerStatus>,
+    // In milliseconds
+    pub position: Option<u32>,
+    pub track: Track,
+    pub repeat: Option<String>,
+    pub shuffle: Option<String>,
+    pub browsable: bool,
+    pub searchable: bool,
+}
+
+impl MediaPlayerProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> MediaPlayerProperties {
+        MediaPlayerProperties {
+            name: props_map.get("Name").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+            status: props_map.get("Status").and_then(|x| x.inner().ok()).and_then(PlayerStatus::from_str),
+            position: props_map.get("Position").and_then(|x| x.inner().ok()),
+            track: props_map.get("Track").map(Track::from_item).unwrap_or_default(),
+            repeat: props_map.get("Repeat").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+            shuffle: props_map.get("Shuffle").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+            browsable: props_map.get("Browsable").and_then(|x| x.inner().ok()).unwrap_or(false),
+            searchable: props_map.get("Searchable").and_then(|x| x.inner().ok()).unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlayerEvent {
+    Status(PlayerStatus),
+    Position(u32),
+    Track(Track),
+}
+
+// The AVRCP target (e.g. a phone's music app) of a connected device
+#[derive(Clone, Debug)]
+pub struct MediaPlayer {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl MediaPlayer {
+    pub fn new(conn: &super::Connection, object_path: &str) -> MediaPlayer {
+        MediaPlayer { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<MediaPlayerProperties, BtError> {
+        let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, MEDIA_PLAYER_INTERFACE, 1000);
+        Ok(MediaPlayerProperties::new(try!(p.get_all())))
+    }
+
+    pub fn get_status(&self) -> Result<Option<PlayerStatus>, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Status"));
+        Ok(val.inner().ok().and_then(PlayerStatus::from_str))
+    }
+
+    pub fn get_position(&self) -> Result<u32, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Position"));
+        val.inner().map_err(|_| BtError::DBusInternal("invalid Position value".to_string()))
+    }
+
+    pub fn get_track(&self) -> Result<Track, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Track"));
+        Ok(Track::from_item(&val))
+    }
+
+    pub fn play(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Play")
+    }
+
+    pub fn pause(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Pause")
+    }
+
+    pub fn stop(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Stop")
+    }
+
+    pub fn next(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Next")
+    }
+
+    pub fn previous(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Previous")
+    }
+
+    // Seeks until play() or stop() is called
+    pub fn fast_forward(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "FastForward")
+    }
+
+    pub fn rewind(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE, "Rewind")
+    }
+
+    // The current folder of a browsable player (see MediaPlayerProperties::browsable)
+    pub fn folder(&self) -> MediaFolder {
+        MediaFolder { conn: self.conn.clone(), object_path: self.object_path.clone() }
+    }
+
+    pub fn watch(&self) -> Result<PlayerWatcher, BtError> {
+        let watcher = try!(properties::watch_properties(&self.conn, &self.object_path, MEDIA_PLAYER_INTERFACE));
+        Ok(PlayerWatcher { watcher: watcher, events: VecDeque::new() })
+    }
+}
+
+// Yields the status, position and track changes of a player
+pub struct PlayerWatcher {
+    watcher: PropertiesWatcher,
+    events: VecDeque<PlayerEvent>,
+}
+
+impl PlayerWatcher {
+    // Ends the iteration when no change arrives within timeout
+    pub fn timeout(self, timeout: Duration) -> PlayerWatcher {
+        PlayerWatcher { watcher: self.watcher.timeout(timeout), events: self.events }
+    }
+}
+
+impl Iterator for PlayerWatcher {
+    type Item = PlayerEvent;
+
+    fn next(&mut self) -> Option<PlayerEvent> {
+        while self.events.is_empty() {
+            let changed = match self.watcher.next() {
+                Some(event) => event.changed,
+                None => return None,
+            };
+
+            for (name, value) in changed {
+                let player_event = match (name.as_str(), value) {
+                    ("Status", PropertyValue::Str(ref status)) => PlayerStatus::from_str(status).map(PlayerEvent::Status),
+                    ("Position", PropertyValue::UInt32(position)) => Some(PlayerEvent::Position(position)),
+                    ("Track", PropertyValue::Other(ref item)) => Some(PlayerEvent::Track(Track::from_item(item))),
+                    _ => None,
+                };
+                self.events.extend(player_event);
+            }
+        }
+
+        self.events.pop_front()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaItemType {
+    Video,
+    Audio,
+    Folder,
+}
+
+impl MediaItemType {
+    fn from_str(s: &str) -> Option<MediaItemType> {
+        match s {
+            "video" => Some(MediaItemType::Video),
+            "audio" => Some(MediaItemType::Audio),
+            "folder" => Some(MediaItemType::Folder),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaItemProperties {
+    pub name: Option<String>,
+    pub item_type: Option<MediaItemType>,
+    // For folders, e.g. "albums", "artists", "playlists"
+    pub folder_type: Option<String>,
+    pub playable: bool,
+    pub metadata: Track,
+}
+
+impl MediaItemProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> MediaItemProperties {
+        let metadata: &[dbus::MessageItem] = props_map.get("Metadata").and_then(|x| x.inner().ok()).unwrap_or(&[]);
+        let metadata = common::dbus_props_to_map(metadata);
+
+        // Items call the track number "Number"
+        let mut track = Track::new(&metadata);
+        if track.track_number.is_none() {
+            track.track_number = metadata.get("Number").and_then(|x| x.inner().ok());
+        }
+
+        MediaItemProperties {
+            name: props_map.get("Name").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+            item_type: props_map.get("Type").and_then(|x| x.inner().ok()).and_then(MediaItemType::from_str),
+            folder_type: props_map.get("FolderType").and_then(|x| x.inner().ok()).map(|x: &str| x.to_string()),
+            playable: props_map.get("Playable").and_then(|x| x.inner().ok()).unwrap_or(false),
+            metadata: track,
+        }
+    }
+}
+
+// A track or folder of the remote player's library
+#[derive(Clone, Debug)]
+pub struct MediaItem {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl MediaItem {
+    pub fn new(conn: &super::Connection, object_path: &str) -> MediaItem {
+        MediaItem { conn: conn.clone(), object_path: object_path.to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_properties(&self) -> Result<MediaItemProperties, BtError> {
+        let p = dbus::Props::new(&self.conn, common::SERVICE_NAME, &self.object_path, MEDIA_ITEM_INTERFACE, 1000);
+        Ok(MediaItemProperties::new(try!(p.get_all())))
+    }
+
+    pub fn play(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_ITEM_INTERFACE, "Play")
+    }
+
+    pub fn add_to_now_playing(&self) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_ITEM_INTERFACE, "AddtoNowPlaying")
+    }
+}
+
+// The browsing side of a player. Listing and changing folders refers to the player's current folder.
+#[derive(Clone, Debug)]
+pub struct MediaFolder {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl MediaFolder {
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_name(&self) -> Result<String, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, MEDIA_FOLDER_INTERFACE, "Name"));
+        val.inner().map(|x: &str| x.to_string()).map_err(|_| BtError::DBusInternal("invalid Name value".to_string()))
+    }
+
+    pub fn get_number_of_items(&self) -> Result<u32, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, MEDIA_FOLDER_INTERFACE, "NumberOfItems"));
+        val.inner().map_err(|_| BtError::DBusInternal("invalid NumberOfItems value".to_string()))
+    }
+
+    // Lists the items from start to end (inclusive), all of them if unset
+    pub fn list_items(&self, start: Option<u32>, end: Option<u32>) -> Result<Vec<(MediaItem, MediaItemProperties)>, BtError> {
+        let mut filter = Vec::new();
+        if let Some(start) = start {
+            filter.push(common::dbus_dict_entry("Start", start));
+        }
+        if let Some(end) = end {
+            filter.push(common::dbus_dict_entry("End", end));
+        }
+
+        let reply = try!(common::dbus_call_method1_with_reply(&self.conn, &self.object_path, MEDIA_FOLDER_INTERFACE, "ListItems",
+                                                              dbus::MessageItem::Array(filter, "{sv}".into())));
+        let items = reply.get_items();
+        let items: &[dbus::MessageItem] = try!(items.first().and_then(|x| x.inner().ok())
+            .ok_or_else(|| BtError::DBusInternal("invalid ListItems reply".to_string())));
+
+        let mut result = Vec::new();
+        for item in items {
+            if let Ok((path, props)) = item.inner() as Result<(&dbus::MessageItem, &dbus::MessageItem), ()> {
+                let path: &str = match *path {
+                    dbus::MessageItem::ObjectPath(ref path) => path,
+                    _ => continue,
+                };
+                let props: &[dbus::MessageItem] = props.inner().unwrap_or(&[]);
+                result.push((MediaItem::new(&self.conn, path), MediaItemProperties::new(common::dbus_props_to_map(props))));
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Enters a folder item, or goes back up with the parent folder
+    pub fn change_folder(&self, folder: &MediaItem) -> Result<(), BtError> {
+        let folder_obj_path = dbus::Path::new(folder.object_path()).unwrap();
+        common::dbus_call_method1(&self.conn, &self.object_path, MEDIA_FOLDER_INTERFACE, "ChangeFolder", folder_obj_path)
+    }
+
+    // Returns the folder holding the results, if the player supports searching
+    pub fn search(&self, value: &str) -> Result<MediaFolder, BtError> {
+        let reply = try!(common::dbus_call_method2_with_reply(&self.conn, &self.object_path, MEDIA_FOLDER_INTERFACE, "Search",
+                                                              value, dbus::MessageItem::Array(vec![], "{sv}".into())));
+        let folder_obj_path: dbus::Path = try!(reply.get1().ok_or_else(|| BtError::DBusInternal("invalid Search reply".to_string())));
+        Ok(MediaFolder { conn: self.conn.clone(), object_path: folder_obj_path.to_string() })
+    }
+}
+
+// The deprecated AVRCP interface of the device itself, all some older stacks offer
+#[derive(Clone, Debug)]
+pub struct MediaControl {
+    conn: super::Connection,
+    object_path: String,
+}
+
+impl MediaControl {
+    pub fn new(device: &Device) -> MediaControl {
+        MediaControl { conn: device.conn().clone(), object_path: device.object_path().to_string() }
+    }
+
+    pub fn object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    pub fn get_connected(&self) -> Result<bool, BtError> {
+        let val = try!(common::dbus_get_property(&self.conn, &self.object_path, MEDIA_CONTROL_INTERFACE, "Connected"));
+        val.inner().map_err(|_| BtError::DBusInternal("invalid Connected value".to_string()))
+    }
+
+    // The MediaPlayer1 object, if BlueZ has one
+    pub fn get_player(&self) -> Result<Option<MediaPlayer>, BtError> {
+        let val = match common::dbus_get_property(&self.conn, &self.object_path, MEDIA_CONTROL_INTERFACE, "Player") {
+            Ok(val) => val,
+            Err(_) => return Ok(None),
+        };
+        match val {
+            dbus::MessageItem::ObjectPath(ref path) => Ok(Some(MediaPlayer::new(&self.conn, path))),
+            _ => Err(BtError::DBusInternal("invalid Player value".to_string())),
+        }
+    }
+
+    fn call(&self, method_name: &str) -> Result<(), BtError> {
+        common::dbus_call_method0(&self.conn, &self.object_path, MEDIA_CONTROL_INTERFACE, method_name)
+    }
+
+    pub fn play(&self) -> Result<(), BtError> {
+        self.call("Play")
+    }
+
+    pub fn pause(&self) -> Result<(), BtError> {
+        self.call("Pause")
+    }
+
+    pub fn stop(&self) -> Result<(), BtError> {
+        self.call("Stop")
+    }
+
+    pub fn next(&self) -> Result<(), BtError> {
+        self.call("Next")
+    }
+
+    pub fn previous(&self) -> Result<(), BtError> {
+        self.call("Previous")
+    }
+
+    pub fn volume_up(&self) -> Result<(), BtError> {
+        self.call("VolumeUp")
+    }
+
+    pub fn volume_down(&self) -> Result<(), BtError> {
+        self.call("VolumeDown")
+    }
+
+    pub fn fast_forward(&self) -> Result<(), BtError> {
+        self.call("FastForward")
+    }
+
+    pub fn rewind(&self) -> Result<(), BtError> {
+        self.call("Rewind")
+    }
+}
+
+// Drives the device through MediaPlayer1, or MediaControl1 where there is no player
+#[derive(Clone, Debug)]
+pub enum RemoteControl {
+    Player(MediaPlayer),
+    Control(MediaControl),
+}
+
+impl RemoteControl {
+    pub fn new(device: &Device) -> Result<RemoteControl, BtError> {
+        let control = MediaControl::new(device);
+        if let Some(player) = try!(control.get_player()) {
+            return Ok(RemoteControl::Player(player));
+        }
+
+        match try!(get_players(device)).into_iter().next() {
+            Some(player) => Ok(RemoteControl::Player(player)),
+            None => Ok(RemoteControl::Control(control)),
+        }
+    }
+
+    pub fn play(&self) -> Result<(), BtError> {
+        match *self {
+            RemoteControl::Player(ref player) => player.play(),
+            RemoteControl::Control(ref control) => control.play(),
+        }
+    }
+
+    pub fn pause(&self) -> Result<(), BtError> {
+        match *self {
+            RemoteControl::Player(ref player) => player.pause(),
+            RemoteControl::Control(ref control) => control.pause(),
+        }
+    }
+
+    pub fn stop(&self) -> Result<(), BtError> {
+        match *self {
+            RemoteControl::Player(ref player) => player.stop(),
+            RemoteControl::Control(ref control) => control.stop(),
+        }
+    }
+
+    pub fn next(&self) -> Result<(), BtError> {
+        match *self {
+            RemoteControl::Player(ref player) => player.next(),
+            RemoteControl::Control(ref control) => control.next(),
+        }
+    }
+
+    pub fn previous(&self) -> Result<(), BtError> {
+        match *self {
+            RemoteControl::Player(ref player) => player.previous(),
+            RemoteControl::Control(ref control) => control.previous(),
+        }
+    }
+
+    pub fn fast_forward(&self) -> Result<(), BtError> {
+        match *self {
+            RemoteControl::Player(ref player) => player.fast_forward(),
+            RemoteControl::Control(ref control) => control.fast_forward(),
+        }
+    }
+
+    pub fn rewind(&self) -> Result<(), BtError> {
+        match *self {
+            RemoteControl::Player(ref player) => player.rewind(),
+            RemoteControl::Control(ref control) => control.rewind(),
+        }
+    }
+
+    // Metadata is only available through MediaPlayer1
+    pub fn player(&self) -> Option<&MediaPlayer> {
+        match *self {
+            RemoteControl::Player(ref player) => Some(player),
+            RemoteControl::Control(_) => None,
+        }
+    }
+}
+
+// The players of the device, usually one while AVRCP is connected
+pub fn get_players(device: &Device) -> Result<Vec<MediaPlayer>, BtError> {
+    common::dbus_get_managed_objects(device.conn(),
+                                     device.object_path(),
+                                     MEDIA_PLAYER_INTERFACE,
+                                     |conn, obj_path| MediaPlayer::new(&conn, obj_path)
+    )
+}
+
+// The playback state of a local player, as shown on the remote device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlayerState {
+    pub status: PlayerStatus,
+    // In milliseconds
+    pub position: u32,
+    pub track: Track,
+}
+
+impl Default for PlayerState {
+    fn default() -> PlayerState {
+        PlayerState { status: PlayerStatus::Stopped, position: 0, track: Track::default() }
+    }
+}
+
+impl PlayerState {
+    fn mpris_status(&self) -> &'static str {
+        match self.status {
+            PlayerStatus::Playing => "Playing",
+            PlayerStatus::Paused | PlayerStatus::ForwardSeek | PlayerStatus::ReverseSeek => "Paused",
+            PlayerStatus::Stopped | PlayerStatus::Error => "Stopped",
+        }
+    }
+
+    fn metadata(&self) -> dbus::MessageItem {
+        fn _str_array(val: &str) -> dbus::MessageItem {
+            dbus::MessageItem::Array(vec![val.into()], "s".into())
+        }
+
+        let track = &self.track;
+        let mut entries = Vec::new();
+        if let Some(ref title) = track.title {
+            entries.push(common::dbus_dict_entry("xesam:title", title.as_str()));
+        }
+        if let Some(ref artist) = track.artist {
+            entries.push(common::dbus_dict_entry("xesam:artist", _str_array(artist)));
+        }
+        if let Some(ref album) = track.album {
+            entries.push(common::dbus_dict_entry("xesam:album", album.as_str()));
+        }
+        if let Some(ref genre) = track.genre {
+            entries.push(common::dbus_dict_entry("xesam:genre", _str_array(genre)));
+        }
+        if let Some(track_number) = track.track_number {
+            entries.push(common::dbus_dict_entry("xesam:trackNumber", dbus::MessageItem::Int32(track_number as i32)));
+        }
+        if let Some(number_of_tracks) = track.number_of_tracks {
+            entries.push(common::dbus_dict_entry("xesam:totalTracks", dbus::MessageItem::Int32(number_of_tracks as i32)));
+        }
+        if let Some(duration) = track.duration {
+            entries.push(common::dbus_dict_entry("mpris:length", dbus::MessageItem::Int64(duration as i64 * 1000)));
+        }
+
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+
+    // MPRIS times are in microseconds
+    fn property(&self, name: &str) -> dbus::MessageItem {
+        match name {
+            "PlaybackStatus" => self.mpris_status().into(),
+            "Position" => dbus::MessageItem::Int64(self.position as i64 * 1000),
+            "Metadata" => self.metadata(),
+            "LoopStatus" => "None".into(),
+            "Shuffle" => dbus::MessageItem::Bool(false),
+            "Rate" => dbus::MessageItem::Double(1.0),
+            // The Can* properties
+            _ => dbus::MessageItem::Bool(true),
+        }
+    }
+
+    fn properties(&self, names: &[&str]) -> dbus::MessageItem {
+        let entries = names.iter().map(|name| {
+            common::dbus_dict_entry(name, self.property(name))
+        }).collect();
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+}
+
+static MPRIS_PROPERTIES: [&'static str; 11] = ["PlaybackStatus", "Position", "Metadata", "LoopStatus", "Shuffle", "Rate",
+                                               "CanPlay", "CanPause", "CanGoNext", "CanGoPrevious", "CanControl"];
+
+// A local player the remote device (e.g. a car stereo) can show and control over AVRCP while
+// this side is the audio source. BlueZ expects an MPRIS player, so that's what gets exported.
+pub trait PlayerTarget {
+    fn get_object_path(&self) -> &str {
+        "/io/bluezrs/player"
+    }
+
+    fn play(&self);
+    fn pause(&self);
+    fn stop(&self);
+    fn next(&self);
+    fn previous(&self);
+}
+
+impl fmt::Debug for PlayerTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "PlayerTarget(object_path: \"{}\")", self.get_object_path())
+    }
+}
+
+type SharedTargetT = Rc<Box<PlayerTarget>>;
+type SharedStateT = Rc<RefCell<PlayerState>>;
+type TargetCallT = fn(&PlayerTarget);
+
+#[derive(Copy, Clone, Default, Debug)]
+struct TData;
+impl dbus::tree::DataType for TData {
+    type Tree = ();
+    type ObjectPath = ();
+    type Property = ();
+    type Interface = ();
+    type Method = ();
+    type Signal = ();
+}
+
+fn state_getter(state: &SharedStateT, name: &'static str) -> impl Fn(&mut dbus::arg::IterAppend, &PropInfo<MTFn<TData>, TData>) -> Result<(), MethodErr> {
+    let state = state.clone();
+    move |i, _| {
+        i.append(state.borrow().property(name));
+        Ok(())
+    }
+}
+
+pub struct PlayerTargetManager {
+    conn: super::Connection,
+    adapter_object_path: String,
+    target: SharedTargetT,
+    state: SharedStateT,
+    registration: Registration,
+}
+
+impl PlayerTargetManager {
+    pub fn new(adapter: &Adapter, target: Box<PlayerTarget>) -> PlayerTargetManager {
+        let target: SharedTargetT = Rc::new(target);
+        let state: SharedStateT = Rc::new(RefCell::new(PlayerState::default()));
+
+        let f = dbus::tree::Factory::new_fn();
+
+        let mut iface = f.interface(MPRIS_PLAYER_INTERFACE, ())
+            .add_p(f.property::<&str, _>("PlaybackStatus", ()).on_get(state_getter(&state, "PlaybackStatus")))
+            .add_p(f.property::<i64, _>("Position", ()).on_get(state_getter(&state, "Position")))
+            .add_p(f.property::<dbus::arg::Dict<&str, dbus::arg::Variant<()>, ()>, _>("Metadata", ()).on_get(state_getter(&state, "Metadata")))
+            .add_p(f.property::<&str, _>("LoopStatus", ()).on_get(state_getter(&state, "LoopStatus")))
+            .add_p(f.property::<bool, _>("Shuffle", ()).on_get(state_getter(&state, "Shuffle")))
+            .add_p(f.property::<f64, _>("Rate", ()).on_get(state_getter(&state, "Rate")));
+        for name in &["CanPlay", "CanPause", "CanGoNext", "CanGoPrevious", "CanControl"] {
+            iface = iface.add_p(f.property::<bool, _>(*name, ()).on_get(state_getter(&state, name)));
+        }
+
+        let methods: [(&str, TargetCallT); 5] = [
+            ("Play", |x| x.play()),
+            ("Pause", |x| x.pause()),
+            ("Stop", |x| x.stop()),
+            ("Next", |x| x.next()),
+            ("Previous", |x| x.previous()),
+        ];
+        for &(name, call) in &methods {
+            let target = target.clone();
+            iface = iface.add_m(f.method(name, (), move |m| {
+                call(&**target);
+                Ok(vec![m.msg.method_return()])
+            }));
+        }
+
+        let (play_pause_target, play_pause_state) = (target.clone(), state.clone());
+        iface = iface.add_m(f.method("PlayPause", (), move |m| {
+            let playing = play_pause_state.borrow().status == PlayerStatus::Playing;
+            if playing { play_pause_target.pause(); } else { play_pause_target.play(); }
+            Ok(vec![m.msg.method_return()])
+        }));
+
+        let tree = f.tree(()).add(f.object_path(target.get_object_path().to_string(), ()).introspectable().add(iface));
+
+        let object_path = target.get_object_path().to_string();
+        let adapter_object_path = adapter.object_path().to_string();
+        let registration = Registration::new(adapter.conn(), vec![object_path.clone()], Rc::new(move |msg| tree.handle(msg)), move |conn| {
+            common::dbus_start_call(conn, &adapter_object_path, MEDIA_INTERFACE, "UnregisterPlayer",
+                                    &[dbus::MessageItem::ObjectPath(dbus::Path::new(object_path.clone()).unwrap())], Duration::from_secs(60), |_| Ok(()))
+        });
+
+        PlayerTargetManager {
+            conn: adapter.conn().clone(),
+            adapter_object_path: adapter.object_path().to_string(),
+            target: target,
+            state: state,
+            registration: registration,
+        }
+    }
+
+    pub fn register_player(&self) -> Result<(), BtError> {
+        let player_obj_path = dbus::Path::new(self.target.get_object_path()).unwrap();
+
+        let properties = self.state.borrow().properties(&MPRIS_PROPERTIES);
+        self.registration.register(|| {
+            common::dbus_call_method2(&self.conn, &self.adapter_object_path, MEDIA_INTERFACE, "RegisterPlayer", player_obj_path, properties)
+        })
+    }
+
+    pub fn unregister_player(&self) -> Result<(), BtError> {
+        self.registration.unregister()
+    }
+
+    pub(crate) fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.state.borrow().clone()
+    }
+
+    fn emit(&self, member: &str, items: &[dbus::MessageItem]) -> Result<(), BtError> {
+        if !self.registration.is_registered() {
+            return Ok(());
+        }
+
+        let interface = if member == "Seeked" { MPRIS_PLAYER_INTERFACE } else { "org.freedesktop.DBus.Properties" };
+        let mut signal = try!(dbus::Message::new_signal(self.target.get_object_path(), interface, member).map_err(BtError::DBusInternal));
+        signal.append_items(items);
+        try!(self.conn.send(signal).map_err(|_| BtError::DBusInternal("failed to send a player signal".to_string())));
+        Ok(())
+    }
+
+    fn emit_changed(&self, names: &[&str]) -> Result<(), BtError> {
+        let changed = self.state.borrow().properties(names);
+        self.emit("PropertiesChanged", &[MPRIS_PLAYER_INTERFACE.into(), changed, dbus::MessageItem::Array(vec![], "s".into())])
+    }
+
+    pub fn set_status(&self, status: PlayerStatus) -> Result<(), BtError> {
+        self.state.borrow_mut().status = status;
+        self.emit_changed(&["PlaybackStatus"])
+    }
+
+    // In milliseconds
+    pub fn set_position(&self, position: u32) -> Result<(), BtError> {
+        self.state.borrow_mut().position = position;
+        self.emit("Seeked", &[dbus::MessageItem::Int64(position as i64 * 1000)])
+    }
+
+    // Also rewinds the position
+    pub fn set_track(&self, track: Track) -> Result<(), BtError> {
+        {
+            let mut state = self.state.borrow_mut();
+            state.track = track;
+            state.position = 0;
+        }
+        self.emit_changed(&["Metadata", "Position"])
+    }
+
+    pub fn handle_message(&self, msg: &dbus::Message) -> bool {
+        self.registration.handle_message(msg)
+    }
+
+    // Also serves the other objects registered on the connection
+    pub fn serve(&self, cb: Option<&Fn() -> bool>) {
+        self.conn.registry().serve(cb)
+    }
+}
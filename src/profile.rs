@@ -0,0 +1,329 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use dbus;
+
+use agent::AgentError;
+use common;
+use device::Device;
+use error::BtError;
+use registry::Registration;
+
+pub static PROFILE_INTERFACE: &'static str = "org.bluez.Profile1";
+pub static PROFILE_MANAGER_INTERFACE: &'static str = "org.bluez.ProfileManager1";
+pub static PROFILE_MANAGER_OBJ_PATH: &'static str = "/org/bluez";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileRole {
+    Client,
+    Server,
+}
+
+impl ProfileRole {
+    fn to_str(self) -> &'static str {
+        match self {
+            ProfileRole::Client => "client",
+            ProfileRole::Server => "server",
+        }
+    }
+}
+
+// The RegisterProfile options, unset ones are left to BlueZ
+#[derive(Clone, Debug, Default)]
+pub struct ProfileOptions {
+    pub name: Option<String>,
+    pub service: Option<String>,
+    pub role: Option<ProfileRole>,
+    pub channel: Option<u16>,
+    pub psm: Option<u16>,
+    pub require_authentication: Option<bool>,
+    pub require_authorization: Option<bool>,
+    pub auto_connect: Option<bool>,
+    pub service_record: Option<String>,
+    pub version: Option<u16>,
+    pub features: Option<u16>,
+}
+
+impl ProfileOptions {
+    fn to_message_item(&self) -> dbus::MessageItem {
+        let mut entries = Vec::new();
+        if let Some(ref name) = self.name {
+            entries.push(common::dbus_dict_entry("Name", name.as_str()));
+        }
+        if let Some(ref service) = self.service {
+            entries.push(common::dbus_dict_entry("Service", service.as_str()));
+        }
+        if let Some(role) = self.role {
+            entries.push(common::dbus_dict_entry("Role", role.to_str()));
+        }
+        if let Some(channel) = self.channel {
+            entries.push(common::dbus_dict_entry("Channel", channel));
+        }
+        if let Some(psm) = self.psm {
+            entries.push(common::dbus_dict_entry("PSM", psm));
+        }
+        if let Some(require_authentication) = self.require_authentication {
+            entries.push(common::dbus_dict_entry("RequireAuthentication", require_authentication));
+        }
+        if let Some(require_authorization) = self.require_authorization {
+            entries.push(common::dbus_dict_entry("RequireAuthorization", require_authorization));
+        }
+        if let Some(auto_connect) = self.auto_connect {
+            entries.push(common::dbus_dict_entry("AutoConnect", auto_connect));
+        }
+        if let Some(ref service_record) = self.service_record {
+            entries.push(common::dbus_dict_entry("ServiceRecord", service_record.as_str()));
+        }
+        if let Some(version) = self.version {
+            entries.push(common::dbus_dict_entry("Version", version));
+        }
+        if let Some(features) = self.features {
+            entries.push(common::dbus_dict_entry("Features", features));
+        }
+
+        dbus::MessageItem::Array(entries, "{sv}".into())
+    }
+}
+
+// The fd properties of NewConnection
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionProperties {
+    pub version: Option<u16>,
+    pub features: Option<u16>,
+}
+
+impl ConnectionProperties {
+    fn new(props_map: BTreeMap<String, dbus::MessageItem>) -> ConnectionProperties {
+        ConnectionProperties {
+            version: props_map.get("Version").and_then(|x| x.inner().ok()),
+            features: props_map.get("Features").and_then(|x| x.inner().ok()),
+        }
+    }
+}
+
+// Errors are reported to BlueZ the same way as agent errors (org.bluez.Error.Rejected etc.)
+pub trait Profile {
+    fn get_object_path(&self) -> &str {
+        "/io/bluezrs/profile1"
+    }
+
+    fn get_uuid(&self) -> &str;
+
+    fn get_options(&self) -> ProfileOptions {
+        ProfileOptions::default()
+    }
+
+    // The profile owns fd, which is closed when dropped (use into_fd() to keep it)
+    fn new_connection(&self, device: Device, fd: dbus::OwnedFd, properties: ConnectionProperties) -> Result<(), AgentError>;
+    fn request_disconnection(&self, device: Device) -> Result<(), AgentError>;
+    fn release(&self);
+}
+
+impl fmt::Debug for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Profile(object_path: \"{}\", uuid: \"{}\")", self.get_object_path(), self.get_uuid())
+    }
+}
+
+type SharedProfileT = Rc<Box<Profile>>;
+
+#[derive(Copy, Clone, Default, Debug)]
+struct TData;
+impl dbus::tree::DataType for TData {
+    type Tree = ();
+    type ObjectPath = SharedProfileT;
+    type Property = ();
+    type Interface = ();
+    type Method = Option<super::Connection>;
+    type Signal = ();
+}
+
+pub struct ProfileManager {
+    conn: super::Connection,
+    profile: SharedProfileT,
+    registration: Registration,
+}
+
+impl ProfileManager {
+    pub fn new(conn: &super::Connection, profile: Box<Profile>) -> ProfileManager {
+        let profile = Rc::new(profile);
+
+        let f = dbus::tree::Factory::new_fn::<TData>();
+
+        let tree = f.tree(()).add(
+            f.object_path(profile.get_object_path().to_string(), profile.clone()).introspectable().add(
+                f.interface(PROFILE_INTERFACE, ())
+                    .add_m(
+                        f.method("NewConnection", Some(conn.clone()), move |m| {
+                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
+                            let profile: &SharedProfileT = m.path.get_data();
+
+                            // Taken from the items, since they own the passed fd
+                            let mut items = m.msg.get_items().into_iter();
+                            let (device_obj_path, fd, props) = match (items.next(), items.next(), items.next()) {
+                                (Some(dbus::MessageItem::ObjectPath(path)), Some(dbus::MessageItem::UnixFd(fd)), Some(dbus::MessageItem::Array(props, _))) => (path, fd, props),
+                                _ => return Err(dbus::tree::MethodErr::no_arg()),
+                            };
+
+                            let properties = ConnectionProperties::new(common::dbus_props_to_map(&props));
+                            match profile.new_connection(Device::new(conn, &device_obj_path), fd, properties) {
+                                Ok(_) => Ok(vec![m.msg.method_return()]),
+                                Err(e) => Err(e.method_err()),
+                            }
+                        }).in_arg(("device", "o")).in_arg(("fd", "h")).in_arg(("fd_properties", "a{sv}"))
+                    )
+                    .add_m(
+                        f.method("RequestDisconnection", Some(conn.clone()), move |m| {
+                            let conn = (m.method.get_data() as &Option<super::Connection>).as_ref().unwrap();
+                            let profile: &SharedProfileT = m.path.get_data();
+
+                            let device_obj_path: dbus::Path = try!(m.msg.get1().ok_or_else(dbus::tree::MethodErr::no_arg));
+                            match profile.request_disconnection(Device::new(conn, &device_obj_path)) {
+                                Ok(_) => Ok(vec![m.msg.method_return()]),
+                                Err(e) => Err(e.method_err()),
+                            }
+                        }).in_arg(("device", "o"))
+                    )
+                    .add_m(
+                        f.method("Release", None, move |m| {
+                            let profile: &SharedProfileT = m.path.get_data();
+                            profile.release();
+                            Ok(vec![m.msg.method_return()])
+                        })
+                    )
+        ));
+
+        let object_path = profile.get_object_path().to_string();
+        let registration = Registration::new(conn, vec![object_path.clone()], Rc::new(move |msg| tree.handle(msg)), move |conn| {
+            common::dbus_start_call(conn, PROFILE_MANAGER_OBJ_PATH, PROFILE_MANAGER_INTERFACE, "UnregisterProfile",
+                                    &[dbus::MessageItem::ObjectPath(dbus::Path::new(object_path.clone()).unwrap())], Duration::from_secs(60), |_| Ok(()))
+        });
+
+        ProfileManager { conn: conn.clone(), profile: profile, registration: registration }
+    }
+
+    pub fn register_profile(&self) -> Result<(), BtError> {
+        let profile_obj_path = dbus::Path::new(self.profile.get_object_path()).unwrap();
+
+        self.registration.register(|| {
+            common::dbus_call_method3(&self.conn, PROFILE_MANAGER_OBJ_PATH, PROFILE_MANAGER_INTERFACE, "RegisterProfile",
+                                      profile_obj_path, self.profile.get_uuid(), self.profile.get_options().to_message_item())
+        })
+    }
+
+    pub fn unregister_profile(&self) -> Result<(), BtError> {
+        self.registration.unregister()
+    }
+
+    pub(crate) fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    pub fn handle_message(&self, msg: &dbus::Message) -> bool {
+        self.registration.handle_message(msg)
+    }
+
+    // Also serves the other objects registered on the connection
+    pub fn serve(&self, cb: Option<&Fn() -> bool>) {
+        self.conn.registry().serve(cb)
+    }
+}
+
+type IncomingT = Rc<RefCell<Option<(Device, dbus::OwnedFd)>>>;
+
+// Every single connection profile gets its own object, so several can be registered at once
+static NEXT_SINGLE_PROFILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Keeps the fd of the first NewConnection, for the modules which take over one link (serial, hfp)
+struct SingleConnectionProfile {
+    object_path: String,
+    uuid: String,
+    options: ProfileOptions,
+    incoming: IncomingT,
+}
+
+impl Profile for SingleConnectionProfile {
+    fn get_object_path(&self) -> &str {
+        &self.object_path
+    }
+
+    fn get_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn get_options(&self) -> ProfileOptions {
+        self.options.clone()
+    }
+
+    fn new_connection(&self, device: Device, fd: dbus::OwnedFd, _properties: ConnectionProperties) -> Result<(), AgentError> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_some() {
+            return Err(AgentError::Rejected);
+        }
+        *incoming = Some((device, fd));
+        Ok(())
+    }
+
+    fn request_disconnection(&self, _device: Device) -> Result<(), AgentError> {
+        Ok(())
+    }
+
+    fn release(&self) {}
+}
+
+// The link handed over to a single connection profile. The profile stays registered as long as
+// the manager is kept.
+pub(crate) type SingleConnectionT = (Device, dbus::OwnedFd, ProfileManager);
+
+// Registers the profile at "<object_path_prefix><n>"
+fn register_single_profile(conn: &super::Connection, object_path_prefix: &str, uuid: &str, options: ProfileOptions) -> Result<(ProfileManager, IncomingT), BtError> {
+    let incoming: IncomingT = Rc::new(RefCell::new(None));
+    let profile = SingleConnectionProfile {
+        object_path: format!("{}{}", object_path_prefix, NEXT_SINGLE_PROFILE_ID.fetch_add(1, Ordering::SeqCst)),
+        uuid: uuid.to_string(),
+        options: options,
+        incoming: incoming.clone(),
+    };
+    let manager = ProfileManager::new(conn, Box::new(profile));
+    try!(manager.register_profile());
+    Ok((manager, incoming))
+}
+
+// Registers the profile and waits for the first device to connect
+pub(crate) fn accept_single(conn: &super::Connection, object_path_prefix: &str, uuid: &str, options: ProfileOptions,
+                            timeout: Option<Duration>) -> Result<SingleConnectionT, BtError> {
+    let (manager, incoming) = try!(register_single_profile(conn, object_path_prefix, uuid, options));
+
+    let now = Instant::now();
+    while conn.registry().is_connected() {
+        conn.registry().process(100);
+
+        if let Some((device, fd)) = incoming.borrow_mut().take() {
+            return Ok((device, fd, manager));
+        }
+        if timeout.map(|x| now.elapsed() >= x).unwrap_or(false) {
+            break;
+        }
+    }
+
+    Err(BtError::Timeout)
+}
+
+// Registers the profile and connects the remote_uuid profile of the device to it
+pub(crate) fn connect_single(device: &Device, object_path_prefix: &str, uuid: &str, remote_uuid: &str,
+                             options: ProfileOptions) -> Result<SingleConnectionT, BtError> {
+    let (manager, incoming) = try!(register_single_profile(device.conn(), object_path_prefix, uuid, options));
+
+    // BlueZ hands over the fd with NewConnection before ConnectProfile returns, waiting serves the profile
+    try!(device.start_connect_profile(remote_uuid).wait());
+
+    let incoming = incoming.borrow_mut().take();
+    match incoming {
+        Some((device, fd)) => Ok((device, fd, manager)),
+        None => Err(BtError::DBusInternal(format!("no connection was passed to the profile {}", manager.profile.get_object_path()))),
+    }
+}
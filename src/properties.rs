@@ -0,0 +1,175 @@
+use std::time::{Duration, Instant};
+
+use dbus;
+
+use common;
+use error::BtError;
+use registry::SignalWatch;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropertyValue {
+    Bool(bool),
+    Byte(u8),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Str(String),
+    ObjectPath(String),
+    StrArray(Vec<String>),
+    Bytes(Vec<u8>),
+    Other(dbus::MessageItem),
+}
+
+impl PropertyValue {
+    fn new(item: dbus::MessageItem) -> PropertyValue {
+        match item {
+            dbus::MessageItem::Bool(val) => PropertyValue::Bool(val),
+            dbus::MessageItem::Byte(val) => PropertyValue::Byte(val),
+            dbus::MessageItem::Int16(val) => PropertyValue::Int16(val),
+            dbus::MessageItem::UInt16(val) => PropertyValue::UInt16(val),
+            dbus::MessageItem::Int32(val) => PropertyValue::Int32(val),
+            dbus::MessageItem::UInt32(val) => PropertyValue::UInt32(val),
+            dbus::MessageItem::Str(val) => PropertyValue::Str(val),
+            dbus::MessageItem::ObjectPath(val) => PropertyValue::ObjectPath(val.to_string()),
+            dbus::MessageItem::Array(ref items, ref sig) if &**sig == "s" => {
+                PropertyValue::StrArray(items.iter().filter_map(|x| (x.inner() as Result<&str, ()>).ok()).map(|x| x.to_string()).collect())
+            }
+            dbus::MessageItem::Array(ref items, ref sig) if &**sig == "y" => {
+                PropertyValue::Bytes(items.iter().filter_map(|x| x.inner().ok()).collect())
+            }
+            item => PropertyValue::Other(item),
+        }
+    }
+}
+
+impl From<bool> for PropertyValue {
+    fn from(val: bool) -> PropertyValue { PropertyValue::Bool(val) }
+}
+
+impl From<u8> for PropertyValue {
+    fn from(val: u8) -> PropertyValue { PropertyValue::Byte(val) }
+}
+
+impl From<i16> for PropertyValue {
+    fn from(val: i16) -> PropertyValue { PropertyValue::Int16(val) }
+}
+
+impl From<u16> for PropertyValue {
+    fn from(val: u16) -> PropertyValue { PropertyValue::UInt16(val) }
+}
+
+impl From<i32> for PropertyValue {
+    fn from(val: i32) -> PropertyValue { PropertyValue::Int32(val) }
+}
+
+impl From<u32> for PropertyValue {
+    fn from(val: u32) -> PropertyValue { PropertyValue::UInt32(val) }
+}
+
+impl<'a> From<&'a str> for PropertyValue {
+    fn from(val: &'a str) -> PropertyValue { PropertyValue::Str(val.to_string()) }
+}
+
+#[derive(Clone, Debug)]
+pub struct PropertiesChangedEvent {
+    pub object_path: String,
+    pub interface: String,
+    pub changed: Vec<(String, PropertyValue)>,
+    pub invalidated: Vec<String>,
+}
+
+impl PropertiesChangedEvent {
+    pub fn get(&self, name: &str) -> Option<&PropertyValue> {
+        self.changed.iter().find(|x| x.0 == name).map(|x| &x.1)
+    }
+}
+
+pub struct PropertiesWatcher {
+    conn: super::Connection,
+    object_path: String,
+    interface: String,
+    watch: SignalWatch,
+    timeout: Option<Duration>,
+}
+
+impl PropertiesWatcher {
+    // Ends the iteration when no change arrives within timeout
+    pub fn timeout(mut self, timeout: Duration) -> PropertiesWatcher {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+}
+
+impl Iterator for PropertiesWatcher {
+    type Item = PropertiesChangedEvent;
+
+    fn next(&mut self) -> Option<PropertiesChangedEvent> {
+        let now = Instant::now();
+
+        while self.conn.registry().is_connected() {
+            if let Some(ref s) = self.watch.next_signal(100) {
+                if s.path().map(|x| *x == *self.object_path).unwrap_or(false) {
+                    if let Some((props_map, invalidated)) = common::dbus_properties_changed_with_invalidated(s, &self.interface) {
+                        return Some(PropertiesChangedEvent {
+                            object_path: self.object_path.clone(),
+                            interface: self.interface.clone(),
+                            changed: props_map.into_iter().map(|(k, v)| (k, PropertyValue::new(v))).collect(),
+                            invalidated: invalidated,
+                        });
+                    }
+                }
+            }
+
+            if self.timeout.map(|x| now.elapsed() >= x).unwrap_or(false) {
+                break;
+            }
+        }
+
+        None
+    }
+}
+
+// Yields the PropertiesChanged signals of any BlueZ object, e.g. ("/org/bluez/hci0", "org.bluez.Adapter1")
+pub fn watch_properties(conn: &super::Connection, object_path: &str, interface: &str) -> Result<PropertiesWatcher, BtError> {
+    let filter = format!("sender='{}',path='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='{}'",
+                         common::SERVICE_NAME, object_path, interface);
+    Ok(PropertiesWatcher {
+        conn: conn.clone(),
+        object_path: object_path.to_string(),
+        interface: interface.to_string(),
+        watch: try!(SignalWatch::new(conn, &[filter])),
+        timeout: None,
+    })
+}
+
+// Blocks until the property has the expected value, which may already be the case
+pub fn wait_for_property<T>(conn: &super::Connection,
+                            object_path: &str,
+                            interface: &str,
+                            name: &str,
+                            expected: T,
+                            timeout: Duration) -> Result<(), BtError> where T: Into<PropertyValue> {
+    let expected = expected.into();
+    let mut watcher = try!(watch_properties(conn, object_path, interface));
+
+    let current = try!(common::dbus_get_property(conn, object_path, interface, name));
+    if PropertyValue::new(current) == expected {
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    while let Some(remaining) = timeout.checked_sub(now.elapsed()) {
+        watcher.timeout = Some(remaining);
+        match watcher.next() {
+            Some(event) => if event.get(name) == Some(&expected) { return Ok(()); },
+            None => break,
+        }
+    }
+
+    Err(BtError::Timeout)
+}
@@ -0,0 +1,103 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use device::{self, Device, RetryPolicy};
+use discovery::StopHandle;
+use error::BtError;
+use properties::{self, PropertyValue};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReconnectStatus {
+    Connected,
+    Disconnected,
+    Reconnecting(u32),
+    // The attempt number and the error message
+    AttemptFailed(u32, String),
+    GaveUp,
+}
+
+pub struct ReconnectSupervisor {
+    device: Device,
+    policy: RetryPolicy,
+    stop_handle: StopHandle,
+}
+
+impl ReconnectSupervisor {
+    pub fn new(device: &Device, policy: RetryPolicy) -> ReconnectSupervisor {
+        ReconnectSupervisor { device: device.clone(), policy: policy, stop_handle: StopHandle::new() }
+    }
+
+    pub fn stop_handle(mut self, stop_handle: &StopHandle) -> ReconnectSupervisor {
+        self.stop_handle = stop_handle.clone();
+        self
+    }
+
+    // Keeps the device connected until the stop handle is triggered. Returns the last connect
+    // error once all attempts of the policy failed in a row.
+    pub fn run<F>(&self, mut f: F) -> Result<(), BtError> where F: FnMut(&ReconnectStatus) {
+        let mut watcher = try!(properties::watch_properties(self.device.conn(), self.device.object_path(), device::DEVICE_INTERFACE))
+            .timeout(Duration::from_millis(500));
+
+        let mut connected = try!(self.device.get_properties()).connected;
+        if connected {
+            f(&ReconnectStatus::Connected);
+        }
+
+        while !self.stop_handle.is_stopped() {
+            if !connected {
+                f(&ReconnectStatus::Disconnected);
+                connected = try!(self.reconnect(&mut f));
+                continue;
+            }
+
+            match watcher.next() {
+                Some(event) if event.get("Connected") == Some(&PropertyValue::Bool(false)) => connected = false,
+                Some(_) => {}
+                None if !self.device.conn().registry().is_connected() => {
+                    return Err(BtError::DBusInternal("connection closed".to_string()));
+                }
+                // Just the timeout
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns false if the stop handle was triggered before the device got connected
+    fn reconnect<F>(&self, f: &mut F) -> Result<bool, BtError> where F: FnMut(&ReconnectStatus) {
+        let mut attempt = 1;
+        loop {
+            f(&ReconnectStatus::Reconnecting(attempt));
+
+            match self.device.connect() {
+                Ok(_) => {
+                    f(&ReconnectStatus::Connected);
+                    return Ok(true);
+                }
+                Err(e) => {
+                    f(&ReconnectStatus::AttemptFailed(attempt, e.to_string()));
+                    if attempt >= self.policy.max_attempts {
+                        f(&ReconnectStatus::GaveUp);
+                        return Err(e);
+                    }
+                }
+            }
+
+            // Sleep in small steps so a stop doesn't have to wait for the whole backoff
+            let delay = self.policy.delay(attempt);
+            let now = Instant::now();
+            while now.elapsed() < delay {
+                if self.stop_handle.is_stopped() {
+                    return Ok(false);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            if self.stop_handle.is_stopped() {
+                return Ok(false);
+            }
+
+            attempt += 1;
+        }
+    }
+}
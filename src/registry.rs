@@ -0,0 +1,627 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use std::os::unix::io::RawFd;
+#[cfg(feature = "async")]
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(feature = "async")]
+use std::thread;
+
+use dbus;
+#[cfg(feature = "async")]
+use libc;
+
+use error::BtError;
+use pending::PendingCall;
+
+// Returns the replies for a method call, or None when the call isn't handled by the object
+pub type ObjectHandlerT = Rc<Fn(&dbus::Message) -> Option<Vec<dbus::Message>>>;
+
+struct PendingReply {
+    reply: Option<dbus::Message>,
+    // Woken when the reply is read
+    waker: Option<Waker>,
+}
+
+// At most this many signals are queued per subscription, the oldest ones are dropped beyond that
+const SIGNAL_QUEUE_LEN: usize = 256;
+
+struct Subscription {
+    id: u32,
+    match_rules: Vec<String>,
+    signals: VecDeque<Rc<dbus::Message>>,
+}
+
+fn push_signal(queue: &mut VecDeque<Rc<dbus::Message>>, signal: Rc<dbus::Message>) {
+    if queue.len() >= SIGNAL_QUEUE_LEN {
+        queue.pop_front();
+    }
+    queue.push_back(signal);
+}
+
+// The objects exported on a connection (agents, profiles, advertisements, GATT applications...).
+// Every Connection clone shares the same registry, so all of them can be served by one loop.
+pub struct ObjectRegistry {
+    dbus: Rc<dbus::Connection>,
+    objects: RefCell<BTreeMap<String, ObjectHandlerT>>,
+    // The method calls sent with send(), by serial, and their replies once they are read
+    replies: RefCell<BTreeMap<u32, PendingReply>>,
+    // Every signal read goes to the subscriptions with a matching rule, or to unclaimed_signals
+    subscriptions: RefCell<Vec<Subscription>>,
+    next_subscription_id: Cell<u32>,
+    unclaimed_signals: RefCell<VecDeque<Rc<dbus::Message>>>,
+    connected: Cell<bool>,
+    // The method call handle() is dispatching, so handlers may keep it to answer later
+    current_call: RefCell<Option<Rc<dbus::Message>>>,
+    #[cfg(feature = "async")]
+    fd_watcher: RefCell<Option<FdWatcher>>,
+}
+
+impl ObjectRegistry {
+    pub(crate) fn new(dbus: Rc<dbus::Connection>) -> ObjectRegistry {
+        ObjectRegistry {
+            dbus: dbus,
+            objects: RefCell::new(BTreeMap::new()),
+            replies: RefCell::new(BTreeMap::new()),
+            subscriptions: RefCell::new(Vec::new()),
+            next_subscription_id: Cell::new(0),
+            unclaimed_signals: RefCell::new(VecDeque::new()),
+            connected: Cell::new(true),
+            current_call: RefCell::new(None),
+            #[cfg(feature = "async")]
+            fd_watcher: RefCell::new(None),
+        }
+    }
+
+    pub fn register(&self, object_path: &str, handler: ObjectHandlerT) -> Result<(), BtError> {
+        if self.is_registered(object_path) {
+            return Err(BtError::DBusInternal(format!("Object path {} is already registered", object_path)));
+        }
+
+        try!(self.dbus.register_object_path(object_path));
+        self.objects.borrow_mut().insert(object_path.to_string(), handler);
+
+        Ok(())
+    }
+
+    // Returns false if nothing was registered at the path
+    pub fn unregister(&self, object_path: &str) -> bool {
+        if self.objects.borrow_mut().remove(object_path).is_some() {
+            self.dbus.unregister_object_path(object_path);
+            return true;
+        }
+        false
+    }
+
+    pub fn is_registered(&self, object_path: &str) -> bool {
+        self.objects.borrow().contains_key(object_path)
+    }
+
+    pub fn object_paths(&self) -> Vec<String> {
+        self.objects.borrow().keys().cloned().collect()
+    }
+
+    // Sends a method call without waiting, its reply is kept for take_reply() once process() reads it
+    pub(crate) fn send(&self, msg: dbus::Message) -> Result<u32, BtError> {
+        let serial = try!(self.dbus.send(msg).map_err(|_| BtError::DBusInternal("failed to send the method call".to_string())));
+        self.replies.borrow_mut().insert(serial, PendingReply { reply: None, waker: None });
+        Ok(serial)
+    }
+
+    pub(crate) fn take_reply(&self, serial: u32) -> Option<dbus::Message> {
+        let mut replies = self.replies.borrow_mut();
+        if replies.get(&serial).map(|x| x.reply.is_some()).unwrap_or(false) {
+            return replies.remove(&serial).and_then(|x| x.reply);
+        }
+        None
+    }
+
+    // The reply of a call nobody waits for any more is dropped when it arrives
+    pub(crate) fn forget_reply(&self, serial: u32) {
+        self.replies.borrow_mut().remove(&serial);
+    }
+
+    // Queues the signals matching one of match_rules until they are taken with next_signal(). The
+    // rules still have to be added to the connection, see SignalWatch.
+    pub(crate) fn subscribe(&self, match_rules: &[String]) -> u32 {
+        let id = self.next_subscription_id.get();
+        self.next_subscription_id.set(id.wrapping_add(1));
+        self.subscriptions.borrow_mut().push(Subscription { id: id, match_rules: match_rules.to_vec(), signals: VecDeque::new() });
+        id
+    }
+
+    pub(crate) fn unsubscribe(&self, id: u32) {
+        self.subscriptions.borrow_mut().retain(|x| x.id != id);
+    }
+
+    pub(crate) fn take_subscribed_signal(&self, id: u32) -> Option<Rc<dbus::Message>> {
+        self.subscriptions.borrow_mut().iter_mut().find(|x| x.id == id).and_then(|x| x.signals.pop_front())
+    }
+
+    // Processes the connection until a signal of the subscription is there, for up to timeout_ms
+    pub(crate) fn next_signal(&self, id: u32, timeout_ms: i32) -> Option<Rc<dbus::Message>> {
+        if let Some(signal) = self.take_subscribed_signal(id) {
+            return Some(signal);
+        }
+
+        let now = Instant::now();
+        let timeout = Duration::from_millis(cmp::max(timeout_ms, 0) as u64);
+        loop {
+            let remaining = timeout.checked_sub(now.elapsed()).unwrap_or_default();
+            self.process(remaining.as_millis() as i32);
+            if let Some(signal) = self.take_subscribed_signal(id) {
+                return Some(signal);
+            }
+            if now.elapsed() >= timeout || !self.is_connected() {
+                return None;
+            }
+        }
+    }
+
+    // The signals read from the connection which no subscription asked for, oldest first
+    pub fn take_signal(&self) -> Option<Rc<dbus::Message>> {
+        self.unclaimed_signals.borrow_mut().pop_front()
+    }
+
+    // Wakes waker once the reply to serial is read, or once there is something to read on the
+    // connection (as nothing else might be reading it), or at deadline
+    #[cfg(feature = "async")]
+    pub(crate) fn wake_on_reply(&self, serial: u32, deadline: Instant, waker: &Waker) {
+        if let Some(pending) = self.replies.borrow_mut().get_mut(&serial) {
+            pending.waker = Some(waker.clone());
+        }
+        self.wake_on_readable(Some(deadline), waker);
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn wake_on_readable(&self, deadline: Option<Instant>, waker: &Waker) {
+        let mut fd_watcher = self.fd_watcher.borrow_mut();
+        if fd_watcher.is_none() {
+            match self.dbus.watch_fds().iter().find(|x| x.readable()) {
+                Some(watch) => *fd_watcher = Some(FdWatcher::new(watch.fd())),
+                None => { waker.wake_by_ref(); return; }
+            }
+        }
+        if let Some(ref fd_watcher) = *fd_watcher {
+            fd_watcher.add(deadline, waker);
+        }
+    }
+
+    // Serves the registered objects and collects the replies to send(), waiting up to timeout_ms
+    // for the first message. Returns how many messages were handled.
+    pub fn process(&self, timeout_ms: i32) -> usize {
+        let mut handled = 0;
+
+        let mut timeout_ms = timeout_ms;
+        loop {
+            match self.dbus.iter(timeout_ms).next() {
+                Some(dbus::ConnectionItem::Nothing) => break,
+                Some(i) => if self.handle(i) { handled += 1 },
+                // Only happens once the connection is closed
+                None => {
+                    self.connected.set(false);
+                    break;
+                }
+            }
+            timeout_ms = 0;
+        }
+
+        handled
+    }
+
+    // False once process() found the connection closed
+    pub fn is_connected(&self) -> bool {
+        self.connected.get()
+    }
+
+    // Processes the connection until cb returns false (it's called at least every 100 ms) or the
+    // connection is closed
+    pub fn serve(&self, cb: Option<&Fn() -> bool>) {
+        while self.is_connected() {
+            self.process(100);
+            if let Some(cb) = cb {
+                if !cb() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // For connections made with Connection::from_dbus, every item read from the connection is
+    // passed here. Returns false if it wasn't for this registry.
+    pub fn handle(&self, item: dbus::ConnectionItem) -> bool {
+        match item {
+            dbus::ConnectionItem::MethodCall(msg) => self.with_call(msg, |msg| self.dispatch(msg)),
+            // Error replies come as MethodReturn too
+            dbus::ConnectionItem::MethodReturn(msg) => {
+                let serial = match msg.get_reply_serial() {
+                    Some(serial) => serial,
+                    None => return false,
+                };
+                let waker = match self.replies.borrow_mut().get_mut(&serial) {
+                    Some(pending) => { pending.reply = Some(msg); pending.waker.take() }
+                    None => return false,
+                };
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                true
+            }
+            dbus::ConnectionItem::Signal(msg) => self.queue_signal(msg),
+            _ => false,
+        }
+    }
+
+    // Returns false if no subscription wanted the signal, it's kept for take_signal() then
+    fn queue_signal(&self, msg: dbus::Message) -> bool {
+        let signal = Rc::new(msg);
+        let mut claimed = false;
+        for subscription in self.subscriptions.borrow_mut().iter_mut() {
+            if subscription.match_rules.iter().any(|x| rule_matches(x, &signal)) {
+                push_signal(&mut subscription.signals, signal.clone());
+                claimed = true;
+            }
+        }
+        if !claimed {
+            push_signal(&mut self.unclaimed_signals.borrow_mut(), signal);
+        }
+        claimed
+    }
+
+    // Runs f with msg as the call returned by current_call()
+    pub(crate) fn with_call<R, F>(&self, msg: dbus::Message, f: F) -> R where F: FnOnce(&dbus::Message) -> R {
+        let call = Rc::new(msg);
+        let previous = self.current_call.replace(Some(call.clone()));
+        let result = f(&call);
+        *self.current_call.borrow_mut() = previous;
+        result
+    }
+
+    // The call being handled if it's msg, for replying to it after the handler returned. None if
+    // it was passed to dispatch() by reference.
+    pub(crate) fn current_call(&self, msg: &dbus::Message) -> Option<Rc<dbus::Message>> {
+        self.current_call.borrow().as_ref().filter(|x| ptr::eq(&***x, msg)).cloned()
+    }
+
+    // Hands the method call to the object registered at its path and sends the replies.
+    // Returns false if no object handled it.
+    pub fn dispatch(&self, msg: &dbus::Message) -> bool {
+        let handler = match msg.path() {
+            // Cloned out of the map, so handlers may (un)register objects themselves
+            Some(path) => match self.objects.borrow().get(&*path) {
+                Some(handler) => handler.clone(),
+                None => return false,
+            },
+            None => return false,
+        };
+
+        match handler(msg) {
+            Some(replies) => {
+                for reply in replies { let _ = self.dbus.send(reply); }
+                true
+            }
+            // The path is registered with libdbus, so it won't answer the call itself
+            None => {
+                let text = format!("{} isn't handled here", msg.path().map(|x| x.to_string()).unwrap_or_default());
+                if let Some(reply) = dbus::Message::new_error(msg, "org.freedesktop.DBus.Error.UnknownObject", &text) {
+                    let _ = self.dbus.send(reply);
+                }
+                false
+            }
+        }
+    }
+}
+
+type UnregisterT = Box<Fn(&super::Connection) -> PendingCall<()>>;
+
+// Orders the registrations of all connections, see Registration::registered_seq()
+static REGISTRATION_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// Objects exported on a connection and registered with a BlueZ manager (agents, profiles, media
+// endpoints...) with one handler for all of them. They're unexported if the registration call
+// fails, and unregistered from both when dropped, in case unregister() wasn't called.
+pub(crate) struct Registration {
+    conn: super::Connection,
+    object_paths: RefCell<Vec<String>>,
+    handler: ObjectHandlerT,
+    // The Unregister* call of the manager
+    unregister: UnregisterT,
+    registered: Cell<bool>,
+    registered_seq: Cell<usize>,
+}
+
+impl Registration {
+    pub(crate) fn new<F>(conn: &super::Connection, object_paths: Vec<String>, handler: ObjectHandlerT, unregister: F) -> Registration
+        where F: Fn(&super::Connection) -> PendingCall<()> + 'static {
+        Registration {
+            conn: conn.clone(),
+            object_paths: RefCell::new(object_paths),
+            handler: handler,
+            unregister: Box::new(unregister),
+            registered: Cell::new(false),
+            registered_seq: Cell::new(0),
+        }
+    }
+
+    // Exports the objects, then makes the Register* call of the manager
+    pub(crate) fn register<F>(&self, register: F) -> Result<(), BtError> where F: FnOnce() -> Result<(), BtError> {
+        let object_paths = self.object_paths.borrow().clone();
+        for (i, object_path) in object_paths.iter().enumerate() {
+            if let Err(e) = self.conn.registry().register(object_path, self.handler.clone()) {
+                for object_path in &object_paths[..i] { self.conn.registry().unregister(object_path); }
+                return Err(e);
+            }
+        }
+        if let Err(e) = register() {
+            self.unexport();
+            return Err(e);
+        }
+        self.registered.set(true);
+        self.registered_seq.set(REGISTRATION_SEQ.fetch_add(1, Ordering::SeqCst));
+
+        Ok(())
+    }
+
+    // Makes the Unregister* call of the manager and unexports the objects once it's answered. The
+    // connection is processed while waiting, so calls BlueZ makes in the meantime (e.g. Release)
+    // are still served.
+    pub(crate) fn unregister(&self) -> Result<(), BtError> {
+        try!((self.unregister)(&self.conn).wait());
+        self.unexport();
+        self.registered.set(false);
+        Ok(())
+    }
+
+    pub(crate) fn is_registered(&self) -> bool {
+        self.registered.get()
+    }
+
+    // Grows with every register() in the process, for undoing registrations in reverse order
+    pub(crate) fn registered_seq(&self) -> usize {
+        self.registered_seq.get()
+    }
+
+    pub(crate) fn conn(&self) -> &super::Connection {
+        &self.conn
+    }
+
+    // For objects added and removed while registered, e.g. batteries of a provider
+    pub(crate) fn add_object(&self, object_path: &str) -> Result<(), BtError> {
+        if self.registered.get() {
+            try!(self.conn.registry().register(object_path, self.handler.clone()));
+        }
+        self.object_paths.borrow_mut().push(object_path.to_string());
+        Ok(())
+    }
+
+    pub(crate) fn remove_object(&self, object_path: &str) {
+        self.object_paths.borrow_mut().retain(|x| x != object_path);
+        if self.registered.get() {
+            self.conn.registry().unregister(object_path);
+        }
+    }
+
+    // Handles a method call of the objects and sends the replies, for messages read elsewhere
+    pub(crate) fn handle_message(&self, msg: &dbus::Message) -> bool {
+        match (self.handler)(msg) {
+            Some(replies) => {
+                for reply in replies { let _ = self.conn.send(reply); }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn unexport(&self) {
+        for object_path in self.object_paths.borrow().iter() {
+            self.conn.registry().unregister(object_path);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if self.registered.get() && self.unregister().is_err() {
+            self.unexport();
+        }
+    }
+}
+
+// Whether msg matches a match rule like "type='signal',interface='org.bluez.Device1'". The sender
+// isn't compared, since signals carry the unique name of the sender rather than the well-known one.
+fn rule_matches(rule: &str, msg: &dbus::Message) -> bool {
+    rule.split(',').all(|part| {
+        let mut pair = part.splitn(2, '=');
+        let (key, val) = match (pair.next(), pair.next()) {
+            (Some(key), Some(val)) => (key.trim(), val.trim().trim_matches('\'')),
+            _ => return true,
+        };
+        match key {
+            "type" => val == "signal",
+            "interface" => msg.interface().map(|x| &*x == val).unwrap_or(false),
+            "member" => msg.member().map(|x| &*x == val).unwrap_or(false),
+            "path" => msg.path().map(|x| &*x == val).unwrap_or(false),
+            "path_namespace" => msg.path().map(|x| val == "/" || &*x == val || x.starts_with(&format!("{}/", val))).unwrap_or(false),
+            "arg0" => msg.get1::<&str>() == Some(val),
+            _ => true,
+        }
+    })
+}
+
+// Queues the signals matching its rules whoever processes the connection, so that watches and the
+// loops serving objects can share a connection without taking each other's signals. The rules are
+// added to the connection for as long as it's alive.
+pub struct SignalWatch {
+    conn: super::Connection,
+    match_rules: Vec<String>,
+    id: u32,
+}
+
+impl SignalWatch {
+    pub fn new(conn: &super::Connection, match_rules: &[String]) -> Result<SignalWatch, BtError> {
+        let mut watch = SignalWatch { conn: conn.clone(), match_rules: Vec::new(), id: conn.registry().subscribe(match_rules) };
+        // Dropping it removes the rules added so far
+        for rule in match_rules {
+            try!(conn.add_match(rule));
+            watch.match_rules.push(rule.clone());
+        }
+        Ok(watch)
+    }
+
+    pub fn conn(&self) -> &super::Connection {
+        &self.conn
+    }
+
+    // Processes the connection until a signal is there, for up to timeout_ms
+    pub fn next_signal(&self, timeout_ms: i32) -> Option<Rc<dbus::Message>> {
+        self.conn.registry().next_signal(self.id, timeout_ms)
+    }
+
+    // The signals queued so far, without reading the connection
+    pub fn take_queued(&self) -> Option<Rc<dbus::Message>> {
+        self.conn.registry().take_subscribed_signal(self.id)
+    }
+
+    // Same as dropping it, but returns the first error removing the rules
+    pub fn close(mut self) -> Result<(), BtError> {
+        self.remove()
+    }
+
+    fn remove(&mut self) -> Result<(), BtError> {
+        self.conn.registry().unsubscribe(self.id);
+        let mut result = Ok(());
+        for rule in self.match_rules.drain(..) {
+            if let Err(err) = self.conn.remove_match(&rule) {
+                if result.is_ok() {
+                    result = Err(err.into());
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Drop for SignalWatch {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+impl fmt::Debug for SignalWatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "SignalWatch({:?})", self.match_rules)
+    }
+}
+
+impl fmt::Debug for ObjectRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "ObjectRegistry({:?})", self.object_paths())
+    }
+}
+
+#[cfg(feature = "async")]
+type FdWatcherStateT = (Mutex<FdWatcherState>, Condvar);
+
+#[cfg(feature = "async")]
+struct FdWatcherState {
+    wakers: Vec<(Waker, Option<Instant>)>,
+    stopped: bool,
+}
+
+// Polls the bus connection on its own thread while tasks wait for it, since the connection itself
+// is only read by whoever processes it
+#[cfg(feature = "async")]
+struct FdWatcher {
+    state: Arc<FdWatcherStateT>,
+}
+
+#[cfg(feature = "async")]
+impl FdWatcher {
+    fn new(fd: RawFd) -> FdWatcher {
+        let state = Arc::new((Mutex::new(FdWatcherState { wakers: Vec::new(), stopped: false }), Condvar::new()));
+        let thread_state = state.clone();
+        thread::spawn(move || FdWatcher::run(fd, &thread_state));
+        FdWatcher { state: state }
+    }
+
+    fn add(&self, deadline: Option<Instant>, waker: &Waker) {
+        let mut state = self.state.0.lock().unwrap_or_else(|e| e.into_inner());
+        state.wakers.retain(|x| !x.0.will_wake(waker));
+        state.wakers.push((waker.clone(), deadline));
+        self.state.1.notify_one();
+    }
+
+    fn run(fd: RawFd, shared: &FdWatcherStateT) {
+        loop {
+            {
+                let mut state = shared.0.lock().unwrap_or_else(|e| e.into_inner());
+                while state.wakers.is_empty() && !state.stopped {
+                    state = shared.1.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                if state.stopped {
+                    return;
+                }
+            }
+
+            // Wakes up every 100 ms for the deadlines and the stop flag
+            let mut pfd = libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 };
+            let readable = unsafe { libc::poll(&mut pfd, 1, 100) } > 0;
+
+            let now = Instant::now();
+            let wakers = {
+                let mut state = shared.0.lock().unwrap_or_else(|e| e.into_inner());
+                if readable {
+                    state.wakers.drain(..).collect()
+                } else {
+                    let (expired, waiting) = state.wakers.drain(..).partition(|x| x.1.map(|x| x <= now).unwrap_or(false));
+                    state.wakers = waiting;
+                    expired
+                }
+            };
+            for (waker, _) in wakers {
+                waker.wake();
+            }
+
+            // A closed connection stays readable, so don't spin on it
+            if pfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for FdWatcher {
+    fn drop(&mut self) {
+        self.state.0.lock().unwrap_or_else(|e| e.into_inner()).stopped = true;
+        self.state.1.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rule_matches;
+    use dbus;
+
+    #[test]
+    fn test_rule_matches() {
+        let msg = dbus::Message::new_signal("/org/bluez/hci0/dev_00_11_22_33_44_55", "org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .unwrap().append1("org.bluez.Device1");
+
+        assert!(rule_matches("type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'", &msg));
+        assert!(rule_matches("type='signal',sender='org.bluez',path_namespace='/org/bluez/hci0',arg0='org.bluez.Device1'", &msg));
+        assert!(rule_matches("type='signal',path='/org/bluez/hci0/dev_00_11_22_33_44_55'", &msg));
+        assert!(!rule_matches("type='signal',path_namespace='/org/bluez/hci1'", &msg));
+        assert!(!rule_matches("type='signal',path_namespace='/org/bluez/hci0/dev_00'", &msg));
+        assert!(!rule_matches("type='signal',interface='org.freedesktop.DBus.ObjectManager'", &msg));
+        assert!(!rule_matches("type='signal',member='InterfacesAdded'", &msg));
+        assert!(!rule_matches("type='signal',arg0='org.bluez.Adapter1'", &msg));
+    }
+}
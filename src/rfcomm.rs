@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use libc;
+
+use address::BtAddress;
+use error::BtError;
+use socket;
+
+const BTPROTO_RFCOMM: libc::c_int = 3;
+
+#[repr(C)]
+struct SockaddrRc {
+    rc_family: libc::sa_family_t,
+    rc_bdaddr: [u8; 6],
+    rc_channel: u8,
+}
+
+// An outgoing RFCOMM connection straight through the kernel, bypassing bluetoothd's profiles.
+// The channel has to be known already (e.g. from the device's SDP record).
+pub struct RfcommStream {
+    address: BtAddress,
+    channel: u8,
+    file: File,
+}
+
+impl RfcommStream {
+    pub fn connect(address: &BtAddress, channel: u8) -> Result<RfcommStream, BtError> {
+        let addr = SockaddrRc {
+            rc_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            rc_bdaddr: address.bytes(),
+            rc_channel: channel,
+        };
+        let file = try!(socket::open(libc::SOCK_STREAM, BTPROTO_RFCOMM));
+        try!(socket::connect(&file, &addr));
+
+        Ok(RfcommStream { address: *address, channel: channel, file: file })
+    }
+
+    pub fn peer_address(&self) -> &BtAddress {
+        &self.address
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    // None blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_RCVTIMEO, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_SNDTIMEO, timeout)
+    }
+}
+
+impl Read for RfcommStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for RfcommStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for RfcommStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,103 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use adapter::Adapter;
+use common;
+use device::{self, Device};
+use error::BtError;
+use monitor::AdvertisementMonitorManager;
+use registry::SignalWatch;
+
+pub struct ScanScheduler<'a> {
+    adapter: Adapter,
+    on_duration: Duration,
+    off_duration: Duration,
+    monitor_manager: Option<&'a AdvertisementMonitorManager>,
+}
+
+impl<'a> ScanScheduler<'a> {
+    pub fn new(adapter: &Adapter, on_duration: Duration, off_duration: Duration) -> ScanScheduler<'a> {
+        ScanScheduler { adapter: adapter.clone(), on_duration: on_duration, off_duration: off_duration, monitor_manager: None }
+    }
+
+    // Monitor calls are served while scheduling; a DeviceFound call ends the off window early
+    pub fn wake_on_monitor(mut self, monitor_manager: &'a AdvertisementMonitorManager) -> ScanScheduler<'a> {
+        self.monitor_manager = Some(monitor_manager);
+        self
+    }
+
+    // Alternates discovery on and off windows until f returns false. f is called once for the devices
+    // BlueZ already knows when the run starts, then for every device found or heard again (its RSSI
+    // changed), at most once per window.
+    pub fn run<F>(&self, mut f: F) -> Result<(), BtError> where F: FnMut(Device) -> bool {
+        let filter1 = format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesAdded'", common::SERVICE_NAME);
+        let filter2 = format!("sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='{}'", common::SERVICE_NAME, device::DEVICE_INTERFACE);
+        let watch = try!(SignalWatch::new(self.adapter.conn(), &[filter1, filter2]));
+
+        let result = self.run_windows(&watch, &mut f);
+
+        try!(watch.close());
+        result
+    }
+
+    fn run_windows<F>(&self, watch: &SignalWatch, f: &mut F) -> Result<(), BtError> where F: FnMut(Device) -> bool {
+        try!(self.adapter.start_discovery());
+        let devices = match device::get_devices(&self.adapter) {
+            Ok(devices) => devices,
+            Err(e) => {
+                let _ = self.adapter.stop_discovery();
+                return Err(e);
+            }
+        };
+        for device in devices {
+            if !f(device) {
+                return self.adapter.stop_discovery();
+            }
+        }
+
+        loop {
+            let keep_going = self.process(watch, self.on_duration, f, false);
+            try!(self.adapter.stop_discovery());
+
+            if !keep_going || !self.process(watch, self.off_duration, f, true) {
+                return Ok(());
+            }
+            try!(self.adapter.start_discovery());
+        }
+    }
+
+    // Returns false once f asked to stop
+    fn process<F>(&self, watch: &SignalWatch, duration: Duration, f: &mut F, wake_on_monitor: bool) -> bool where F: FnMut(Device) -> bool {
+        let conn = self.adapter.conn();
+        let now = Instant::now();
+        let devices_found = self.monitor_manager.map(|x| x.devices_found());
+        let mut reported = HashSet::new();
+
+        while let Some(remaining) = duration.checked_sub(now.elapsed()) {
+            if !conn.registry().is_connected() {
+                break;
+            }
+            if let Some(s) = watch.next_signal(cmp::min(remaining.as_millis(), 100) as i32) {
+                let obj_path = match common::dbus_interfaces_added(&s, device::DEVICE_INTERFACE) {
+                    Some(obj_path) => Some(obj_path),
+                    None => common::dbus_properties_changed(&s, device::DEVICE_INTERFACE)
+                        .and_then(|props| if props.contains_key("RSSI") { s.path().map(|x| x.to_string()) } else { None }),
+                };
+
+                if let Some(obj_path) = obj_path {
+                    let device = Device::new(conn, &obj_path);
+                    if device.adapter_object_path() == self.adapter.object_path() && reported.insert(obj_path) && !f(device) {
+                        return false;
+                    }
+                }
+            }
+
+            if wake_on_monitor && self.monitor_manager.map(|x| x.devices_found()) != devices_found {
+                return true;
+            }
+        }
+
+        true
+    }
+}
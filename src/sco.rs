@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use libc;
+
+use address::BtAddress;
+use error::BtError;
+use socket;
+
+const BTPROTO_SCO: libc::c_int = 2;
+const SOL_SCO: libc::c_int = 17;
+const SCO_OPTIONS: libc::c_int = 1;
+const BT_DEFER_SETUP: libc::c_int = 7;
+const BT_VOICE: libc::c_int = 11;
+
+const BT_VOICE_TRANSPARENT: u16 = 0x0003;
+const BT_VOICE_CVSD_16BIT: u16 = 0x0060;
+
+#[repr(C)]
+struct SockaddrSco {
+    sco_family: libc::sa_family_t,
+    sco_bdaddr: [u8; 6],
+}
+
+impl SockaddrSco {
+    fn new(address: &BtAddress) -> SockaddrSco {
+        SockaddrSco { sco_family: libc::AF_BLUETOOTH as libc::sa_family_t, sco_bdaddr: address.bytes() }
+    }
+}
+
+#[repr(C)]
+struct ScoOptions {
+    mtu: u16,
+}
+
+// The air coding of the link. Transparent passes the frames as they are, which is what mSBC
+// (HFP wideband speech) and LC3-SWB need; the codec itself is negotiated over the HFP AT commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScoCodec {
+    Cvsd,
+    Transparent,
+}
+
+impl ScoCodec {
+    fn voice_setting(self) -> u16 {
+        match self {
+            ScoCodec::Cvsd => BT_VOICE_CVSD_16BIT,
+            ScoCodec::Transparent => BT_VOICE_TRANSPARENT,
+        }
+    }
+}
+
+fn set_codec(sock: &File, codec: ScoCodec) -> Result<(), BtError> {
+    socket::set_option(sock, socket::SOL_BLUETOOTH, BT_VOICE, &codec.voice_setting())
+}
+
+// A SCO/eSCO audio link. Every read and write is one audio packet of at most mtu() bytes.
+pub struct ScoStream {
+    address: BtAddress,
+    codec: ScoCodec,
+    file: File,
+}
+
+impl ScoStream {
+    // The device has to be connected already (usually with the HFP/HSP service level connection up)
+    pub fn connect(adapter_address: &BtAddress, address: &BtAddress, codec: ScoCodec) -> Result<ScoStream, BtError> {
+        let file = try!(socket::open(libc::SOCK_SEQPACKET, BTPROTO_SCO));
+        try!(socket::bind(&file, &SockaddrSco::new(adapter_address)));
+        try!(set_codec(&file, codec));
+        try!(socket::connect(&file, &SockaddrSco::new(address)));
+
+        Ok(ScoStream { address: *address, codec: codec, file: file })
+    }
+
+    pub fn peer_address(&self) -> &BtAddress {
+        &self.address
+    }
+
+    pub fn codec(&self) -> ScoCodec {
+        self.codec
+    }
+
+    pub fn mtu(&self) -> Result<u16, BtError> {
+        let options: ScoOptions = try!(socket::get_option(&self.file, SOL_SCO, SCO_OPTIONS));
+        Ok(options.mtu)
+    }
+
+    // None blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_RCVTIMEO, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_SNDTIMEO, timeout)
+    }
+}
+
+impl Read for ScoStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for ScoStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for ScoStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// Accepts the audio links opened by remote devices
+pub struct ScoListener {
+    codec: ScoCodec,
+    file: File,
+}
+
+impl ScoListener {
+    pub fn bind(adapter_address: &BtAddress, codec: ScoCodec) -> Result<ScoListener, BtError> {
+        let file = try!(socket::open(libc::SOCK_SEQPACKET, BTPROTO_SCO));
+        try!(socket::bind(&file, &SockaddrSco::new(adapter_address)));
+        // Deferred, so the codec can be set on each link before it's accepted
+        try!(socket::set_option(&file, socket::SOL_BLUETOOTH, BT_DEFER_SETUP, &1u32));
+        try!(socket::listen(&file, 1));
+
+        Ok(ScoListener { codec: codec, file: file })
+    }
+
+    // Blocks until a device opens a link
+    pub fn accept(&self) -> Result<ScoStream, BtError> {
+        let (mut file, peer): (File, SockaddrSco) = try!(socket::accept(&self.file));
+        try!(set_codec(&file, self.codec));
+
+        // With deferred setup, the first read completes the connection
+        let mut buf = [0u8; 1];
+        try!(file.read(&mut buf));
+
+        Ok(ScoStream { address: BtAddress::from_bytes(peer.sco_bdaddr), codec: self.codec, file: file })
+    }
+}
+
+impl AsRawFd for ScoListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
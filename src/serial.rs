@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use dbus;
+use libc;
+
+use device::Device;
+use error::BtError;
+use profile::{self, ProfileManager, ProfileOptions, ProfileRole};
+use socket;
+use uuid;
+
+static SERIAL_PORT_OBJ_PATH: &'static str = "/io/bluezrs/serial_port";
+
+// An SPP (RFCOMM) link, either accepted from a remote device or connected to one
+pub struct SerialPort {
+    device: Device,
+    file: File,
+    // The profile stays registered while the port is open
+    _manager: ProfileManager,
+}
+
+impl SerialPort {
+    // Registers an SPP server (on channel, if given) and waits for the first device to connect
+    pub fn accept(conn: &super::Connection, channel: Option<u16>, timeout: Option<Duration>) -> Result<SerialPort, BtError> {
+        let options = ProfileOptions {
+            name: Some("Serial Port".to_string()),
+            role: Some(ProfileRole::Server),
+            channel: channel,
+            ..ProfileOptions::default()
+        };
+        let (device, fd, manager) = try!(profile::accept_single(conn, SERIAL_PORT_OBJ_PATH, uuid::SERIAL_PORT_UUID, options, timeout));
+        SerialPort::new(device, fd, manager)
+    }
+
+    // Connects to the SPP service of the device
+    pub fn connect(device: &Device) -> Result<SerialPort, BtError> {
+        let options = ProfileOptions {
+            role: Some(ProfileRole::Client),
+            ..ProfileOptions::default()
+        };
+        let (device, fd, manager) = try!(profile::connect_single(device, SERIAL_PORT_OBJ_PATH, uuid::SERIAL_PORT_UUID, uuid::SERIAL_PORT_UUID, options));
+        SerialPort::new(device, fd, manager)
+    }
+
+    fn new(device: Device, fd: dbus::OwnedFd, manager: ProfileManager) -> Result<SerialPort, BtError> {
+        Ok(SerialPort { device: device, file: try!(socket::from_owned_fd(fd)), _manager: manager })
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    // None blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_RCVTIMEO, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), BtError> {
+        socket::set_timeout(self.file.as_raw_fd(), libc::SO_SNDTIMEO, timeout)
+    }
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for SerialPort {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
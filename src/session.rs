@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+
+use dbus;
+
+use adapter::{self, Adapter, AdapterProperties};
+use common;
+use device::{self, Device, DeviceProperties};
+use error::BtError;
+use registry::SignalWatch;
+
+type PropsMap = BTreeMap<String, dbus::MessageItem>;
+
+// Caches all adapters and devices with their properties. The cache is kept current by
+// process() (or handle_signal() when dispatching signals yourself).
+pub struct Session {
+    conn: super::Connection,
+    watch: SignalWatch,
+    adapters: BTreeMap<String, PropsMap>,
+    devices: BTreeMap<String, PropsMap>,
+}
+
+impl Session {
+    pub fn new(conn: &super::Connection) -> Result<Session, BtError> {
+        // Subscribe before fetching the objects, so no change falls in between
+        let rules = [
+            format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesAdded'", common::SERVICE_NAME),
+            format!("sender='{}',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesRemoved'", common::SERVICE_NAME),
+            format!("sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'", common::SERVICE_NAME),
+        ];
+        let mut session = Session {
+            conn: conn.clone(),
+            watch: try!(SignalWatch::new(conn, &rules)),
+            adapters: BTreeMap::new(),
+            devices: BTreeMap::new(),
+        };
+
+        let adapters = try!(common::dbus_get_managed_objects_with_props(conn, "/", adapter::ADAPTER_INTERFACE,
+                                                                       |_, obj_path, props_map| (obj_path.to_string(), props_map)));
+        let devices = try!(common::dbus_get_managed_objects_with_props(conn, "/", device::DEVICE_INTERFACE,
+                                                                      |_, obj_path, props_map| (obj_path.to_string(), props_map)));
+        session.adapters = adapters.into_iter().collect();
+        session.devices = devices.into_iter().collect();
+
+        Ok(session)
+    }
+
+    // Objects whose cached properties are incomplete (e.g. an invalidated address) are left out
+    pub fn adapters(&self) -> Vec<(Adapter, AdapterProperties)> {
+        self.adapters.iter()
+            .filter_map(|(obj_path, props_map)| AdapterProperties::new(props_map.clone()).ok().map(|p| (Adapter::new(&self.conn, obj_path), p)))
+            .collect()
+    }
+
+    pub fn devices(&self) -> Vec<(Device, DeviceProperties)> {
+        self.devices.iter()
+            .filter_map(|(obj_path, props_map)| DeviceProperties::new(props_map.clone()).ok().map(|p| (Device::new(&self.conn, obj_path), p)))
+            .collect()
+    }
+
+    pub fn adapter_devices(&self, adapter: &Adapter) -> Vec<(Device, DeviceProperties)> {
+        self.devices().into_iter().filter(|x| x.0.adapter_object_path() == adapter.object_path()).collect()
+    }
+
+    pub fn adapter_properties(&self, adapter: &Adapter) -> Option<AdapterProperties> {
+        self.adapters.get(adapter.object_path()).and_then(|x| AdapterProperties::new(x.clone()).ok())
+    }
+
+    pub fn device_properties(&self, device: &Device) -> Option<DeviceProperties> {
+        self.devices.get(device.object_path()).and_then(|x| DeviceProperties::new(x.clone()).ok())
+    }
+
+    // Applies pending signals to the cache, waiting up to timeout_ms for the first one
+    pub fn process(&mut self, timeout_ms: i32) {
+        let mut signal = self.watch.next_signal(timeout_ms);
+        while let Some(s) = signal {
+            self.handle_signal(&s);
+            signal = self.watch.take_queued();
+        }
+    }
+
+    // Returns true if the signal changed the cache
+    pub fn handle_signal(&mut self, s: &dbus::Message) -> bool {
+        if let Some((obj_path, props_map)) = common::dbus_interfaces_added_with_props(s, adapter::ADAPTER_INTERFACE) {
+            self.adapters.insert(obj_path, props_map);
+            return true;
+        }
+        if let Some((obj_path, props_map)) = common::dbus_interfaces_added_with_props(s, device::DEVICE_INTERFACE) {
+            self.devices.insert(obj_path, props_map);
+            return true;
+        }
+
+        if let Some(obj_path) = common::dbus_interfaces_removed(s, adapter::ADAPTER_INTERFACE) {
+            let prefix = format!("{}/", obj_path);
+            self.devices.retain(|k, _| !k.starts_with(&prefix));
+            return self.adapters.remove(&obj_path).is_some();
+        }
+        if let Some(obj_path) = common::dbus_interfaces_removed(s, device::DEVICE_INTERFACE) {
+            return self.devices.remove(&obj_path).is_some();
+        }
+
+        let obj_path = match s.path() {
+            Some(path) => path.to_string(),
+            None => return false,
+        };
+
+        let (objects, changed) = if let Some(changed) = common::dbus_properties_changed_with_invalidated(s, adapter::ADAPTER_INTERFACE) {
+            (&mut self.adapters, changed)
+        } else if let Some(changed) = common::dbus_properties_changed_with_invalidated(s, device::DEVICE_INTERFACE) {
+            (&mut self.devices, changed)
+        } else {
+            return false;
+        };
+
+        if let Some(cached) = objects.get_mut(&obj_path) {
+            let (props_map, invalidated) = changed;
+            cached.extend(props_map);
+            for name in invalidated {
+                cached.remove(&name);
+            }
+            return true;
+        }
+
+        false
+    }
+}
@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::ptr;
+
+use agent::AgentManager;
+use battery::BatteryProviderManager;
+use discovery::{DiscoverySession, StopHandle};
+use error::BtError;
+use media::MediaEndpointManager;
+use monitor::AdvertisementMonitorManager;
+#[cfg(feature = "obex")]
+use obex_agent::ObexAgentManager;
+use player::PlayerTargetManager;
+use profile::ProfileManager;
+use registry::Registration;
+
+// Stopping the handle (it only touches an atomic flag, so that's safe from a signal handler or
+// another thread) ends serve(), as well as the discovery sessions started with it
+pub struct ShutdownCoordinator<'a> {
+    conn: super::Connection,
+    stop_handle: StopHandle,
+    discovery_sessions: RefCell<Vec<DiscoverySession<'a>>>,
+    // Of the agents, profiles, monitors, endpoints... added, in any order
+    registrations: Vec<&'a Registration>,
+}
+
+impl<'a> ShutdownCoordinator<'a> {
+    pub fn new(conn: &super::Connection, stop_handle: &StopHandle) -> ShutdownCoordinator<'a> {
+        ShutdownCoordinator {
+            conn: conn.clone(),
+            stop_handle: stop_handle.clone(),
+            discovery_sessions: RefCell::new(Vec::new()),
+            registrations: Vec::new(),
+        }
+    }
+
+    // Discovery started by other processes is left alone, only the sessions added here are stopped
+    pub fn add_discovery_session(&mut self, session: DiscoverySession<'a>) {
+        self.discovery_sessions.borrow_mut().push(session);
+    }
+
+    pub fn add_agent_manager(&mut self, agent_manager: &'a AgentManager) {
+        self.registrations.push(agent_manager.registration());
+    }
+
+    pub fn add_profile_manager(&mut self, profile_manager: &'a ProfileManager) {
+        self.registrations.push(profile_manager.registration());
+    }
+
+    pub fn add_monitor_manager(&mut self, monitor_manager: &'a AdvertisementMonitorManager) {
+        self.registrations.push(monitor_manager.registration());
+    }
+
+    pub fn add_media_endpoint_manager(&mut self, endpoint_manager: &'a MediaEndpointManager) {
+        self.registrations.push(endpoint_manager.registration());
+    }
+
+    pub fn add_player_target_manager(&mut self, player_manager: &'a PlayerTargetManager) {
+        self.registrations.push(player_manager.registration());
+    }
+
+    pub fn add_battery_provider_manager(&mut self, provider_manager: &'a BatteryProviderManager) {
+        self.registrations.push(provider_manager.registration());
+    }
+
+    // obexd is on the session bus, so the agent is usually on another connection than the rest
+    #[cfg(feature = "obex")]
+    pub fn add_obex_agent_manager(&mut self, agent_manager: &'a ObexAgentManager) {
+        self.registrations.push(agent_manager.registration());
+    }
+
+    // Serves all registered objects until the stop handle is stopped, then cleans up
+    pub fn serve(&self) -> Result<(), BtError> {
+        let stop_handle = self.stop_handle.clone();
+        self.conn.registry().serve(Some(&move || !stop_handle.is_stopped()));
+
+        self.cleanup()
+    }
+
+    // Stops discovery first so no new devices show up, then unregisters the exported objects in
+    // reverse order of registration and finally answers whatever BlueZ sent in the meantime (e.g.
+    // Release calls). Every step is attempted; the first error is returned.
+    pub fn cleanup(&self) -> Result<(), BtError> {
+        let mut result = Ok(());
+
+        let discovery_sessions: Vec<DiscoverySession> = self.discovery_sessions.borrow_mut().drain(..).collect();
+        for session in discovery_sessions {
+            let r = session.stop();
+            if result.is_ok() { result = r; }
+        }
+
+        // Each connection is processed until the reply of its Unregister* call is there
+        let mut registrations: Vec<&Registration> = self.registrations.iter().cloned().filter(|x| x.is_registered()).collect();
+        registrations.sort_by_key(|x| cmp::Reverse(x.registered_seq()));
+        for registration in &registrations {
+            let r = registration.unregister();
+            if result.is_ok() { result = r; }
+        }
+
+        // BlueZ's calls are ordered before its replies, so only those read along with them are left
+        let mut conns = vec![&self.conn];
+        for registration in &self.registrations {
+            if !conns.iter().any(|x| ptr::eq(x.registry(), registration.conn().registry())) {
+                conns.push(registration.conn());
+            }
+        }
+        for conn in conns {
+            conn.registry().process(0);
+        }
+
+        result
+    }
+}
@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+use dbus;
+use libc;
+
+use error::BtError;
+
+#[cfg(any(feature = "l2cap", feature = "sco"))]
+pub const SOL_BLUETOOTH: libc::c_int = 274;
+
+fn check(r: libc::c_int) -> Result<libc::c_int, BtError> {
+    if r < 0 {
+        return Err(BtError::Io(io::Error::last_os_error()));
+    }
+    Ok(r)
+}
+
+// SO_RCVTIMEO / SO_SNDTIMEO, None blocks forever
+pub fn set_timeout(fd: RawFd, option: libc::c_int, timeout: Option<Duration>) -> Result<(), BtError> {
+    let timeout = timeout.unwrap_or_default();
+    let tv = libc::timeval { tv_sec: timeout.as_secs() as libc::time_t, tv_usec: timeout.subsec_micros() as libc::suseconds_t };
+    let r = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, option,
+                         &tv as *const libc::timeval as *const libc::c_void, mem::size_of::<libc::timeval>() as libc::socklen_t)
+    };
+    try!(check(r));
+    Ok(())
+}
+
+// Takes a socket BlueZ passed to a profile (NewConnection). BlueZ hands it over non-blocking, so
+// O_NONBLOCK is cleared for reads and writes to block (up to the SO_RCVTIMEO / SO_SNDTIMEO timeouts).
+pub fn from_owned_fd(fd: dbus::OwnedFd) -> Result<File, BtError> {
+    let file = unsafe { File::from_raw_fd(fd.into_fd()) };
+    let fd = file.as_raw_fd();
+    let flags = try!(check(unsafe { libc::fcntl(fd, libc::F_GETFL) }));
+    try!(check(unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) }));
+    Ok(file)
+}
+
+// The returned file closes the socket when dropped
+#[cfg(any(feature = "rfcomm", feature = "l2cap", feature = "sco"))]
+pub fn open(sock_type: libc::c_int, protocol: libc::c_int) -> Result<File, BtError> {
+    let fd = try!(check(unsafe { libc::socket(libc::AF_BLUETOOTH, sock_type | libc::SOCK_CLOEXEC, protocol) }));
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// addr is one of the sockaddr_* structs
+#[cfg(any(feature = "rfcomm", feature = "l2cap", feature = "sco"))]
+pub fn connect<A>(sock: &File, addr: &A) -> Result<(), BtError> {
+    try!(check(unsafe { libc::connect(sock.as_raw_fd(), addr as *const A as *const libc::sockaddr, mem::size_of::<A>() as libc::socklen_t) }));
+    Ok(())
+}
+
+#[cfg(any(feature = "l2cap", feature = "sco"))]
+pub fn bind<A>(sock: &File, addr: &A) -> Result<(), BtError> {
+    try!(check(unsafe { libc::bind(sock.as_raw_fd(), addr as *const A as *const libc::sockaddr, mem::size_of::<A>() as libc::socklen_t) }));
+    Ok(())
+}
+
+#[cfg(any(feature = "l2cap", feature = "sco"))]
+pub fn listen(sock: &File, backlog: libc::c_int) -> Result<(), BtError> {
+    try!(check(unsafe { libc::listen(sock.as_raw_fd(), backlog) }));
+    Ok(())
+}
+
+// Returns the new connection and its peer address
+#[cfg(any(feature = "l2cap", feature = "sco"))]
+pub fn accept<A>(sock: &File) -> Result<(File, A), BtError> {
+    let mut addr: A = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<A>() as libc::socklen_t;
+    let fd = try!(check(unsafe {
+        libc::accept4(sock.as_raw_fd(), &mut addr as *mut A as *mut libc::sockaddr, &mut len, libc::SOCK_CLOEXEC)
+    }));
+    Ok((unsafe { File::from_raw_fd(fd) }, addr))
+}
+
+#[cfg(any(feature = "l2cap", feature = "sco"))]
+pub fn set_option<T>(sock: &File, level: libc::c_int, option: libc::c_int, val: &T) -> Result<(), BtError> {
+    try!(check(unsafe {
+        libc::setsockopt(sock.as_raw_fd(), level, option, val as *const T as *const libc::c_void, mem::size_of::<T>() as libc::socklen_t)
+    }));
+    Ok(())
+}
+
+#[cfg(any(feature = "l2cap", feature = "sco"))]
+pub fn get_option<T>(sock: &File, level: libc::c_int, option: libc::c_int) -> Result<T, BtError> {
+    let mut val: T = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    try!(check(unsafe { libc::getsockopt(sock.as_raw_fd(), level, option, &mut val as *mut T as *mut libc::c_void, &mut len) }));
+    Ok(val)
+}
+
+// getsockname, e.g. for the PSM the kernel picked for a listener
+#[cfg(feature = "l2cap")]
+pub fn local_address<A>(sock: &File) -> Result<A, BtError> {
+    let mut addr: A = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<A>() as libc::socklen_t;
+    try!(check(unsafe { libc::getsockname(sock.as_raw_fd(), &mut addr as *mut A as *mut libc::sockaddr, &mut len) }));
+    Ok(addr)
+}
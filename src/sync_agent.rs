@@ -0,0 +1,87 @@
+use std::fmt;
+use std::sync::Arc;
+
+use agent::{Agent, AgentCapability, AgentError, AgentManager, PairingRequest, ServeHandle};
+
+pub type SharedSyncAgentT = Arc<Agent + Send + Sync>;
+
+impl fmt::Debug for Agent + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "SyncAgent(object_path: \"{}\", capability: \"{}\")", self.get_object_path(), self.get_capability().to_str())
+    }
+}
+
+// The shared agent as served by AgentManager on the worker thread
+struct SyncAgentWrapper(SharedSyncAgentT);
+
+impl Agent for SyncAgentWrapper {
+    fn get_object_path(&self) -> &str {
+        self.0.get_object_path()
+    }
+
+    fn get_capability(&self) -> AgentCapability {
+        self.0.get_capability()
+    }
+
+    fn request_pincode(&self, request: PairingRequest) -> Result<String, AgentError> {
+        self.0.request_pincode(request)
+    }
+
+    fn display_pincode(&self, request: PairingRequest, pincode: &str) -> Result<(), AgentError> {
+        self.0.display_pincode(request, pincode)
+    }
+
+    fn request_passkey(&self, request: PairingRequest) -> Result<u32, AgentError> {
+        self.0.request_passkey(request)
+    }
+
+    fn display_passkey(&self, request: PairingRequest, passkey: u32, entered: u16) {
+        self.0.display_passkey(request, passkey, entered)
+    }
+
+    fn request_confirmation(&self, request: PairingRequest, passkey: u32) -> Result<(), AgentError> {
+        self.0.request_confirmation(request, passkey)
+    }
+
+    fn request_authorization(&self, request: PairingRequest) -> Result<(), AgentError> {
+        self.0.request_authorization(request)
+    }
+
+    fn authorize_service(&self, request: PairingRequest, uuid: &str) -> Result<(), AgentError> {
+        self.0.authorize_service(request, uuid)
+    }
+
+    fn authorize_service_with_name(&self, request: PairingRequest, uuid: &str, service_name: Option<&str>) -> Result<(), AgentError> {
+        self.0.authorize_service_with_name(request, uuid, service_name)
+    }
+
+    fn cancel(&self) {
+        self.0.cancel()
+    }
+
+    fn release(&self) {
+        self.0.release()
+    }
+}
+
+// Same as AgentManager, but the agent is shared with other threads and served on a worker thread
+// with its own connection, so the rest of the application isn't blocked by the agent loop
+pub struct SyncAgentManager {
+    agent: SharedSyncAgentT,
+}
+
+impl SyncAgentManager {
+    pub fn new(agent: SharedSyncAgentT) -> SyncAgentManager {
+        SyncAgentManager { agent: agent }
+    }
+
+    pub fn agent(&self) -> &SharedSyncAgentT {
+        &self.agent
+    }
+
+    // Registers the agent on a new connection of a worker thread and serves it until the handle is stopped
+    pub fn serve_in_background(&self, default_agent: bool) -> ServeHandle {
+        let agent = self.agent.clone();
+        AgentManager::serve_in_background(move || Box::new(SyncAgentWrapper(agent)), default_agent)
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::io;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+
+use dbus;
+use libc;
+
+use error::BtError;
+
+type JobT = Box<FnOnce(&super::Connection) + Send>;
+
+// Wakes the I/O thread up when a job is queued or the connection is dropped
+struct EventFd(libc::c_int);
+
+impl EventFd {
+    fn new() -> Result<EventFd, BtError> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(BtError::Io(io::Error::last_os_error()));
+        }
+        Ok(EventFd(fd))
+    }
+
+    fn wake(&self) {
+        let val: u64 = 1;
+        unsafe { libc::write(self.0, &val as *const u64 as *const libc::c_void, 8) };
+    }
+
+    fn reset(&self) {
+        let mut val: u64 = 0;
+        unsafe { libc::read(self.0, &mut val as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+struct SyncConnectionInner {
+    bus: dbus::BusType,
+    // Taken when dropped, the I/O thread stops once it finds the queue closed
+    jobs: Mutex<Option<mpsc::Sender<JobT>>>,
+    event_fd: Arc<EventFd>,
+    thread: thread::Thread,
+}
+
+impl Drop for SyncConnectionInner {
+    fn drop(&mut self) {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner()).take();
+        self.event_fd.wake();
+    }
+}
+
+// The shared connections, so every SyncConnection of a bus uses the same one
+static CONNECTIONS: Mutex<Vec<(dbus::BusType, Weak<SyncConnectionInner>)>> = Mutex::new(Vec::new());
+
+// A Send + Sync stand-in for Connection. dbus::Connection can't leave the thread it was made on,
+// so the connection to the bus lives on its own I/O thread, which runs the jobs of every thread
+// and serves the objects registered on it in between. All SyncConnections of a bus share it, and
+// it's closed once the last of them is dropped.
+#[derive(Clone)]
+pub struct SyncConnection {
+    inner: Arc<SyncConnectionInner>,
+}
+
+impl SyncConnection {
+    pub fn system() -> Result<SyncConnection, BtError> {
+        SyncConnection::with_bus(dbus::BusType::System)
+    }
+
+    pub fn session() -> Result<SyncConnection, BtError> {
+        SyncConnection::with_bus(dbus::BusType::Session)
+    }
+
+    pub(crate) fn with_bus(bus: dbus::BusType) -> Result<SyncConnection, BtError> {
+        let mut connections = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+        connections.retain(|x| x.1.strong_count() > 0);
+        if let Some(inner) = connections.iter().find(|x| x.0 == bus).and_then(|x| x.1.upgrade()) {
+            return Ok(SyncConnection { inner: inner });
+        }
+
+        let inner = Arc::new(try!(SyncConnection::start(bus)));
+        connections.push((bus, Arc::downgrade(&inner)));
+        Ok(SyncConnection { inner: inner })
+    }
+
+    pub fn bus(&self) -> dbus::BusType {
+        self.inner.bus
+    }
+
+    // Runs f with the connection on the I/O thread and waits for its result
+    pub fn run<T, F>(&self, f: F) -> Result<T, BtError>
+        where F: FnOnce(&super::Connection) -> Result<T, BtError> + Send + 'static, T: Send + 'static {
+        if thread::current().id() == self.inner.thread.id() {
+            return Err(BtError::DBusInternal("SyncConnection::run() can't be called from its I/O thread".to_string()));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let job: JobT = Box::new(move |conn: &super::Connection| { let _ = tx.send(f(conn)); });
+        let sent = match *self.inner.jobs.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(ref jobs) => jobs.send(job).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(BtError::DBusInternal("the I/O thread has stopped".to_string()));
+        }
+        self.inner.event_fd.wake();
+
+        rx.recv().unwrap_or_else(|_| Err(BtError::DBusInternal("the I/O thread has stopped".to_string())))
+    }
+
+    fn start(bus: dbus::BusType) -> Result<SyncConnectionInner, BtError> {
+        let event_fd = Arc::new(try!(EventFd::new()));
+        let thread_event_fd = event_fd.clone();
+
+        // The connection is made on the I/O thread, which reports whether that worked
+        let (jobs_tx, jobs_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            match super::Connection::with_bus(bus) {
+                Ok(conn) => {
+                    let _ = ready_tx.send(Ok(()));
+                    SyncConnection::run_io(&conn, &jobs_rx, &thread_event_fd);
+                }
+                Err(e) => { let _ = ready_tx.send(Err(e)); }
+            }
+        });
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(BtError::DBusInternal("the I/O thread panicked".to_string())),
+        }
+
+        Ok(SyncConnectionInner { bus: bus, jobs: Mutex::new(Some(jobs_tx)), event_fd: event_fd, thread: thread.thread().clone() })
+    }
+
+    fn run_io(conn: &super::Connection, jobs: &mpsc::Receiver<JobT>, event_fd: &EventFd) {
+        let dbus_fd = conn.watch_fds().iter().find(|x| x.readable()).map(|x| x.fd());
+
+        loop {
+            loop {
+                match jobs.try_recv() {
+                    Ok(job) => job(conn),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                }
+            }
+
+            // Blocking calls of the jobs may have queued messages without the fd being readable
+            conn.registry().process(0);
+
+            // Wakes up every 100 ms anyway, like the other loops
+            let mut pfds = vec![libc::pollfd { fd: event_fd.0, events: libc::POLLIN, revents: 0 }];
+            if conn.registry().is_connected() {
+                if let Some(fd) = dbus_fd {
+                    pfds.push(libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 });
+                }
+            }
+            unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, 100) };
+
+            if pfds[0].revents & libc::POLLIN != 0 {
+                event_fd.reset();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SyncConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "SyncConnection(bus: {:?})", self.inner.bus)
+    }
+}
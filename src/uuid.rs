@@ -0,0 +1,113 @@
+pub static SERIAL_PORT_UUID: &'static str = "00001101-0000-1000-8000-00805f9b34fb";
+pub static OBEX_OPP_UUID: &'static str = "00001105-0000-1000-8000-00805f9b34fb";
+pub static OBEX_FTP_UUID: &'static str = "00001106-0000-1000-8000-00805f9b34fb";
+pub static HSP_HS_UUID: &'static str = "00001108-0000-1000-8000-00805f9b34fb";
+pub static A2DP_SOURCE_UUID: &'static str = "0000110a-0000-1000-8000-00805f9b34fb";
+pub static A2DP_SINK_UUID: &'static str = "0000110b-0000-1000-8000-00805f9b34fb";
+pub static AVRCP_TARGET_UUID: &'static str = "0000110c-0000-1000-8000-00805f9b34fb";
+pub static AVRCP_REMOTE_UUID: &'static str = "0000110e-0000-1000-8000-00805f9b34fb";
+pub static HSP_AG_UUID: &'static str = "00001112-0000-1000-8000-00805f9b34fb";
+pub static PANU_UUID: &'static str = "00001115-0000-1000-8000-00805f9b34fb";
+pub static NAP_UUID: &'static str = "00001116-0000-1000-8000-00805f9b34fb";
+pub static GN_UUID: &'static str = "00001117-0000-1000-8000-00805f9b34fb";
+pub static HFP_HS_UUID: &'static str = "0000111e-0000-1000-8000-00805f9b34fb";
+pub static HFP_AG_UUID: &'static str = "0000111f-0000-1000-8000-00805f9b34fb";
+pub static HID_UUID: &'static str = "00001124-0000-1000-8000-00805f9b34fb";
+pub static BAA_SERVICE_UUID: &'static str = "00001851-0000-1000-8000-00805f9b34fb";
+pub static BCAA_SERVICE_UUID: &'static str = "00001852-0000-1000-8000-00805f9b34fb";
+pub static PAC_SINK_UUID: &'static str = "00002bc9-0000-1000-8000-00805f9b34fb";
+pub static PAC_SOURCE_UUID: &'static str = "00002bcb-0000-1000-8000-00805f9b34fb";
+
+static BASE_UUID_SUFFIX: &'static str = "-0000-1000-8000-00805f9b34fb";
+
+static PROFILE_NAMES: &'static [(u16, &'static str)] = &[
+    (0x1101, "Serial Port"),
+    (0x1103, "Dial-up Networking"),
+    (0x1104, "IrMC Sync"),
+    (0x1105, "OBEX Object Push"),
+    (0x1106, "OBEX File Transfer"),
+    (0x1108, "Headset"),
+    (0x110a, "A2DP Audio Source"),
+    (0x110b, "A2DP Audio Sink"),
+    (0x110c, "A/V Remote Control Target"),
+    (0x110d, "Advanced Audio Distribution"),
+    (0x110e, "A/V Remote Control"),
+    (0x110f, "A/V Remote Control Controller"),
+    (0x1112, "Headset Audio Gateway"),
+    (0x1115, "PAN User"),
+    (0x1116, "PAN Network Access Point"),
+    (0x1117, "PAN Group Ad-hoc Network"),
+    (0x111e, "Handsfree"),
+    (0x111f, "Handsfree Audio Gateway"),
+    (0x1124, "Human Interface Device"),
+    (0x112d, "SIM Access"),
+    (0x112f, "Phonebook Access Server"),
+    (0x1130, "Phonebook Access"),
+    (0x1132, "Message Access Server"),
+    (0x1133, "Message Notification Server"),
+    (0x1134, "Message Access"),
+    (0x1200, "PnP Information"),
+    (0x1800, "Generic Access"),
+    (0x1801, "Generic Attribute"),
+    (0x180a, "Device Information"),
+    (0x180d, "Heart Rate"),
+    (0x180f, "Battery"),
+    (0x1812, "HID over GATT"),
+];
+
+pub fn uuid16_to_uuid128(uuid16: u16) -> String {
+    format!("0000{:04x}{}", uuid16, BASE_UUID_SUFFIX)
+}
+
+// Returns the 16-bit alias for UUIDs built on the Bluetooth base UUID
+pub fn uuid128_to_uuid16(uuid: &str) -> Option<u16> {
+    let uuid = uuid.to_lowercase();
+    if uuid.len() != 36 || !uuid.starts_with("0000") || !uuid.ends_with(BASE_UUID_SUFFIX) {
+        return None;
+    }
+    match uuid.get(4..8) {
+        Some(hex) if hex.chars().all(|x| x.is_ascii_hexdigit()) => u16::from_str_radix(hex, 16).ok(),
+        _ => None,
+    }
+}
+
+pub fn get_profile_name(uuid: &str) -> Option<&'static str> {
+    uuid128_to_uuid16(uuid).and_then(|uuid16| {
+        PROFILE_NAMES.iter().find(|x| x.0 == uuid16).map(|x| x.1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_uuids() {
+        assert_eq!(uuid16_to_uuid128(0x110b), "0000110b-0000-1000-8000-00805f9b34fb");
+        assert_eq!(uuid128_to_uuid16("0000110b-0000-1000-8000-00805f9b34fb"), Some(0x110b));
+        assert_eq!(uuid128_to_uuid16("0000180F-0000-1000-8000-00805F9B34FB"), Some(0x180f));
+        assert_eq!(uuid128_to_uuid16(&uuid16_to_uuid128(0xffff)), Some(0xffff));
+    }
+
+    #[test]
+    fn reject_invalid_uuids() {
+        assert_eq!(uuid128_to_uuid16(""), None);
+        assert_eq!(uuid128_to_uuid16("110b"), None);
+        // Not on the base UUID
+        assert_eq!(uuid128_to_uuid16("e2c56db5-dffb-48d2-b060-d0f5a71096e0"), None);
+        assert_eq!(uuid128_to_uuid16("1000110b-0000-1000-8000-00805f9b34fb"), None);
+        assert_eq!(uuid128_to_uuid16("0000110b-0000-1000-8000-00805f9b34fb0"), None);
+        // Invalid hex digits, including multi-byte characters at the 16-bit alias
+        assert_eq!(uuid128_to_uuid16("0000+10b-0000-1000-8000-00805f9b34fb"), None);
+        assert_eq!(uuid128_to_uuid16("0000xyzw-0000-1000-8000-00805f9b34fb"), None);
+        assert_eq!(uuid128_to_uuid16("0000\u{e9}10-0000-1000-8000-00805f9b34fb"), None);
+    }
+
+    #[test]
+    fn profile_names() {
+        assert_eq!(get_profile_name("0000110b-0000-1000-8000-00805f9b34fb"), Some("A2DP Audio Sink"));
+        assert_eq!(get_profile_name("0000180f-0000-1000-8000-00805f9b34fb"), Some("Battery"));
+        assert_eq!(get_profile_name("00000001-0000-1000-8000-00805f9b34fb"), None);
+        assert_eq!(get_profile_name("e2c56db5-dffb-48d2-b060-d0f5a71096e0"), None);
+    }
+}